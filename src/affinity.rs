@@ -0,0 +1,126 @@
+//! Pinning the workers to a set of cores, e.g. to keep them off the cores
+//! reserved for a latency-sensitive process on the same machine.
+//!
+//! Each worker thread pins itself when it starts, and the processes it
+//! runs, ffmpeg among them, inherit its affinity. Linux only.
+use std::fmt;
+use std::str::FromStr;
+
+/// Highest CPU number that can be pinned to, plus one.
+#[cfg(target_os = "linux")]
+const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+#[cfg(not(target_os = "linux"))]
+const MAX_CPUS: usize = 1024;
+
+/// A list of CPUs, parsed from e.g. `0-3,6`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuSet {
+    cpus: Vec<usize>,
+}
+
+impl FromStr for CpuSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cpus = Vec::new();
+        for part in s.split(',').map(str::trim) {
+            let parse = |n: &str| {
+                Some(n.trim())
+                    // parse would also take a sign
+                    .filter(|n| n.bytes().all(|b| b.is_ascii_digit()))
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .filter(|&n| n < MAX_CPUS)
+                    .ok_or_else(|| format!("invalid CPU number {:?}", n))
+            };
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (parse(first)?, parse(last)?),
+                None => (parse(part)?, parse(part)?),
+            };
+            if first > last {
+                return Err(format!("invalid CPU range {:?}", part));
+            }
+            cpus.extend(first..=last);
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(CpuSet { cpus })
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cpus: Vec<String> = self.cpus.iter().map(usize::to_string).collect();
+        f.write_str(&cpus.join(","))
+    }
+}
+
+impl CpuSet {
+    /// Restrict the calling thread, and what it spawns from now on, to
+    /// these CPUs.
+    #[cfg(target_os = "linux")]
+    pub fn pin_current_thread(&self) -> std::io::Result<()> {
+        // SAFETY: an all-zero cpu_set_t is the empty set
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &cpu in &self.cpus {
+            // SAFETY: the CPU number was checked to fit the set
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        // SAFETY: the set is valid for its full size; 0 is this thread
+        let ret = unsafe { libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn pin_current_thread(&self) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "CPU affinity is only supported on Linux",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpus(s: &str) -> Vec<usize> {
+        s.parse::<CpuSet>()
+            .unwrap_or_else(|e| panic!("{}: {}", s, e))
+            .cpus
+    }
+
+    #[test]
+    fn lists_and_ranges() {
+        assert_eq!(cpus("0-3,6"), [0, 1, 2, 3, 6]);
+        assert_eq!(cpus("5"), [5]);
+        assert_eq!(cpus(" 2 - 3 , 0 "), [0, 2, 3]);
+        // Sorted, once each
+        assert_eq!(cpus("6,0-3,2-4"), [0, 1, 2, 3, 4, 6]);
+        assert_eq!("0-3,6".parse::<CpuSet>().unwrap().to_string(), "0,1,2,3,6");
+    }
+
+    #[test]
+    fn reversed_ranges_are_refused() {
+        let refusal = "3-1".parse::<CpuSet>().unwrap_err();
+        assert!(refusal.contains("invalid CPU range \"3-1\""), "{}", refusal);
+    }
+
+    #[test]
+    fn cpus_must_fit_the_set() {
+        let last = MAX_CPUS - 1;
+        assert_eq!(cpus(&last.to_string()), [last]);
+        let refusal = MAX_CPUS.to_string().parse::<CpuSet>().unwrap_err();
+        assert!(refusal.contains("invalid CPU number"), "{}", refusal);
+        assert!(format!("0-{}", MAX_CPUS).parse::<CpuSet>().is_err());
+    }
+
+    #[test]
+    fn malformed_lists_are_refused() {
+        for s in ["", "0-3,", ",1", "a", "1-", "-1", "+1", "0-+3", "1-2-3"] {
+            assert!(s.parse::<CpuSet>().is_err(), "{:?}", s);
+        }
+    }
+}
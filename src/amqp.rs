@@ -0,0 +1,899 @@
+//! RabbitMQ job queue consumer.
+//!
+//! A minimal AMQP 0-9-1 client, enough to consume one queue without
+//! pulling in a full client library. Each message names one input, as
+//! described in [`crate::message`]. A message is acknowledged only once its job has succeeded, including delivery, so
+//! messages of a worker that dies are redelivered by the broker to another.
+//! Failed jobs are rejected, which dead-letters them if the queue is set up
+//! for it, or optionally requeued.
+//!
+//! The same queue can also be the shared [`JobQueue`] of a cluster, through
+//! [`AmqpQueue`].
+use crate::jobs::{JobRecord, JobStatus};
+use crate::message::{self, MAX_MESSAGE};
+use crate::queue::{self, JobQueue};
+use crate::source::Source;
+use crate::url::percent_decode;
+use crate::{Error, Result, Submitter, TranscodeJob, shutdown};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const FRAME_METHOD: u8 = 1;
+const FRAME_HEADER: u8 = 2;
+const FRAME_BODY: u8 = 3;
+const FRAME_HEARTBEAT: u8 = 8;
+const FRAME_END: u8 = 0xCE;
+
+/// Heartbeat interval asked for when the broker leaves it to the client.
+const HEARTBEAT: u16 = 30;
+/// Pause before reconnecting after the connection was lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A broker, parsed from `amqp://[user[:password]@]host[:port][/vhost]`.
+///
+/// As in other AMQP clients, the user and password default to `guest` and
+/// the virtual host to `/`, written `%2f` in the URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AmqpLocation {
+    pub user: String,
+    pub password: String,
+    pub host: String,
+    pub port: u16,
+    pub vhost: String,
+}
+
+impl AmqpLocation {
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("amqp://")?;
+        let (authority, vhost) = rest.split_once('/').unwrap_or((rest, ""));
+        let (userinfo, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => (Some(userinfo), host),
+            None => (None, authority),
+        };
+        let (user, password) = match userinfo {
+            Some(info) => match info.split_once(':') {
+                Some((user, password)) => {
+                    (percent_decode(user, false), percent_decode(password, false))
+                }
+                None => (percent_decode(info, false), "guest".to_string()),
+            },
+            None => ("guest".to_string(), "guest".to_string()),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+            _ => (host_port, 5672),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return None;
+        }
+        let vhost = match vhost {
+            "" => "/".to_string(),
+            vhost => percent_decode(vhost, false),
+        };
+        Some(AmqpLocation {
+            user,
+            password,
+            host: host.to_string(),
+            port,
+            vhost,
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "amqp://{}@{}:{}/{}",
+            self.user,
+            self.host,
+            self.port,
+            self.vhost.replace('/', "%2f")
+        )
+    }
+}
+
+/// What to consume and how to settle the messages.
+#[derive(Clone, Debug)]
+pub struct AmqpConfig {
+    pub queue: String,
+    /// Unacknowledged messages the broker hands out at a time.
+    pub prefetch: u16,
+    /// Put messages of failed jobs back on the queue instead of rejecting
+    /// them.
+    pub requeue_failed: bool,
+    /// Scratch directory for inputs given as URLs.
+    pub work_dir: PathBuf,
+}
+
+/// Encoder for method arguments.
+#[derive(Default)]
+struct Args(Vec<u8>);
+
+impl Args {
+    fn u8(mut self, v: u8) -> Self {
+        self.0.push(v);
+        self
+    }
+
+    fn u16(mut self, v: u16) -> Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    fn u32(mut self, v: u32) -> Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    fn u64(mut self, v: u64) -> Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    fn short(mut self, s: &str) -> Self {
+        let s = &s.as_bytes()[..s.len().min(255)];
+        self.0.push(s.len() as u8);
+        self.0.extend_from_slice(s);
+        self
+    }
+
+    fn long(mut self, s: &[u8]) -> Self {
+        self.0.extend_from_slice(&(s.len() as u32).to_be_bytes());
+        self.0.extend_from_slice(s);
+        self
+    }
+}
+
+/// Decoder for method arguments and content headers.
+struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> std::io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "truncated AMQP frame",
+            ));
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> std::io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> std::io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn short(&mut self) -> std::io::Result<String> {
+        let len = self.take(1)?[0] as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+}
+
+struct Frame {
+    kind: u8,
+    channel: u16,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Class and method IDs of a method frame.
+    fn method(&self) -> Option<(u16, u16)> {
+        if self.kind != FRAME_METHOD || self.payload.len() < 4 {
+            return None;
+        }
+        let id = |i: usize| u16::from_be_bytes([self.payload[i], self.payload[i + 1]]);
+        Some((id(0), id(2)))
+    }
+
+    /// Arguments of a method frame.
+    fn args(&self) -> Fields<'_> {
+        Fields {
+            buf: self.payload.get(4..).unwrap_or_default(),
+        }
+    }
+}
+
+/// Error for a `Connection.Close` or `Channel.Close` from the broker.
+fn closed_by_broker(frame: &Frame) -> std::io::Error {
+    let mut args = frame.args();
+    let code = args.u16().unwrap_or_default();
+    let text = args.short().unwrap_or_default();
+    std::io::Error::other(format!("broker closed the connection: {} {}", code, text))
+}
+
+/// One connection with a single channel.
+struct Connection {
+    stream: TcpStream,
+    /// Bytes read but not yet parsed into frames.
+    buf: Vec<u8>,
+    /// Largest frame the broker accepts, or 0 for no limit.
+    frame_max: usize,
+    heartbeat: Duration,
+    last_sent: Instant,
+    last_received: Instant,
+}
+
+impl Connection {
+    /// Connect and authenticate, then open channel 1.
+    fn open(location: &AmqpLocation) -> std::io::Result<Self> {
+        let stream = TcpStream::connect((location.host.as_str(), location.port))?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut conn = Connection {
+            stream,
+            buf: Vec::new(),
+            frame_max: 0,
+            heartbeat: Duration::ZERO,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+        };
+        conn.stream.write_all(b"AMQP\x00\x00\x09\x01")?;
+
+        conn.expect(10, 10)?; // Connection.Start
+        let mut response = vec![0];
+        response.extend_from_slice(location.user.as_bytes());
+        response.push(0);
+        response.extend_from_slice(location.password.as_bytes());
+        let properties = Args::default()
+            .short("product")
+            .u8(b'S')
+            .long(b"transcoderexpress");
+        let start_ok = Args::default()
+            .long(&properties.0)
+            .short("PLAIN")
+            .long(&response)
+            .short("en_US");
+        conn.send_method(0, 10, 11, start_ok)?;
+
+        let tune = conn.expect(10, 30)?;
+        let mut args = tune.args();
+        let channel_max = args.u16()?;
+        let frame_max = u32::from_be_bytes(args.take(4)?.try_into().unwrap());
+        let heartbeat = match args.u16()? {
+            0 => HEARTBEAT,
+            proposed => proposed.min(HEARTBEAT),
+        };
+        conn.frame_max = frame_max as usize;
+        conn.heartbeat = Duration::from_secs(heartbeat.into());
+        let tune_ok = Args::default()
+            .u16(channel_max)
+            .u32(frame_max)
+            .u16(heartbeat);
+        conn.send_method(0, 10, 31, tune_ok)?;
+
+        let open = Args::default().short(&location.vhost).short("").u8(0);
+        conn.send_method(0, 10, 40, open)?;
+        conn.expect(10, 41)?;
+        conn.send_method(1, 20, 10, Args::default().short(""))?;
+        conn.expect(20, 11)?;
+        Ok(conn)
+    }
+
+    fn send_frame(&mut self, kind: u8, channel: u16, payload: &[u8]) -> std::io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 8);
+        frame.push(kind);
+        frame.extend_from_slice(&channel.to_be_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame.push(FRAME_END);
+        self.stream.write_all(&frame)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn send_method(
+        &mut self,
+        channel: u16,
+        class: u16,
+        method: u16,
+        args: Args,
+    ) -> std::io::Result<()> {
+        let payload = Args::default().u16(class).u16(method).0;
+        self.send_frame(FRAME_METHOD, channel, &[&payload[..], &args.0].concat())
+    }
+
+    /// The next frame, or `None` if nothing arrived within the read timeout.
+    fn read_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        loop {
+            if self.buf.len() >= 7 {
+                let size = u32::from_be_bytes(self.buf[3..7].try_into().unwrap()) as usize;
+                if self.buf.len() >= size + 8 {
+                    if self.buf[size + 7] != FRAME_END {
+                        return Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            "malformed AMQP frame",
+                        ));
+                    }
+                    let frame = Frame {
+                        kind: self.buf[0],
+                        channel: u16::from_be_bytes([self.buf[1], self.buf[2]]),
+                        payload: self.buf[7..size + 7].to_vec(),
+                    };
+                    self.buf.drain(..size + 8);
+                    self.last_received = Instant::now();
+                    return Ok(Some(frame));
+                }
+            }
+            let mut chunk = [0u8; 16 * 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Wait for a specific method during setup.
+    fn expect(&mut self, class: u16, method: u16) -> std::io::Result<Frame> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while Instant::now() < deadline {
+            let Some(frame) = self.read_frame()? else {
+                continue;
+            };
+            match frame.method() {
+                Some(ids) if ids == (class, method) => return Ok(frame),
+                Some((10, 50) | (20, 40)) => return Err(closed_by_broker(&frame)),
+                _ => {}
+            }
+        }
+        Err(std::io::Error::new(
+            ErrorKind::TimedOut,
+            "no answer from the AMQP broker",
+        ))
+    }
+
+    /// Wait for the next method on channel 1.
+    fn method(&mut self) -> std::io::Result<Frame> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while Instant::now() < deadline {
+            let Some(frame) = self.read_frame()? else {
+                continue;
+            };
+            match frame.method() {
+                Some((10, 50) | (20, 40)) => return Err(closed_by_broker(&frame)),
+                Some(_) if frame.channel == 1 => return Ok(frame),
+                _ => {}
+            }
+        }
+        Err(std::io::Error::new(
+            ErrorKind::TimedOut,
+            "no answer from the AMQP broker",
+        ))
+    }
+
+    /// Read the content header and body following a delivery.
+    fn content(&mut self) -> std::io::Result<Vec<u8>> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let mut size = None;
+        let mut body = Vec::new();
+        while size.is_none_or(|size| (body.len() as u64) < size) {
+            if Instant::now() > deadline {
+                return Err(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    "incomplete AMQP message",
+                ));
+            }
+            let Some(frame) = self.read_frame()? else {
+                continue;
+            };
+            match (frame.kind, frame.method()) {
+                (FRAME_METHOD, Some((10, 50) | (20, 40))) => return Err(closed_by_broker(&frame)),
+                (FRAME_HEADER, _) => {
+                    let mut fields = Fields {
+                        buf: &frame.payload,
+                    };
+                    fields.take(4)?; // Class and weight
+                    let len = fields.u64()?;
+                    if len > MAX_MESSAGE {
+                        return Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("AMQP message of {} bytes", len),
+                        ));
+                    }
+                    size = Some(len);
+                }
+                (FRAME_BODY, _) => body.extend_from_slice(&frame.payload),
+                _ => {}
+            }
+        }
+        Ok(body)
+    }
+
+    /// Publish a persistent message to `queue` through the default
+    /// exchange, and wait for the broker to confirm it.
+    fn publish(&mut self, queue: &str, body: &[u8]) -> std::io::Result<()> {
+        let publish = Args::default().u16(0).short("").short(queue).u8(0);
+        self.send_method(1, 60, 40, publish)?;
+        // Properties: content type, and delivery mode 2 (persistent)
+        let header = Args::default()
+            .u16(60)
+            .u16(0)
+            .u64(body.len() as u64)
+            .u16(0x9000)
+            .short("application/json")
+            .u8(2);
+        self.send_frame(FRAME_HEADER, 1, &header.0)?;
+        let chunk = match self.frame_max {
+            0 => 128 * 1024,
+            max => max - 8,
+        };
+        for part in body.chunks(chunk) {
+            self.send_frame(FRAME_BODY, 1, part)?;
+        }
+        loop {
+            match self.method()?.method() {
+                Some((60, 80)) => return Ok(()),
+                Some((60, 120)) => return Err(std::io::Error::other("the broker refused the job")),
+                _ => {}
+            }
+        }
+    }
+
+    /// Handle whatever arrived while the connection was not in use.
+    fn drain(&mut self) -> std::io::Result<()> {
+        self.stream.set_nonblocking(true)?;
+        let drained = (|| {
+            while let Some(frame) = self.read_frame()? {
+                if let Some((10, 50) | (20, 40)) = frame.method() {
+                    return Err(closed_by_broker(&frame));
+                }
+            }
+            Ok(())
+        })();
+        self.stream.set_nonblocking(false)?;
+        drained
+    }
+
+    /// Send a heartbeat if one is due, and fail if the broker went quiet.
+    fn keep_alive(&mut self) -> std::io::Result<()> {
+        if self.heartbeat.is_zero() {
+            return Ok(());
+        }
+        if self.last_received.elapsed() > self.heartbeat * 2 {
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "missed heartbeats from the AMQP broker",
+            ));
+        }
+        if self.last_sent.elapsed() >= self.heartbeat / 2 {
+            self.send_frame(FRAME_HEARTBEAT, 0, &[])?;
+        }
+        Ok(())
+    }
+}
+
+/// A delivery whose content is still arriving.
+struct Incoming {
+    tag: u64,
+    size: Option<u64>,
+    body: Vec<u8>,
+}
+
+/// State shared between an [`AmqpSource`] and its consumer thread.
+struct Consumer {
+    location: AmqpLocation,
+    config: AmqpConfig,
+    healthy: AtomicBool,
+    stop: AtomicBool,
+}
+
+impl Consumer {
+    fn stopping(&self) -> bool {
+        self.stop.load(Ordering::SeqCst) || shutdown::requested()
+    }
+
+    /// Consume until the connection fails or the source is stopped.
+    fn session(&self, submitter: &Submitter, events: &Receiver<JobRecord>) -> std::io::Result<()> {
+        let mut conn = Connection::open(&self.location)?;
+        let qos = Args::default().u32(0).u16(self.config.prefetch).u8(0);
+        conn.send_method(1, 60, 10, qos)?;
+        conn.expect(60, 11)?;
+        let consume = Args::default()
+            .u16(0)
+            .short(&self.config.queue)
+            .short("")
+            .u8(0)
+            .u32(0);
+        conn.send_method(1, 60, 20, consume)?;
+        conn.expect(60, 21)?;
+        info!(
+            "Consuming AMQP queue {} on {}",
+            self.config.queue,
+            self.location.describe()
+        );
+        self.healthy.store(true, Ordering::SeqCst);
+
+        // Delivery tags of the jobs in flight, by job ID
+        let mut pending: HashMap<String, u64> = HashMap::new();
+        let mut incoming: Option<Incoming> = None;
+        while !self.stopping() {
+            while let Ok(record) = events.try_recv() {
+                if !record.status.finished() {
+                    continue;
+                }
+                let Some(tag) = pending.remove(&record.id) else {
+                    continue;
+                };
+                match record.status {
+                    JobStatus::Succeeded | JobStatus::Skipped => {
+                        debug!("Acknowledging AMQP message {}", tag);
+                        conn.send_method(1, 60, 80, Args::default().u64(tag).u8(0))?;
+                    }
+                    _ => {
+                        let requeue = self.config.requeue_failed;
+                        debug!("Rejecting AMQP message {} (requeue: {})", tag, requeue);
+                        let bits = if requeue { 0b10 } else { 0 };
+                        conn.send_method(1, 60, 120, Args::default().u64(tag).u8(bits))?;
+                    }
+                }
+            }
+            conn.keep_alive()?;
+
+            let Some(frame) = conn.read_frame()? else {
+                continue;
+            };
+            if frame.channel != 1 {
+                match frame.method() {
+                    Some((10, 50)) => return Err(closed_by_broker(&frame)),
+                    _ => continue,
+                }
+            }
+            match (frame.kind, frame.method()) {
+                (FRAME_METHOD, Some((60, 60))) => {
+                    // Basic.Deliver: consumer tag, delivery tag, redelivered
+                    let mut args = frame.args();
+                    args.short()?;
+                    let tag = args.u64()?;
+                    incoming = Some(Incoming {
+                        tag,
+                        size: None,
+                        body: Vec::new(),
+                    });
+                }
+                (FRAME_METHOD, Some((20, 40))) => return Err(closed_by_broker(&frame)),
+                (FRAME_METHOD, Some((60, 30))) => {
+                    return Err(std::io::Error::other("the broker cancelled the consumer"));
+                }
+                (FRAME_HEADER, _) => {
+                    if let Some(message) = incoming.as_mut() {
+                        let mut fields = Fields {
+                            buf: &frame.payload,
+                        };
+                        fields.take(4)?; // Class and weight
+                        message.size = Some(fields.u64()?);
+                    }
+                }
+                (FRAME_BODY, _) => {
+                    if let Some(message) = incoming.as_mut() {
+                        message.body.extend_from_slice(&frame.payload);
+                    }
+                }
+                _ => {}
+            }
+
+            if let Some(Incoming {
+                tag,
+                size: Some(size),
+                ..
+            }) = incoming
+            {
+                let body = &incoming.as_ref().unwrap().body;
+                if size > MAX_MESSAGE || body.len() as u64 >= size {
+                    let submitted = if size > MAX_MESSAGE {
+                        warn!("Rejecting AMQP message {} of {} bytes", tag, size);
+                        None
+                    } else {
+                        message::submit(submitter, &self.config.work_dir, "AMQP", body)
+                    };
+                    match submitted {
+                        Some(id) => {
+                            pending.insert(id, tag);
+                        }
+                        None => {
+                            conn.send_method(1, 60, 120, Args::default().u64(tag).u8(0))?;
+                        }
+                    }
+                    incoming = None;
+                }
+            }
+        }
+        // Unacknowledged messages go back to the queue
+        let close = Args::default().u16(200).short("shutdown").u16(0).u16(0);
+        let _ = conn.send_method(0, 10, 50, close);
+        Ok(())
+    }
+}
+
+/// Consumes job messages from a RabbitMQ queue.
+pub struct AmqpSource {
+    consumer: Arc<Consumer>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AmqpSource {
+    pub fn new(location: AmqpLocation, config: AmqpConfig) -> Self {
+        AmqpSource {
+            consumer: Arc::new(Consumer {
+                location,
+                config,
+                healthy: AtomicBool::new(true),
+                stop: AtomicBool::new(false),
+            }),
+            thread: None,
+        }
+    }
+}
+
+impl Source for AmqpSource {
+    fn scan(&mut self, _submitter: &Submitter) -> Result<usize> {
+        Err(Error::Config(
+            "--batch is not supported for amqp:// inputs".to_string(),
+        ))
+    }
+
+    fn watch(&mut self, submitter: Submitter) -> Result<()> {
+        let consumer = self.consumer.clone();
+        let events = submitter.jobs().subscribe();
+        self.thread = Some(std::thread::spawn(move || {
+            while !consumer.stopping() {
+                if let Err(e) = consumer.session(&submitter, &events) {
+                    warn!(
+                        "AMQP connection to {} failed: {}",
+                        consumer.location.describe(),
+                        e
+                    );
+                    consumer.healthy.store(false, Ordering::SeqCst);
+                    let retry = Instant::now() + RECONNECT_DELAY;
+                    while Instant::now() < retry && !consumer.stopping() {
+                        std::thread::sleep(Duration::from_millis(200));
+                    }
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    fn healthy(&self) -> bool {
+        self.consumer.healthy.load(Ordering::SeqCst)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} queue {}",
+            self.consumer.location.describe(),
+            self.consumer.config.queue
+        )
+    }
+}
+
+impl Drop for AmqpSource {
+    fn drop(&mut self) {
+        self.consumer.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Connection of an [`AmqpQueue`], replaced when it fails.
+#[derive(Default)]
+struct Link {
+    conn: Option<Connection>,
+    /// Counts the connections made, as a delivery tag is only valid on the
+    /// connection it came from.
+    generation: u64,
+}
+
+/// State shared between an [`AmqpQueue`] and its heartbeat thread.
+struct QueueState {
+    location: AmqpLocation,
+    queue: String,
+    requeue_failed: bool,
+    link: Mutex<Link>,
+    /// Connection generation and delivery tag of the jobs taken, by job ID.
+    taken: Mutex<HashMap<String, (u64, u64)>>,
+    stop: AtomicBool,
+}
+
+impl QueueState {
+    /// Run `f` on the connection, connecting first if needed. A connection
+    /// that failed is dropped, so the next call reconnects.
+    fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&mut Connection, u64) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let mut link = self.link.lock().unwrap();
+        let link = &mut *link;
+        let conn = match &mut link.conn {
+            Some(conn) => conn,
+            None => {
+                let mut conn = Connection::open(&self.location)?;
+                conn.send_method(1, 85, 10, Args::default().u8(0))?; // Confirm.Select
+                conn.expect(85, 11)?;
+                // Passive, so a missing queue is an error rather than
+                // silently dropping what is published
+                let declare = Args::default().u16(0).short(&self.queue).u8(1).u32(0);
+                conn.send_method(1, 50, 10, declare)?;
+                conn.expect(50, 11)?;
+                link.generation += 1;
+                link.conn.insert(conn)
+            }
+        };
+        let result = f(conn, link.generation);
+        if result.is_err() {
+            link.conn = None;
+        }
+        result
+    }
+}
+
+/// A job queue in a RabbitMQ queue, shared by every instance consuming it.
+///
+/// Jobs are published as persistent messages in the format of
+/// [`crate::queue::encode`], which [`AmqpSource`] also accepts. A job taken
+/// stays unacknowledged until it is done, so the broker redelivers the jobs
+/// of an instance whose connection is lost.
+pub struct AmqpQueue {
+    state: Arc<QueueState>,
+    heartbeat: Option<JoinHandle<()>>,
+}
+
+impl AmqpQueue {
+    /// Connect to the existing queue `queue`.
+    pub fn open(
+        location: AmqpLocation,
+        queue: &str,
+        requeue_failed: bool,
+    ) -> std::io::Result<Self> {
+        let state = Arc::new(QueueState {
+            location,
+            queue: queue.to_string(),
+            requeue_failed,
+            link: Mutex::new(Link::default()),
+            taken: Mutex::new(HashMap::new()),
+            stop: AtomicBool::new(false),
+        });
+        state.with_connection(|_, _| Ok(()))?;
+        info!(
+            "Sharing queue {} on {}",
+            state.queue,
+            state.location.describe()
+        );
+        // Jobs can take longer than the heartbeat interval, so heartbeats
+        // are kept up from a thread of their own
+        let heartbeat = {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                while !state.stop.load(Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_millis(500));
+                    let mut link = state.link.lock().unwrap();
+                    if let Some(conn) = link.conn.as_mut()
+                        && let Err(e) = conn.drain().and_then(|()| conn.keep_alive())
+                    {
+                        warn!(
+                            "AMQP connection to {} failed: {}",
+                            state.location.describe(),
+                            e
+                        );
+                        link.conn = None;
+                    }
+                }
+            })
+        };
+        Ok(AmqpQueue {
+            state,
+            heartbeat: Some(heartbeat),
+        })
+    }
+}
+
+impl JobQueue for AmqpQueue {
+    fn push(&self, job: &TranscodeJob) -> std::io::Result<()> {
+        let payload = queue::encode(job);
+        self.state
+            .with_connection(|conn, _| conn.publish(&self.state.queue, payload.as_bytes()))
+    }
+
+    fn pop(&self, wait: Duration) -> std::io::Result<Option<TranscodeJob>> {
+        let got = self.state.with_connection(|conn, generation| {
+            let get = Args::default().u16(0).short(&self.state.queue).u8(0);
+            conn.send_method(1, 60, 70, get)?;
+            let frame = conn.method()?;
+            match frame.method() {
+                Some((60, 71)) => {
+                    let tag = frame.args().u64()?;
+                    Ok(Some((generation, tag, conn.content()?)))
+                }
+                Some((60, 72)) => Ok(None),
+                _ => Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "unexpected answer to Basic.Get",
+                )),
+            }
+        })?;
+        let Some((generation, tag, body)) = got else {
+            // Poll, as a blocking consumer would take more jobs than
+            // there are free workers
+            std::thread::sleep(wait);
+            return Ok(None);
+        };
+        let payload = String::from_utf8_lossy(&body);
+        let Some(job) = queue::decode(&payload) else {
+            error!("Rejecting malformed job from the AMQP queue: {}", payload);
+            self.state.with_connection(|conn, _| {
+                conn.send_method(1, 60, 120, Args::default().u64(tag).u8(0))
+            })?;
+            return Ok(None);
+        };
+        self.state
+            .taken
+            .lock()
+            .unwrap()
+            .insert(job.id.clone(), (generation, tag));
+        Ok(Some(job))
+    }
+
+    fn done(&self, record: &JobRecord) {
+        let Some((taken_on, tag)) = self.state.taken.lock().unwrap().remove(&record.id) else {
+            return;
+        };
+        let settled = self.state.with_connection(|conn, generation| {
+            if generation != taken_on {
+                warn!(
+                    "Job {} was taken on a lost AMQP connection, so the broker redelivers it",
+                    record.id
+                );
+                return Ok(());
+            }
+            match record.status {
+                JobStatus::Succeeded | JobStatus::Skipped => {
+                    conn.send_method(1, 60, 80, Args::default().u64(tag).u8(0))
+                }
+                _ => {
+                    let bits = if self.state.requeue_failed { 0b10 } else { 0 };
+                    conn.send_method(1, 60, 120, Args::default().u64(tag).u8(bits))
+                }
+            }
+        });
+        if let Err(e) = settled {
+            error!("Failed to settle AMQP message of job {}: {}", record.id, e);
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} queue {}",
+            self.state.location.describe(),
+            self.state.queue
+        )
+    }
+}
+
+impl Drop for AmqpQueue {
+    fn drop(&mut self) {
+        self.state.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.heartbeat.take() {
+            let _ = thread.join();
+        }
+        if let Some(mut conn) = self.state.link.lock().unwrap().conn.take() {
+            let close = Args::default().u16(200).short("shutdown").u16(0).u16(0);
+            let _ = conn.send_method(0, 10, 50, close);
+        }
+    }
+}
@@ -0,0 +1,448 @@
+//! Checks of the finished outputs, recorded in a JSON sidecar next to each.
+//!
+//! With any check enabled, the output is read back once, natively and
+//! whatever the backend, and every check looks at each sample on the way.
+//! The findings go in `<output>.json`, which is delivered with the output,
+//! and those worth a look, e.g. sustained clipping, become warnings of the
+//! job: they are logged, counted in the run summary and kept in the audit
+//! log.
+mod dtmf;
+mod loudness;
+
+pub use dtmf::Tone;
+pub use loudness::Loudness;
+
+use crate::json;
+use crate::relocate;
+use crate::wav::{WavReader, WavWriter};
+use clap::ValueEnum;
+use dtmf::Detector;
+use loudness::Meter;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Samples read from the output at a time.
+const BLOCK: usize = 16_384;
+
+/// Magnitude from which a sample counts as at full scale; the positive
+/// peak of 16-bit PCM.
+const FULL_SCALE: f32 = 32767.0 / 32768.0;
+
+/// Consecutive samples at full scale that make a run of clipping, rather
+/// than a peak that happens to touch it.
+const MIN_RUN: u64 = 3;
+
+/// Length of the windows whose levels the noise floor is estimated from,
+/// in seconds, and the share of the quietest of them it is taken at.
+const NOISE_WINDOW: f64 = 0.05;
+const NOISE_PERCENTILE: f64 = 0.1;
+
+/// Floor of the levels in the sidecar, as JSON has no infinity for those
+/// of digital silence.
+const MIN_DBFS: f64 = -144.0;
+
+/// What to do about a DC offset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DcAction {
+    /// Record it, and warn about it from the threshold.
+    Detect,
+    /// Also shift the output back to zero from the threshold.
+    Remove,
+}
+
+/// DC offsets from which the action is taken, as a share of full scale.
+#[derive(Clone, Copy, Debug)]
+pub struct DcOffset {
+    pub action: DcAction,
+    pub threshold: f64,
+}
+
+/// Which checks run on every output.
+#[derive(Clone, Debug, Default)]
+pub struct Checks {
+    /// Warn about outputs whose runs of clipping add up to this much.
+    pub clipping: Option<Duration>,
+    /// RMS level in dBFS below which an output is silent, and so set aside.
+    pub silence: Option<f64>,
+    /// Measure loudness after EBU R 128.
+    pub loudness: bool,
+    /// Record the RMS level, peak, DC offset and noise floor.
+    pub signal: bool,
+    /// Detect DTMF key presses.
+    pub dtmf: bool,
+    /// Measure the DC offset, and correct it if asked to.
+    pub dc_offset: Option<DcOffset>,
+}
+
+impl Checks {
+    pub fn any(&self) -> bool {
+        self.clipping.is_some()
+            || self.silence.is_some()
+            || self.loudness
+            || self.signal
+            || self.dtmf
+            || self.dc_offset.is_some()
+    }
+}
+
+/// Runs of samples at full scale, a sign of capture gain set too high.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Clipping {
+    pub runs: u64,
+    /// Samples in all runs, and in the longest.
+    pub samples: u64,
+    pub longest: u64,
+    /// The run in progress at the sample last seen.
+    current: u64,
+}
+
+impl Clipping {
+    fn sample(&mut self, sample: f32) {
+        if sample.abs() >= FULL_SCALE {
+            self.current += 1;
+            return;
+        }
+        self.end_run();
+    }
+
+    fn end_run(&mut self) {
+        if self.current >= MIN_RUN {
+            self.runs += 1;
+            self.samples += self.current;
+            self.longest = self.longest.max(self.current);
+        }
+        self.current = 0;
+    }
+}
+
+/// Running statistics of the samples: sums for the RMS level and the DC
+/// offset, the peak, and the levels of short windows for the noise floor.
+#[derive(Clone, Debug, Default)]
+pub struct Level {
+    squares: f64,
+    sum: f64,
+    samples: u64,
+    peak: f32,
+    /// Variances of the windows done so far, and the sums of the one being
+    /// filled.
+    windows: Vec<f64>,
+    window: (f64, f64),
+    per_window: u64,
+}
+
+/// Level in dBFS of a mean square.
+fn dbfs(power: f64) -> f64 {
+    (10.0 * power.log10()).max(MIN_DBFS)
+}
+
+impl Level {
+    fn new(sample_rate: u32) -> Self {
+        Level {
+            per_window: ((f64::from(sample_rate) * NOISE_WINDOW) as u64).max(1),
+            ..Default::default()
+        }
+    }
+
+    fn sample(&mut self, sample: f32) {
+        let square = f64::from(sample) * f64::from(sample);
+        self.squares += square;
+        self.sum += f64::from(sample);
+        self.samples += 1;
+        self.peak = self.peak.max(sample.abs());
+        self.window.0 += f64::from(sample);
+        self.window.1 += square;
+        if self.samples.is_multiple_of(self.per_window) {
+            // Without the DC offset, which is reported on its own
+            let n = self.per_window as f64;
+            let mean = self.window.0 / n;
+            self.windows
+                .push((self.window.1 / n - mean * mean).max(0.0));
+            self.window = (0.0, 0.0);
+        }
+    }
+
+    /// RMS level in dBFS.
+    pub fn rms(&self) -> f64 {
+        dbfs(self.squares / self.samples.max(1) as f64)
+    }
+
+    /// Sample peak in dBFS.
+    pub fn peak(&self) -> f64 {
+        dbfs(f64::from(self.peak) * f64::from(self.peak))
+    }
+
+    /// Mean of the samples, as a share of full scale.
+    pub fn dc_offset(&self) -> f64 {
+        self.sum / self.samples.max(1) as f64
+    }
+
+    /// Level in dBFS of the quiet windows, between the words, where what
+    /// is heard is the noise of the recording; `None` for outputs shorter
+    /// than a window.
+    pub fn noise_floor(&self) -> Option<f64> {
+        let mut windows = self.windows.clone();
+        windows.sort_by(f64::total_cmp);
+        let at =
+            ((windows.len() as f64 * NOISE_PERCENTILE) as usize).min(windows.len().checked_sub(1)?);
+        Some(dbfs(windows[at]))
+    }
+}
+
+/// What the checks found in one output.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub sample_rate: u32,
+    pub clipping: Option<Clipping>,
+    pub level: Option<Level>,
+    pub loudness: Option<Loudness>,
+    /// DC offset of the output as transcoded, and whether it was removed.
+    pub dc_offset: Option<(f64, bool)>,
+    /// DTMF key presses, in order.
+    pub dtmf: Option<Vec<Tone>>,
+    /// Below the silence threshold, so not worth delivering.
+    pub silent: bool,
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    fn seconds(&self, samples: u64) -> f64 {
+        samples as f64 / f64::from(self.sample_rate.max(1))
+    }
+
+    fn to_json(&self, checks: &Checks, input: &Path, output: &Path) -> String {
+        let mut object = json::Object::new()
+            .str("input", &input.to_string_lossy())
+            .str("output", &output.to_string_lossy())
+            .raw(
+                "warnings",
+                &json::array(self.warnings.iter().map(|w| json::string(w))),
+            );
+        if let Some(level) = &self.level
+            && checks.silence.is_some()
+        {
+            object = object
+                .num("rms_dbfs", format!("{:.1}", level.rms()))
+                .num("silent", self.silent);
+        }
+        if let Some(level) = &self.level
+            && checks.signal
+        {
+            object = object.raw(
+                "signal",
+                &json::Object::new()
+                    .num("rms_dbfs", format!("{:.1}", level.rms()))
+                    .num("peak_dbfs", format!("{:.1}", level.peak()))
+                    .num("dc_offset", format!("{:.6}", level.dc_offset()))
+                    .raw(
+                        "noise_floor_dbfs",
+                        &level
+                            .noise_floor()
+                            .map_or("null".to_string(), |floor| format!("{:.1}", floor)),
+                    )
+                    .finish(),
+            );
+        }
+        if let Some(loudness) = &self.loudness {
+            object = object.raw(
+                "loudness",
+                &json::Object::new()
+                    .raw(
+                        "integrated_lufs",
+                        &loudness
+                            .integrated
+                            .map_or("null".to_string(), |lufs| format!("{:.1}", lufs)),
+                    )
+                    .num("range_lu", format!("{:.1}", loudness.range))
+                    .num(
+                        "true_peak_dbtp",
+                        format!("{:.1}", loudness.true_peak.max(-144.0)),
+                    )
+                    .finish(),
+            );
+        }
+        if let Some((offset, removed)) = self.dc_offset {
+            object = object.raw(
+                "dc_offset",
+                &json::Object::new()
+                    .num("measured", format!("{:.6}", offset))
+                    .num("removed", removed)
+                    .finish(),
+            );
+        }
+        if let Some(tones) = &self.dtmf {
+            let keys: String = tones.iter().map(|tone| tone.key).collect();
+            object = object.raw(
+                "dtmf",
+                &json::Object::new()
+                    .str("keys", &keys)
+                    .raw(
+                        "presses",
+                        &json::array(tones.iter().map(|tone| {
+                            json::Object::new()
+                                .str("key", &tone.key.to_string())
+                                .num("start_seconds", format!("{:.3}", tone.start))
+                                .num("end_seconds", format!("{:.3}", tone.end))
+                                .finish()
+                        })),
+                    )
+                    .finish(),
+            );
+        }
+        if let Some(clipping) = &self.clipping {
+            object = object.raw(
+                "clipping",
+                &json::Object::new()
+                    .num("runs", clipping.runs)
+                    .num("seconds", format!("{:.3}", self.seconds(clipping.samples)))
+                    .num(
+                        "longest_seconds",
+                        format!("{:.3}", self.seconds(clipping.longest)),
+                    )
+                    .finish(),
+            );
+        }
+        object.finish()
+    }
+}
+
+/// `<output>.json`, e.g. `call_transcoded.wav.json`.
+pub fn sidecar(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".json");
+    PathBuf::from(path)
+}
+
+/// Mean of the samples of `output`, as a share of full scale.
+fn mean(output: &Path) -> std::io::Result<f64> {
+    let mut reader = WavReader::open(output)?;
+    let (mut sum, mut count) = (0.0, 0u64);
+    let mut samples = Vec::with_capacity(BLOCK);
+    loop {
+        samples.clear();
+        if reader.read_frames(BLOCK, &mut samples)? == 0 {
+            break;
+        }
+        sum += samples.iter().map(|&s| f64::from(s)).sum::<f64>();
+        count += samples.len() as u64;
+    }
+    Ok(sum / count.max(1) as f64)
+}
+
+/// Shift `output` by `offset`, into a new file in its place, as it may be
+/// linked to a cache entry or another output.
+fn shift(output: &Path, offset: f64) -> std::io::Result<()> {
+    let mut reader = WavReader::open(output)?;
+    let format = *reader.format();
+    let partial = output.with_extension("wav.dc.partial");
+    let mut writer = WavWriter::create(&partial, format.sample_rate, format.channels)?;
+    let mut samples = Vec::with_capacity(BLOCK);
+    loop {
+        samples.clear();
+        if reader.read_frames(BLOCK / usize::from(format.channels).max(1), &mut samples)? == 0 {
+            break;
+        }
+        samples.iter_mut().for_each(|s| *s += offset as f32);
+        writer.write(&samples)?;
+    }
+    writer.finish()?;
+    std::fs::rename(&partial, output)
+}
+
+/// Run the checks on `output` and write their findings to its sidecar.
+pub(crate) fn analyze(checks: &Checks, input: &Path, output: &Path) -> std::io::Result<Report> {
+    // Before the other checks, so that they see the output as delivered
+    let mut dc_offset = None;
+    let mut dc_warning = None;
+    if let Some(dc) = checks.dc_offset {
+        let offset = mean(output)?;
+        let significant = offset.abs() >= dc.threshold;
+        let remove = significant && dc.action == DcAction::Remove;
+        if remove {
+            shift(output, -offset)?;
+        }
+        if significant {
+            dc_warning = Some(format!(
+                "Input has a DC offset of {:.4} of full scale{}",
+                offset,
+                if remove { ", removed" } else { "" }
+            ));
+        }
+        dc_offset = Some((offset, remove));
+    }
+    let mut reader = WavReader::open(output)?;
+    let channels = usize::from(reader.format().channels);
+    let mut report = Report {
+        sample_rate: reader.format().sample_rate,
+        clipping: checks.clipping.map(|_| Clipping::default()),
+        level: (checks.silence.is_some() || checks.signal)
+            .then(|| Level::new(reader.format().sample_rate)),
+        dc_offset,
+        warnings: dc_warning.into_iter().collect(),
+        ..Default::default()
+    };
+    let mut meter = checks
+        .loudness
+        .then(|| Meter::new(reader.format().sample_rate));
+    let mut detector = checks
+        .dtmf
+        .then(|| Detector::new(reader.format().sample_rate));
+    let mut samples = Vec::with_capacity(BLOCK);
+    loop {
+        samples.clear();
+        if reader.read_frames(BLOCK / channels.max(1), &mut samples)? == 0 {
+            break;
+        }
+        if let Some(clipping) = report.clipping.as_mut() {
+            samples.iter().for_each(|&s| clipping.sample(s));
+        }
+        if let Some(level) = report.level.as_mut() {
+            samples.iter().for_each(|&s| level.sample(s));
+        }
+        if let Some(meter) = meter.as_mut() {
+            samples.iter().for_each(|&s| meter.sample(s));
+        }
+        if let Some(detector) = detector.as_mut() {
+            samples.iter().for_each(|&s| detector.sample(s));
+        }
+    }
+    report.loudness = meter.map(|meter| meter.finish());
+    report.dtmf = detector.map(Detector::finish);
+
+    if let Some(clipping) = report.clipping.as_mut() {
+        clipping.end_run();
+    }
+    if let (Some(clipping), Some(limit)) = (report.clipping, checks.clipping) {
+        let clipped = report.seconds(clipping.samples);
+        if clipped > 0.0 && clipped >= limit.as_secs_f64() {
+            report.warnings.push(format!(
+                "Output clips for {:.3}s in total, up to {:.3}s at a time; the capture gain may be set too high",
+                clipped,
+                report.seconds(clipping.longest)
+            ));
+        }
+    }
+
+    if let (Some(level), Some(threshold)) = (&report.level, checks.silence) {
+        report.silent = level.rms() < threshold;
+    }
+
+    let path = sidecar(output);
+    let partial = path.with_extension("json.partial");
+    std::fs::write(&partial, report.to_json(checks, input, output) + "\n")?;
+    std::fs::rename(&partial, &path)?;
+    Ok(report)
+}
+
+/// Move a silent output and its sidecar into `silent/` next to it, out of
+/// the way of the outputs worth delivering, returning where it went.
+pub(crate) fn set_aside(output: &Path) -> std::io::Result<PathBuf> {
+    let (Some(dir), Some(name)) = (output.parent(), output.file_name()) else {
+        return Err(std::io::Error::other("output has no file name"));
+    };
+    let silent = dir.join("silent");
+    std::fs::create_dir_all(&silent)?;
+    let moved = silent.join(name);
+    relocate::move_file(output, &moved)?;
+    relocate::move_file(&sidecar(output), &sidecar(&moved))?;
+    Ok(moved)
+}
@@ -0,0 +1,174 @@
+//! Detection of DTMF key presses, with Goertzel filters at the eight tone
+//! frequencies run over overlapping blocks of the output.
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Frequencies of the rows and the columns of the keypad, in Hz.
+const ROWS: [f64; 4] = [697.0, 770.0, 852.0, 941.0];
+const COLUMNS: [f64; 4] = [1209.0, 1336.0, 1477.0, 1633.0];
+
+const KEYS: [[char; 4]; 4] = [
+    ['1', '2', '3', 'A'],
+    ['4', '5', '6', 'B'],
+    ['7', '8', '9', 'C'],
+    ['*', '0', '#', 'D'],
+];
+
+/// Length of the blocks the filters run over, in seconds; long enough
+/// to tell neighbouring frequencies apart, and started every half block.
+const BLOCK: f64 = 0.0256;
+
+/// Share of a block's energy the two tones of a key must make up, which
+/// speech and music do not come close to.
+const MIN_SHARE: f64 = 0.6;
+
+/// Times the strongest frequency of the rows, and of the columns, must be
+/// stronger than the next.
+const MIN_DOMINANCE: f64 = 4.0;
+
+/// Most that the powers of the row and the column tones may differ by,
+/// 8 dB as telephone networks allow.
+const MAX_TWIST: f64 = 6.3;
+
+/// Blocks quieter than this RMS level, in full scale, are not looked at.
+const MIN_LEVEL: f64 = 0.003;
+
+/// Blocks in a row a key must be heard in to count as pressed, about
+/// 40ms, and missed in for the press to end.
+const MIN_BLOCKS: usize = 2;
+
+/// A key press.
+#[derive(Clone, Copy, Debug)]
+pub struct Tone {
+    pub key: char,
+    /// Start and end in seconds.
+    pub start: f64,
+    pub end: f64,
+}
+
+/// A key heard in consecutive blocks, and the misses since.
+struct Run {
+    key: char,
+    first: u64,
+    last: u64,
+    blocks: usize,
+    missed: usize,
+}
+
+/// DTMF detector of one output, fed one sample at a time.
+pub struct Detector {
+    rate: f64,
+    length: usize,
+    hop: usize,
+    /// Goertzel coefficients of the rows, then the columns.
+    coefficients: [f64; 8],
+    /// The latest samples, newest last, and the samples seen.
+    window: VecDeque<f64>,
+    samples: u64,
+    run: Option<Run>,
+    tones: Vec<Tone>,
+}
+
+impl Detector {
+    pub fn new(rate: u32) -> Self {
+        let rate = f64::from(rate.max(1));
+        let length = ((rate * BLOCK).round() as usize).max(2);
+        let mut coefficients = [0.0; 8];
+        for (coefficient, frequency) in coefficients.iter_mut().zip(ROWS.iter().chain(&COLUMNS)) {
+            *coefficient = 2.0 * (2.0 * PI * frequency / rate).cos();
+        }
+        Detector {
+            rate,
+            length,
+            hop: length / 2,
+            coefficients,
+            window: VecDeque::with_capacity(length),
+            samples: 0,
+            run: None,
+            tones: Vec::new(),
+        }
+    }
+
+    pub fn sample(&mut self, sample: f32) {
+        if self.window.len() == self.length {
+            self.window.pop_front();
+        }
+        self.window.push_back(f64::from(sample));
+        self.samples += 1;
+        if self.window.len() == self.length && self.samples.is_multiple_of(self.hop as u64) {
+            let key = self.key();
+            self.block(key);
+        }
+    }
+
+    /// The key whose tones the latest block is made of, if any.
+    fn key(&self) -> Option<char> {
+        let energy: f64 = self.window.iter().map(|x| x * x).sum();
+        if (energy / self.length as f64).sqrt() < MIN_LEVEL {
+            return None;
+        }
+        let mut powers = [0.0; 8];
+        for (power, coefficient) in powers.iter_mut().zip(self.coefficients) {
+            let (mut s1, mut s2) = (0.0, 0.0);
+            for x in &self.window {
+                let s = x + coefficient * s1 - s2;
+                s2 = s1;
+                s1 = s;
+            }
+            // As a share of the block's energy: one for a pure tone
+            *power =
+                (s1 * s1 + s2 * s2 - coefficient * s1 * s2) / (energy * self.length as f64 / 2.0);
+        }
+        let strongest = |powers: &[f64]| {
+            let mut sorted: Vec<(usize, f64)> = powers.iter().copied().enumerate().collect();
+            sorted.sort_by(|a, b| b.1.total_cmp(&a.1));
+            (sorted[0].1 >= MIN_DOMINANCE * sorted[1].1).then_some(sorted[0])
+        };
+        let (row, row_power) = strongest(&powers[..4])?;
+        let (column, column_power) = strongest(&powers[4..])?;
+        let twist =
+            row_power.max(column_power) / row_power.min(column_power).max(f64::MIN_POSITIVE);
+        (row_power + column_power >= MIN_SHARE && twist <= MAX_TWIST).then(|| KEYS[row][column])
+    }
+
+    /// Follow the press in progress with the key of the latest block.
+    fn block(&mut self, key: Option<char>) {
+        let at = self.samples;
+        match (&mut self.run, key) {
+            (Some(run), Some(key)) if run.key == key => {
+                run.last = at;
+                run.blocks += 1;
+                run.missed = 0;
+            }
+            (Some(run), None) if run.missed + 1 < MIN_BLOCKS => run.missed += 1,
+            (_, key) => {
+                self.end_run();
+                self.run = key.map(|key| Run {
+                    key,
+                    first: at,
+                    last: at,
+                    blocks: 1,
+                    missed: 0,
+                });
+            }
+        }
+    }
+
+    fn end_run(&mut self) {
+        if let Some(run) = self.run.take()
+            && run.blocks >= MIN_BLOCKS
+        {
+            self.tones.push(Tone {
+                key: run.key,
+                start: (run.first - self.length as u64) as f64 / self.rate,
+                end: run.last as f64 / self.rate,
+            });
+        }
+    }
+
+    /// The key presses heard, in order.
+    pub fn finish(mut self) -> Vec<Tone> {
+        self.end_run();
+        self.tones
+    }
+}
@@ -0,0 +1,207 @@
+//! Loudness meter after EBU R 128: integrated loudness and loudness range
+//! from K-weighted, gated blocks, and the true peak from 4x oversampling.
+use std::f64::consts::PI;
+
+/// Length of the gating sub-blocks, which momentary and short-term blocks
+/// are made of, in seconds.
+const STEP: f64 = 0.1;
+
+/// Sub-blocks in a momentary block (400ms) and a short-term one (3s).
+const MOMENTARY: usize = 4;
+const SHORT_TERM: usize = 30;
+
+/// Blocks quieter than this are left out of either measure.
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Below the mean of the blocks above the absolute gate, in LU, at which
+/// blocks are left out of the integrated loudness and the range.
+const INTEGRATED_GATE: f64 = 10.0;
+const RANGE_GATE: f64 = 20.0;
+
+/// Oversampling factor and taps per phase of the true-peak interpolator.
+const OVERSAMPLE: usize = 4;
+const TAPS: usize = 12;
+
+/// A second-order IIR filter section.
+#[derive(Clone, Copy, Debug, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The two stages of the K-weighting filter, a high shelf for the head
+/// and a high-pass, for `rate`, as in ITU-R BS.1770.
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+    [shelf, high_pass]
+}
+
+/// Loudness in LUFS of a mean square.
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// Mean of the powers, in LUFS.
+fn mean_lufs(powers: &[f64]) -> f64 {
+    lufs(powers.iter().sum::<f64>() / powers.len().max(1) as f64)
+}
+
+/// Loudness of one output, fed one channel of samples at a time.
+pub struct Meter {
+    filters: [Biquad; 2],
+    /// Sum of squares of the sub-blocks done so far, and of the one
+    /// being filled.
+    steps: Vec<f64>,
+    current: f64,
+    in_current: usize,
+    per_step: usize,
+    /// Polyphase interpolator and the latest input samples, newest last.
+    phases: Vec<[f64; TAPS]>,
+    history: [f64; TAPS],
+    peak: f64,
+}
+
+/// What a [`Meter`] measured.
+#[derive(Clone, Copy, Debug)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS; `None` for an output with nothing
+    /// above the absolute gate.
+    pub integrated: Option<f64>,
+    /// Loudness range in LU.
+    pub range: f64,
+    /// True peak in dBTP.
+    pub true_peak: f64,
+}
+
+impl Meter {
+    pub fn new(rate: u32) -> Self {
+        let rate = f64::from(rate.max(1));
+        // Windowed sinc cutting off at the input's Nyquist frequency
+        let phases = (0..OVERSAMPLE)
+            .map(|phase| {
+                let mut taps = [0.0; TAPS];
+                for (i, tap) in taps.iter_mut().enumerate() {
+                    let n = (i * OVERSAMPLE + phase) as f64;
+                    let length = (TAPS * OVERSAMPLE) as f64;
+                    let x = (n - length / 2.0) / OVERSAMPLE as f64;
+                    let sinc = if x == 0.0 {
+                        1.0
+                    } else {
+                        (PI * x).sin() / (PI * x)
+                    };
+                    let window = 0.5 - 0.5 * (2.0 * PI * n / length).cos();
+                    *tap = sinc * window;
+                }
+                taps
+            })
+            .collect();
+        Meter {
+            filters: k_weighting(rate),
+            steps: Vec::new(),
+            current: 0.0,
+            in_current: 0,
+            per_step: ((rate * STEP).round() as usize).max(1),
+            phases,
+            history: [0.0; TAPS],
+            peak: 0.0,
+        }
+    }
+
+    pub fn sample(&mut self, sample: f32) {
+        let x = f64::from(sample);
+        let shelved = self.filters[0].process(x);
+        let weighted = self.filters[1].process(shelved);
+        self.current += weighted * weighted;
+        self.in_current += 1;
+        if self.in_current == self.per_step {
+            self.steps.push(self.current);
+            self.current = 0.0;
+            self.in_current = 0;
+        }
+
+        self.history.rotate_left(1);
+        self.history[TAPS - 1] = x;
+        self.peak = self.peak.max(x.abs());
+        for taps in &self.phases {
+            let y: f64 = taps
+                .iter()
+                .rev()
+                .zip(&self.history)
+                .map(|(tap, x)| tap * x)
+                .sum();
+            self.peak = self.peak.max(y.abs());
+        }
+    }
+
+    /// Mean squares of the blocks of `length` sub-blocks, one every
+    /// sub-block.
+    fn blocks(&self, length: usize) -> Vec<f64> {
+        let samples = (length * self.per_step) as f64;
+        self.steps
+            .windows(length)
+            .map(|window| window.iter().sum::<f64>() / samples)
+            .collect()
+    }
+
+    pub fn finish(&self) -> Loudness {
+        let gated = |blocks: Vec<f64>, relative: f64| -> Vec<f64> {
+            let loud: Vec<f64> = blocks
+                .into_iter()
+                .filter(|&power| lufs(power) > ABSOLUTE_GATE)
+                .collect();
+            let threshold = mean_lufs(&loud) - relative;
+            loud.into_iter()
+                .filter(|&power| lufs(power) > threshold)
+                .collect()
+        };
+        let momentary = gated(self.blocks(MOMENTARY), INTEGRATED_GATE);
+        let mut short_term: Vec<f64> = gated(self.blocks(SHORT_TERM), RANGE_GATE)
+            .into_iter()
+            .map(lufs)
+            .collect();
+        short_term.sort_by(f64::total_cmp);
+        let range = match short_term.len() {
+            0 => 0.0,
+            n => {
+                let at = |p: f64| short_term[((p * n as f64).ceil() as usize).clamp(1, n) - 1];
+                at(0.95) - at(0.10)
+            }
+        };
+        Loudness {
+            integrated: (!momentary.is_empty()).then(|| mean_lufs(&momentary)),
+            range,
+            true_peak: 20.0 * self.peak.log10(),
+        }
+    }
+}
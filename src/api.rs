@@ -0,0 +1,223 @@
+//! REST API for submitting jobs and following their status.
+//!
+//! - `POST /jobs` with `{"path": "..."}` queues a file already readable by
+//!   the daemon, and with `{"url": "..."}` downloads an HTTP(S) URL into
+//!   the scratch directory first. Both answer with the new job's record.
+//! - `GET /jobs/{id}` returns one job's record.
+//! - `GET /jobs`, optionally with `?status=failed` or another status, lists
+//!   the known jobs, oldest first.
+//! - `DELETE /jobs/{id}` cancels a queued or running job.
+//! - `POST /jobs/bump` with `{"id": "..."}` or `{"path": "..."}` moves a
+//!   queued job, found by its ID or its input, ahead of the jobs of normal
+//!   priority and answers with its record.
+//! - `GET /events` streams the record of every job that changes, one JSON
+//!   object per line, with empty lines as keep-alives. With `?id=` it
+//!   follows a single job and ends once that job has finished.
+//!
+//! These are the same operations as the `TranscoderExpress` gRPC service
+//! described in `proto/transcoderexpress.proto`.
+use crate::http::{Endpoint, Request, Response};
+use crate::jobs::JobStatus;
+use crate::{Submitter, fetch, json, shutdown};
+use log::info;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+
+/// Largest `POST /jobs` body accepted, in bytes.
+const MAX_BODY: u64 = 64 * 1024;
+/// Idle time after which an event stream sends a keep-alive line.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// The `/jobs` resource.
+pub struct JobsEndpoint {
+    submitter: Submitter,
+    /// Where URL inputs are downloaded to.
+    dir: PathBuf,
+}
+
+impl JobsEndpoint {
+    pub fn new(submitter: Submitter, dir: impl Into<PathBuf>) -> Self {
+        JobsEndpoint {
+            submitter,
+            dir: dir.into(),
+        }
+    }
+
+    fn record(&self, id: &str, status: u16) -> Response {
+        match self.submitter.jobs().get(id) {
+            Some(record) => Response::json(status, record.to_json()),
+            None => Response::error(404, "no such job"),
+        }
+    }
+
+    fn list(&self, request: &Request) -> Response {
+        let status = match request.query("status") {
+            None => None,
+            Some(name) => match JobStatus::parse(name) {
+                Some(status) => Some(status),
+                None => return Response::error(400, &format!("unknown status: {}", name)),
+            },
+        };
+        let records = self.submitter.jobs().list(status);
+        let body = json::Object::new()
+            .raw("jobs", &json::array(records.iter().map(|r| r.to_json())))
+            .finish();
+        Response::json(200, body)
+    }
+
+    fn cancel(&self, id: &str) -> Response {
+        let Some(record) = self.submitter.jobs().cancel(id) else {
+            return Response::error(404, "no such job");
+        };
+        let status = match record.status {
+            JobStatus::Cancelled => 200,
+            // The transcoder is being stopped
+            JobStatus::Running => 202,
+            _ => return Response::error(409, "job already finished"),
+        };
+        info!("Cancelled job {} through the API", id);
+        Response::json(status, record.to_json())
+    }
+
+    fn watch(&self, request: &Request) -> Response {
+        let jobs = self.submitter.jobs().clone();
+        let events = jobs.subscribe();
+        let only = request.query("id").map(str::to_string);
+        let current = match &only {
+            Some(id) => match jobs.get(id) {
+                Some(record) => Some(record),
+                None => return Response::error(404, "no such job"),
+            },
+            None => None,
+        };
+        Response::stream(
+            "application/x-ndjson",
+            Box::new(move |out: &mut dyn Write| {
+                if let Some(record) = current {
+                    writeln!(out, "{}", record.to_json())?;
+                    out.flush()?;
+                    if record.status.finished() {
+                        return Ok(());
+                    }
+                }
+                let mut last = Instant::now();
+                while !shutdown::requested() {
+                    match events.recv_timeout(Duration::from_secs(1)) {
+                        Ok(record) => {
+                            if only.as_ref().is_some_and(|id| *id != record.id) {
+                                continue;
+                            }
+                            writeln!(out, "{}", record.to_json())?;
+                            out.flush()?;
+                            last = Instant::now();
+                            if only.is_some() && record.status.finished() {
+                                break;
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) if last.elapsed() >= KEEP_ALIVE => {
+                            writeln!(out)?;
+                            out.flush()?;
+                            last = Instant::now();
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    fn bump(&self, request: &mut Request) -> Response {
+        let json = match read_body(request) {
+            Ok(json) => json,
+            Err(response) => return response,
+        };
+        let jobs = self.submitter.jobs();
+        let id = match (
+            json.get("id").and_then(|v| v.as_str()),
+            json.get("path").and_then(|v| v.as_str()),
+        ) {
+            (Some(id), _) => id.to_string(),
+            (None, Some(path)) => match jobs.queued_with_input(Path::new(path)) {
+                Some(id) => id,
+                None => return Response::error(404, "no queued job of this path"),
+            },
+            (None, None) => return Response::error(400, "expected an \"id\" or \"path\" field"),
+        };
+        match jobs.bump(&id) {
+            Some(record) if record.status == JobStatus::Queued => {
+                info!("Moved job {} to the front of the queue through the API", id);
+                Response::json(200, record.to_json())
+            }
+            Some(_) => Response::error(409, "job is not queued"),
+            None => Response::error(404, "no such job"),
+        }
+    }
+
+    fn submit(&self, request: &mut Request) -> Response {
+        let json = match read_body(request) {
+            Ok(json) => json,
+            Err(response) => return response,
+        };
+        let field = |name| json.get(name).and_then(|v| v.as_str());
+
+        if let Some(path) = field("path") {
+            let path = Path::new(path);
+            if !path.is_absolute() || !path.is_file() {
+                return Response::error(400, "path must be an absolute path to a regular file");
+            }
+            let id = self.submitter.submit(path);
+            info!("Queued {:?} through the API as job {}", path, id);
+            self.record(&id, 202)
+        } else if let Some(url) = field("url") {
+            if !fetch::supported(url) {
+                return Response::error(400, "only http:// and https:// URLs are supported");
+            }
+            let id = fetch::submit_url(&self.submitter, &self.dir, url);
+            self.record(&id, 202)
+        } else {
+            Response::error(400, "expected a \"path\" or \"url\" field")
+        }
+    }
+}
+
+/// The JSON body of a request, or the response refusing it.
+fn read_body(request: &mut Request) -> Result<json::Value, Response> {
+    if request.content_length().is_none_or(|n| n > MAX_BODY) {
+        return Err(Response::error(
+            411,
+            "a JSON body of at most 64 KiB is required",
+        ));
+    }
+    let mut body = String::new();
+    if request.body.read_to_string(&mut body).is_err() {
+        return Err(Response::error(400, "body is not valid UTF-8"));
+    }
+    json::parse(&body).ok_or_else(|| Response::error(400, "body is not valid JSON"))
+}
+
+impl Endpoint for JobsEndpoint {
+    fn handle(&self, request: &mut Request) -> Option<Response> {
+        if request.path == "/events" {
+            return Some(match request.method.as_str() {
+                "GET" => self.watch(request),
+                _ => Response::error(405, "use GET"),
+            });
+        }
+        let rest = request.path.strip_prefix("/jobs")?;
+        Some(match (request.method.as_str(), rest) {
+            ("GET", "" | "/") => self.list(request),
+            ("POST", "" | "/") => self.submit(request),
+            ("POST", "/bump") => self.bump(request),
+            (_, "/bump") => Response::error(405, "use POST"),
+            ("GET", id) if id.starts_with('/') => self.record(&id[1..], 200),
+            ("DELETE", id) if id.starts_with('/') => self.cancel(&id[1..]),
+            (_, "" | "/") => Response::error(405, "use GET or POST"),
+            (_, id) if id.starts_with('/') => Response::error(405, "use GET or DELETE"),
+            _ => return None,
+        })
+    }
+}
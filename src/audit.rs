@@ -0,0 +1,267 @@
+//! Append-only JSONL audit log.
+//!
+//! One record per job, independent of the diagnostic log and its level. The
+//! file is reopened in append mode for every record and synced before
+//! returning, so external log rotation and crashes never lose or truncate
+//! earlier entries. The log doubles as the durable history of the jobs,
+//! read back by [`read`] and exported by [`export`].
+use crate::JobResult;
+use crate::host;
+use crate::json;
+use crate::sha256;
+use clap::ValueEnum;
+use log::error;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A job as the audit log recorded it.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub started: SystemTime,
+    pub finished: SystemTime,
+    pub input: PathBuf,
+    pub input_sha256: Option<String>,
+    pub input_bytes: u64,
+    pub output: PathBuf,
+    pub output_sha256: Option<String>,
+    pub output_bytes: u64,
+    /// Seconds of audio in the output; records written before it was
+    /// logged lack it.
+    pub audio_seconds: Option<f64>,
+    pub succeeded: bool,
+    pub error_class: Option<String>,
+}
+
+impl Entry {
+    fn parse(line: &str) -> Option<Entry> {
+        let record = json::parse(line)?;
+        let text = |key| record.get(key).and_then(|v| v.as_str());
+        let time = |key| humantime::parse_rfc3339(text(key)?).ok();
+        let number = |key| record.get(key).and_then(|v| v.as_u64()).unwrap_or(0);
+        Some(Entry {
+            started: time("started")?,
+            finished: time("finished")?,
+            input: PathBuf::from(text("input")?),
+            input_sha256: text("input_sha256").map(str::to_string),
+            input_bytes: number("input_bytes"),
+            output: PathBuf::from(text("output")?),
+            output_sha256: text("output_sha256").map(str::to_string),
+            output_bytes: number("output_bytes"),
+            audio_seconds: record.get("audio_seconds").and_then(|v| v.as_f64()),
+            succeeded: text("status")? == "succeeded",
+            error_class: text("error_class").map(str::to_string),
+        })
+    }
+}
+
+impl Entry {
+    /// The directory of the input: the first one below `root`, e.g. that
+    /// of a customer, or else the one it is in.
+    pub fn directory(&self, root: Option<&Path>) -> PathBuf {
+        root.and_then(|root| self.input.strip_prefix(root).ok())
+            .and_then(|relative| {
+                let mut components = relative.components();
+                let first = components.next()?;
+                // A file right in the root has no directory below it
+                components.next().map(|_| PathBuf::from(first.as_os_str()))
+            })
+            .unwrap_or_else(|| self.input.parent().unwrap_or(Path::new("")).to_path_buf())
+    }
+}
+
+/// Formats of [`export`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// Comma-separated values with a header row.
+    Csv,
+    /// A JSON array of objects.
+    Json,
+}
+
+/// Columns of an export, in order.
+const COLUMNS: [&str; 14] = [
+    "started",
+    "finished",
+    "status",
+    "error_class",
+    "directory",
+    "input",
+    "input_bytes",
+    "input_sha256",
+    "output",
+    "output_bytes",
+    "output_sha256",
+    "audio_seconds",
+    "audio_minutes",
+    "elapsed_seconds",
+];
+
+/// `field` quoted for CSV where it needs to be.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Write `entries` to `out` in `format`, with the directory of each input
+/// as [`Entry::directory`] finds it below `root`.
+pub fn export(
+    entries: &[Entry],
+    format: ExportFormat,
+    root: Option<&Path>,
+    out: &mut impl Write,
+) -> std::io::Result<()> {
+    let timestamp = |t: SystemTime| humantime::format_rfc3339_millis(t).to_string();
+    let rows = entries.iter().map(|entry| {
+        let audio = entry.audio_seconds.unwrap_or(0.0);
+        let elapsed = entry
+            .finished
+            .duration_since(entry.started)
+            .unwrap_or_default()
+            .as_secs_f64();
+        [
+            timestamp(entry.started),
+            timestamp(entry.finished),
+            if entry.succeeded {
+                "succeeded"
+            } else {
+                "failed"
+            }
+            .to_string(),
+            entry.error_class.clone().unwrap_or_default(),
+            entry.directory(root).to_string_lossy().into_owned(),
+            entry.input.to_string_lossy().into_owned(),
+            entry.input_bytes.to_string(),
+            entry.input_sha256.clone().unwrap_or_default(),
+            entry.output.to_string_lossy().into_owned(),
+            entry.output_bytes.to_string(),
+            entry.output_sha256.clone().unwrap_or_default(),
+            format!("{:.3}", audio),
+            format!("{:.3}", audio / 60.0),
+            format!("{:.3}", elapsed),
+        ]
+    });
+    match format {
+        ExportFormat::Csv => {
+            writeln!(out, "{}", COLUMNS.join(","))?;
+            for row in rows {
+                let fields: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        }
+        ExportFormat::Json => {
+            let objects = rows.map(|row| {
+                COLUMNS
+                    .iter()
+                    .zip(&row)
+                    .fold(
+                        json::Object::new(),
+                        |object, (column, value)| match *column {
+                            "input_bytes" | "output_bytes" | "audio_seconds" | "audio_minutes"
+                            | "elapsed_seconds" => object.num(column, value),
+                            _ if value.is_empty() => object,
+                            _ => object.str(column, value),
+                        },
+                    )
+                    .finish()
+            });
+            writeln!(out, "{}", json::array(objects))?;
+        }
+    }
+    Ok(())
+}
+
+/// Every record of the audit log at `path`, oldest first; lines that are
+/// not records, e.g. one cut short by a crash, are skipped.
+pub fn read(path: &Path) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for line in BufReader::new(std::fs::File::open(path)?).lines() {
+        if let Some(entry) = Entry::parse(&line?) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Writes audit records to a JSONL file.
+pub struct AuditLog {
+    path: PathBuf,
+    user: String,
+    host: String,
+}
+
+/// Hash a file for the audit record, or `None` if it cannot be read.
+fn hash(path: &Path) -> Option<String> {
+    sha256::file(path).ok()
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        AuditLog {
+            path,
+            user: host::user(),
+            host: host::name(),
+        }
+    }
+
+    /// Append a record for a finished job.
+    pub fn record(&self, outcome: &JobResult) {
+        let timestamp = |t: SystemTime| humantime::format_rfc3339_millis(t).to_string();
+        let finished = outcome.started_at + outcome.elapsed;
+        let output_hash = match outcome.error {
+            None => hash(&outcome.output),
+            Some(_) => None,
+        };
+        let mut record = json::Object::new()
+            .str("started", &timestamp(outcome.started_at))
+            .str("finished", &timestamp(finished))
+            .str("user", &self.user)
+            .str("host", &self.host)
+            .num("pid", std::process::id())
+            .str("input", &outcome.input.to_string_lossy())
+            .opt_str("input_sha256", hash(&outcome.input).as_deref())
+            .num("input_bytes", outcome.input_bytes)
+            .str("output", &outcome.output.to_string_lossy())
+            .opt_str("output_sha256", output_hash.as_deref())
+            .num("output_bytes", outcome.output_bytes);
+        if let Some(audio) = outcome.audio {
+            record = record.num("audio_seconds", audio.as_secs_f64());
+        }
+        let record = record
+            .str(
+                "status",
+                if outcome.error.is_none() {
+                    "succeeded"
+                } else {
+                    "failed"
+                },
+            )
+            .opt_str("error_class", outcome.error_class())
+            .raw(
+                "warnings",
+                &json::array(outcome.warnings.iter().map(|w| json::string(w))),
+            )
+            .raw(
+                "command",
+                &json::array(outcome.command.iter().map(|a| json::string(a))),
+            )
+            .finish();
+
+        if let Err(e) = self.append(&record) {
+            error!("Failed to write audit record to {:?}: {}", self.path, e);
+        }
+    }
+
+    fn append(&self, record: &str) -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(format!("{}\n", record).as_bytes())?;
+        file.sync_data()
+    }
+}
@@ -0,0 +1,427 @@
+//! Azure Blob Storage, through the Blob REST API and the curl executable.
+//!
+//! Requests are authorized with a shared access signature from
+//! `AZURE_STORAGE_SAS_TOKEN` or, without one, with a managed identity token
+//! from the App Service identity endpoint or the instance metadata service.
+//! URLs and tokens are handed to curl on its standard input, so neither
+//! shows up in the process list.
+use crate::curl::{self, scratch_path, uri_encode, xml_elements};
+use crate::json::{self, Value};
+use crate::sink::RemoteTarget;
+use crate::source::{RemoteObject, RemoteStore};
+use log::debug;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const API_VERSION: &str = "2021-08-06";
+const RESOURCE: &str = "https://storage.azure.com/";
+const IMDS_TOKEN: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+/// Files larger than this are uploaded as separate blocks.
+const BLOCK_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Smallest block size for block uploads.
+const BLOCK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Most blocks a blob can be committed from.
+const MAX_BLOCKS: u64 = 50_000;
+
+/// Tokens are refreshed this long before they expire.
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+
+/// A storage account, container and blob name prefix, parsed from
+/// `az://account/container/prefix`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AzureLocation {
+    pub account: String,
+    pub container: String,
+    /// Name prefix, empty or ending in `/`.
+    pub prefix: String,
+}
+
+impl AzureLocation {
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("az://")?;
+        let (account, rest) = rest.split_once('/')?;
+        let (container, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if account.is_empty() || container.is_empty() {
+            return None;
+        }
+        let mut prefix = prefix.trim_matches('/').to_string();
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        Some(AzureLocation {
+            account: account.to_string(),
+            container: container.to_string(),
+            prefix,
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("az://{}/{}/{}", self.account, self.container, self.prefix)
+    }
+}
+
+/// Connection settings shared by the Azure source and sink.
+#[derive(Clone, Debug, Default)]
+pub struct AzureConfig {
+    /// Custom blob endpoint including the account, e.g. Azurite's
+    /// `http://127.0.0.1:10000/devstoreaccount1`.
+    pub endpoint: Option<String>,
+}
+
+enum Credentials {
+    /// Query string appended to every request.
+    Sas(String),
+    ManagedIdentity {
+        token: Mutex<Option<(String, Instant)>>,
+    },
+}
+
+/// Fetch a managed identity token for Azure Storage and its lifetime.
+fn identity_token() -> std::io::Result<(String, Duration)> {
+    let resource = uri_encode(RESOURCE, false);
+    let response = match (
+        std::env::var("IDENTITY_ENDPOINT"),
+        std::env::var("IDENTITY_HEADER"),
+    ) {
+        // App Service, Functions and Container Apps
+        (Ok(endpoint), Ok(secret)) => curl::run(
+            "Azure identity",
+            [
+                "-K".to_string(),
+                "-".to_string(),
+                format!("{}?resource={}&api-version=2019-08-01", endpoint, resource),
+            ],
+            Some(
+                format!(
+                    "header = {}\n",
+                    curl::quote(&format!("X-IDENTITY-HEADER: {}", secret))
+                )
+                .as_bytes(),
+            ),
+        )?,
+        _ => {
+            let mut url = format!(
+                "{}?api-version=2018-02-01&resource={}",
+                IMDS_TOKEN, resource
+            );
+            if let Ok(client_id) = std::env::var("AZURE_CLIENT_ID") {
+                url.push_str(&format!("&client_id={}", uri_encode(&client_id, false)));
+            }
+            curl::run("Azure identity", ["-H", "Metadata: true", &url], None)?
+        }
+    };
+    let response = json::parse(&String::from_utf8_lossy(&response))
+        .ok_or_else(|| std::io::Error::other("invalid token response"))?;
+    let token = response
+        .get("access_token")
+        .and_then(Value::as_str)
+        .ok_or_else(|| std::io::Error::other("token response without access_token"))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let lifetime = response
+        .get("expires_in")
+        .and_then(Value::as_u64)
+        .or_else(|| {
+            let expires_on = response.get("expires_on")?.as_u64()?;
+            Some(expires_on.saturating_sub(now))
+        })
+        .unwrap_or(3600);
+    Ok((token.to_string(), Duration::from_secs(lifetime)))
+}
+
+/// Authorized requests against one container.
+pub struct AzureClient {
+    /// URL of the container, without a trailing slash.
+    base: String,
+    credentials: Credentials,
+    /// Directory for partial downloads and upload blocks.
+    work_dir: PathBuf,
+}
+
+impl AzureClient {
+    pub fn new(
+        account: &str,
+        container: &str,
+        config: &AzureConfig,
+        work_dir: &Path,
+    ) -> std::io::Result<Self> {
+        let endpoint = match &config.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://{}.blob.core.windows.net", account),
+        };
+        let credentials = match std::env::var("AZURE_STORAGE_SAS_TOKEN") {
+            Ok(sas) if !sas.is_empty() => Credentials::Sas(sas.trim_start_matches('?').to_string()),
+            _ => Credentials::ManagedIdentity {
+                token: Mutex::new(None),
+            },
+        };
+        std::fs::create_dir_all(work_dir)?;
+        let client = AzureClient {
+            base: format!("{}/{}", endpoint, uri_encode(container, false)),
+            credentials,
+            work_dir: work_dir.to_path_buf(),
+        };
+        // Fail at startup rather than on the first job
+        client.bearer()?;
+        Ok(client)
+    }
+
+    /// Managed identity token, refreshed when close to expiry; `None` when
+    /// a SAS is used instead.
+    fn bearer(&self) -> std::io::Result<Option<String>> {
+        let Credentials::ManagedIdentity { token } = &self.credentials else {
+            return Ok(None);
+        };
+        let mut cached = token.lock().unwrap();
+        if let Some((token, expires)) = cached.as_ref()
+            && Instant::now() + TOKEN_MARGIN < *expires
+        {
+            return Ok(Some(token.clone()));
+        }
+        let (token, lifetime) = identity_token()?;
+        debug!("Refreshed Azure access token");
+        *cached = Some((token.clone(), Instant::now() + lifetime));
+        Ok(Some(token))
+    }
+
+    /// Run one authorized request for `path` below the container and the
+    /// given query string, returning the response body.
+    fn request<S: AsRef<OsStr>>(
+        &self,
+        path: &str,
+        query: &str,
+        args: impl IntoIterator<Item = S>,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut url = self.base.clone();
+        if !path.is_empty() {
+            url.push('/');
+            url.push_str(&uri_encode(path, true));
+        }
+        let mut query = query.to_string();
+        if let Credentials::Sas(sas) = &self.credentials {
+            if !query.is_empty() {
+                query.push('&');
+            }
+            query.push_str(sas);
+        }
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+
+        let mut config = format!(
+            "url = {}\nheader = {}\n",
+            curl::quote(&url),
+            curl::quote(&format!("x-ms-version: {}", API_VERSION))
+        );
+        if let Some(token) = self.bearer()? {
+            config.push_str(&format!(
+                "header = {}\n",
+                curl::quote(&format!("Authorization: Bearer {}", token))
+            ));
+        }
+        let mut all: Vec<OsString> = vec!["-K".into(), "-".into()];
+        all.extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        curl::run("Azure", all, Some(config.as_bytes()))
+    }
+
+    /// Every blob whose name starts with `prefix`.
+    pub fn list(&self, prefix: &str) -> std::io::Result<Vec<RemoteObject>> {
+        let mut objects = Vec::new();
+        let mut marker: Option<String> = None;
+        loop {
+            let mut query = format!(
+                "restype=container&comp=list&prefix={}",
+                uri_encode(prefix, false)
+            );
+            if let Some(marker) = &marker {
+                query.push_str(&format!("&marker={}", uri_encode(marker, false)));
+            }
+            let body = self.request("", &query, std::iter::empty::<&str>())?;
+            let body = String::from_utf8_lossy(&body);
+            for blob in xml_elements(&body, "Blob") {
+                let field = |tag| xml_elements(&blob, tag).pop().unwrap_or_default();
+                let name = field("Name");
+                objects.push(RemoteObject {
+                    key: name.strip_prefix(prefix).unwrap_or(&name).to_string(),
+                    version: field("Etag"),
+                    size: field("Content-Length").parse().unwrap_or(0),
+                });
+            }
+            marker = xml_elements(&body, "NextMarker")
+                .pop()
+                .filter(|m| !m.is_empty());
+            if marker.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
+
+    /// Download a blob to `dest`, via a temporary file next to it.
+    pub fn get(&self, name: &str, dest: &Path) -> std::io::Result<()> {
+        let mut partial = dest.as_os_str().to_os_string();
+        partial.push(".part");
+        let result = self.request(name, "", [OsStr::new("-o"), &partial]);
+        match result {
+            Ok(_) => std::fs::rename(&partial, dest),
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                Err(e)
+            }
+        }
+    }
+
+    /// Upload a file as a block blob, in blocks if it is large.
+    pub fn put(
+        &self,
+        name: &str,
+        file: &Path,
+        content_type: &str,
+        access_tier: Option<&str>,
+    ) -> std::io::Result<()> {
+        let size = std::fs::metadata(file)?.len();
+        let tier = access_tier.map(|tier| format!("x-ms-access-tier: {}", tier));
+        if size <= BLOCK_THRESHOLD {
+            let mut args = vec![
+                OsString::from("-T"),
+                file.into(),
+                "-H".into(),
+                "x-ms-blob-type: BlockBlob".into(),
+                "-H".into(),
+                format!("Content-Type: {}", content_type).into(),
+            ];
+            if let Some(tier) = tier {
+                args.extend(["-H".into(), tier.into()]);
+            }
+            self.request(name, "", args)?;
+            return Ok(());
+        }
+
+        // Uncommitted blocks are discarded by the service after a week, so
+        // a failed upload needs no cleanup beyond the local block file
+        let block_size = BLOCK_SIZE.max(size.div_ceil(MAX_BLOCKS));
+        let mut input = File::open(file)?;
+        let block_file = scratch_path(&self.work_dir, "azure", "part");
+        let list_file = scratch_path(&self.work_dir, "azure", "xml");
+        let result = (|| {
+            let mut list = String::from(r#"<?xml version="1.0" encoding="utf-8"?><BlockList>"#);
+            for number in 1..=size.div_ceil(block_size) {
+                let mut block = File::create(&block_file)?;
+                std::io::copy(&mut (&mut input).take(block_size), &mut block)?;
+                drop(block);
+                // IDs must be base64 and of equal length; eight digits are both
+                let id = format!("{:08}", number);
+                debug!("Uploading block {} of {:?}", number, file);
+                self.request(
+                    name,
+                    &format!("comp=block&blockid={}", id),
+                    [OsStr::new("-T"), block_file.as_os_str()],
+                )?;
+                list.push_str(&format!("<Latest>{}</Latest>", id));
+            }
+            list.push_str("</BlockList>");
+            std::fs::write(&list_file, list)?;
+
+            let mut data = OsString::from("@");
+            data.push(&list_file);
+            let mut args = vec![
+                OsString::from("-X"),
+                "PUT".into(),
+                "-H".into(),
+                "Content-Type: application/xml".into(),
+                "-H".into(),
+                format!("x-ms-blob-content-type: {}", content_type).into(),
+                "--data-binary".into(),
+                data,
+            ];
+            if let Some(tier) = &tier {
+                args.extend(["-H".into(), tier.into()]);
+            }
+            self.request(name, "comp=blocklist", args)?;
+            Ok(())
+        })();
+        let _ = std::fs::remove_file(&block_file);
+        let _ = std::fs::remove_file(&list_file);
+        result
+    }
+}
+
+/// Blobs below a prefix of a container, for a polling source.
+pub struct AzureStore {
+    client: AzureClient,
+    location: AzureLocation,
+}
+
+impl AzureStore {
+    pub fn new(
+        location: AzureLocation,
+        config: &AzureConfig,
+        work_dir: &Path,
+    ) -> std::io::Result<Self> {
+        Ok(AzureStore {
+            client: AzureClient::new(&location.account, &location.container, config, work_dir)?,
+            location,
+        })
+    }
+}
+
+impl RemoteStore for AzureStore {
+    fn list(&self) -> std::io::Result<Vec<RemoteObject>> {
+        self.client.list(&self.location.prefix)
+    }
+
+    fn fetch(&self, key: &str, dest: &Path) -> std::io::Result<()> {
+        self.client
+            .get(&format!("{}{}", self.location.prefix, key), dest)
+    }
+
+    fn describe(&self) -> String {
+        self.location.describe()
+    }
+}
+
+/// Uploads outputs below a prefix of a container.
+pub struct AzureTarget {
+    client: AzureClient,
+    location: AzureLocation,
+    access_tier: Option<String>,
+}
+
+impl AzureTarget {
+    pub fn new(
+        location: AzureLocation,
+        config: &AzureConfig,
+        access_tier: Option<String>,
+        work_dir: &Path,
+    ) -> std::io::Result<Self> {
+        Ok(AzureTarget {
+            client: AzureClient::new(&location.account, &location.container, config, work_dir)?,
+            location,
+            access_tier,
+        })
+    }
+}
+
+impl RemoteTarget for AzureTarget {
+    fn upload(&self, file: &Path, name: &str, content_type: &str) -> std::io::Result<()> {
+        self.client.put(
+            &format!("{}{}", self.location.prefix, name),
+            file,
+            content_type,
+            self.access_tier.as_deref(),
+        )
+    }
+
+    fn describe(&self) -> String {
+        self.location.describe()
+    }
+}
@@ -0,0 +1,559 @@
+//! Transcoding engines.
+//!
+//! The pipeline only sees the [`TranscodeBackend`] trait, so an engine can be
+//! swapped without touching the queue or the notifiers.
+mod ffmpeg;
+#[cfg(feature = "gstreamer")]
+mod gstreamer;
+#[cfg(feature = "native")]
+mod native;
+
+pub(crate) use ffmpeg::{AGC_FILTER, probe_channels};
+pub use ffmpeg::{FfmpegBackend, Segments};
+#[cfg(feature = "gstreamer")]
+pub use gstreamer::GstreamerBackend;
+#[cfg(feature = "native")]
+pub use native::NativeBackend;
+
+use crate::TranscodeOptions;
+use crate::parts;
+use crate::raw::{self, RawInput};
+use clap::ValueEnum;
+use std::cell::{Cell, RefCell};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often a running child is checked against its deadline.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// What a backend reports back for one file.
+pub struct BackendOutput {
+    /// The command line that was run, for logs and audit records.
+    pub command: Vec<String>,
+    /// Diagnostic output of the engine, e.g. ffmpeg's stderr.
+    pub log: String,
+    pub success: bool,
+}
+
+/// An engine that converts one input file into a mono WAV file of the
+/// preset's format.
+pub trait TranscodeBackend: Send + Sync {
+    /// Transcode `input` into `output`.
+    ///
+    /// An `Err` means the engine could not be run at all; a failed
+    /// conversion is reported through [`BackendOutput::success`].
+    fn transcode(&self, input: &Path, output: &Path) -> std::io::Result<BackendOutput>;
+
+    /// Transcode each of `files`, pairs of input and output, e.g. a batch
+    /// of small files that an engine can take on in one run instead of
+    /// being started for each. The default transcodes them one by one.
+    fn transcode_many(&self, files: &[(&Path, &Path)]) -> std::io::Result<Vec<BackendOutput>> {
+        files
+            .iter()
+            .map(|(input, output)| self.transcode(input, output))
+            .collect()
+    }
+
+    /// Check that the engine can be run at all, e.g. to tell an engine
+    /// broken by an upgrade from a bad input. The default assumes it can.
+    fn available(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// ffmpeg's input options for `input`: those of the first of
+/// `raw_inputs` that matches it, or of a concat list of parts.
+pub(crate) fn input_options(raw_inputs: &[RawInput], input: &Path) -> Vec<String> {
+    match raw::find(raw_inputs, input) {
+        Some(raw) => raw.input_options().to_vec(),
+        None => parts::input_options(input)
+            .iter()
+            .map(|option| option.to_string())
+            .collect(),
+    }
+}
+
+/// `path` as an argument of ffmpeg and the tools like it, that cannot be
+/// taken for an option, for stdin or for a protocol such as `concat:`:
+/// relative paths, e.g. `-y.wav`, start with `./`. On Windows, paths too
+/// long to be opened as they are take their `\\?\` form.
+pub(crate) fn path_arg(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    if std::path::absolute(path).is_ok_and(|path| path.as_os_str().len() >= MAX_PATH) {
+        return verbatim(path);
+    }
+    match path.has_root() || path.is_absolute() {
+        true => path.to_path_buf(),
+        false => Path::new(".").join(path),
+    }
+}
+
+/// Longest path, with its terminating NUL, that Windows opens without the
+/// `\\?\` prefix unless long paths are enabled for the whole system.
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+/// `path` made absolute in the `\\?\` form, which lifts the length limit
+/// but is taken literally: `\\?\C:\...` for drives and
+/// `\\?\UNC\server\share\...` for shares. Paths in that form already,
+/// and device paths, stay as they are.
+#[cfg(windows)]
+fn verbatim(path: &Path) -> PathBuf {
+    use std::ffi::OsString;
+    use std::path::{Component, Prefix};
+
+    // Also normalizes separators and `..`, which the form does not allow
+    let Ok(absolute) = std::path::absolute(path) else {
+        return path.to_path_buf();
+    };
+    let mut components = absolute.components();
+    let mut verbatim = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(letter) => OsString::from(format!(r"\\?\{}:", letter as char)),
+            Prefix::UNC(server, share) => {
+                let mut verbatim = OsString::from(r"\\?\UNC\");
+                verbatim.push(server);
+                verbatim.push(r"\");
+                verbatim.push(share);
+                verbatim
+            }
+            _ => return absolute,
+        },
+        _ => return absolute,
+    };
+    for component in components {
+        if let Component::Normal(name) = component {
+            verbatim.push(r"\");
+            verbatim.push(name);
+        }
+    }
+    PathBuf::from(verbatim)
+}
+
+/// Built-in backends, selectable with `--backend`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BackendKind {
+    /// Run the ffmpeg executable as a subprocess.
+    #[default]
+    Ffmpeg,
+    /// Convert in-process without ffmpeg (PCM and float WAV input only).
+    #[cfg(feature = "native")]
+    Native,
+    /// Run a gst-launch-1.0 pipeline as a subprocess.
+    #[cfg(feature = "gstreamer")]
+    Gstreamer,
+}
+
+impl BackendKind {
+    pub fn create(self, options: &TranscodeOptions) -> Box<dyn TranscodeBackend> {
+        let timeout = options.timeout;
+        let priority = options.priority;
+        let limits = options.limits;
+        match self {
+            BackendKind::Ffmpeg => Box::new(FfmpegBackend {
+                timeout,
+                priority,
+                limits,
+                downmix: options.downmix,
+                pan: options.downmix_pan.clone(),
+                raw: options.raw_inputs.clone(),
+                agc: options.agc,
+                preset: options.preset,
+                segments: options.segment_length.map(|length| Segments {
+                    length,
+                    processes: options.jobs.max(1),
+                }),
+            }),
+            #[cfg(feature = "native")]
+            BackendKind::Native => Box::new(NativeBackend {
+                preset: options.preset,
+            }),
+            #[cfg(feature = "gstreamer")]
+            BackendKind::Gstreamer => Box::new(GstreamerBackend {
+                timeout,
+                priority,
+                limits,
+                preset: options.preset,
+            }),
+        }
+    }
+}
+
+/// What to do once the transcoder cannot be started for a while, e.g.
+/// with ffmpeg removed by a system upgrade.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TranscoderMissing {
+    /// Hold back every job, and carry on once it can be started again.
+    #[default]
+    Wait,
+    /// Stop, exiting with a non-zero status.
+    Exit,
+}
+
+/// Whether `program` runs and exits successfully with `arg`, e.g. its
+/// `-version`.
+pub(crate) fn runs(program: &str, arg: &str) -> std::io::Result<()> {
+    let status = Command::new(program)
+        .arg(arg)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(std::io::Error::other(format!(
+            "{} {} failed: {}",
+            program, arg, status
+        ))),
+    }
+}
+
+/// I/O scheduling class of the transcoder processes, as set by `ionice`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IoClass {
+    /// Only get disk time when no other process wants it.
+    Idle,
+    /// The normal class, at its lowest priority.
+    BestEffort,
+}
+
+/// CPU and I/O priority of the transcoder processes, so that bulk work
+/// yields to the services running next to it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Priority {
+    /// Niceness, from -20 to 19; raising the priority above the current
+    /// one needs privileges.
+    pub nice: Option<i32>,
+    /// Only applied on Linux.
+    pub io_class: Option<IoClass>,
+}
+
+impl Priority {
+    /// Have `command` run at this priority.
+    pub(crate) fn apply(self, command: &mut Command) -> &mut Command {
+        #[cfg(unix)]
+        if self.nice.is_some() || self.io_class.is_some() {
+            use std::os::unix::process::CommandExt;
+            // SAFETY: `set` only makes system calls, which are safe to make
+            // between fork and exec
+            unsafe { command.pre_exec(move || self.set()) };
+        }
+        command
+    }
+
+    /// Set the priority of the current process.
+    #[cfg(unix)]
+    fn set(self) -> std::io::Result<()> {
+        if let Some(nice) = self.nice {
+            // SAFETY: plain system call on this process
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(class) = self.io_class {
+            const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+            const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+            let ioprio = match class {
+                IoClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+                IoClass::BestEffort => (2 << IOPRIO_CLASS_SHIFT) | 7,
+            };
+            // SAFETY: plain system call on this process
+            if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resource limits of each transcoder process, so that a pathological
+/// input cannot run the host out of memory or take up every core.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// Most bytes of address space, as set by `ulimit -v`; allocations
+    /// beyond it fail, which makes the transcoder give up on the file.
+    pub memory: Option<u64>,
+    /// Most cores the processes of one job run on, which ffmpeg is also
+    /// asked to decode and filter with as many threads.
+    pub cpus: Option<usize>,
+}
+
+/// Where the share of the cores of the next job starts, so that jobs
+/// running at once are kept to different cores.
+#[cfg(target_os = "linux")]
+static NEXT_CORE: AtomicUsize = AtomicUsize::new(0);
+
+/// The cores a job's processes are kept to under the CPU limit.
+#[derive(Clone, Copy)]
+pub(crate) struct Cores {
+    #[cfg(target_os = "linux")]
+    set: libc::cpu_set_t,
+}
+
+impl Limits {
+    /// The cores of the job this thread runs: those it was given with
+    /// [`with_cores`], or else the next `cpus` of the cores the thread may
+    /// run on, e.g. those of `--cpu-set`. None without a limit, or if the
+    /// thread may not run on more cores than that anyway.
+    pub(crate) fn cores(self) -> Option<Cores> {
+        let cpus = self.cpus?;
+        if let Some(cores) = JOB_CORES.with(Cell::get) {
+            return Some(cores);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            // SAFETY: an all-zero cpu_set_t is the empty set
+            let mut allowed: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            // SAFETY: the set is valid for its full size; 0 is this thread
+            if unsafe { libc::sched_getaffinity(0, std::mem::size_of_val(&allowed), &mut allowed) }
+                != 0
+            {
+                return None;
+            }
+            let allowed: Vec<usize> = (0..libc::CPU_SETSIZE as usize)
+                // SAFETY: the CPU number is within the set
+                .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &allowed) })
+                .collect();
+            let share = share(&allowed, NEXT_CORE.fetch_add(cpus, Ordering::SeqCst), cpus)?;
+            // SAFETY: as above
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            for cpu in share {
+                // SAFETY: the CPU number came from a set of the same size
+                unsafe { libc::CPU_SET(cpu, &mut set) };
+            }
+            Some(Cores { set })
+        }
+        #[cfg(not(target_os = "linux"))]
+        None
+    }
+
+    /// Have `command` run within the memory limit, and on the cores of
+    /// its job; ffmpeg is also passed the CPU limit as options.
+    pub(crate) fn apply(self, command: &mut Command) -> &mut Command {
+        #[cfg(target_os = "linux")]
+        if let Some(Cores { set }) = self.cores() {
+            use std::os::unix::process::CommandExt;
+            // SAFETY: sched_setaffinity is a plain system call, safe to make
+            // between fork and exec
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                })
+            };
+        }
+        #[cfg(unix)]
+        if let Some(memory) = self.memory {
+            use std::os::unix::process::CommandExt;
+            let limit = libc::rlimit {
+                rlim_cur: memory as libc::rlim_t,
+                rlim_max: memory as libc::rlim_t,
+            };
+            // SAFETY: setrlimit is a plain system call, safe to make between
+            // fork and exec
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                })
+            };
+        }
+        command
+    }
+}
+
+/// How the ffmpeg backend turns inputs with two or more channels into
+/// mono, instead of ffmpeg's default mix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Downmix {
+    /// The mean of all channels.
+    Average,
+    /// The first channel only.
+    Left,
+    /// The second channel only.
+    Right,
+    /// The front center channel, or the mean of the first two channels for
+    /// layouts without one.
+    Center,
+    /// The `pan` filter expression given with `--downmix-pan`.
+    CustomPan,
+}
+
+thread_local! {
+    /// Set while a worker runs a job that can be cancelled.
+    static CANCEL: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+    /// Set while a thread runs processes for a job whose cores were chosen
+    /// on another thread.
+    static JOB_CORES: Cell<Option<Cores>> = const { Cell::new(None) };
+}
+
+/// `cpus` of the `allowed` cores from the `start`th on, wrapping around,
+/// or None if there are no more than `cpus` of them.
+fn share(allowed: &[usize], start: usize, cpus: usize) -> Option<Vec<usize>> {
+    (allowed.len() > cpus).then(|| {
+        (start..start + cpus)
+            .map(|i| allowed[i % allowed.len()])
+            .collect()
+    })
+}
+
+/// Call `f` with `cancel` as the flag that kills the subprocesses it
+/// starts through [`run`] on this thread.
+pub(crate) fn cancellable<T>(cancel: Arc<AtomicBool>, f: impl FnOnce() -> T) -> T {
+    CANCEL.with(|c| *c.borrow_mut() = Some(cancel));
+    let result = f();
+    CANCEL.with(|c| *c.borrow_mut() = None);
+    result
+}
+
+/// The cancellation flag of the job running on this thread, for handing
+/// to the threads it starts.
+pub(crate) fn current_cancel() -> Option<Arc<AtomicBool>> {
+    CANCEL.with(|c| c.borrow().clone())
+}
+
+/// Call `f` with the processes it starts on this thread kept to `cores`,
+/// e.g. those of the segments of one job.
+pub(crate) fn with_cores<T>(cores: Option<Cores>, f: impl FnOnce() -> T) -> T {
+    JOB_CORES.with(|c| c.set(cores));
+    let result = f();
+    JOB_CORES.with(|c| c.set(None));
+    result
+}
+
+/// Output of a subprocess run by [`run`].
+pub(crate) struct ChildOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+    pub timed_out: bool,
+}
+
+/// Run a command to completion, killing it once `timeout` has passed or the
+/// job is cancelled.
+pub(crate) fn run(
+    command: &mut Command,
+    timeout: Option<Duration>,
+) -> std::io::Result<ChildOutput> {
+    let started = Instant::now();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain both pipes so a chatty child cannot block on a full buffer
+    let drain = |pipe: Option<Box<dyn Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            String::from_utf8_lossy(&buf).into_owned()
+        })
+    };
+    let stdout = drain(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = drain(child.stderr.take().map(|p| Box::new(p) as _));
+
+    let cancel = current_cancel();
+    let mut timed_out = false;
+    let mut cancelled = false;
+    let status = if timeout.is_none() && cancel.is_none() {
+        child.wait()?
+    } else {
+        loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            timed_out = timeout.is_some_and(|t| started.elapsed() >= t);
+            cancelled = cancel.as_ref().is_some_and(|c| c.load(Ordering::SeqCst));
+            if timed_out || cancelled {
+                let _ = child.kill();
+                break child.wait()?;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    };
+
+    let mut stderr = stderr.join().unwrap_or_default();
+    if timed_out {
+        stderr.push_str(&format!(
+            "Transcoding timed out after {}\n",
+            humantime::format_duration(timeout.unwrap_or_default())
+        ));
+    } else if cancelled {
+        stderr.push_str("Transcoding cancelled\n");
+    }
+    Ok(ChildOutput {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr,
+        timed_out,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{path_arg, share};
+    use std::path::Path;
+
+    #[test]
+    fn jobs_take_the_cores_in_turn() {
+        let allowed = [0, 1, 2, 3, 6, 7];
+        assert_eq!(share(&allowed, 0, 2), Some(vec![0, 1]));
+        assert_eq!(share(&allowed, 2, 2), Some(vec![2, 3]));
+        assert_eq!(share(&allowed, 4, 2), Some(vec![6, 7]));
+        assert_eq!(share(&allowed, 6, 2), Some(vec![0, 1]));
+        assert_eq!(share(&allowed, 5, 3), Some(vec![7, 0, 1]));
+        // Nothing to restrict
+        assert_eq!(share(&allowed, 0, 6), None);
+        assert_eq!(share(&[0], 0, 2), None);
+    }
+
+    #[test]
+    fn relative_paths_cannot_be_options() {
+        for name in ["-y.wav", "-", "--", "-i", "-f lavfi.wav", "concat:a.wav"] {
+            let arg = path_arg(Path::new(name));
+            assert!(arg.starts_with("."), "{:?}", arg);
+            assert!(!arg.to_string_lossy().starts_with('-'), "{:?}", arg);
+            assert_eq!(arg, Path::new(".").join(name));
+        }
+        assert_eq!(path_arg(Path::new("in/-y.wav")), Path::new("./in/-y.wav"));
+    }
+
+    #[test]
+    fn absolute_paths_stay_as_they_are() {
+        let dir = std::env::temp_dir();
+        for name in ["-y.wav", "a\nb.wav", "it's \"quoted\".wav"] {
+            assert_eq!(path_arg(&dir.join(name)), dir.join(name));
+        }
+    }
+
+    #[test]
+    fn hostile_names_still_name_the_file() {
+        let dir = std::env::temp_dir().join(format!("path-arg-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "-y.wav",
+            "--",
+            "a\nb.wav",
+            "it's \"quoted\".wav",
+            "-\n-i.wav",
+        ] {
+            std::fs::write(dir.join(name), name).unwrap();
+            // As a tool run in `dir` would open it
+            let arg = path_arg(Path::new(name));
+            assert_eq!(std::fs::read_to_string(dir.join(arg)).unwrap(), name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,640 @@
+//! ffmpeg subprocess backend.
+use super::{BackendOutput, Downmix, Limits, Priority, TranscodeBackend};
+use crate::parts;
+use crate::preset::Preset;
+use crate::raw::{self, RawInput};
+use crate::{json, sandbox, throttle, wav};
+use log::debug;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Automatic gain control: `dynaudnorm` evens out the level frame by frame,
+/// with a window of 31 frames of 500ms, so that the gain follows a change
+/// of speaker within seconds without pumping on every word.
+pub(crate) const AGC_FILTER: &str = "dynaudnorm=f=500:g=31";
+
+/// Duration of an input in seconds, asked of ffprobe.
+fn probe_duration(input: &Path) -> Option<f64> {
+    let output = sandbox::command("ffprobe", &[input], &[])
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "csv=p=0",
+        ])
+        .args(parts::input_options(input))
+        .arg(super::path_arg(input))
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let duration: f64 = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .ok()?;
+    (output.status.success() && duration > 0.0).then_some(duration)
+}
+
+/// Channel layouts with a front center channel, as ffprobe names them.
+const CENTER_LAYOUTS: &[&str] = &[
+    "3.0",
+    "4.0",
+    "5.0",
+    "5.0(side)",
+    "5.1",
+    "5.1(side)",
+    "6.0",
+    "6.1",
+    "6.1(back)",
+    "7.0",
+    "7.0(front)",
+    "7.1",
+    "7.1(wide)",
+    "7.1(wide-side)",
+    "hexagonal",
+    "octagonal",
+];
+
+/// Channel count and layout of the first audio stream of an input, asked
+/// of ffprobe.
+pub(crate) fn probe_channels(input: &Path) -> Option<(usize, String)> {
+    let output = sandbox::command("ffprobe", &[input], &[])
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "a:0",
+            "-show_entries",
+            "stream=channels,channel_layout",
+            "-of",
+            "csv=p=0",
+        ])
+        .args(parts::input_options(input))
+        .arg(super::path_arg(input))
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut fields = stdout.trim().split(',');
+    let channels: usize = fields.next()?.parse().ok()?;
+    let layout = fields.next().unwrap_or_default().to_string();
+    output.status.success().then_some((channels, layout))
+}
+
+/// A chapter of an input, as ffprobe reports it.
+struct Chapter {
+    start: f64,
+    end: f64,
+    title: String,
+}
+
+/// Chapters embedded in an input, e.g. the cues of a podcast or the
+/// chapters of a video, asked of ffprobe.
+fn probe_chapters(input: &Path) -> Vec<Chapter> {
+    let Ok(output) = sandbox::command("ffprobe", &[input], &[])
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "chapter=start_time,end_time:chapter_tags=title",
+            "-of",
+            "csv=p=0",
+        ])
+        .args(parts::input_options(input))
+        .arg(super::path_arg(input))
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+    else {
+        return Vec::new();
+    };
+    if !output.status.success() {
+        return Vec::new();
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            // The title comes last, and may have commas of its own
+            let mut fields = line.splitn(3, ',');
+            Some(Chapter {
+                start: fields.next()?.trim().parse().ok()?,
+                end: fields.next()?.trim().parse().ok()?,
+                title: fields.next().unwrap_or_default().trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Write `<output>.cues.json` for an input split into `count` segments of
+/// `length` seconds, so that the relationship of the segments to the
+/// timeline of the input is kept: the segments, and the chapters of the
+/// input, or one per segment where it has none. The output keeps the
+/// input's timeline, so the times are of either.
+fn write_cues(input: &Path, output: &Path, duration: f64, length: f64, count: usize) {
+    let segments: Vec<(f64, f64)> = (0..count)
+        .map(|i| (i as f64 * length, ((i + 1) as f64 * length).min(duration)))
+        .collect();
+    let embedded = probe_chapters(input);
+    let (source, chapters) = if embedded.is_empty() {
+        let generated = segments
+            .iter()
+            .enumerate()
+            .map(|(i, &(start, end))| Chapter {
+                start,
+                end,
+                title: format!("Segment {}", i + 1),
+            })
+            .collect();
+        ("segments", generated)
+    } else {
+        ("input", embedded)
+    };
+    let record = json::Object::new()
+        .str("input", &input.to_string_lossy())
+        .str("output", &output.to_string_lossy())
+        .raw(
+            "segments",
+            &json::array(segments.iter().enumerate().map(|(i, (start, end))| {
+                json::Object::new()
+                    .num("index", i)
+                    .num("start_seconds", format!("{:.3}", start))
+                    .num("end_seconds", format!("{:.3}", end))
+                    .finish()
+            })),
+        )
+        .str("chapters_from", source)
+        .raw(
+            "chapters",
+            &json::array(chapters.iter().map(|chapter| {
+                json::Object::new()
+                    .str("title", &chapter.title)
+                    .num("start_seconds", format!("{:.3}", chapter.start))
+                    .num("end_seconds", format!("{:.3}", chapter.end))
+                    .finish()
+            })),
+        )
+        .finish();
+    let mut path = output.as_os_str().to_owned();
+    path.push(".cues.json");
+    let path = PathBuf::from(path);
+    let partial = path.with_extension("json.partial");
+    let written =
+        std::fs::write(&partial, record + "\n").and_then(|()| std::fs::rename(&partial, &path));
+    if let Err(e) = written {
+        debug!("Failed to write the cues of {:?}: {}", output, e);
+    }
+}
+
+/// Value of `-readrate`, the speed ffmpeg reads at as a multiple of
+/// realtime, that keeps it within the [`throttle`] limits: the write limit
+/// as a multiple of the output's byte rate, and the read limit as one of
+/// the input's, where its duration is known.
+fn read_rate(input: &Path, duration: Option<f64>, preset: Preset) -> Option<f64> {
+    let write = throttle::write_limit().map(|limit| limit as f64 / f64::from(preset.byte_rate()));
+    let read = throttle::read_limit().and_then(|limit| {
+        let size = std::fs::metadata(input).ok()?.len() as f64;
+        if duration.is_none() {
+            debug!(
+                "No duration for {:?}, so its reads are not throttled",
+                input
+            );
+        }
+        Some(limit as f64 / (size / duration?).max(1.0))
+    });
+    match (read, write) {
+        (Some(read), Some(write)) => Some(read.min(write)),
+        (rate, None) | (None, rate) => rate,
+    }
+}
+
+/// ffmpeg processes running for files, across all jobs.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Splitting of long inputs into segments that are transcoded in parallel
+/// and joined, so that one long file does not keep the other workers idle.
+#[derive(Clone, Copy, Debug)]
+pub struct Segments {
+    /// Length of each segment; inputs up to twice as long are not split.
+    pub length: Duration,
+    /// Most ffmpeg processes running at once, across all jobs; segments
+    /// only take up what the other jobs leave free.
+    pub processes: usize,
+}
+
+/// Runs the `ffmpeg` executable found on `PATH` for every file.
+#[derive(Default)]
+pub struct FfmpegBackend {
+    /// Kill ffmpeg if a single file, or segment, takes longer than this.
+    pub timeout: Option<Duration>,
+    pub priority: Priority,
+    pub limits: Limits,
+    pub segments: Option<Segments>,
+    /// How multichannel inputs become mono; ffmpeg's default mix if unset.
+    pub downmix: Option<Downmix>,
+    /// Channel expression of the `pan` filter for [`Downmix::CustomPan`],
+    /// e.g. `c0=0.8*c0+0.2*c1`.
+    pub pan: Option<String>,
+    /// Parameters of headerless PCM inputs, of which the first that
+    /// matches an input applies.
+    pub raw: Vec<RawInput>,
+    /// Even out the level with [`AGC_FILTER`].
+    pub agc: bool,
+    /// Format of the outputs.
+    pub preset: Preset,
+}
+
+impl FfmpegBackend {
+    /// Transcode audio from stdin into a WAV stream on stdout, with
+    /// ffmpeg's own errors on stderr.
+    ///
+    /// The stream cannot be rewound to fill in its header, so the data size
+    /// there is left as ffmpeg writes it for unseekable outputs.
+    pub fn stream(&self) -> std::io::Result<ExitStatus> {
+        let started = Instant::now();
+        let cpus = self.limits.cpus.map(|cpus| cpus.to_string());
+        let mut child = self
+            .command(&[], &[])
+            .args(["-hide_banner", "-loglevel", "error"])
+            .args(
+                cpus.iter()
+                    .flat_map(|cpus| ["-filter_threads", cpus, "-threads", cpus]),
+            )
+            .args(["-i", "pipe:0"])
+            .args(self.preset.output_options())
+            .args(["-f", "wav", "pipe:1"])
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| std::io::Error::new(e.kind(), format!("Cannot run ffmpeg: {}", e)))?;
+        let Some(timeout) = self.timeout else {
+            return child.wait();
+        };
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            if started.elapsed() >= timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!(
+                        "Transcoding timed out after {}",
+                        humantime::format_duration(timeout)
+                    ),
+                ));
+            }
+            std::thread::sleep(super::POLL_INTERVAL);
+        }
+    }
+}
+
+impl FfmpegBackend {
+    /// An ffmpeg command at the configured priority and within the memory
+    /// limit, that may read `reads` and write next to `writes`.
+    fn command(&self, reads: &[&Path], writes: &[&Path]) -> Command {
+        let mut command = sandbox::command("ffmpeg", reads, writes);
+        self.limits.apply(self.priority.apply(&mut command));
+        command
+    }
+
+    /// Duration of `input` in seconds: of a raw one by its size, otherwise
+    /// asked of ffprobe.
+    fn duration(&self, input: &Path) -> Option<f64> {
+        match raw::find(&self.raw, input) {
+            Some(raw) => Some(raw.duration(std::fs::metadata(input).ok()?.len())),
+            None => probe_duration(input),
+        }
+    }
+
+    /// The `pan` filter that downmixes `input` as configured, if it has
+    /// more than one channel.
+    fn downmix_filter(&self, input: &Path) -> Option<String> {
+        let downmix = self.downmix?;
+        let probed = match raw::find(&self.raw, input) {
+            // Raw PCM has no layout
+            Some(raw) => Some((usize::from(raw.channels), String::new())),
+            None => probe_channels(input),
+        };
+        let Some((channels, layout)) = probed else {
+            debug!("No channel count for {:?}, so ffmpeg downmixes it", input);
+            return None;
+        };
+        if channels < 2 {
+            return None;
+        }
+        let mean = |count: usize| {
+            let inputs: Vec<String> = (0..count).map(|c| format!("c{}", c)).collect();
+            // `<` scales the gains to sum to one
+            format!("c0<{}", inputs.join("+"))
+        };
+        let expression = match downmix {
+            Downmix::Average => mean(channels),
+            Downmix::Left => "c0=c0".to_string(),
+            Downmix::Right => "c0=c1".to_string(),
+            Downmix::Center if CENTER_LAYOUTS.contains(&layout.as_str()) => "c0=FC".to_string(),
+            Downmix::Center => mean(2),
+            Downmix::CustomPan => self.pan.clone()?,
+        };
+        Some(format!("pan=mono|{}", expression))
+    }
+
+    /// The filters `input` goes through: the downmix, then the gain
+    /// control, as configured.
+    fn filter(&self, input: &Path) -> Option<String> {
+        let filters: Vec<String> = self
+            .downmix_filter(input)
+            .into_iter()
+            .chain(self.agc.then(|| AGC_FILTER.to_string()))
+            .collect();
+        (!filters.is_empty()).then(|| filters.join(","))
+    }
+
+    /// Transcode the whole input, or the `(start, length)` in seconds of
+    /// it, through `filter` if given.
+    fn transcode_part(
+        &self,
+        input: &Path,
+        output: &Path,
+        part: Option<(f64, f64)>,
+        filter: Option<&str>,
+    ) -> std::io::Result<BackendOutput> {
+        let rate = read_rate(input, self.duration(input), self.preset)
+            .map(|rate| format!("{:.3}", rate.max(0.001)));
+        let options = super::input_options(&self.raw, input);
+        let cpus = self.limits.cpus.map(|cpus| cpus.to_string());
+        let part = part.map(|(start, length)| (format!("{:.3}", start), format!("{:.3}", length)));
+        let mut args: Vec<&OsStr> = Vec::new();
+        if let Some(rate) = &rate {
+            args.extend([OsStr::new("-readrate"), OsStr::new(rate)]);
+        }
+        if let Some((start, length)) = &part {
+            args.extend(["-ss", start, "-t", length].map(OsStr::new));
+        }
+        if let Some(cpus) = &cpus {
+            args.extend(["-filter_threads", cpus, "-threads", cpus].map(OsStr::new));
+        }
+        let (input_arg, output_arg) = (super::path_arg(input), super::path_arg(output));
+        args.extend(options.iter().map(OsStr::new));
+        args.extend([OsStr::new("-i"), input_arg.as_os_str()]);
+        if let Some(filter) = filter {
+            args.extend([OsStr::new("-af"), OsStr::new(filter)]);
+        }
+        args.extend(self.preset.output_options().map(OsStr::new));
+        args.push(output_arg.as_os_str());
+
+        // Transcode the file to mono WAV at the preset's rate
+        RUNNING.fetch_add(1, Ordering::SeqCst);
+        let result = super::run(self.command(&[input], &[output]).args(&args), self.timeout);
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+        let result = result?;
+
+        Ok(BackendOutput {
+            command: std::iter::once("ffmpeg".as_ref())
+                .chain(args)
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            log: result.stdout + &result.stderr,
+            success: result.status.success() && !result.timed_out,
+        })
+    }
+
+    /// Transcode `count` segments of `length` seconds each into the parts
+    /// of the output, taking in idle capacity for them as it frees up, and
+    /// join them.
+    fn transcode_segments(
+        &self,
+        input: &Path,
+        output: &Path,
+        length: f64,
+        count: usize,
+        processes: usize,
+        filter: Option<&str>,
+    ) -> std::io::Result<BackendOutput> {
+        let name = output.file_name().unwrap_or_default().to_string_lossy();
+        let parts: Vec<PathBuf> = (0..count)
+            .map(|i| output.with_file_name(format!(".{}.segment{:04}.wav", name, i)))
+            .collect();
+        let next = AtomicUsize::new(0);
+        let results: Mutex<Vec<Option<std::io::Result<BackendOutput>>>> =
+            Mutex::new((0..count).map(|_| None).collect());
+        // Transcode the next segment, if any is left
+        let take = || {
+            let i = next.fetch_add(1, Ordering::SeqCst);
+            if i >= count {
+                return false;
+            }
+            let result =
+                self.transcode_part(input, &parts[i], Some((i as f64 * length, length)), filter);
+            if !result.as_ref().is_ok_and(|r| r.success) {
+                // No point in the others
+                next.store(count, Ordering::SeqCst);
+            }
+            results.lock().unwrap()[i] = Some(result);
+            true
+        };
+        let cancel = super::current_cancel();
+        // The segments share the cores of the job, rather than each taking
+        // as many
+        let cores = self.limits.cores();
+        super::with_cores(cores, || {
+            std::thread::scope(|scope| {
+                let mut helpers = 0;
+                loop {
+                    // This job's own process is among the running ones
+                    while helpers + 1 < count
+                        && RUNNING.load(Ordering::SeqCst) + helpers < processes
+                    {
+                        helpers += 1;
+                        let cancel = cancel.clone();
+                        scope.spawn(move || {
+                            super::with_cores(cores, || match cancel {
+                                Some(cancel) => super::cancellable(cancel, || while take() {}),
+                                None => while take() {},
+                            })
+                        });
+                    }
+                    if !take() {
+                        break;
+                    }
+                }
+            })
+        });
+        debug!("Transcoded {:?} in {} segments", input, count);
+
+        let mut merged = BackendOutput {
+            command: Vec::new(),
+            log: String::new(),
+            success: true,
+        };
+        let mut failure = None;
+        for (i, result) in results.into_inner().unwrap().into_iter().enumerate() {
+            match result {
+                Some(Ok(result)) => {
+                    if merged.command.is_empty() {
+                        merged.command = result.command;
+                    }
+                    merged.log.push_str(&format!(
+                        "Segment {} of {}:\n{}",
+                        i + 1,
+                        count,
+                        result.log
+                    ));
+                    merged.success &= result.success;
+                }
+                Some(Err(e)) => failure = Some(e),
+                // Not started after another segment failed
+                None => merged.success = false,
+            }
+        }
+        if merged.success
+            && let Err(e) = wav::concat(&parts, output)
+        {
+            merged
+                .log
+                .push_str(&format!("Joining the segments failed: {}\n", e));
+            merged.success = false;
+        }
+        for part in &parts {
+            let _ = std::fs::remove_file(part);
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(merged),
+        }
+    }
+}
+
+impl TranscodeBackend for FfmpegBackend {
+    fn transcode(&self, input: &Path, output: &Path) -> std::io::Result<BackendOutput> {
+        let filter = self.filter(input);
+        if let Some(segments) = &self.segments
+            && let Some(duration) = self.duration(input)
+        {
+            let length = segments.length.as_secs_f64();
+            if length > 0.0 && duration > 2.0 * length {
+                let count = (duration / length).ceil() as usize;
+                let result = self.transcode_segments(
+                    input,
+                    output,
+                    length,
+                    count,
+                    segments.processes,
+                    filter.as_deref(),
+                );
+                if result.as_ref().is_ok_and(|result| result.success) {
+                    write_cues(input, output, duration, length, count);
+                }
+                return result;
+            }
+        }
+        self.transcode_part(input, output, None, filter.as_deref())
+    }
+
+    fn available(&self) -> std::io::Result<()> {
+        super::runs("ffmpeg", "-version")
+    }
+
+    /// One ffmpeg run with every input, each mapped to its own output. If
+    /// it fails, the files are transcoded one by one, so that the failure
+    /// is reported for the file that caused it.
+    fn transcode_many(&self, files: &[(&Path, &Path)]) -> std::io::Result<Vec<BackendOutput>> {
+        if files.len() < 2 {
+            return files
+                .iter()
+                .map(|(input, output)| self.transcode(input, output))
+                .collect();
+        }
+        let rates: Vec<Option<String>> = files
+            .iter()
+            .map(|(input, _)| {
+                read_rate(input, self.duration(input), self.preset)
+                    .map(|rate| format!("{:.3}", rate.max(0.001)))
+            })
+            .collect();
+        let options: Vec<Vec<String>> = files
+            .iter()
+            .map(|(input, _)| super::input_options(&self.raw, input))
+            .collect();
+        let filters: Vec<Option<String>> =
+            files.iter().map(|(input, _)| self.filter(input)).collect();
+        let maps: Vec<String> = (0..files.len()).map(|i| format!("{}:a:0", i)).collect();
+        let cpus = self.limits.cpus.map(|cpus| cpus.to_string());
+        let inputs: Vec<PathBuf> = files
+            .iter()
+            .map(|(input, _)| super::path_arg(input))
+            .collect();
+        let outputs: Vec<PathBuf> = files
+            .iter()
+            .map(|(_, output)| super::path_arg(output))
+            .collect();
+        let mut args: Vec<&OsStr> = Vec::new();
+        if let Some(cpus) = &cpus {
+            args.extend(["-filter_threads", cpus].map(OsStr::new));
+        }
+        for ((input, rate), options) in inputs.iter().zip(&rates).zip(&options) {
+            if let Some(rate) = rate {
+                args.extend([OsStr::new("-readrate"), OsStr::new(rate)]);
+            }
+            if let Some(cpus) = &cpus {
+                args.extend(["-threads", cpus].map(OsStr::new));
+            }
+            args.extend(options.iter().map(OsStr::new));
+            args.extend([OsStr::new("-i"), input.as_os_str()]);
+        }
+        for ((output, map), filter) in outputs.iter().zip(&maps).zip(&filters) {
+            args.extend([OsStr::new("-map"), OsStr::new(map)]);
+            if let Some(filter) = filter {
+                args.extend([OsStr::new("-af"), OsStr::new(filter)]);
+            }
+            args.extend(self.preset.output_options().map(OsStr::new));
+            args.push(output.as_os_str());
+        }
+
+        let reads: Vec<&Path> = files.iter().map(|(input, _)| *input).collect();
+        let writes: Vec<&Path> = files.iter().map(|(_, output)| *output).collect();
+        RUNNING.fetch_add(1, Ordering::SeqCst);
+        let result = super::run(
+            self.command(&reads, &writes).args(&args),
+            self.timeout.map(|timeout| timeout * files.len() as u32),
+        );
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+        let result = result?;
+        if !result.status.success() || result.timed_out {
+            debug!(
+                "Transcoding a batch of {} files failed, retrying one by one: {}",
+                files.len(),
+                result.stderr.trim()
+            );
+            for (_, output) in files {
+                let _ = std::fs::remove_file(output);
+            }
+            return files
+                .iter()
+                .map(|(input, output)| self.transcode(input, output))
+                .collect();
+        }
+
+        let command: Vec<String> = std::iter::once("ffmpeg".as_ref())
+            .chain(args)
+            .map(|arg: &OsStr| arg.to_string_lossy().into_owned())
+            .collect();
+        let log = result.stdout + &result.stderr;
+        Ok(files
+            .iter()
+            .map(|_| BackendOutput {
+                command: command.clone(),
+                log: log.clone(),
+                success: true,
+            })
+            .collect())
+    }
+}
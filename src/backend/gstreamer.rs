@@ -0,0 +1,83 @@
+//! GStreamer backend, for systems where GStreamer is the sanctioned media stack.
+use super::{BackendOutput, Limits, Priority, TranscodeBackend};
+use crate::preset::Preset;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+/// Runs a `gst-launch-1.0` pipeline producing the same mono s16 WAV as the
+/// ffmpeg backend.
+#[derive(Default)]
+pub struct GstreamerBackend {
+    /// Kill gst-launch if a single file takes longer than this.
+    pub timeout: Option<Duration>,
+    pub priority: Priority,
+    pub limits: Limits,
+    /// Format of the outputs.
+    pub preset: Preset,
+}
+
+/// Quote a property value for gst-launch, which re-parses its arguments as
+/// a single pipeline description.
+fn quoted(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl TranscodeBackend for GstreamerBackend {
+    fn transcode(&self, input: &Path, output: &Path) -> std::io::Result<BackendOutput> {
+        // The pipeline description is a string, so paths must be UTF-8
+        let (Some(input), Some(output)) = (input.to_str(), output.to_str()) else {
+            return Ok(BackendOutput {
+                command: vec!["gst-launch-1.0".to_string()],
+                log: format!("Cannot pass non-UTF-8 path {:?} to gst-launch-1.0\n", input),
+                success: false,
+            });
+        };
+        let source = format!("location={}", quoted(input));
+        let sink = format!("location={}", quoted(output));
+        let caps = format!(
+            "audio/x-raw,format=S16LE,channels=1,rate={}",
+            self.preset.sample_rate()
+        );
+        let args = [
+            "-q",
+            "filesrc",
+            &source,
+            "!",
+            "decodebin",
+            "!",
+            "audioconvert",
+            "!",
+            "audioresample",
+            "!",
+            &caps,
+            "!",
+            "wavenc",
+            "!",
+            "filesink",
+            &sink,
+        ];
+
+        let result = super::run(
+            self.limits
+                .apply(self.priority.apply(&mut Command::new("gst-launch-1.0")))
+                .args(args),
+            self.timeout,
+        )?;
+
+        // gst-launch reports pipeline errors on stdout
+        let log = result.stdout + &result.stderr;
+        Ok(BackendOutput {
+            command: std::iter::once("gst-launch-1.0")
+                .chain(args)
+                .map(String::from)
+                .collect(),
+            log,
+            success: result.status.success() && !result.timed_out,
+        })
+    }
+
+    fn available(&self) -> std::io::Result<()> {
+        super::runs("gst-launch-1.0", "--version")
+    }
+}
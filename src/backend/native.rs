@@ -0,0 +1,218 @@
+//! Pure-Rust backend without an ffmpeg dependency.
+//!
+//! Reads PCM and IEEE float WAV input, downmixes to mono by averaging the
+//! channels, resamples to the preset's rate with a windowed-sinc polyphase
+//! filter and writes 16-bit PCM. Compressed formats still need the ffmpeg backend.
+use super::{BackendOutput, TranscodeBackend};
+use crate::preset::Preset;
+use crate::wav::{WavReader, WavWriter};
+use std::path::Path;
+
+/// Zero crossings of the sinc kernel on each side of the centre tap.
+const ZERO_CROSSINGS: f64 = 16.0;
+
+/// Frames decoded per read.
+const CHUNK_FRAMES: usize = 16 * 1024;
+
+/// Decodes, resamples and encodes in-process.
+#[derive(Default)]
+pub struct NativeBackend {
+    /// Format of the outputs.
+    pub preset: Preset,
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Streaming polyphase resampler for a fixed rational rate change.
+struct Resampler {
+    in_rate: u64,
+    out_rate: u64,
+    /// Taps on each side of the centre.
+    half: usize,
+    /// One set of `2 * half` coefficients per output phase.
+    phases: Vec<Vec<f32>>,
+    /// Buffered input; `buf[0]` is input sample number `buf_start`.
+    buf: Vec<f32>,
+    buf_start: u64,
+    /// Total input samples pushed so far.
+    pushed: u64,
+    /// Index of the next output sample.
+    next: u64,
+}
+
+impl Resampler {
+    fn new(in_rate: u32, out_rate: u32) -> Self {
+        let g = gcd(u64::from(in_rate), u64::from(out_rate));
+        let (in_rate, out_rate) = (u64::from(in_rate) / g, u64::from(out_rate) / g);
+        // Low-pass below the lower of the two Nyquist frequencies
+        let cutoff = if in_rate > out_rate {
+            0.95 * out_rate as f64 / in_rate as f64
+        } else {
+            1.0
+        };
+        let half = (ZERO_CROSSINGS / cutoff).ceil() as usize;
+
+        let phases = (0..out_rate)
+            .map(|phase| {
+                let frac = phase as f64 / out_rate as f64;
+                (0..2 * half)
+                    .map(|j| {
+                        // Distance from the output position to input tap j
+                        let d = frac + half as f64 - 1.0 - j as f64;
+                        let x = std::f64::consts::PI * cutoff * d;
+                        let sinc = if x.abs() < 1e-9 { 1.0 } else { x.sin() / x };
+                        let w = d / half as f64;
+                        let window = if w.abs() >= 1.0 {
+                            0.0
+                        } else {
+                            0.5 * (1.0 + (std::f64::consts::PI * w).cos())
+                        };
+                        (cutoff * sinc * window) as f32
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Resampler {
+            in_rate,
+            out_rate,
+            half,
+            phases,
+            buf: Vec::new(),
+            buf_start: 0,
+            pushed: 0,
+            next: 0,
+        }
+    }
+
+    /// Input sample at an absolute index, zero outside the signal.
+    fn sample(&self, index: i64) -> f32 {
+        if index < self.buf_start as i64 {
+            return 0.0;
+        }
+        self.buf
+            .get((index - self.buf_start as i64) as usize)
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    /// Compute every output sample whose input window is available, or all
+    /// remaining samples once the input is complete.
+    fn drain(&mut self, out: &mut Vec<f32>, finished: bool) {
+        let total = (self.pushed * self.out_rate).div_ceil(self.in_rate);
+        loop {
+            let pos = self.next * self.in_rate;
+            let centre = (pos / self.out_rate) as i64;
+            let first = centre - self.half as i64 + 1;
+            let last = centre + self.half as i64;
+            if finished {
+                if self.next >= total {
+                    break;
+                }
+            } else if last >= self.pushed as i64 {
+                break;
+            }
+
+            let coefs = &self.phases[(pos % self.out_rate) as usize];
+            let value = coefs
+                .iter()
+                .enumerate()
+                .map(|(j, c)| c * self.sample(first + j as i64))
+                .sum();
+            out.push(value);
+            self.next += 1;
+        }
+
+        // Drop input that no future output needs
+        let needed = ((self.next * self.in_rate / self.out_rate) as i64 - self.half as i64 + 1)
+            .max(self.buf_start as i64) as u64;
+        let drop = (needed - self.buf_start) as usize;
+        if drop > 0 {
+            self.buf.drain(..drop.min(self.buf.len()));
+            self.buf_start += drop as u64;
+        }
+    }
+
+    fn push(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        self.buf.extend_from_slice(input);
+        self.pushed += input.len() as u64;
+        self.drain(out, false);
+    }
+
+    fn finish(&mut self, out: &mut Vec<f32>) {
+        self.drain(out, true);
+    }
+}
+
+/// Convert one file, returning a description of what was done.
+fn convert(input: &Path, output: &Path, preset: Preset) -> std::io::Result<String> {
+    let target_rate = preset.sample_rate();
+    let mut reader = WavReader::open(input)?;
+    let format = *reader.format();
+    let channels = usize::from(format.channels);
+    let mut writer = WavWriter::create(output, target_rate, 1)?;
+    let mut resampler = (format.sample_rate != target_rate)
+        .then(|| Resampler::new(format.sample_rate, target_rate));
+
+    let mut frames = Vec::with_capacity(CHUNK_FRAMES * channels);
+    let mut mono = Vec::with_capacity(CHUNK_FRAMES);
+    let mut resampled = Vec::new();
+    loop {
+        frames.clear();
+        if reader.read_frames(CHUNK_FRAMES, &mut frames)? == 0 {
+            break;
+        }
+        mono.clear();
+        mono.extend(
+            frames
+                .chunks_exact(channels)
+                .map(|f| f.iter().sum::<f32>() / channels as f32),
+        );
+        match resampler.as_mut() {
+            Some(r) => {
+                resampled.clear();
+                r.push(&mono, &mut resampled);
+                writer.write(&resampled)?;
+            }
+            None => writer.write(&mono)?,
+        }
+    }
+    if let Some(r) = resampler.as_mut() {
+        resampled.clear();
+        r.finish(&mut resampled);
+        writer.write(&resampled)?;
+    }
+    writer.finish()?;
+
+    Ok(format!(
+        "{:?} {}-bit, {} Hz, {} channel(s) -> s16, {} Hz, mono\n",
+        format.sample_format, format.bits, format.sample_rate, format.channels, target_rate
+    ))
+}
+
+impl TranscodeBackend for NativeBackend {
+    fn transcode(&self, input: &Path, output: &Path) -> std::io::Result<BackendOutput> {
+        let command = vec![
+            "native".to_string(),
+            input.display().to_string(),
+            output.display().to_string(),
+        ];
+        let (log, success) = match convert(input, output, self.preset) {
+            Ok(log) => (log, true),
+            Err(e) => {
+                let _ = std::fs::remove_file(output);
+                (
+                    format!("Invalid data found when processing input: {}\n", e),
+                    false,
+                )
+            }
+        };
+        Ok(BackendOutput {
+            command,
+            log,
+            success,
+        })
+    }
+}
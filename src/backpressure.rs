@@ -0,0 +1,71 @@
+//! Holding back new files while the queue is saturated, so that a flood of
+//! inputs does not grow the queue and the job store without bound.
+//!
+//! Once the number of queued jobs reaches the high-water mark, submitting
+//! blocks until it has drained below the low-water mark, which holds back
+//! the source that submits: a watcher stops enqueueing, a poller stops
+//! polling and an upload through the API waits.
+use crate::jobs::JobStore;
+use crate::shutdown;
+use log::info;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Interval at which a held submission checks the queue again.
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Queue lengths at which submitting is held back and resumed.
+#[derive(Clone, Copy, Debug)]
+pub struct Watermarks {
+    pub high: usize,
+    /// Below `high`; submitting resumes once fewer jobs are queued.
+    pub low: usize,
+}
+
+/// The watermarks, and since when submitting is held back, if it is.
+pub(crate) struct Backpressure {
+    marks: Watermarks,
+    held_since: Mutex<Option<Instant>>,
+}
+
+impl Backpressure {
+    pub(crate) fn new(marks: Watermarks) -> Self {
+        Backpressure {
+            marks,
+            held_since: Mutex::new(None),
+        }
+    }
+
+    /// Block while the queue is saturated, or until a shutdown.
+    pub(crate) fn wait(&self, jobs: &JobStore) {
+        while !shutdown::requested() {
+            let queued = jobs.queued_count();
+            {
+                let mut held_since = self.held_since.lock().unwrap();
+                match *held_since {
+                    Some(since) if queued < self.marks.low => {
+                        info!(
+                            "Queue drained to {} jobs after {}, taking new files again",
+                            queued,
+                            humantime::format_duration(Duration::from_secs(
+                                since.elapsed().as_secs()
+                            ))
+                        );
+                        *held_since = None;
+                        return;
+                    }
+                    Some(_) => {}
+                    None if queued < self.marks.high => return,
+                    None => {
+                        info!(
+                            "Queue is full with {} jobs, holding new files until fewer than {} are queued",
+                            queued, self.marks.low
+                        );
+                        *held_since = Some(Instant::now());
+                    }
+                }
+            }
+            std::thread::sleep(CHECK_INTERVAL);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! Broadcast WAV metadata: a `bext` chunk in every output, with where and
+//! when its audio originated, for archives that require provenance.
+use crate::preset::Preset;
+use crate::wav;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Lengths of the fixed-size text fields of the chunk, in bytes.
+const DESCRIPTION: usize = 256;
+const ORIGINATOR: usize = 32;
+const ORIGINATOR_REFERENCE: usize = 32;
+
+/// Version 1 of the chunk, which adds the UMID to version 0; the fields
+/// of version 2 stay zero within the reserved bytes.
+const VERSION: u16 = 1;
+const UMID: usize = 64;
+const RESERVED: usize = 190;
+
+/// What goes in the `bext` chunk of every output.
+#[derive(Clone, Debug)]
+pub struct Bwf {
+    /// Description of the sound; the input's file name if unset.
+    pub description: Option<String>,
+    /// Name of the organization or system that made the file.
+    pub originator: String,
+}
+
+/// `s` as ASCII padded with NULs to `len` bytes, cut if longer.
+fn field(s: &str, len: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = s
+        .chars()
+        .map(|c| if c.is_ascii() { c as u8 } else { b'?' })
+        .take(len)
+        .collect();
+    bytes.resize(len, 0);
+    bytes
+}
+
+impl Bwf {
+    /// The chunk for the output of `input`, of job `id`, made with
+    /// `preset`: the origination date and time are those the input was
+    /// last modified at, in UTC, and the time reference counts samples at
+    /// the preset's rate.
+    fn chunk(&self, input: &Path, id: &str, preset: Preset) -> Vec<u8> {
+        let rate = preset.sample_rate();
+        let modified = std::fs::metadata(input)
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or_else(|_| SystemTime::now());
+        // e.g. 2024-05-01T13:45:10Z
+        let stamp = humantime::format_rfc3339_seconds(modified).to_string();
+        let since_midnight = modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() % 86_400);
+        let description = self.description.clone().unwrap_or_else(|| {
+            input
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        });
+
+        let mut chunk = Vec::new();
+        chunk.extend(field(&description, DESCRIPTION));
+        chunk.extend(field(&self.originator, ORIGINATOR));
+        chunk.extend(field(id, ORIGINATOR_REFERENCE));
+        chunk.extend(field(&stamp[0..10], 10));
+        chunk.extend(field(&stamp[11..19], 8));
+        chunk.extend((since_midnight * u64::from(rate)).to_le_bytes());
+        chunk.extend(VERSION.to_le_bytes());
+        chunk.extend([0; UMID]);
+        chunk.extend([0; RESERVED]);
+        chunk.extend(
+            format!(
+                "A=PCM,F={},W=16,M=mono,T=transcoderexpress {}\r\n",
+                rate,
+                env!("CARGO_PKG_VERSION")
+            )
+            .as_bytes(),
+        );
+        chunk
+    }
+
+    /// Write the chunk into the output of `input`.
+    pub(crate) fn tag(
+        &self,
+        input: &Path,
+        output: &Path,
+        id: &str,
+        preset: Preset,
+    ) -> std::io::Result<()> {
+        wav::set_chunk(output, b"bext", &self.chunk(input, id, preset))
+    }
+}
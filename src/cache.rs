@@ -0,0 +1,128 @@
+//! A persistent cache of outputs, keyed by the contents of the input and
+//! the settings that shape the output, so that a file dropped again is not
+//! transcoded again.
+//!
+//! Entries are hard links to the outputs where the cache and the output
+//! directory share a file system, and copies elsewhere. An output linked to
+//! an entry is unlinked before it is transcoded again, so that the entry
+//! is not overwritten with it. Restoring an entry touches it, so the cache
+//! can be pruned by age, e.g. by deleting the entries not modified for a
+//! month.
+use crate::TranscodeOptions;
+use crate::jobs;
+use crate::preset::Preset;
+use crate::routing::Variant;
+use crate::sha256::{self, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The cache directory given with `--cache-dir`.
+#[derive(Clone, Debug)]
+pub struct ResultCache {
+    dir: PathBuf,
+}
+
+impl ResultCache {
+    pub fn new(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(ResultCache { dir })
+    }
+
+    /// The key of an input transcoded with `options`, as `variant`.
+    pub fn key(
+        &self,
+        input: &Path,
+        options: &TranscodeOptions,
+        variant: &Variant,
+    ) -> std::io::Result<String> {
+        let mut hasher = Sha256::default();
+        hasher.update(sha256::file(input)?.as_bytes());
+        // Everything that changes the bytes of the output
+        hasher.update(
+            format!(
+                "\0{}\0{:?}\0{:?}",
+                env!("CARGO_PKG_VERSION"),
+                variant.backend,
+                variant.segment_length
+            )
+            .as_bytes(),
+        );
+        // Only when set, so that existing entries keep their keys
+        if let Some(downmix) = options.downmix {
+            hasher.update(format!("\0{:?}\0{:?}", downmix, options.downmix_pan).as_bytes());
+        }
+        if options.agc {
+            hasher.update(b"\0agc");
+        }
+        if variant.preset != Preset::default() {
+            hasher.update(format!("\0{}", variant.preset.as_str()).as_bytes());
+        }
+        if let Some(raw) = crate::raw::find(&options.raw_inputs, input) {
+            hasher.update(
+                format!("\0{}:{}:{}", raw.format, raw.sample_rate, raw.channels).as_bytes(),
+            );
+        }
+        Ok(hasher.hex())
+    }
+
+    fn entry(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(format!("{}.wav", key))
+    }
+
+    /// Put the cached output for `key` at `output`, returning whether there
+    /// was one.
+    pub fn restore(&self, key: &str, output: &Path) -> std::io::Result<bool> {
+        let entry = self.entry(key);
+        if !entry.is_file() {
+            return Ok(false);
+        }
+        place(&entry, output)?;
+        // Kept when pruning by age
+        if let Ok(file) = std::fs::File::options().write(true).open(&entry) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Ok(true)
+    }
+
+    /// Keep `output` as the cached output for `key`.
+    pub fn store(&self, key: &str, output: &Path) -> std::io::Result<()> {
+        let entry = self.entry(key);
+        if let Some(dir) = entry.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        place(output, &entry)
+    }
+}
+
+/// Unlink `output` if it is linked elsewhere, e.g. to a cache entry, so
+/// that writing it creates a new file instead of changing the entry.
+pub fn detach(output: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match std::fs::symlink_metadata(output) {
+            Ok(metadata) if metadata.is_file() && metadata.nlink() > 1 => {
+                std::fs::remove_file(output)?
+            }
+            _ => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = output;
+    Ok(())
+}
+
+/// Link or copy `from` to `to` under a temporary name, then move it into
+/// place, so that `to` is never seen half written.
+pub(crate) fn place(from: &Path, to: &Path) -> std::io::Result<()> {
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let partial = to.with_file_name(format!(".{}.{}.part", name, jobs::new_id()));
+    let placed = std::fs::hard_link(from, &partial)
+        .or_else(|_| std::fs::copy(from, &partial).map(drop))
+        .and_then(|()| std::fs::rename(&partial, to));
+    if placed.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    placed
+}
@@ -0,0 +1,265 @@
+//! Claims on inputs, so that instances watching the same shared directory,
+//! e.g. on NFS, transcode each file once.
+//!
+//! Before a file is transcoded, a marker for it is created in the claim
+//! directory with `O_EXCL`, which only one instance can do; the others skip
+//! the file. Markers are named after the input's path, size and
+//! modification time, so a file replaced by another is claimed anew, and
+//! the instances must see the inputs under the same paths. While a job
+//! runs, its marker is touched regularly. A marker left untouched for
+//! longer than the stale period, or left on this host by a process that is
+//! gone, belongs to a dead instance and is taken over. The marker of a
+//! finished job stays as a record that the file is done; that of a failed
+//! or skipped job is removed, so that the file can be tried again.
+use crate::host;
+use crate::jobs;
+use crate::json::{self, Value};
+use crate::sha256::Sha256;
+use log::{debug, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{RecvTimeoutError, Sender, channel};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// The claim directory given with `--claim-dir`.
+#[derive(Clone, Debug)]
+pub struct Claims {
+    dir: PathBuf,
+    /// A running job's marker untouched for this long is taken over.
+    stale_after: Duration,
+}
+
+/// What became of an attempt to claim an input.
+pub enum Claimed {
+    /// This instance holds the claim and processes the file.
+    Ours(Claim),
+    /// Another instance, described by host and process, is processing it.
+    Theirs(String),
+    /// The file was already transcoded, by the instance described.
+    Done(String),
+}
+
+/// The contents of a marker.
+struct Holder {
+    done: bool,
+    host: String,
+    pid: u32,
+    token: String,
+}
+
+impl Holder {
+    fn parse(text: &str) -> Option<Self> {
+        let value = json::parse(text)?;
+        let field = |key| value.get(key).and_then(Value::as_str).map(str::to_string);
+        Some(Holder {
+            done: field("state")? == "done",
+            host: field("host")?,
+            pid: value.get("pid").and_then(Value::as_u64)? as u32,
+            token: field("token")?,
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("{} (pid {})", self.host, self.pid)
+    }
+}
+
+impl Claims {
+    pub fn new(dir: impl Into<PathBuf>, stale_after: Duration) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Claims { dir, stale_after })
+    }
+
+    /// The marker of the input's current contents.
+    fn marker(&self, input: &Path) -> std::io::Result<PathBuf> {
+        let metadata = std::fs::metadata(input)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut hasher = Sha256::default();
+        hasher.update(input.canonicalize()?.as_os_str().as_encoded_bytes());
+        hasher.update(format!("\0{}\0{}", metadata.len(), modified.as_nanos()).as_bytes());
+        Ok(self.dir.join(format!("{}.claim", &hasher.hex()[..32])))
+    }
+
+    /// Claim `input` for this instance, unless another holds it.
+    pub fn claim(&self, input: &Path) -> std::io::Result<Claimed> {
+        let marker = self.marker(input)?;
+        let token = jobs::new_id();
+        let record = json::Object::new()
+            .str("input", &input.to_string_lossy())
+            .str("state", "running")
+            .str("host", &host::name())
+            .num("pid", std::process::id())
+            .str("token", &token)
+            .finish();
+        // A second attempt after taking over a stale claim, which another
+        // instance may win
+        for _ in 0..2 {
+            match write_new(&marker, &record) {
+                Ok(()) => {
+                    debug!("Claimed {:?} with {:?}", input, marker);
+                    return Ok(Claimed::Ours(Claim::hold(marker, self.stale_after)));
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+            let (holder, age) = match read(&marker) {
+                Ok(found) => found,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let Some(holder) = holder else {
+                // Created, but not written yet, or by something else
+                if age < self.stale_after {
+                    return Ok(Claimed::Theirs("another instance".to_string()));
+                }
+                self.take_over(&marker, None)?;
+                continue;
+            };
+            if holder.done {
+                return Ok(Claimed::Done(holder.describe()));
+            }
+            let gone = holder.host == host::name() && !alive(holder.pid);
+            if age < self.stale_after && !gone {
+                return Ok(Claimed::Theirs(holder.describe()));
+            }
+            warn!(
+                "Taking over the stale claim of {} on {:?}",
+                holder.describe(),
+                input
+            );
+            self.take_over(&marker, Some(&holder.token))?;
+        }
+        Ok(Claimed::Theirs("another instance".to_string()))
+    }
+
+    /// Remove a stale marker, found with `token`.
+    fn take_over(&self, marker: &Path, token: Option<&str>) -> std::io::Result<()> {
+        // Renamed aside first, so that only one instance takes it over
+        let aside = marker.with_extension(format!("stale-{}", jobs::new_id()));
+        match std::fs::rename(marker, &aside) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        }
+        // Another instance may have taken it over and claimed the file
+        // again since it was read; such a claim is put back
+        let taken = read(&aside).ok().and_then(|(holder, _)| holder);
+        if taken.as_ref().map(|h| h.token.as_str()) != token {
+            let _ = std::fs::hard_link(&aside, marker);
+        }
+        std::fs::remove_file(&aside)
+    }
+}
+
+/// A claim held by this instance, touched until the job is done and
+/// released when dropped, unless [`Claim::complete`] kept it.
+pub struct Claim {
+    marker: PathBuf,
+    stop: Option<Sender<()>>,
+    heartbeat: Option<JoinHandle<()>>,
+    done: bool,
+}
+
+impl Claim {
+    fn hold(marker: PathBuf, stale_after: Duration) -> Self {
+        let (stop, stopped) = channel::<()>();
+        let heartbeat = {
+            let marker = marker.clone();
+            let interval = (stale_after / 4).max(Duration::from_secs(1));
+            std::thread::spawn(move || {
+                // Until the sender is dropped
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let touched = OpenOptions::new()
+                        .write(true)
+                        .open(&marker)
+                        .and_then(|file| file.set_modified(SystemTime::now()));
+                    if let Err(e) = touched {
+                        warn!("Failed to refresh the claim {:?}: {}", marker, e);
+                    }
+                }
+            })
+        };
+        Claim {
+            marker,
+            stop: Some(stop),
+            heartbeat: Some(heartbeat),
+            done: false,
+        }
+    }
+
+    fn stop(&mut self) {
+        drop(self.stop.take());
+        if let Some(heartbeat) = self.heartbeat.take() {
+            let _ = heartbeat.join();
+        }
+    }
+
+    /// Keep the marker, recording that the file was transcoded to `output`.
+    pub fn complete(mut self, input: &Path, output: &Path) {
+        self.stop();
+        let record = json::Object::new()
+            .str("input", &input.to_string_lossy())
+            .str("state", "done")
+            .str("host", &host::name())
+            .num("pid", std::process::id())
+            .str("token", "")
+            .str("output", &output.to_string_lossy())
+            .finish();
+        let partial = self
+            .marker
+            .with_extension(format!("done-{}", jobs::new_id()));
+        let written = File::create(&partial)
+            .and_then(|mut file| writeln!(file, "{}", record))
+            .and_then(|()| std::fs::rename(&partial, &self.marker));
+        match written {
+            Ok(()) => self.done = true,
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                warn!("Failed to mark the claim {:?} done: {}", self.marker, e);
+            }
+        }
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.stop();
+        if !self.done
+            && let Err(e) = std::fs::remove_file(&self.marker)
+        {
+            warn!("Failed to release the claim {:?}: {}", self.marker, e);
+        }
+    }
+}
+
+/// Create `path` with `record` in it, if it does not exist yet.
+fn write_new(path: &Path, record: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    writeln!(file, "{}", record)
+}
+
+/// The holder of a marker, if it can be parsed, and the marker's age.
+fn read(path: &Path) -> std::io::Result<(Option<Holder>, Duration)> {
+    let text = std::fs::read_to_string(path)?;
+    let modified = std::fs::metadata(path)?.modified()?;
+    Ok((Holder::parse(&text), modified.elapsed().unwrap_or_default()))
+}
+
+/// Whether a process of this host is still running.
+#[cfg(unix)]
+fn alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks that the process exists
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Whether a process of this host is still running.
+#[cfg(not(unix))]
+fn alive(_pid: u32) -> bool {
+    true
+}
@@ -0,0 +1,124 @@
+//! Concurrency limits per class of input, so that a few giant video files
+//! do not take up every worker while the short audio files wait.
+//!
+//! A class is a set of extensions, or one of the built-in `audio` and
+//! `video` classes. A job whose class is at its limit is set aside, and the
+//! workers take it up again once one of the class's jobs is done; the other
+//! jobs go ahead in the meantime.
+use std::path::Path;
+use std::str::FromStr;
+
+/// Extensions of the built-in `video` class: containers that usually
+/// carry video, and so take long to demux.
+const VIDEO: &[&str] = &[
+    "3gp", "avi", "flv", "m2ts", "m4v", "mkv", "mov", "mp4", "mpeg", "mpg", "mts", "ts", "webm",
+    "wmv",
+];
+
+/// Extensions of the built-in `audio` class.
+const AUDIO: &[&str] = &[
+    "aac", "aif", "aiff", "amr", "caf", "flac", "m4a", "mp3", "oga", "ogg", "opus", "wav", "wma",
+];
+
+/// A class of inputs and the most of its jobs run at once, parsed from
+/// e.g. `video=2`, `audio=8` or `mkv,mp4=2`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConcurrencyClass {
+    /// The class as given, for logs.
+    pub name: String,
+    /// Lowercase extensions without the dot.
+    extensions: Vec<String>,
+    pub limit: usize,
+}
+
+impl FromStr for ConcurrencyClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, limit) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("invalid class {:?}, expected CLASS=COUNT", s))?;
+        let limit = limit
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|&limit| limit > 0)
+            .ok_or_else(|| format!("invalid limit {:?}, expected a positive count", limit))?;
+        let name = name.trim();
+        let extensions = match name {
+            "video" => VIDEO.iter().map(|e| e.to_string()).collect(),
+            "audio" => AUDIO.iter().map(|e| e.to_string()).collect(),
+            _ => name
+                .split(',')
+                .map(|e| e.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|e| !e.is_empty())
+                .collect::<Vec<_>>(),
+        };
+        if extensions.is_empty() {
+            return Err(format!("no extensions in class {:?}", s));
+        }
+        Ok(ConcurrencyClass {
+            name: name.to_string(),
+            extensions,
+            limit,
+        })
+    }
+}
+
+impl ConcurrencyClass {
+    /// Whether `path` belongs to this class, by its extension.
+    pub fn matches(&self, path: &Path) -> bool {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| self.extensions.iter().any(|x| x.eq_ignore_ascii_case(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn class(s: &str) -> ConcurrencyClass {
+        s.parse().unwrap_or_else(|e| panic!("{}: {}", s, e))
+    }
+
+    #[test]
+    fn built_in_classes() {
+        let video = class("video=2");
+        assert_eq!(video.name, "video");
+        assert_eq!(video.limit, 2);
+        assert!(video.matches(Path::new("in/film.MKV")));
+        assert!(video.matches(Path::new("in/clip.mp4")));
+        assert!(!video.matches(Path::new("in/call.wav")));
+        let audio = class(" audio = 8 ");
+        assert_eq!(audio.limit, 8);
+        assert!(audio.matches(Path::new("call.opus")));
+        assert!(!audio.matches(Path::new("film.mkv")));
+    }
+
+    #[test]
+    fn classes_of_extensions() {
+        let class = class("mkv, .MP4=3");
+        assert_eq!(class.name, "mkv, .MP4");
+        assert_eq!(class.extensions, ["mkv", "mp4"]);
+        assert!(class.matches(Path::new("a.mp4")));
+        assert!(class.matches(Path::new("a.Mkv")));
+        assert!(!class.matches(Path::new("a.mov")));
+        assert!(!class.matches(Path::new("mkv")));
+    }
+
+    #[test]
+    fn invalid_classes_are_refused() {
+        for (s, error) in [
+            ("video", "expected CLASS=COUNT"),
+            ("video=0", "expected a positive count"),
+            ("video=-1", "expected a positive count"),
+            ("video=many", "expected a positive count"),
+            ("=2", "no extensions"),
+            (", .=2", "no extensions"),
+        ] {
+            let refusal = s.parse::<ConcurrencyClass>().unwrap_err();
+            assert!(refusal.contains(error), "{}: {}", s, refusal);
+        }
+    }
+}
@@ -0,0 +1,605 @@
+//! Local control socket, for operating a running instance without HTTP.
+//!
+//! A client connects to the Unix socket, writes one command line and reads
+//! the reply until the connection is closed. The first line of a reply is
+//! `ok` or `error <message>`; any output follows. Only the owner of the
+//! process can connect, as the socket is created with mode 0600.
+//!
+//! Commands: `status`, `status json`, `pause`, `resume`, `rescan`,
+//! `queue`, `show <id>`, `remove <id>`, `bump <id or path>`, `cancel <id>`,
+//! `set-level <level>`,
+//! `backfill`, `backfill list`, `submit <path>`, `submit-urgent <path>` and
+//! `retry-failed [class=<class>,...] [since=<secs>] [until=<secs>]`,
+//! with the times in seconds since the Unix epoch.
+use crate::jobs::{JobRecord, JobStatus};
+use crate::marker::DoneMarker;
+use crate::preset::Preset;
+use crate::quarantine::Quarantine;
+use crate::{Submitter, health, json, pause, shutdown, source};
+use clap::ValueEnum;
+use log::{LevelFilter, debug, error, info, warn};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Longest command accepted, in bytes.
+const MAX_COMMAND: u64 = 4 * 1024;
+/// A client that stalls this long is disconnected.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Failed jobs listed by `status`, the latest.
+const RECENT_FAILURES: usize = 5;
+
+/// State shared between a [`ControlSocket`] and its thread.
+struct Shared {
+    submitter: Submitter,
+    rescan: AtomicBool,
+    started: SystemTime,
+    quarantine: Option<Quarantine>,
+    /// The local input directory, for backfills.
+    input_dir: Option<PathBuf>,
+    /// Leave the junk files out of backfills.
+    filter_junk: bool,
+    /// Only backfill the inputs whose marker is there.
+    done_marker: Option<DoneMarker>,
+}
+
+/// Which failed jobs `retry-failed` queues again.
+#[derive(Default)]
+struct RetryFilter {
+    /// Error classes, or quarantine reasons; any if empty.
+    classes: Vec<String>,
+    /// Only jobs that failed in this range.
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+}
+
+impl RetryFilter {
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        let mut filter = RetryFilter::default();
+        let time = |value: &str| {
+            value
+                .parse()
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+                .map_err(|_| format!("invalid time {:?}", value))
+        };
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("class", classes)) => {
+                    filter.classes = classes.split(',').map(str::to_string).collect()
+                }
+                Some(("since", value)) => filter.since = Some(time(value)?),
+                Some(("until", value)) => filter.until = Some(time(value)?),
+                _ => return Err(format!("unknown retry-failed filter {:?}", arg)),
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, class: &str, at: SystemTime) -> bool {
+        (self.classes.is_empty() || self.classes.iter().any(|c| c == class))
+            && self.since.is_none_or(|since| at >= since)
+            && self.until.is_none_or(|until| at <= until)
+    }
+}
+
+/// Seconds since `t`.
+fn since(t: SystemTime) -> u64 {
+    SystemTime::now()
+        .duration_since(t)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Every field of `record`, one per line.
+fn describe(record: &JobRecord) -> String {
+    let timestamp = |t: Option<SystemTime>| {
+        t.map_or_else(String::new, |t| {
+            humantime::format_rfc3339_seconds(t).to_string()
+        })
+    };
+    let mut output = format!(
+        "id: {}\nstatus: {}\ninput: {}\noutput: {}\nqueued at: {}\nstarted at: {}\nfinished at: {}\n",
+        record.id,
+        record.status.as_str(),
+        record.input.display(),
+        record
+            .output
+            .as_ref()
+            .map(|output| output.display().to_string())
+            .unwrap_or_default(),
+        timestamp(Some(record.queued_at)),
+        timestamp(record.started_at),
+        timestamp(record.finished_at)
+    );
+    if record.urgent {
+        output.push_str("priority: high\n");
+    }
+    if let Some(class) = record.error_class {
+        output.push_str(&format!("error class: {}\n", class));
+    }
+    if let Some(error) = &record.error {
+        output.push_str(&format!("error: {}\n", error.trim_end()));
+    }
+    output
+}
+
+impl Shared {
+    fn serve(&self, stream: UnixStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut line = String::new();
+        BufReader::new(&stream)
+            .take(MAX_COMMAND)
+            .read_line(&mut line)?;
+        let command = line.trim();
+        debug!("Control command: {}", command);
+        let reply = match self.execute(command) {
+            Ok(output) => format!("ok\n{}", output),
+            Err(message) => format!("error {}\n", message),
+        };
+        (&stream).write_all(reply.as_bytes())
+    }
+
+    /// Whether the workers are paused, what degrades the process, the queue
+    /// depth, the running jobs with how much audio each has written, the
+    /// latest failures and the uptime, as text or as a JSON object.
+    fn status(&self, as_json: bool) -> String {
+        let jobs = self.submitter.jobs();
+        let running = jobs.list(Some(JobStatus::Running));
+        let mut failed = jobs.list(Some(JobStatus::Failed));
+        failed.sort_by_key(|record| record.finished_at);
+        let failed = &failed[failed.len().saturating_sub(RECENT_FAILURES)..];
+        let uptime = since(self.started);
+        let degraded = health::conditions();
+        if as_json {
+            return json::Object::new()
+                .raw("paused", if pause::paused() { "true" } else { "false" })
+                .raw(
+                    "degraded",
+                    &json::array(degraded.iter().map(|(condition, detail)| {
+                        json::Object::new()
+                            .str("condition", condition)
+                            .str("detail", detail)
+                            .finish()
+                    })),
+                )
+                .num("uptime_seconds", uptime)
+                .num("queued", jobs.queued_count())
+                .raw(
+                    "running",
+                    &json::array(running.iter().map(|record| {
+                        json::Object::new()
+                            .str("id", &record.id)
+                            .str("input", &record.input.to_string_lossy())
+                            .num("running_seconds", record.started_at.map_or(0, since))
+                            .num("written_seconds", record.written())
+                            .finish()
+                    })),
+                )
+                .raw(
+                    "recent_failures",
+                    &json::array(failed.iter().rev().map(JobRecord::to_json)),
+                )
+                .finish();
+        }
+        let mut output = format!(
+            "paused: {}\nuptime: {}\nqueued: {}\nrunning: {}\n",
+            if pause::paused() { "yes" } else { "no" },
+            humantime::format_duration(Duration::from_secs(uptime)),
+            jobs.queued_count(),
+            running.len()
+        );
+        for (condition, detail) in &degraded {
+            output.push_str(&format!("degraded: {} {}\n", condition, detail));
+        }
+        for record in &running {
+            output.push_str(&format!(
+                "  {} {} ({}s, {}s of audio written)\n",
+                record.id,
+                record.input.display(),
+                record.started_at.map_or(0, since),
+                record.written()
+            ));
+        }
+        output.push_str(&format!("recent failures: {}\n", failed.len()));
+        for record in failed.iter().rev() {
+            output.push_str(&format!(
+                "  {} {} ({}): {}\n",
+                record.id,
+                record.input.display(),
+                record.error_class.unwrap_or("failed"),
+                record
+                    .error
+                    .as_deref()
+                    .and_then(|e| e.lines().last())
+                    .unwrap_or_default()
+            ));
+        }
+        output
+    }
+
+    /// Queue the failed jobs that `filter` matches again, with the
+    /// quarantined inputs it matches moved back to where they came from.
+    fn retry_failed(&self, filter: &RetryFilter) -> Result<String, String> {
+        let jobs = self.submitter.jobs();
+        let released = match &self.quarantine {
+            Some(quarantine) => quarantine
+                .release(|reason, at| filter.matches(reason, at))
+                .map_err(|e| format!("cannot release quarantined inputs: {}", e))?,
+            None => Vec::new(),
+        };
+        let mut output = String::new();
+        let mut retried = Vec::new();
+        for input in &released {
+            let id = self.submitter.submit(input);
+            output.push_str(&format!("{} {} (from quarantine)\n", id, input.display()));
+            retried.push((input.clone(), id));
+        }
+        let mut gone = 0;
+        for record in jobs.list(Some(JobStatus::Failed)) {
+            let failed_at = record.finished_at.unwrap_or(record.queued_at);
+            if record.retried_as.is_some()
+                || !filter.matches(record.error_class.unwrap_or_default(), failed_at)
+            {
+                continue;
+            }
+            let retry = match retried.iter().find(|(input, _)| *input == record.input) {
+                Some((_, id)) => id.clone(),
+                None if record.input.is_file() => {
+                    let id = self.submitter.submit(&record.input);
+                    output.push_str(&format!("{} {}\n", id, record.input.display()));
+                    retried.push((record.input.clone(), id.clone()));
+                    id
+                }
+                None => {
+                    gone += 1;
+                    continue;
+                }
+            };
+            jobs.retried(&record.id, &retry);
+        }
+        info!(
+            "Retrying {} failed jobs from the control socket",
+            retried.len()
+        );
+        output.push_str(&format!("retried: {}\n", retried.len()));
+        if gone > 0 {
+            output.push_str(&format!("inputs gone: {}\n", gone));
+        }
+        Ok(output)
+    }
+
+    /// Queue the inputs that have no output and are not queued or running
+    /// already, e.g. because their events were missed while the instance
+    /// was down, or with `list_only` only list them with their outputs.
+    fn backfill(&self, list_only: bool) -> Result<String, String> {
+        let dir = self
+            .input_dir
+            .as_deref()
+            .ok_or("backfill needs a local input directory")?;
+        let inputs = source::missing_outputs(
+            &self.submitter,
+            dir,
+            self.filter_junk,
+            self.done_marker.as_ref(),
+        )
+        .map_err(|e| format!("cannot scan {}: {}", dir.display(), e))?;
+        let mut output = String::new();
+        let missing = inputs.len();
+        for (input, target) in inputs {
+            if list_only {
+                output.push_str(&format!("{} -> {}\n", input.display(), target.display()));
+            } else {
+                let id = self.submitter.submit(&input);
+                output.push_str(&format!("{} {}\n", id, input.display()));
+            }
+        }
+        if !list_only {
+            info!(
+                "Backfill from the control socket queued {} inputs without an output",
+                missing
+            );
+        }
+        output.push_str(&format!("missing: {}\n", missing));
+        Ok(output)
+    }
+
+    fn execute(&self, command: &str) -> Result<String, String> {
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        if name == "submit" || name == "submit-urgent" {
+            // The rest of the line, as paths may have spaces
+            let (preset, path) = submit_args(&command[name.len()..])?;
+            if path.as_os_str().is_empty() {
+                return Err("submit needs an argument".to_string());
+            }
+            if !path.is_file() {
+                return Err(format!("{} is not a file", path.display()));
+            }
+            let urgent = name == "submit-urgent";
+            let id = match preset {
+                Some(preset) => self.submitter.submit_with_preset(path, preset, urgent),
+                None if urgent => self.submitter.submit_urgent(path),
+                None => self.submitter.submit(path),
+            };
+            info!("Queued {:?} from the control socket", path);
+            return Ok(format!("{} {}\n", id, path.display()));
+        }
+        if name == "bump" {
+            // An ID, or the rest of the line as a path
+            let target = command[name.len()..].trim();
+            if target.is_empty() {
+                return Err("bump needs an argument".to_string());
+            }
+            let jobs = self.submitter.jobs();
+            let id = match jobs.get(target) {
+                Some(_) => target.to_string(),
+                None => jobs
+                    .queued_with_input(Path::new(target))
+                    .ok_or_else(|| format!("no job {} and no queued job of that path", target))?,
+            };
+            return match jobs.bump(&id) {
+                Some(record) if record.status == JobStatus::Queued => {
+                    info!(
+                        "Moved job {} to the front of the queue from the control socket",
+                        id
+                    );
+                    Ok(format!("{} {}\n", id, record.input.display()))
+                }
+                Some(record) => Err(format!(
+                    "job {} is {}, not queued",
+                    id,
+                    record.status.as_str()
+                )),
+                None => Err(format!("no job {}", id)),
+            };
+        }
+        if name == "retry-failed" {
+            let args: Vec<&str> = words.collect();
+            return self.retry_failed(&RetryFilter::parse(&args)?);
+        }
+        let arg = words.next();
+        if words.next().is_some() {
+            return Err(format!("too many arguments to {}", name));
+        }
+        let jobs = self.submitter.jobs();
+        match (name, arg) {
+            ("status", None) => Ok(self.status(false)),
+            ("status", Some("json")) => Ok(self.status(true) + "\n"),
+            ("pause", None) => {
+                pause::pause();
+                info!("Paused from the control socket");
+                Ok(String::new())
+            }
+            ("resume", None) => {
+                pause::resume();
+                info!("Resumed from the control socket");
+                Ok(String::new())
+            }
+            ("rescan", None) => {
+                self.rescan.store(true, Ordering::SeqCst);
+                Ok("rescan requested\n".to_string())
+            }
+            ("backfill", None) => self.backfill(false),
+            ("backfill", Some("list")) => self.backfill(true),
+            ("backfill", Some(arg)) => Err(format!("unknown backfill argument {:?}", arg)),
+            ("queue", None) => {
+                let mut queued = jobs.list(Some(JobStatus::Queued));
+                // In the order the workers take them
+                queued.sort_by_key(|record| !record.urgent);
+                Ok(queued
+                    .iter()
+                    .map(|record| {
+                        format!(
+                            "{} {}s {}{}\n",
+                            record.id,
+                            since(record.queued_at),
+                            record.input.display(),
+                            if record.urgent {
+                                " (high priority)"
+                            } else {
+                                ""
+                            }
+                        )
+                    })
+                    .collect())
+            }
+            ("show", Some(id)) => jobs
+                .get(id)
+                .map(|record| describe(&record))
+                .ok_or_else(|| format!("no job {}", id)),
+            ("remove", Some(id)) => match jobs.get(id).map(|record| record.status) {
+                Some(JobStatus::Queued) => {
+                    jobs.cancel(id);
+                    info!("Removed job {} from the queue from the control socket", id);
+                    Ok(format!("{} removed\n", id))
+                }
+                Some(status) => Err(format!("job {} is {}, not queued", id, status.as_str())),
+                None => Err(format!("no job {}", id)),
+            },
+            ("cancel", Some(id)) => match jobs.cancel(id) {
+                Some(record) => Ok(format!("{} {}\n", record.id, record.status.as_str())),
+                None => Err(format!("no job {}", id)),
+            },
+            ("set-level", Some(level)) => {
+                let level: LevelFilter = level
+                    .parse()
+                    .map_err(|_| format!("unknown log level {}", level))?;
+                log::set_max_level(level);
+                info!("Log level set to {} from the control socket", level);
+                Ok(String::new())
+            }
+            ("status", Some(format)) => Err(format!("unknown status format {:?}", format)),
+            ("pause" | "resume" | "rescan" | "queue", Some(_)) => {
+                Err(format!("{} takes no argument", name))
+            }
+            ("show" | "remove" | "cancel" | "set-level", None) => {
+                Err(format!("{} needs an argument", name))
+            }
+            _ => Err(format!("unknown command {:?}", name)),
+        }
+    }
+}
+
+/// The preset and path of a `submit` command, from the rest of its line:
+/// `[--preset NAME] PATH`, where the path may have spaces.
+fn submit_args(rest: &str) -> Result<(Option<Preset>, &Path), String> {
+    let rest = rest.trim();
+    let Some(option) = rest.strip_prefix("--preset ") else {
+        return Ok((None, Path::new(rest)));
+    };
+    let (name, path) = option
+        .trim_start()
+        .split_once(' ')
+        .unwrap_or((option.trim(), ""));
+    let preset = Preset::from_str(name, true).map_err(|_| format!("unknown preset {:?}", name))?;
+    Ok((Some(preset), Path::new(path.trim())))
+}
+
+/// A control socket served on a background thread until it is dropped or
+/// a shutdown is requested.
+pub struct ControlSocket {
+    path: PathBuf,
+    shared: Arc<Shared>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ControlSocket {
+    /// Listen on `path`, replacing a stale socket left by an instance that
+    /// did not exit cleanly; `retry-failed` also releases the inputs of
+    /// `quarantine`, and `backfill` looks for inputs in `input_dir`, with
+    /// `filter_junk` leaving the junk files out and `done_marker` the
+    /// inputs without a marker.
+    pub fn start(
+        path: &Path,
+        submitter: Submitter,
+        quarantine: Option<Quarantine>,
+        input_dir: Option<PathBuf>,
+        filter_junk: bool,
+        done_marker: Option<DoneMarker>,
+    ) -> std::io::Result<Self> {
+        if UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(
+                ErrorKind::AddrInUse,
+                "another instance is listening on it",
+            ));
+        }
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        // Non-blocking, so the accept loop notices when to stop
+        listener.set_nonblocking(true)?;
+        let shared = Arc::new(Shared {
+            submitter,
+            rescan: AtomicBool::new(false),
+            started: SystemTime::now(),
+            quarantine,
+            input_dir,
+            filter_junk,
+            done_marker,
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let shared = shared.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) && !shutdown::requested() {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            if let Err(e) = stream
+                                .set_nonblocking(false)
+                                .and_then(|()| shared.serve(stream))
+                            {
+                                warn!("Control connection failed: {}", e);
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            std::thread::sleep(Duration::from_millis(100));
+                        }
+                        Err(e) => {
+                            error!("Failed to accept control connection: {}", e);
+                            std::thread::sleep(Duration::from_secs(1));
+                        }
+                    }
+                }
+            })
+        };
+        info!("Listening for control commands on {}", path.display());
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+            shared,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Whether a rescan was requested since the last call.
+    pub fn rescan_requested(&self) -> bool {
+        self.shared.rescan.swap(false, Ordering::SeqCst)
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Send one command to the control socket at `path` and return its output,
+/// or the error it replied with.
+pub fn request(path: &Path, command: &str) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    writeln!(stream, "{}", command)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    let (status, output) = reply.split_once('\n').unwrap_or((&reply, ""));
+    match status.split_once(' ') {
+        _ if status == "ok" => Ok(output.to_string()),
+        Some(("error", message)) => Err(std::io::Error::other(message.to_string())),
+        _ => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "malformed reply from the control socket",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn submit_takes_paths_with_spaces() {
+        let (preset, path) = submit_args(" /srv/in/call 7.wav ").unwrap();
+        assert_eq!(preset, None);
+        assert_eq!(path, Path::new("/srv/in/call 7.wav"));
+    }
+
+    #[test]
+    fn submit_takes_a_preset_before_the_path() {
+        let (preset, path) = submit_args(" --preset telephony /srv/in/call 7.wav").unwrap();
+        assert_eq!(preset, Some(Preset::Telephony));
+        assert_eq!(path, Path::new("/srv/in/call 7.wav"));
+        let (preset, path) = submit_args("--preset archive").unwrap();
+        assert_eq!(preset, Some(Preset::Archive));
+        assert_eq!(path, Path::new(""));
+    }
+
+    #[test]
+    fn submit_refuses_unknown_presets() {
+        assert!(submit_args("--preset vinyl /srv/in/call.wav").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! Helpers shared by the object storage integrations, which make their
+//! HTTP requests with the curl executable.
+
+// Each storage feature uses its own subset of these
+#![allow(dead_code)]
+
+use crate::throttle;
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Percent-encode a string for a URL path or query, optionally keeping `/`.
+pub fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Quote a value for a curl config file.
+pub fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A unique hidden file name in `dir`, e.g. `.s3-<pid>-<n>.part`.
+pub fn scratch_path(dir: &Path, prefix: &str, extension: &str) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    dir.join(format!(
+        ".{}-{}-{}.{}",
+        prefix,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        extension
+    ))
+}
+
+/// Write a file only the current user can read, for credentials.
+pub fn private_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// Value of the last `name:` header in a response dumped with `-i`.
+pub fn response_header(response: &[u8], name: &str) -> Option<String> {
+    String::from_utf8_lossy(response)
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(field, _)| field.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+        .next_back()
+}
+
+/// Inner text of every `<tag>` element, with entities decoded.
+pub fn xml_elements(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        found.push(
+            rest[..end]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&"),
+        );
+        rest = &rest[end + close.len()..];
+    }
+    found
+}
+
+/// Run curl with retries on transient errors, returning the response body.
+/// `stdin` is fed to curl, e.g. as a config file with `-K -`.
+///
+/// File uploads (`-T`) are held to the write limit of [`throttle`], and
+/// downloads into a file (`-o`) to the read limit.
+pub fn run<S: AsRef<OsStr>>(
+    service: &str,
+    args: impl IntoIterator<Item = S>,
+    stdin: Option<&[u8]>,
+) -> std::io::Result<Vec<u8>> {
+    let args: Vec<S> = args.into_iter().collect();
+    let has = |flag: &str| args.iter().any(|arg| arg.as_ref() == flag);
+    let limit = match (has("-T"), has("-o")) {
+        (true, _) => throttle::write_limit(),
+        (false, true) => throttle::read_limit(),
+        (false, false) => None,
+    };
+    let mut command = Command::new("curl");
+    command.args(["-sS", "--fail", "--retry", "3"]);
+    if let Some(limit) = limit {
+        command.arg("--limit-rate").arg(limit.to_string());
+    }
+    let mut child = command
+        .args(args)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let (Some(data), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        pipe.write_all(data)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "{} request failed: {}",
+            service,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
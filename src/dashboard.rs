@@ -0,0 +1,213 @@
+//! Web dashboard for operators, served next to the HTTP API.
+//!
+//! `GET /dashboard` is a single page, embedded in the binary, that shows
+//! the queue, the running jobs with the audio they have written so far,
+//! the failures with ffmpeg's diagnostics and the throughput per hour,
+//! with buttons to cancel and retry jobs. It needs no bearer token itself
+//! but asks for one if the API does, and uses the API and these routes:
+//!
+//! - `GET /dashboard/progress` lists the running jobs with the seconds of
+//!   audio each has written.
+//! - `GET /dashboard/throughput?hours=N` counts the jobs that finished in
+//!   each of the last `N` hours, 24 by default, with their audio: from the
+//!   audit log if there is one, else from the jobs in memory.
+//! - `POST /dashboard/retry/{id}` queues the input of a failed or
+//!   cancelled job again.
+use crate::audit;
+use crate::http::{Body, Endpoint, Request, Response};
+use crate::jobs::JobStatus;
+use crate::{Submitter, json};
+use log::{info, warn};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The page, with its script and styles inline.
+const PAGE: &str = include_str!("dashboard.html");
+/// Width of a throughput bucket.
+const BUCKET: Duration = Duration::from_secs(3600);
+/// Most hours of throughput returned, 30 days.
+const MAX_HOURS: u64 = 30 * 24;
+
+/// Jobs and audio of one hour.
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+    succeeded: u64,
+    failed: u64,
+    audio_seconds: f64,
+}
+
+/// The `/dashboard` page and its routes.
+pub struct DashboardEndpoint {
+    submitter: Submitter,
+    /// Where the history behind the throughput comes from, if anywhere
+    /// beyond the jobs in memory.
+    audit_log: Option<PathBuf>,
+}
+
+impl DashboardEndpoint {
+    pub fn new(submitter: Submitter, audit_log: Option<PathBuf>) -> Self {
+        DashboardEndpoint {
+            submitter,
+            audit_log,
+        }
+    }
+
+    fn progress(&self) -> Response {
+        let running = self.submitter.jobs().list(Some(JobStatus::Running));
+        let body = json::Object::new()
+            .raw(
+                "jobs",
+                &json::array(running.iter().map(|record| {
+                    json::Object::new()
+                        .str("id", &record.id)
+                        .num("written_seconds", record.written())
+                        .finish()
+                })),
+            )
+            .finish();
+        Response::json(200, body)
+    }
+
+    fn throughput(&self, request: &Request) -> Response {
+        let hours = match request.query("hours") {
+            None => 24,
+            Some(hours) => match hours.parse::<u64>() {
+                Ok(hours) if (1..=MAX_HOURS).contains(&hours) => hours,
+                _ => {
+                    return Response::error(
+                        400,
+                        &format!("hours must be between 1 and {}", MAX_HOURS),
+                    );
+                }
+            },
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        // Buckets start on the hour, the last one is the current hour
+        let first = (now / BUCKET.as_secs() + 1 - hours) * BUCKET.as_secs();
+        let mut buckets = vec![Bucket::default(); hours as usize];
+        let mut add = |finished: SystemTime, succeeded: bool, audio_seconds: f64| {
+            let at = finished
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let Some(bucket) = at
+                .checked_sub(first)
+                .and_then(|offset| buckets.get_mut((offset / BUCKET.as_secs()) as usize))
+            else {
+                return;
+            };
+            if succeeded {
+                bucket.succeeded += 1;
+                bucket.audio_seconds += audio_seconds;
+            } else {
+                bucket.failed += 1;
+            }
+        };
+        let source = match &self.audit_log {
+            Some(path) => match audit::read(path) {
+                Ok(entries) => {
+                    for entry in entries {
+                        add(
+                            entry.finished,
+                            entry.succeeded,
+                            entry.audio_seconds.unwrap_or_default(),
+                        );
+                    }
+                    "audit log"
+                }
+                Err(e) => {
+                    warn!("Cannot read the audit log {}: {}", path.display(), e);
+                    return Response::error(500, "cannot read the audit log");
+                }
+            },
+            None => {
+                for record in self.submitter.jobs().list(None) {
+                    let Some(finished) = record.finished_at else {
+                        continue;
+                    };
+                    match record.status {
+                        JobStatus::Succeeded => add(finished, true, record.written() as f64),
+                        JobStatus::Failed => add(finished, false, 0.0),
+                        _ => {}
+                    }
+                }
+                "memory"
+            }
+        };
+        let body = json::Object::new()
+            .str("source", source)
+            .num("bucket_seconds", BUCKET.as_secs())
+            .raw(
+                "buckets",
+                &json::array(buckets.iter().enumerate().map(|(i, bucket)| {
+                    let start = UNIX_EPOCH + Duration::from_secs(first) + BUCKET * i as u32;
+                    json::Object::new()
+                        .str(
+                            "start",
+                            &humantime::format_rfc3339_seconds(start).to_string(),
+                        )
+                        .num("succeeded", bucket.succeeded)
+                        .num("failed", bucket.failed)
+                        .num("audio_seconds", format!("{:.1}", bucket.audio_seconds))
+                        .finish()
+                })),
+            )
+            .finish();
+        Response::json(200, body)
+    }
+
+    fn retry(&self, id: &str) -> Response {
+        let jobs = self.submitter.jobs();
+        let Some(record) = jobs.get(id) else {
+            return Response::error(404, "no such job");
+        };
+        if !matches!(record.status, JobStatus::Failed | JobStatus::Cancelled) {
+            return Response::error(409, "only failed and cancelled jobs can be retried");
+        }
+        if let Some(retry) = &record.retried_as {
+            return Response::error(409, &format!("already retried as job {}", retry));
+        }
+        if !record.input.is_file() {
+            return Response::error(
+                409,
+                "the input is gone; quarantined inputs are retried with retry-failed",
+            );
+        }
+        let retry = self.submitter.submit(&record.input);
+        jobs.retried(id, &retry);
+        info!("Retrying job {} as {} from the dashboard", id, retry);
+        match jobs.get(&retry) {
+            Some(record) => Response::json(202, record.to_json()),
+            None => Response::error(404, "no such job"),
+        }
+    }
+}
+
+impl Endpoint for DashboardEndpoint {
+    fn handle(&self, request: &mut Request) -> Option<Response> {
+        let rest = request.path.strip_prefix("/dashboard")?;
+        Some(match (request.method.as_str(), rest) {
+            ("GET" | "HEAD", "" | "/") => Response {
+                status: 200,
+                content_type: "text/html; charset=utf-8",
+                headers: Vec::new(),
+                body: Body::Bytes(PAGE.as_bytes().to_vec()),
+            }
+            .header("Cache-Control", "no-cache"),
+            ("GET", "/progress") => self.progress(),
+            ("GET", "/throughput") => self.throughput(request),
+            ("POST", id) if id.starts_with("/retry/") => self.retry(&id["/retry/".len()..]),
+            (_, "" | "/" | "/progress" | "/throughput") => Response::error(405, "use GET"),
+            (_, id) if id.starts_with("/retry/") => Response::error(405, "use POST"),
+            _ => return None,
+        })
+    }
+
+    fn public(&self, request: &Request) -> bool {
+        matches!(request.method.as_str(), "GET" | "HEAD")
+            && matches!(request.path.as_str(), "/dashboard" | "/dashboard/")
+    }
+}
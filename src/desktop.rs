@@ -0,0 +1,86 @@
+//! Desktop notifications.
+//!
+//! Uses the notification tool that ships with each platform, the same way
+//! transcoding shells out to ffmpeg: `notify-send` (D-Bus) on Linux and BSD,
+//! `osascript` on macOS and PowerShell on Windows.
+use crate::JobResult;
+use log::warn;
+use std::process::Command;
+use std::thread;
+
+/// Build the platform command that shows a notification.
+#[cfg(target_os = "macos")]
+fn command(title: &str, body: &str) -> Command {
+    let quote = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+    let script = format!(
+        "display notification \"{}\" with title \"{}\"",
+        quote(body),
+        quote(title)
+    );
+    let mut cmd = Command::new("osascript");
+    cmd.args(["-e", &script]);
+    cmd
+}
+
+/// Build the platform command that shows a notification.
+#[cfg(windows)]
+fn command(title: &str, body: &str) -> Command {
+    let quote = |s: &str| s.replace('\'', "''");
+    let script = format!(
+        "Add-Type -AssemblyName System.Windows.Forms; \
+         $n = New-Object System.Windows.Forms.NotifyIcon; \
+         $n.Icon = [System.Drawing.SystemIcons]::Information; \
+         $n.Visible = $true; \
+         $n.ShowBalloonTip(5000, '{}', '{}', 'Info'); \
+         Start-Sleep -Seconds 5; $n.Dispose()",
+        quote(title),
+        quote(body)
+    );
+    let mut cmd = Command::new("powershell");
+    cmd.args(["-NoProfile", "-NonInteractive", "-Command", &script]);
+    cmd
+}
+
+/// Build the platform command that shows a notification.
+#[cfg(not(any(target_os = "macos", windows)))]
+fn command(title: &str, body: &str) -> Command {
+    let mut cmd = Command::new("notify-send");
+    cmd.args(["--app-name=transcoderexpress", title, body]);
+    cmd
+}
+
+/// Show a toast for a finished job.
+pub fn notify(outcome: &JobResult) {
+    let name = outcome
+        .input
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (title, body) = match &outcome.error {
+        None => (
+            "Transcoding finished",
+            format!("{} saved to {}", name, outcome.output.display()),
+        ),
+        Some(e) => (
+            "Transcoding failed",
+            format!("{}: {}", name, e.lines().last().unwrap_or_default()),
+        ),
+    };
+
+    show(title, &body);
+}
+
+/// Show a toast for something that is not about one job.
+pub fn alert(title: &str, body: &str) {
+    show(title, body);
+}
+
+fn show(title: &str, body: &str) {
+    // Windows balloon tips block while shown, so reap the child elsewhere
+    match command(title, body).spawn() {
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => warn!("Failed to show desktop notification: {}", e),
+    }
+}
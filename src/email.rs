@@ -0,0 +1,180 @@
+//! SMTP digest notifications.
+//!
+//! Job outcomes are collected and mailed as a single digest, either when the
+//! queue drains after a batch of files or once a day. Only plain SMTP is
+//! spoken, which is what internal relays on air-gapped networks expect.
+use crate::JobResult;
+use clap::ValueEnum;
+use log::{error, info};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// When to send the collected digest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DigestSchedule {
+    /// Send when the queue is empty after processing one or more files.
+    Batch,
+    /// Send once every 24 hours.
+    Daily,
+}
+
+/// SMTP relay and envelope settings.
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Collects job outcomes and mails them according to the schedule.
+pub struct EmailDigest {
+    config: SmtpConfig,
+    schedule: DigestSchedule,
+    successes: Vec<String>,
+    failures: Vec<(String, String)>,
+    since: Instant,
+}
+
+impl EmailDigest {
+    pub fn new(config: SmtpConfig, schedule: DigestSchedule) -> Self {
+        EmailDigest {
+            config,
+            schedule,
+            successes: Vec::new(),
+            failures: Vec::new(),
+            since: Instant::now(),
+        }
+    }
+
+    /// Add a finished job to the pending digest.
+    pub fn record(&mut self, outcome: &JobResult) {
+        let input = outcome.input.display().to_string();
+        match &outcome.error {
+            None => self.successes.push(input),
+            Some(e) => self.failures.push((input, e.clone())),
+        }
+    }
+
+    /// Called when the queue has drained.
+    pub fn batch_done(&mut self) {
+        if self.schedule == DigestSchedule::Batch {
+            self.flush();
+        }
+    }
+
+    /// Called periodically by the consumer while idle.
+    pub fn tick(&mut self) {
+        if self.schedule == DigestSchedule::Daily && self.since.elapsed() >= DAY {
+            self.flush();
+        }
+    }
+
+    /// Send an alert right away, outside of the digest.
+    pub fn alert(&self, subject: &str, message: &str) {
+        let subject = format!("transcoderexpress: {}", subject);
+        match send(&self.config, &subject, message) {
+            Ok(()) => info!("Alert email sent to {}", self.config.to.join(", ")),
+            Err(e) => error!("Failed to send alert email: {}", e),
+        }
+    }
+
+    /// Send the pending digest, if there is anything in it.
+    fn flush(&mut self) {
+        self.since = Instant::now();
+        if self.successes.is_empty() && self.failures.is_empty() {
+            return;
+        }
+
+        let subject = format!(
+            "transcoderexpress: {} succeeded, {} failed",
+            self.successes.len(),
+            self.failures.len()
+        );
+        let mut body = String::new();
+        if !self.failures.is_empty() {
+            body.push_str("Failed:\n");
+            for (input, reason) in &self.failures {
+                let reason = reason.lines().last().unwrap_or_default();
+                body.push_str(&format!("  {}: {}\n", input, reason));
+            }
+            body.push('\n');
+        }
+        if !self.successes.is_empty() {
+            body.push_str("Succeeded:\n");
+            for input in &self.successes {
+                body.push_str(&format!("  {}\n", input));
+            }
+        }
+
+        match send(&self.config, &subject, &body) {
+            Ok(()) => info!("Digest email sent to {}", self.config.to.join(", ")),
+            Err(e) => error!("Failed to send digest email: {}", e),
+        }
+        self.successes.clear();
+        self.failures.clear();
+    }
+}
+
+/// Read an SMTP reply and check that its code starts with `expect`.
+fn reply(reader: &mut impl BufRead, expect: char) -> std::io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(std::io::Error::other("connection closed by server"));
+        }
+        if !line.starts_with(expect) {
+            return Err(std::io::Error::other(format!(
+                "unexpected reply: {}",
+                line.trim_end()
+            )));
+        }
+        // Multi-line replies use '-' after the code on all but the last line
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+/// Deliver a single message over plain SMTP.
+fn send(config: &SmtpConfig, subject: &str, body: &str) -> std::io::Result<()> {
+    let stream = TcpStream::connect((config.host.as_str(), config.port))?;
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    reply(&mut reader, '2')?;
+    writer.write_all(b"EHLO localhost\r\n")?;
+    reply(&mut reader, '2')?;
+    writer.write_all(format!("MAIL FROM:<{}>\r\n", config.from).as_bytes())?;
+    reply(&mut reader, '2')?;
+    for to in &config.to {
+        writer.write_all(format!("RCPT TO:<{}>\r\n", to).as_bytes())?;
+        reply(&mut reader, '2')?;
+    }
+    writer.write_all(b"DATA\r\n")?;
+    reply(&mut reader, '3')?;
+
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        config.from,
+        config.to.join(", "),
+        subject
+    );
+    for line in body.lines() {
+        // Dot-stuffing, so a line with a single '.' does not end the message
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push_str(".\r\n");
+    writer.write_all(message.as_bytes())?;
+    reply(&mut reader, '2')?;
+
+    writer.write_all(b"QUIT\r\n")?;
+    Ok(())
+}
@@ -0,0 +1,82 @@
+//! Errors that stop the pipeline, and the exit codes they map to.
+//!
+//! A failed conversion is not an error here; it is reported through
+//! [`JobResult::error`](crate::JobResult::error) and the run carries on.
+//! Only a batch run that is over sums its failures up as one.
+use std::fmt;
+
+/// A fatal pipeline error.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid command line or configuration.
+    Config(String),
+    /// The input could not be watched.
+    Watch(notify::Error),
+    /// The transcoder could not be started, e.g. ffmpeg is not installed.
+    Spawn(std::io::Error),
+    /// Any other I/O failure at runtime.
+    Io(std::io::Error),
+    /// Files of a batch run failed to transcode.
+    Failed { failed: u64, succeeded: u64 },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Process exit code for this error:
+    ///
+    /// | Code | Meaning            |
+    /// |------|--------------------|
+    /// | 1    | runtime failure    |
+    /// | 2    | bad configuration  |
+    /// | 3    | watcher failure    |
+    /// | 4    | transcoder missing |
+    /// | 5    | some files failed  |
+    /// | 6    | every file failed  |
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Io(_) => 1,
+            Error::Config(_) => 2,
+            Error::Watch(_) => 3,
+            Error::Spawn(_) => 4,
+            Error::Failed { succeeded: 0, .. } => 6,
+            Error::Failed { .. } => 5,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message) => write!(f, "invalid configuration: {}", message),
+            Error::Watch(e) => write!(f, "failed to watch input: {}", e),
+            Error::Spawn(e) => write!(f, "failed to start the transcoder: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Failed { failed, succeeded } => {
+                write!(f, "{} files failed, {} succeeded", failed, succeeded)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Config(_) | Error::Failed { .. } => None,
+            Error::Watch(e) => Some(e),
+            Error::Spawn(e) | Error::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(e: notify::Error) -> Self {
+        Error::Watch(e)
+    }
+}
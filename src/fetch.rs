@@ -0,0 +1,161 @@
+//! Jobs whose input is an HTTP(S) URL.
+//!
+//! The file is downloaded with the curl executable into a scratch
+//! directory on a thread of its own, then queued like a file fetched from
+//! remote storage. The job is recorded as queued from the start, so it can
+//! be followed, and cancelled, while it downloads. An interrupted download
+//! is resumed, also by a later job for the same URL.
+use crate::sha256::Sha256;
+use crate::{Submitter, curl, jobs};
+use log::{error, info, warn};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Attempts at a download, each resuming where the previous one stopped.
+const ATTEMPTS: u32 = 5;
+/// Largest `.url` file read, in bytes.
+const MAX_URL_FILE: u64 = 64 * 1024;
+
+/// Whether `url` can be downloaded.
+pub fn supported(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// The last path component of a client-supplied name, so a file cannot be
+/// written outside the scratch directory.
+pub fn safe_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    match name.trim() {
+        "" | "." | ".." => "upload".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Download `url` into `dir` in the background and queue it, returning the
+/// job ID. A failed download fails the job.
+pub fn submit_url(submitter: &Submitter, dir: &Path, url: &str) -> String {
+    let id = jobs::new_id();
+    submitter.jobs().queued(&id, Path::new(url));
+    let submitter = submitter.clone();
+    let dir = dir.to_path_buf();
+    let url = url.to_string();
+    let job = id.clone();
+    std::thread::spawn(move || download(&submitter, &dir, &job, &url));
+    id
+}
+
+/// The URL a `.url` drop file points to: its first line that is an
+/// HTTP(S) URL, also in the `URL=` form of Windows Internet shortcuts.
+pub fn url_file(path: &Path) -> std::io::Result<Option<String>> {
+    let mut text = String::new();
+    File::open(path)?
+        .take(MAX_URL_FILE)
+        .read_to_string(&mut text)?;
+    Ok(text
+        .lines()
+        .map(|line| line.trim())
+        .map(|line| line.strip_prefix("URL=").unwrap_or(line))
+        .find(|line| supported(line))
+        .map(str::to_string))
+}
+
+/// Partial downloads in progress in this process.
+static DOWNLOADING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Claims a partial download file while it is written.
+struct Partial(PathBuf);
+
+impl Partial {
+    /// The partial file of `url`, named after the URL so that a later job
+    /// for the same URL resumes it, unless it is already being written.
+    fn claim(dir: &Path, id: &str, url: &str, name: &str) -> Self {
+        let mut hasher = Sha256::default();
+        hasher.update(url.as_bytes());
+        let stable = dir.join(format!(".{}-{}.part", &hasher.hex()[..16], name));
+        let mut downloading = DOWNLOADING.lock().unwrap();
+        let path = match downloading.contains(&stable) {
+            true => dir.join(format!(".{}-{}.part", id, name)),
+            false => stable,
+        };
+        downloading.push(path.clone());
+        Partial(path)
+    }
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        DOWNLOADING.lock().unwrap().retain(|p| *p != self.0);
+    }
+}
+
+fn size(path: &Path) -> u64 {
+    std::fs::metadata(path).map_or(0, |m| m.len())
+}
+
+/// Download `url` to `partial`, resuming after interrupted attempts.
+fn fetch(url: &str, partial: &Path) -> std::io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        let before = size(partial);
+        let result = curl::run(
+            "Download",
+            [
+                "-L".as_ref(),
+                "--proto".as_ref(),
+                "=http,https".as_ref(),
+                "-C".as_ref(),
+                "-".as_ref(),
+                url.as_ref(),
+                "-o".as_ref(),
+                partial.as_os_str(),
+            ],
+            None,
+        );
+        match result {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt >= ATTEMPTS => return Err(e),
+            Err(e) if before > 0 && size(partial) <= before => {
+                // Nothing was added, e.g. as the server cannot resume
+                warn!("Restarting download of {}: {}", url, e);
+                std::fs::remove_file(partial)?;
+            }
+            Err(e) => warn!(
+                "Download of {} stopped at {} bytes, resuming: {}",
+                url,
+                size(partial),
+                e
+            ),
+        }
+        attempt += 1;
+    }
+}
+
+fn download(submitter: &Submitter, dir: &Path, id: &str, url: &str) {
+    let name = url
+        .split(['?', '#'])
+        .next()
+        .and_then(|u| u.rsplit('/').next())
+        .unwrap_or_default();
+    let name = safe_name(name);
+    let dest = dir.join(format!("{}-{}", id, name));
+    let partial = Partial::claim(dir, id, url, &name);
+    let result = std::fs::create_dir_all(dir)
+        .and_then(|()| fetch(url, &partial.0))
+        .and_then(|()| std::fs::rename(&partial.0, &dest));
+    match result {
+        Ok(()) => {
+            info!("Downloaded {} for job {}", url, id);
+            submitter.submit_fetched_as(id, &dest);
+        }
+        Err(e) => {
+            // What was downloaded is kept, for a retry to resume
+            error!("Failed to download {} for job {}: {}", url, id, e);
+            submitter
+                .jobs()
+                .failed(id, &e.to_string(), "download_failed");
+        }
+    }
+}
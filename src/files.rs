@@ -0,0 +1,246 @@
+//! Read-only HTTP access to the output directory.
+//!
+//! `GET /<path>` returns a file below the directory, honouring a single
+//! `Range: bytes=...` so that large outputs can be fetched in parts or
+//! resumed; `HEAD` gives its size. On a directory, it returns a JSON
+//! listing: `{"files": [{"name", "size", "modified"}]}`, with `/` after the
+//! names of subdirectories. Hidden files, e.g. partial uploads, and outputs
+//! of jobs still running are not served.
+use crate::http::{Body, Endpoint, Request, Response};
+use crate::jobs::JobStore;
+use crate::json;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// Content type of a served file, guessed from its extension.
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("wav") => "audio/wav",
+        Some("json") => "application/json",
+        Some("txt" | "log" | "srt") => "text/plain; charset=utf-8",
+        Some("csv") => "text/csv",
+        Some("xml") => "application/xml",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The byte range asked for by a `Range` header, for a file of `size`
+/// bytes: `Ok(None)` for the whole file, and `Err(())` if unsatisfiable.
+fn range(header: Option<&str>, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return Ok(None);
+    };
+    // Several ranges would need a multipart response; the whole file is
+    // an allowed answer instead
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            (size.saturating_sub(suffix), size.checked_sub(1).ok_or(())?)
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, size.saturating_sub(1)),
+        (start, end) => (
+            start.parse().map_err(|_| ())?,
+            end.parse::<u64>()
+                .map_err(|_| ())?
+                .min(size.saturating_sub(1)),
+        ),
+    };
+    if start > end || start >= size {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// Serves the files below one directory.
+pub struct FilesEndpoint {
+    root: PathBuf,
+    jobs: Arc<JobStore>,
+}
+
+impl FilesEndpoint {
+    pub fn new(root: impl Into<PathBuf>, jobs: Arc<JobStore>) -> Self {
+        FilesEndpoint {
+            root: root.into(),
+            jobs,
+        }
+    }
+
+    /// The local path of a request path, if it names nothing hidden and
+    /// stays inside the root once links are resolved.
+    fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for component in Path::new(request_path.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) if !part.to_string_lossy().starts_with('.') => {
+                    path.push(part)
+                }
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        self.inside(&path).then_some(path)
+    }
+
+    /// Whether `path` exists and stays inside the root once links are
+    /// resolved.
+    fn inside(&self, path: &Path) -> bool {
+        path.canonicalize()
+            .ok()
+            .zip(self.root.canonicalize().ok())
+            .is_some_and(|(path, root)| path.starts_with(root))
+    }
+
+    fn list(&self, dir: &Path) -> Response {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Response::error(403, "directory cannot be read");
+        };
+        let mut files: Vec<(String, u64, String)> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .filter(|entry| self.inside(&entry.path()) && !self.jobs.in_progress(&entry.path()))
+            .filter_map(|entry| {
+                let metadata = std::fs::metadata(entry.path()).ok()?;
+                let mut name = entry.file_name().to_string_lossy().into_owned();
+                if metadata.is_dir() {
+                    name.push('/');
+                }
+                let modified = metadata.modified().ok()?;
+                Some((
+                    name,
+                    if metadata.is_dir() { 0 } else { metadata.len() },
+                    humantime::format_rfc3339_seconds(modified).to_string(),
+                ))
+            })
+            .collect();
+        files.sort();
+        let files = files.iter().map(|(name, size, modified)| {
+            json::Object::new()
+                .str("name", name)
+                .num("size", size)
+                .str("modified", modified)
+                .finish()
+        });
+        Response::json(
+            200,
+            json::Object::new()
+                .raw("files", &json::array(files))
+                .finish(),
+        )
+    }
+
+    fn get(&self, request: &Request) -> Response {
+        let Some(path) = self.resolve(&request.path) else {
+            return Response::error(404, "not found");
+        };
+        if path.is_dir() {
+            return self.list(&path);
+        }
+        if self.jobs.in_progress(&path) {
+            return Response::error(404, "not found");
+        }
+        let Ok(mut file) = File::open(&path) else {
+            return Response::error(403, "file cannot be read");
+        };
+        let size = file.metadata().map_or(0, |m| m.len());
+        let (status, start, length) = match range(request.header("Range"), size) {
+            Ok(None) => (200, 0, size),
+            Ok(Some((start, end))) => (206, start, end - start + 1),
+            Err(()) => {
+                return Response::error(416, "range not satisfiable")
+                    .header("Content-Range", format!("bytes */{}", size));
+            }
+        };
+        let mut response = Response {
+            status,
+            content_type: content_type(&path),
+            headers: vec![("Accept-Ranges", "bytes".to_string())],
+            body: Body::Sized(
+                length,
+                Box::new(move |out: &mut dyn Write| {
+                    file.seek(SeekFrom::Start(start))?;
+                    std::io::copy(&mut file.take(length), out)?;
+                    Ok(())
+                }),
+            ),
+        };
+        if status == 206 {
+            response = response.header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, start + length - 1, size),
+            );
+        }
+        response
+    }
+}
+
+impl Endpoint for FilesEndpoint {
+    fn handle(&self, request: &mut Request) -> Option<Response> {
+        Some(match request.method.as_str() {
+            "GET" | "HEAD" => self.get(request),
+            _ => Response::error(405, "the output directory is read-only"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The status and headers of a `GET` of `path` below `root`.
+    fn get(root: &Path, path: &str, range: &str) -> (u16, Vec<(&'static str, String)>) {
+        let endpoint = FilesEndpoint::new(root, Arc::new(JobStore::default()));
+        let mut body: &[u8] = &[];
+        let mut request = Request::with_body("GET", path, &mut body, 0).header_set("Range", range);
+        let response = endpoint.handle(&mut request).unwrap();
+        (response.status, response.headers)
+    }
+
+    #[test]
+    fn responses_follow_the_range() {
+        let root = std::env::temp_dir().join(format!("files-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("call.wav"), vec![0u8; 1000]).unwrap();
+        let (status, headers) = get(&root, "/call.wav", "bytes=-500");
+        assert_eq!(status, 206);
+        assert!(headers.contains(&("Content-Range", "bytes 500-999/1000".to_string())));
+        let (status, headers) = get(&root, "/call.wav", "bytes=1000-");
+        assert_eq!(status, 416);
+        assert!(headers.contains(&("Content-Range", "bytes */1000".to_string())));
+        assert_eq!(get(&root, "/call.wav", "bytes=0-1,5-9").0, 200);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ranges_are_clamped_to_the_file() {
+        assert_eq!(range(Some("bytes=0-99"), 1000), Ok(Some((0, 99))));
+        assert_eq!(range(Some("bytes=-500"), 1000), Ok(Some((500, 999))));
+        assert_eq!(range(Some("bytes=-5000"), 1000), Ok(Some((0, 999))));
+        assert_eq!(range(Some("bytes=100-"), 1000), Ok(Some((100, 999))));
+        assert_eq!(range(Some("bytes=900-5000"), 1000), Ok(Some((900, 999))));
+        assert_eq!(range(Some(" bytes= 1 - 2 "), 1000), Ok(Some((1, 2))));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_refused() {
+        assert_eq!(range(Some("bytes=-0"), 1000), Err(()));
+        assert_eq!(range(Some("bytes=1000-"), 1000), Err(()));
+        assert_eq!(range(Some("bytes=1000-1200"), 1000), Err(()));
+        assert_eq!(range(Some("bytes=500-100"), 1000), Err(()));
+        assert_eq!(range(Some("bytes=-5"), 0), Err(()));
+        assert_eq!(range(Some("bytes=abc"), 1000), Err(()));
+        assert_eq!(range(Some("bytes=a-b"), 1000), Err(()));
+    }
+
+    #[test]
+    fn other_requests_get_the_whole_file() {
+        assert_eq!(range(None, 1000), Ok(None));
+        assert_eq!(range(Some("bytes=0-1,5-9"), 1000), Ok(None));
+        assert_eq!(range(Some("items=0-1"), 1000), Ok(None));
+    }
+}
@@ -0,0 +1,215 @@
+//! Duplicate detection by acoustic fingerprint, for the same audio in
+//! another container or under another name, which the byte hashes of the
+//! cache cannot match.
+//!
+//! Every input is fingerprinted with Chromaprint's `fpcalc` before it is
+//! transcoded. An input whose fingerprint matches that of an earlier one,
+//! allowing for lossy re-encoding, is a duplicate: it is skipped, or the
+//! earlier output is linked to its output. Fingerprints of the outputs
+//! made are appended to an index file, so that duplicates are found
+//! across runs.
+use crate::backend;
+use clap::ValueEnum;
+use log::{debug, warn};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+/// Share of differing bits below which two fingerprints are of the same
+/// audio; re-encoding the same audio stays well below it, while unrelated
+/// audio differs in about half.
+const MAX_BIT_ERROR: f64 = 0.1;
+
+/// Items a fingerprint may be shifted by against another, for encoders
+/// that pad the start.
+const MAX_SHIFT: usize = 3;
+
+/// Seconds two durations may differ by for the same audio.
+const MAX_DURATION_DIFF: f64 = 1.0;
+
+/// What to do with a duplicate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Duplicates {
+    /// Skip the job.
+    Skip,
+    /// Hard-link, or copy, the earlier output to the duplicate's output.
+    Link,
+}
+
+/// The raw fingerprint of an input, as `fpcalc -raw` gives it.
+#[derive(Clone, Debug)]
+pub struct Fingerprint {
+    duration: f64,
+    items: Vec<u32>,
+}
+
+impl Fingerprint {
+    /// Fingerprint `input` with fpcalc.
+    pub fn of(input: &Path) -> Result<Self, String> {
+        let output = Command::new("fpcalc")
+            .arg("-raw")
+            .arg(backend::path_arg(input))
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Cannot run fpcalc: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "fpcalc failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut duration = None;
+        let mut items = None;
+        for line in stdout.lines() {
+            match line.split_once('=') {
+                Some(("DURATION", value)) => duration = value.trim().parse().ok(),
+                Some(("FINGERPRINT", value)) => items = parse_items(value),
+                _ => {}
+            }
+        }
+        match (duration, items) {
+            (Some(duration), Some(items)) if !items.is_empty() => {
+                Ok(Fingerprint { duration, items })
+            }
+            _ => Err("fpcalc printed no fingerprint".to_string()),
+        }
+    }
+
+    /// Whether `other` is of the same audio.
+    fn matches(&self, other: &Fingerprint) -> bool {
+        if (self.duration - other.duration).abs() > MAX_DURATION_DIFF {
+            return false;
+        }
+        let error = |a: &[u32], b: &[u32]| {
+            let len = a.len().min(b.len());
+            let bits: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+            (len > 0).then(|| f64::from(bits) / (len as f64 * 32.0))
+        };
+        (0..=MAX_SHIFT.min(self.items.len().saturating_sub(1)))
+            .flat_map(|shift| {
+                [
+                    error(&self.items[shift..], &other.items),
+                    error(&self.items, other.items.get(shift..).unwrap_or_default()),
+                ]
+            })
+            .flatten()
+            .any(|error| error < MAX_BIT_ERROR)
+    }
+}
+
+/// Comma-separated fingerprint items.
+fn parse_items(s: &str) -> Option<Vec<u32>> {
+    s.trim()
+        .split(',')
+        .map(|item| {
+            // fpcalc prints signed items in some versions
+            let item = item.trim();
+            item.parse::<u32>()
+                .ok()
+                .or_else(|| item.parse::<i32>().ok().map(|i| i as u32))
+        })
+        .collect()
+}
+
+/// An earlier input, where its output went, and its fingerprint.
+#[derive(Debug)]
+struct Entry {
+    input: PathBuf,
+    output: PathBuf,
+    fingerprint: Fingerprint,
+}
+
+/// An earlier input of the same audio.
+pub struct Original {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+/// The fingerprints of the outputs made so far, kept in a file of one
+/// tab-separated line per output: duration, input, output, fingerprint.
+/// Clones share the entries.
+#[derive(Clone, Debug)]
+pub struct FingerprintIndex {
+    pub duplicates: Duplicates,
+    path: PathBuf,
+    entries: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl FingerprintIndex {
+    /// Load the index at `path`, which need not exist yet.
+    pub fn open(path: impl Into<PathBuf>, duplicates: Duplicates) -> std::io::Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let entries: Vec<Entry> = text
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                let duration = fields.next()?.parse().ok()?;
+                let input = PathBuf::from(fields.next()?);
+                let output = PathBuf::from(fields.next()?);
+                let items = parse_items(fields.next()?)?;
+                Some(Entry {
+                    input,
+                    output,
+                    fingerprint: Fingerprint { duration, items },
+                })
+            })
+            .collect();
+        debug!("Loaded {} fingerprints from {:?}", entries.len(), path);
+        Ok(FingerprintIndex {
+            duplicates,
+            path,
+            entries: Arc::new(Mutex::new(entries)),
+        })
+    }
+
+    /// The latest earlier input of the same audio whose output is still
+    /// there.
+    pub fn find(&self, fingerprint: &Fingerprint) -> Option<Original> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .rev()
+            .find(|entry| entry.fingerprint.matches(fingerprint) && entry.output.is_file())
+            .map(|entry| Original {
+                input: entry.input.clone(),
+                output: entry.output.clone(),
+            })
+    }
+
+    /// Record the fingerprint of the input that `output` was made of.
+    pub fn add(&self, fingerprint: Fingerprint, input: &Path, output: &Path) {
+        let items: Vec<String> = fingerprint.items.iter().map(u32::to_string).collect();
+        let line = format!(
+            "{}\t{}\t{}\t{}\n",
+            fingerprint.duration,
+            input.to_string_lossy(),
+            output.to_string_lossy(),
+            items.join(",")
+        );
+        let mut entries = self.entries.lock().unwrap();
+        let appended = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(e) = appended {
+            warn!("Failed to add to fingerprint index {:?}: {}", self.path, e);
+        }
+        entries.push(Entry {
+            input: input.to_path_buf(),
+            output: output.to_path_buf(),
+            fingerprint,
+        });
+    }
+}
@@ -0,0 +1,200 @@
+//! FTP and FTPS directories, through the curl executable.
+//!
+//! Only the files directly in the directory are listed. Their size and
+//! modification time, as shown in the server's `LIST` output, serve as the
+//! version, so a file that is rewritten is fetched again. Unix and DOS
+//! style listings are understood.
+use crate::curl::{self, uri_encode};
+use crate::source::{RemoteObject, RemoteStore};
+use crate::url::percent_decode;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+/// How the connection is protected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FtpSecurity {
+    /// Plain FTP, `ftp://`.
+    None,
+    /// TLS from the start, usually on port 990, `ftps://`.
+    Implicit,
+    /// Upgraded with `AUTH TLS` on the control port, `ftpes://`.
+    Explicit,
+}
+
+/// A directory on a server, parsed from
+/// `ftp://[user[:password]@]host[:port]/path/`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FtpLocation {
+    pub security: FtpSecurity,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Host and optional port.
+    pub host: String,
+    /// Directory, empty or ending in `/`.
+    pub path: String,
+}
+
+impl FtpLocation {
+    pub fn parse(url: &str) -> Option<Self> {
+        let (scheme, rest) = url.split_once("://")?;
+        let security = match scheme {
+            "ftp" => FtpSecurity::None,
+            "ftps" => FtpSecurity::Implicit,
+            "ftpes" => FtpSecurity::Explicit,
+            _ => return None,
+        };
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (user, password, host) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => {
+                let (user, password) = match userinfo.split_once(':') {
+                    Some((user, password)) => (user, Some(percent_decode(password, false))),
+                    None => (userinfo, None),
+                };
+                (Some(percent_decode(user, false)), password, host)
+            }
+            None => (None, None, authority),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let mut path = path.trim_matches('/').to_string();
+        if !path.is_empty() {
+            path.push('/');
+        }
+        Some(FtpLocation {
+            security,
+            user,
+            password,
+            host: host.to_string(),
+            path,
+        })
+    }
+
+    fn describe(&self) -> String {
+        let scheme = match self.security {
+            FtpSecurity::None => "ftp",
+            FtpSecurity::Implicit => "ftps",
+            FtpSecurity::Explicit => "ftpes",
+        };
+        match &self.user {
+            Some(user) => format!("{}://{}@{}/{}", scheme, user, self.host, self.path),
+            None => format!("{}://{}/{}", scheme, self.host, self.path),
+        }
+    }
+}
+
+/// The rest of `line` after its first `n` whitespace-separated fields.
+fn after_fields(line: &str, n: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..n {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest
+}
+
+/// Parse one line of a `LIST` response, skipping anything but plain files.
+fn parse_list_line(line: &str) -> Option<RemoteObject> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let first = *fields.first()?;
+    let (size, version, name) = if first.len() == 10 && fields.len() >= 9 {
+        // -rw-r--r--  1 owner group  1234 Oct 14 10:00 name
+        if !first.starts_with('-') {
+            return None;
+        }
+        (fields[4], fields[4..8].join(" "), after_fields(line, 8))
+    } else if first.contains('-') && fields.len() >= 4 {
+        // 10-14-26  10:00AM        1234 name
+        if fields[2] == "<DIR>" {
+            return None;
+        }
+        (fields[2], fields[0..3].join(" "), after_fields(line, 3))
+    } else {
+        return None;
+    };
+    Some(RemoteObject {
+        key: name.to_string(),
+        version,
+        size: size.parse().ok()?,
+    })
+}
+
+/// Files in a directory on an FTP server, for a polling source.
+pub struct FtpStore {
+    location: FtpLocation,
+    /// Directory URL without credentials, ending in `/`.
+    url: String,
+}
+
+impl FtpStore {
+    pub fn new(location: FtpLocation) -> Self {
+        let scheme = match location.security {
+            FtpSecurity::Implicit => "ftps",
+            FtpSecurity::None | FtpSecurity::Explicit => "ftp",
+        };
+        let path = location
+            .path
+            .split('/')
+            .map(|part| uri_encode(part, false))
+            .collect::<Vec<_>>()
+            .join("/");
+        FtpStore {
+            url: format!("{}://{}/{}", scheme, location.host, path),
+            location,
+        }
+    }
+
+    /// Run one curl request, with the credentials passed on its stdin.
+    fn request<S: AsRef<OsStr>>(
+        &self,
+        args: impl IntoIterator<Item = S>,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut config = String::new();
+        if let Some(user) = &self.location.user {
+            let password = self
+                .location
+                .password
+                .clone()
+                .or_else(|| std::env::var("FTP_PASSWORD").ok())
+                .unwrap_or_default();
+            config.push_str(&format!(
+                "user = {}\n",
+                curl::quote(&format!("{}:{}", user, password))
+            ));
+        }
+        let mut all: Vec<OsString> = vec!["-K".into(), "-".into()];
+        if self.location.security == FtpSecurity::Explicit {
+            all.push("--ssl-reqd".into());
+        }
+        all.extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        curl::run("FTP", all, Some(config.as_bytes()))
+    }
+}
+
+impl RemoteStore for FtpStore {
+    fn list(&self) -> std::io::Result<Vec<RemoteObject>> {
+        let listing = self.request([&self.url])?;
+        Ok(String::from_utf8_lossy(&listing)
+            .lines()
+            .filter_map(parse_list_line)
+            .collect())
+    }
+
+    fn fetch(&self, key: &str, dest: &Path) -> std::io::Result<()> {
+        let mut partial = dest.as_os_str().to_os_string();
+        partial.push(".part");
+        let url = format!("{}{}", self.url, uri_encode(key, false));
+        let result = self.request([OsStr::new(&url), OsStr::new("-o"), &partial]);
+        match result {
+            Ok(_) => std::fs::rename(&partial, dest),
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                Err(e)
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        self.location.describe()
+    }
+}
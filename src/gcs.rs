@@ -0,0 +1,501 @@
+//! Google Cloud Storage, through the JSON API and the curl executable.
+//!
+//! Access tokens are minted from the service account key file named by
+//! `GOOGLE_APPLICATION_CREDENTIALS`, signing the token request with the
+//! openssl executable, or fetched from the metadata server when running on
+//! GCE, GKE or Cloud Run. Tokens are handed to curl on its standard input
+//! rather than on its command line.
+use crate::curl::{self, response_header, scratch_path, uri_encode};
+use crate::json::{self, Value};
+use crate::sink::RemoteTarget;
+use crate::source::{RemoteObject, RemoteStore};
+use log::debug;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const API: &str = "https://storage.googleapis.com/storage/v1";
+const UPLOAD_API: &str = "https://storage.googleapis.com/upload/storage/v1";
+const METADATA_TOKEN: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// Bytes sent per resumable upload request; a multiple of 256 KiB.
+const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Times a failed chunk is resumed before the upload is given up.
+const CHUNK_ATTEMPTS: u32 = 5;
+
+/// Tokens are refreshed this long before they expire.
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+
+/// A bucket and object name prefix, parsed from `gs://bucket/prefix`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GcsLocation {
+    pub bucket: String,
+    /// Name prefix, empty or ending in `/`.
+    pub prefix: String,
+}
+
+impl GcsLocation {
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("gs://")?;
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return None;
+        }
+        let mut prefix = prefix.trim_matches('/').to_string();
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        Some(GcsLocation {
+            bucket: bucket.to_string(),
+            prefix,
+        })
+    }
+}
+
+fn base64url(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (u32::from(*b) << (16 - 8 * i)));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[((n >> (18 - 6 * i)) & 63) as usize] as char);
+        }
+    }
+    out
+}
+
+/// Where access tokens come from.
+enum Credentials {
+    /// A service account key; the private key is kept in a 0600 file for
+    /// openssl.
+    ServiceAccount {
+        email: String,
+        key_file: PathBuf,
+        token_uri: String,
+    },
+    /// The metadata server of the instance we run on.
+    Metadata,
+}
+
+impl Credentials {
+    fn load(work_dir: &Path) -> std::io::Result<Self> {
+        let Some(path) = std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS") else {
+            return Ok(Credentials::Metadata);
+        };
+        let invalid = |what: &str| {
+            std::io::Error::other(format!("{} in {}", what, Path::new(&path).display()))
+        };
+        let key =
+            json::parse(&std::fs::read_to_string(&path)?).ok_or_else(|| invalid("invalid JSON"))?;
+        let field = |name: &str| {
+            key.get(name)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| invalid(&format!("missing {}", name)))
+        };
+        let email = field("client_email")?;
+        let private_key = field("private_key")?;
+        let token_uri = field("token_uri")
+            .unwrap_or_else(|_| "https://oauth2.googleapis.com/token".to_string());
+        std::fs::create_dir_all(work_dir)?;
+        let key_file = scratch_path(work_dir, "gcs", "pem");
+        curl::private_file(&key_file, &private_key)?;
+        Ok(Credentials::ServiceAccount {
+            email,
+            key_file,
+            token_uri,
+        })
+    }
+
+    /// Fetch a new access token and its lifetime.
+    fn token(&self) -> std::io::Result<(String, Duration)> {
+        let response = match self {
+            Credentials::ServiceAccount {
+                email,
+                key_file,
+                token_uri,
+            } => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let header = base64url(br#"{"alg":"RS256","typ":"JWT"}"#);
+                let claims = json::Object::new()
+                    .str("iss", email)
+                    .str("scope", SCOPE)
+                    .str("aud", token_uri)
+                    .num("iat", now)
+                    .num("exp", now + 3600)
+                    .finish();
+                let unsigned = format!("{}.{}", header, base64url(claims.as_bytes()));
+                let signature = sign(key_file, unsigned.as_bytes())?;
+                let body = format!(
+                    "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer&assertion={}.{}",
+                    unsigned,
+                    base64url(&signature)
+                );
+                curl::run(
+                    "GCS token",
+                    ["--data-binary", "@-", token_uri.as_str()],
+                    Some(body.as_bytes()),
+                )?
+            }
+            Credentials::Metadata => curl::run(
+                "GCS metadata",
+                ["-H", "Metadata-Flavor: Google", METADATA_TOKEN],
+                None,
+            )?,
+        };
+        let response = json::parse(&String::from_utf8_lossy(&response))
+            .ok_or_else(|| std::io::Error::other("invalid token response"))?;
+        let token = response
+            .get("access_token")
+            .and_then(Value::as_str)
+            .ok_or_else(|| std::io::Error::other("token response without access_token"))?;
+        let lifetime = response
+            .get("expires_in")
+            .and_then(Value::as_u64)
+            .unwrap_or(3600);
+        Ok((token.to_string(), Duration::from_secs(lifetime)))
+    }
+}
+
+impl Drop for Credentials {
+    fn drop(&mut self) {
+        if let Credentials::ServiceAccount { key_file, .. } = self {
+            let _ = std::fs::remove_file(key_file);
+        }
+    }
+}
+
+/// Status code of the final response dumped with `-i`, skipping interim
+/// `100 Continue` responses.
+fn response_status(response: &[u8]) -> Option<u16> {
+    String::from_utf8_lossy(response)
+        .lines()
+        .filter(|line| line.starts_with("HTTP/"))
+        .filter_map(|line| line.split_whitespace().nth(1)?.parse().ok())
+        .next_back()
+}
+
+/// RSASSA-PKCS1-v1_5 SHA-256 signature with openssl.
+fn sign(key_file: &Path, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut child = Command::new("openssl")
+        .args(["dgst", "-sha256", "-sign"])
+        .arg(key_file)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(data)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "openssl failed to sign the token request: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// Authorized requests against one bucket.
+pub struct GcsClient {
+    bucket: String,
+    credentials: Credentials,
+    token: Mutex<Option<(String, Instant)>>,
+    /// Directory for partial downloads and upload chunks.
+    work_dir: PathBuf,
+}
+
+impl GcsClient {
+    pub fn new(bucket: &str, work_dir: &Path) -> std::io::Result<Self> {
+        let client = GcsClient {
+            bucket: bucket.to_string(),
+            credentials: Credentials::load(work_dir)?,
+            token: Mutex::new(None),
+            work_dir: work_dir.to_path_buf(),
+        };
+        // Fail at startup rather than on the first job
+        client.access_token()?;
+        Ok(client)
+    }
+
+    /// A valid access token, refreshed when close to expiry.
+    fn access_token(&self) -> std::io::Result<String> {
+        let mut cached = self.token.lock().unwrap();
+        if let Some((token, expires)) = cached.as_ref()
+            && Instant::now() + TOKEN_MARGIN < *expires
+        {
+            return Ok(token.clone());
+        }
+        let (token, lifetime) = self.credentials.token()?;
+        debug!("Refreshed GCS access token");
+        *cached = Some((token.clone(), Instant::now() + lifetime));
+        Ok(token)
+    }
+
+    /// Run one authorized curl request, returning the response body.
+    fn request<S: AsRef<OsStr>>(
+        &self,
+        args: impl IntoIterator<Item = S>,
+    ) -> std::io::Result<Vec<u8>> {
+        let config = format!(
+            "header = {}\n",
+            curl::quote(&format!("Authorization: Bearer {}", self.access_token()?))
+        );
+        let mut all: Vec<OsString> = vec!["-K".into(), "-".into()];
+        all.extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        curl::run("GCS", all, Some(config.as_bytes()))
+    }
+
+    /// Every object whose name starts with `prefix`.
+    pub fn list(&self, prefix: &str) -> std::io::Result<Vec<RemoteObject>> {
+        let mut objects = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut url = format!(
+                "{}/b/{}/o?prefix={}&fields=items(name,generation,size),nextPageToken",
+                API,
+                uri_encode(&self.bucket, false),
+                uri_encode(prefix, false)
+            );
+            if let Some(token) = &page_token {
+                url.push_str(&format!("&pageToken={}", uri_encode(token, false)));
+            }
+            let body = self.request([url])?;
+            let page = json::parse(&String::from_utf8_lossy(&body))
+                .ok_or_else(|| std::io::Error::other("invalid GCS list response"))?;
+            for item in page.get("items").map(Value::as_array).unwrap_or_default() {
+                let Some(name) = item.get("name").and_then(Value::as_str) else {
+                    continue;
+                };
+                objects.push(RemoteObject {
+                    key: name.strip_prefix(prefix).unwrap_or(name).to_string(),
+                    version: item
+                        .get("generation")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string(),
+                    size: item.get("size").and_then(Value::as_u64).unwrap_or(0),
+                });
+            }
+            page_token = page
+                .get("nextPageToken")
+                .and_then(Value::as_str)
+                .map(str::to_string);
+            if page_token.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
+
+    /// Download an object to `dest`, via a temporary file next to it.
+    pub fn get(&self, name: &str, dest: &Path) -> std::io::Result<()> {
+        let mut partial = dest.as_os_str().to_os_string();
+        partial.push(".part");
+        let url = format!(
+            "{}/b/{}/o/{}?alt=media",
+            API,
+            uri_encode(&self.bucket, false),
+            uri_encode(name, false)
+        );
+        let result = self.request([OsStr::new(&url), OsStr::new("-o"), &partial]);
+        match result {
+            Ok(_) => std::fs::rename(&partial, dest),
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                Err(e)
+            }
+        }
+    }
+
+    /// Upload a file to `name` with a resumable upload, resuming from the
+    /// last byte the service acknowledged when a chunk fails.
+    pub fn put(
+        &self,
+        name: &str,
+        file: &Path,
+        content_type: &str,
+        storage_class: Option<&str>,
+    ) -> std::io::Result<()> {
+        let size = std::fs::metadata(file)?.len();
+        let mut metadata = json::Object::new()
+            .str("name", name)
+            .str("contentType", content_type);
+        if let Some(class) = storage_class {
+            metadata = metadata.str("storageClass", class);
+        }
+        let metadata = metadata.finish();
+        let url = format!(
+            "{}/b/{}/o?uploadType=resumable",
+            UPLOAD_API,
+            uri_encode(&self.bucket, false)
+        );
+        let response = self.request([
+            "-i",
+            "-H",
+            "Content-Type: application/json; charset=UTF-8",
+            "-H",
+            &format!("X-Upload-Content-Type: {}", content_type),
+            "-H",
+            &format!("X-Upload-Content-Length: {}", size),
+            "--data-binary",
+            &metadata,
+            &url,
+        ])?;
+        let session = response_header(&response, "Location")
+            .ok_or_else(|| std::io::Error::other("GCS did not return an upload session"))?;
+
+        let mut input = File::open(file)?;
+        let chunk_file = scratch_path(&self.work_dir, "gcs", "part");
+        let result = (|| {
+            let mut offset = 0;
+            let mut failures = 0;
+            loop {
+                input.seek(SeekFrom::Start(offset))?;
+                let mut chunk = File::create(&chunk_file)?;
+                let len = std::io::copy(&mut (&mut input).take(CHUNK_SIZE), &mut chunk)?;
+                drop(chunk);
+                let range = if len == 0 {
+                    format!("Content-Range: bytes */{}", size)
+                } else {
+                    format!(
+                        "Content-Range: bytes {}-{}/{}",
+                        offset,
+                        offset + len - 1,
+                        size
+                    )
+                };
+                debug!("Uploading {} of {:?}", range, file);
+                let sent = self.request([
+                    OsStr::new("-i"),
+                    OsStr::new("-X"),
+                    OsStr::new("PUT"),
+                    OsStr::new("-H"),
+                    OsStr::new(&range),
+                    OsStr::new("-T"),
+                    chunk_file.as_os_str(),
+                    OsStr::new(&session),
+                ]);
+                let response = match sent {
+                    Ok(response) => response,
+                    Err(e) => {
+                        failures += 1;
+                        if failures >= CHUNK_ATTEMPTS {
+                            return Err(e);
+                        }
+                        debug!("Resuming upload of {:?} after: {}", file, e);
+                        self.request([
+                            "-i",
+                            "-X",
+                            "PUT",
+                            "-H",
+                            &format!("Content-Range: bytes */{}", size),
+                            "--data-binary",
+                            "",
+                            &session,
+                        ])?
+                    }
+                };
+                match response_status(&response) {
+                    Some(200 | 201) => return Ok(()),
+                    // Incomplete; `Range: bytes=0-N` is what has been stored
+                    Some(308) => {
+                        offset = response_header(&response, "Range")
+                            .and_then(|r| r.rsplit('-').next()?.parse::<u64>().ok())
+                            .map_or(0, |last| last + 1);
+                    }
+                    status => {
+                        return Err(std::io::Error::other(format!(
+                            "unexpected GCS upload response: {:?}",
+                            status
+                        )));
+                    }
+                }
+            }
+        })();
+        let _ = std::fs::remove_file(&chunk_file);
+        if result.is_err() {
+            let _ = self.request(["-X", "DELETE", &session]);
+        }
+        result
+    }
+}
+
+/// Objects below a prefix of a bucket, for a polling source.
+pub struct GcsStore {
+    client: GcsClient,
+    location: GcsLocation,
+}
+
+impl GcsStore {
+    pub fn new(location: GcsLocation, work_dir: &Path) -> std::io::Result<Self> {
+        Ok(GcsStore {
+            client: GcsClient::new(&location.bucket, work_dir)?,
+            location,
+        })
+    }
+}
+
+impl RemoteStore for GcsStore {
+    fn list(&self) -> std::io::Result<Vec<RemoteObject>> {
+        self.client.list(&self.location.prefix)
+    }
+
+    fn fetch(&self, key: &str, dest: &Path) -> std::io::Result<()> {
+        self.client
+            .get(&format!("{}{}", self.location.prefix, key), dest)
+    }
+
+    fn describe(&self) -> String {
+        format!("gs://{}/{}", self.location.bucket, self.location.prefix)
+    }
+}
+
+/// Uploads outputs below a prefix of a bucket.
+pub struct GcsTarget {
+    client: GcsClient,
+    location: GcsLocation,
+    storage_class: Option<String>,
+}
+
+impl GcsTarget {
+    pub fn new(
+        location: GcsLocation,
+        storage_class: Option<String>,
+        work_dir: &Path,
+    ) -> std::io::Result<Self> {
+        Ok(GcsTarget {
+            client: GcsClient::new(&location.bucket, work_dir)?,
+            location,
+            storage_class,
+        })
+    }
+}
+
+impl RemoteTarget for GcsTarget {
+    fn upload(&self, file: &Path, name: &str, content_type: &str) -> std::io::Result<()> {
+        self.client.put(
+            &format!("{}{}", self.location.prefix, name),
+            file,
+            content_type,
+            self.storage_class.as_deref(),
+        )
+    }
+
+    fn describe(&self) -> String {
+        format!("gs://{}/{}", self.location.bucket, self.location.prefix)
+    }
+}
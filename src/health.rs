@@ -0,0 +1,44 @@
+//! Conditions that degrade the process without stopping it, e.g. a
+//! transcoder that cannot be started for now.
+//!
+//! The parts that hit them keep retrying; meanwhile the heartbeat and the
+//! systemd watchdog report the process unhealthy and `ctl status` lists
+//! the conditions, until they clear.
+use log::{info, warn};
+use std::sync::Mutex;
+
+/// Each condition in effect, with what is wrong.
+static DEGRADED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// Put `condition` in effect, or update what is wrong.
+pub fn set(condition: impl Into<String>, detail: impl Into<String>) {
+    let condition = condition.into();
+    let detail = detail.into();
+    let mut degraded = DEGRADED.lock().unwrap();
+    match degraded.iter_mut().find(|(name, _)| *name == condition) {
+        Some((_, current)) => *current = detail,
+        None => {
+            warn!("Degraded, {}: {}", condition, detail);
+            degraded.push((condition, detail));
+        }
+    }
+}
+
+/// End `condition`, if it is in effect.
+pub fn clear(condition: &str) {
+    let mut degraded = DEGRADED.lock().unwrap();
+    if let Some(index) = degraded.iter().position(|(name, _)| *name == condition) {
+        degraded.remove(index);
+        info!("No longer degraded, {} cleared", condition);
+    }
+}
+
+/// The conditions in effect, in the order they arose.
+pub fn conditions() -> Vec<(String, String)> {
+    DEGRADED.lock().unwrap().clone()
+}
+
+/// Whether no condition is in effect.
+pub fn healthy() -> bool {
+    DEGRADED.lock().unwrap().is_empty()
+}
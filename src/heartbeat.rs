@@ -0,0 +1,46 @@
+//! Liveness heartbeat file.
+//!
+//! The main loop rewrites the file with the current time at a fixed interval
+//! as long as the process is healthy. External checks only need to compare
+//! the file's modification time against the interval.
+use log::{error, info, warn};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+pub struct Heartbeat {
+    path: PathBuf,
+    interval: Duration,
+    last: Option<Instant>,
+    healthy: bool,
+}
+
+impl Heartbeat {
+    pub fn new(path: PathBuf, interval: Duration) -> Self {
+        Heartbeat {
+            path,
+            interval,
+            last: None,
+            healthy: true,
+        }
+    }
+
+    /// Touch the file if it is due and the process is healthy.
+    pub fn beat(&mut self, healthy: bool) {
+        if healthy != self.healthy {
+            if healthy {
+                info!("Healthy again, resuming heartbeat");
+            } else {
+                error!("Unhealthy, no longer touching {:?}", self.path);
+            }
+            self.healthy = healthy;
+        }
+        if !healthy || self.last.is_some_and(|t| t.elapsed() < self.interval) {
+            return;
+        }
+        self.last = Some(Instant::now());
+        let now = humantime::format_rfc3339_seconds(SystemTime::now());
+        if let Err(e) = std::fs::write(&self.path, format!("{}\n", now)) {
+            warn!("Failed to write heartbeat file {:?}: {}", self.path, e);
+        }
+    }
+}
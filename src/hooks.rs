@@ -0,0 +1,113 @@
+//! User commands run before and after each job.
+//!
+//! Hooks run through the shell with the job described in `TRANSCODER_*`
+//! environment variables, e.g. to virus-scan an input before it is
+//! transcoded or to hand the output to an ingestion script afterwards.
+use crate::JobResult;
+use clap::ValueEnum;
+use log::{debug, warn};
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+/// What a failing hook does to its job.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum HookFailure {
+    /// Fail the job; a failing pre-hook also prevents transcoding.
+    #[default]
+    Fail,
+    /// Log a warning and carry on.
+    Warn,
+}
+
+/// Commands configured with `--pre-hook` and `--post-hook`.
+#[derive(Clone, Debug, Default)]
+pub struct Hooks {
+    pub pre: Option<String>,
+    pub post: Option<String>,
+    pub on_failure: HookFailure,
+}
+
+fn shell(script: &str) -> Command {
+    if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.args(["/C", script]);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.args(["-c", script]);
+        command
+    }
+}
+
+/// Run one hook, returning a description of the failure if it failed.
+fn run(name: &str, script: &str, env: &[(&str, OsString)]) -> Option<String> {
+    let mut command = shell(script);
+    for (key, value) in env {
+        command.env(key, value);
+    }
+    let failure = match crate::backend::run(&mut command, None) {
+        Ok(output) if output.status.success() => {
+            debug!("{} succeeded: {}", name, script);
+            return None;
+        }
+        Ok(output) => format!(
+            "{} failed ({}): {}",
+            name,
+            output.status,
+            output.stderr.trim_end()
+        ),
+        Err(e) => format!("{} failed to run: {}", name, e),
+    };
+    warn!("{}", failure);
+    Some(failure)
+}
+
+impl Hooks {
+    /// Run the pre-hook for a job. An error means the job must not go ahead.
+    pub fn before(&self, input: &Path, output: &Path) -> Result<(), String> {
+        let Some(script) = &self.pre else {
+            return Ok(());
+        };
+        let env = [
+            ("TRANSCODER_INPUT", input.into()),
+            ("TRANSCODER_OUTPUT", output.into()),
+            ("TRANSCODER_STATUS", "pending".into()),
+        ];
+        match run("Pre-hook", script, &env) {
+            Some(failure) if self.on_failure == HookFailure::Fail => Err(failure),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run the post-hook for a finished job, failing it if the hook fails
+    /// and failures are fatal.
+    pub fn after(&self, result: &mut JobResult) {
+        let Some(script) = &self.post else {
+            return;
+        };
+        let status = match result.error {
+            None => "succeeded",
+            Some(_) => "failed",
+        };
+        let env = [
+            ("TRANSCODER_INPUT", result.input.clone().into()),
+            ("TRANSCODER_OUTPUT", result.output.clone().into()),
+            ("TRANSCODER_STATUS", status.into()),
+            (
+                "TRANSCODER_DURATION",
+                format!("{:.3}", result.elapsed.as_secs_f64()).into(),
+            ),
+            (
+                "TRANSCODER_ERROR_CLASS",
+                result.error_class().unwrap_or_default().into(),
+            ),
+        ];
+        if let Some(failure) = run("Post-hook", script, &env)
+            && self.on_failure == HookFailure::Fail
+            && result.error.is_none()
+        {
+            result.error = Some(failure + "\n");
+        }
+    }
+}
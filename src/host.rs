@@ -0,0 +1,27 @@
+//! Information about the machine and user running the process.
+
+/// Name of the user running the process.
+pub fn user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Host name of the machine.
+#[cfg(unix)]
+pub fn name() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its full length
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if ret != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Host name of the machine.
+#[cfg(not(unix))]
+pub fn name() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
@@ -0,0 +1,404 @@
+//! Embedded HTTP server for the API endpoints.
+//!
+//! A deliberately small HTTP/1.1 implementation on top of the standard
+//! library: every connection carries a single request and is handled on its
+//! own thread, and request bodies are streamed to the endpoint instead of
+//! being buffered, so large uploads do not sit in memory.
+use crate::url::percent_decode;
+use crate::{json, shutdown};
+use log::{debug, error, info, warn};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Longest request line or header accepted, in bytes.
+const MAX_LINE: usize = 8 * 1024;
+/// Most headers accepted in one request.
+const MAX_HEADERS: usize = 100;
+/// A client that stalls this long mid-request is disconnected.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An incoming request, with its body still to be read.
+pub struct Request<'a> {
+    pub method: String,
+    /// Decoded path, without the query string.
+    pub path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    /// The body, limited to its Content-Length.
+    pub body: std::io::Take<&'a mut dyn BufRead>,
+}
+
+impl Request<'_> {
+    /// Value of a header, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// First value of a query parameter.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Declared length of the body.
+    pub fn content_length(&self) -> Option<u64> {
+        self.header("Content-Length")?.trim().parse().ok()
+    }
+}
+
+#[cfg(test)]
+impl<'a> Request<'a> {
+    /// A request as the server hands it to the endpoints, with `body` as
+    /// its Content-Length.
+    pub(crate) fn with_body(
+        method: &str,
+        path: &str,
+        body: &'a mut dyn BufRead,
+        length: u64,
+    ) -> Self {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: Vec::new(),
+            headers: vec![("Content-Length".to_string(), length.to_string())],
+            body: body.take(length),
+        }
+    }
+
+    /// The request with another header.
+    pub(crate) fn header_set(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// A function writing a streamed response body.
+pub type Stream = Box<dyn FnOnce(&mut dyn Write) -> std::io::Result<()> + Send>;
+
+/// The body of a response.
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// Written until the function returns, then the connection is closed.
+    Stream(Stream),
+    /// Exactly this many bytes, written by the function, e.g. from a file.
+    Sized(u64, Stream),
+}
+
+/// A response to send back.
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    /// Headers beyond the content type and length.
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    /// A JSON response; `body` is already serialized.
+    pub fn json(status: u16, body: String) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: Body::Bytes(body.into_bytes()),
+        }
+    }
+
+    /// A body of unknown length, e.g. a stream of events.
+    pub fn stream(content_type: &'static str, stream: Stream) -> Self {
+        Response {
+            status: 200,
+            content_type,
+            headers: Vec::new(),
+            body: Body::Stream(stream),
+        }
+    }
+
+    /// Add a header.
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    /// A JSON error response: `{"error": message}`.
+    pub fn error(status: u16, message: &str) -> Self {
+        Response::json(status, json::Object::new().str("error", message).finish())
+    }
+}
+
+/// A part of the API, e.g. the upload endpoint.
+pub trait Endpoint: Send + Sync {
+    /// Answer the request, or return `None` if it is not for this endpoint.
+    fn handle(&self, request: &mut Request) -> Option<Response>;
+
+    /// Whether the request may be answered without the bearer token, e.g.
+    /// for a static page that asks for it.
+    fn public(&self, _request: &Request) -> bool {
+        false
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Read one CRLF-terminated line, without the terminator.
+fn read_line(reader: &mut dyn BufRead) -> std::io::Result<String> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\n") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "request line too long or truncated",
+        ));
+    }
+    while line.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+        line.pop();
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// Whether the request carries the expected bearer token.
+fn authorized(request: &Request, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let given = request
+        .header("Authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .trim();
+    // Compare in constant time, so the token cannot be guessed byte by byte
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// State shared by the accept loop and the connection threads.
+struct Shared {
+    endpoints: Vec<Box<dyn Endpoint>>,
+    token: Option<String>,
+}
+
+impl Shared {
+    /// Read one request from the connection and answer it.
+    fn serve(&self, stream: TcpStream) -> std::io::Result<()> {
+        let peer = stream.peer_addr()?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+
+        let line = read_line(&mut reader)?;
+        let mut parts = line.split(' ');
+        let (Some(method), Some(target), Some(version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return respond(
+                &mut writer,
+                Response::error(400, "malformed request line"),
+                false,
+            );
+        };
+        if !version.starts_with("HTTP/1.") {
+            return respond(
+                &mut writer,
+                Response::error(400, "unsupported HTTP version"),
+                false,
+            );
+        }
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(&mut reader)?;
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() == MAX_HEADERS {
+                return respond(&mut writer, Response::error(400, "too many headers"), false);
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                let (name, value) = p.split_once('=').unwrap_or((p, ""));
+                (percent_decode(name, true), percent_decode(value, true))
+            })
+            .collect();
+        let chunked = headers.iter().any(|(n, v)| {
+            n.eq_ignore_ascii_case("Transfer-Encoding") && !v.eq_ignore_ascii_case("identity")
+        });
+        let reader: &mut dyn BufRead = &mut reader;
+        let mut request = Request {
+            method: method.to_string(),
+            path: percent_decode(path, false),
+            query,
+            headers,
+            body: reader.take(0),
+        };
+        if let Some(length) = request.content_length() {
+            request.body.set_limit(length);
+        }
+        debug!("{} {} from {}", request.method, request.path, peer);
+
+        let response = if chunked {
+            Response::error(411, "chunked request bodies are not supported")
+        } else if !authorized(&request, self.token.as_deref())
+            && !self
+                .endpoints
+                .iter()
+                .any(|endpoint| endpoint.public(&request))
+        {
+            warn!("Rejected unauthenticated request from {}", peer);
+            Response::error(401, "missing or wrong bearer token")
+        } else {
+            self.endpoints
+                .iter()
+                .find_map(|endpoint| endpoint.handle(&mut request))
+                .unwrap_or_else(|| Response::error(404, "not found"))
+        };
+        let head = request.method == "HEAD";
+        respond(&mut writer, response, head)
+    }
+}
+
+/// Send a response; for a HEAD request, without its body.
+fn respond(writer: &mut TcpStream, response: Response, head: bool) -> std::io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nConnection: close\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+    )?;
+    for (name, value) in &response.headers {
+        write!(writer, "{}: {}\r\n", name, value)?;
+    }
+    match response.body {
+        Body::Bytes(body) => {
+            write!(writer, "Content-Length: {}\r\n\r\n", body.len())?;
+            if !head {
+                writer.write_all(&body)?;
+            }
+        }
+        Body::Sized(length, stream) => {
+            write!(writer, "Content-Length: {}\r\n\r\n", length)?;
+            if !head {
+                stream(writer)?;
+            }
+        }
+        Body::Stream(stream) => {
+            write!(writer, "Cache-Control: no-cache\r\n\r\n")?;
+            writer.flush()?;
+            if !head {
+                stream(writer)?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+/// An HTTP server running on a background thread until it is dropped or a
+/// shutdown is requested.
+pub struct Server {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Listen on `addr` and serve the endpoints, in order. If `token` is
+    /// set, every request must carry it as a bearer token.
+    pub fn start(
+        addr: &str,
+        token: Option<String>,
+        endpoints: Vec<Box<dyn Endpoint>>,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        // Non-blocking, so the accept loop notices when to stop
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared { endpoints, token });
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) && !shutdown::requested() {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            let shared = shared.clone();
+                            std::thread::spawn(move || {
+                                if let Err(e) = stream
+                                    .set_nonblocking(false)
+                                    .and_then(|()| shared.serve(stream))
+                                {
+                                    debug!("HTTP connection failed: {}", e);
+                                }
+                            });
+                        }
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                            std::thread::sleep(Duration::from_millis(100));
+                        }
+                        Err(e) => {
+                            error!("Failed to accept HTTP connection: {}", e);
+                            std::thread::sleep(Duration::from_secs(1));
+                        }
+                    }
+                }
+            })
+        };
+        info!("Listening for HTTP requests on {}", addr);
+        Ok(Server {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Address the server is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,476 @@
+//! Upload endpoint for applications without access to the input directory.
+//!
+//! `POST /ingest` takes the file either as the raw request body, named by
+//! the `name` query parameter, or as the first file field of a
+//! `multipart/form-data` form. It is stored in a scratch directory, queued
+//! like a file fetched from remote storage, and answered with a job ID.
+//! The ID also prefixes the stored name, and so the output name, which keeps
+//! uploads with the same name apart.
+use crate::Submitter;
+use crate::fetch::safe_name;
+use crate::http::{Endpoint, Request, Response};
+use crate::jobs;
+use crate::json;
+use log::{error, info};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Longest multipart header line accepted, in bytes.
+const MAX_PART_HEADER: usize = 8 * 1024;
+
+/// A parameter of a header value such as `form-data; name="file"`.
+fn header_param<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+    value.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+fn truncated() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "multipart body ended early",
+    )
+}
+
+/// Streaming reader for the parts of a `multipart/form-data` body.
+struct Multipart<R> {
+    reader: R,
+    buf: Vec<u8>,
+    /// `\r\n--` and the boundary.
+    delimiter: Vec<u8>,
+}
+
+impl<R: Read> Multipart<R> {
+    fn new(reader: R, boundary: &str) -> Self {
+        Multipart {
+            reader,
+            // The first delimiter has no line break before it
+            buf: b"\r\n".to_vec(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+        }
+    }
+
+    /// Read more of the body, returning false at its end.
+    fn fill(&mut self) -> std::io::Result<bool> {
+        let mut chunk = [0u8; 64 * 1024];
+        let n = self.reader.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+
+    fn line(&mut self) -> std::io::Result<String> {
+        loop {
+            if let Some(i) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buf[..i]).into_owned();
+                self.buf.drain(..i + 2);
+                return Ok(line);
+            }
+            if self.buf.len() > MAX_PART_HEADER || !self.fill()? {
+                return Err(truncated());
+            }
+        }
+    }
+
+    /// The headers of the next part.
+    fn headers(&mut self) -> std::io::Result<Vec<(String, String)>> {
+        let mut headers = Vec::new();
+        loop {
+            let line = self.line()?;
+            if line.is_empty() {
+                return Ok(headers);
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+            }
+        }
+    }
+
+    /// Copy the content of the current part up to the next delimiter,
+    /// returning whether another part follows.
+    fn copy(&mut self, out: &mut dyn Write) -> std::io::Result<bool> {
+        loop {
+            let found = self
+                .buf
+                .windows(self.delimiter.len())
+                .position(|w| w == self.delimiter);
+            if let Some(i) = found {
+                out.write_all(&self.buf[..i])?;
+                self.buf.drain(..i + self.delimiter.len());
+                while self.buf.len() < 2 {
+                    if !self.fill()? {
+                        return Err(truncated());
+                    }
+                }
+                if self.buf.starts_with(b"--") {
+                    return Ok(false);
+                }
+                // Rest of the delimiter line, normally empty
+                self.line()?;
+                return Ok(true);
+            }
+            // Keep enough to recognize a delimiter split across reads
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                let n = self.buf.len() - keep;
+                out.write_all(&self.buf[..n])?;
+                self.buf.drain(..n);
+            }
+            if !self.fill()? {
+                return Err(truncated());
+            }
+        }
+    }
+}
+
+/// Why an upload was not queued.
+enum Rejected {
+    Client(u16, String),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Rejected {
+    fn from(e: std::io::Error) -> Self {
+        Rejected::Io(e)
+    }
+}
+
+/// `POST /ingest`: store the uploaded file and queue it.
+pub struct IngestEndpoint {
+    dir: PathBuf,
+    submitter: Submitter,
+    max_size: u64,
+}
+
+impl IngestEndpoint {
+    /// Store uploads of up to `max_size` bytes in `dir`, queueing them with
+    /// `submitter`.
+    pub fn new(dir: impl Into<PathBuf>, submitter: Submitter, max_size: u64) -> Self {
+        IngestEndpoint {
+            dir: dir.into(),
+            submitter,
+            max_size,
+        }
+    }
+
+    /// Write the upload of `length` bytes to the scratch directory,
+    /// returning its path and the client's name for it.
+    fn store(
+        &self,
+        request: &mut Request,
+        id: &str,
+        length: u64,
+    ) -> Result<(PathBuf, String), Rejected> {
+        let content_type = request.header("Content-Type").unwrap_or_default();
+        let boundary = content_type
+            .to_ascii_lowercase()
+            .starts_with("multipart/")
+            .then(|| header_param(content_type, "boundary").map(str::to_string));
+        let path = |name: &str| self.dir.join(format!("{}-{}", id, safe_name(name)));
+
+        std::fs::create_dir_all(&self.dir)?;
+        match boundary {
+            None => {
+                let name = request.query("name").unwrap_or("upload").to_string();
+                let path = path(&name);
+                let mut file = BufWriter::new(File::create(&path)?);
+                if std::io::copy(&mut request.body, &mut file)? < length {
+                    return Err(Rejected::Client(
+                        400,
+                        "request body ended early".to_string(),
+                    ));
+                }
+                file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+                Ok((path, name))
+            }
+            Some(None) => Err(Rejected::Client(
+                400,
+                "multipart body without a boundary".to_string(),
+            )),
+            Some(Some(boundary)) => {
+                let mut parts = Multipart::new(&mut request.body, &boundary);
+                // Skip the preamble, then look for the first file field
+                let mut more = parts.copy(&mut std::io::sink())?;
+                let mut stored = None;
+                while more {
+                    let headers = parts.headers()?;
+                    let file_name = headers
+                        .iter()
+                        .find(|(n, _)| n == "content-disposition")
+                        .and_then(|(_, v)| header_param(v, "filename"))
+                        .map(str::to_string);
+                    match file_name {
+                        Some(name) if stored.is_none() => {
+                            let path = path(&name);
+                            let mut file = BufWriter::new(File::create(&path)?);
+                            more = parts.copy(&mut file)?;
+                            file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+                            stored = Some((path, name));
+                        }
+                        _ => more = parts.copy(&mut std::io::sink())?,
+                    }
+                }
+                stored.ok_or_else(|| Rejected::Client(400, "no file field in form".to_string()))
+            }
+        }
+    }
+
+    fn ingest(&self, request: &mut Request) -> Response {
+        let Some(length) = request.content_length() else {
+            return Response::error(411, "Content-Length is required");
+        };
+        if length > self.max_size {
+            return Response::error(
+                413,
+                &format!("uploads are limited to {} bytes", self.max_size),
+            );
+        }
+
+        let id = jobs::new_id();
+        match self.store(request, &id, length) {
+            Ok((path, name)) => {
+                info!("Received upload {:?} as job {}", name, id);
+                self.submitter.submit_fetched_as(&id, &path);
+                Response::json(
+                    202,
+                    json::Object::new()
+                        .str("id", &id)
+                        .str("name", &name)
+                        .finish(),
+                )
+            }
+            Err(rejected) => {
+                remove_partial(&self.dir, &id);
+                match rejected {
+                    Rejected::Client(status, message) => Response::error(status, &message),
+                    Rejected::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        Response::error(400, &e.to_string())
+                    }
+                    Rejected::Io(e) => {
+                        error!("Failed to store upload {}: {}", id, e);
+                        Response::error(500, "failed to store the upload")
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Remove whatever was written for a failed upload.
+fn remove_partial(dir: &Path, id: &str) {
+    let prefix = format!("{}-", id);
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+impl Endpoint for IngestEndpoint {
+    fn handle(&self, request: &mut Request) -> Option<Response> {
+        if request.path != "/ingest" {
+            return None;
+        }
+        Some(match request.method.as_str() {
+            "POST" | "PUT" => self.ingest(request),
+            _ => Response::error(405, "use POST to upload a file"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Body;
+    use crate::notifications::Notifiers;
+    use crate::{Pipeline, TranscodeOptions};
+
+    /// Hands out a body a few bytes at a time, as a slow client would.
+    struct Trickle<'a> {
+        body: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.step.min(buf.len()).min(self.body.len());
+            buf[..n].copy_from_slice(&self.body[..n]);
+            self.body = &self.body[n..];
+            Ok(n)
+        }
+    }
+
+    fn form(preamble: &str, parts: &[(&str, &str)], closed: bool) -> String {
+        let mut body = preamble.to_string();
+        for (disposition, content) in parts {
+            body.push_str(&format!(
+                "--XyZ\r\nContent-Disposition: form-data; {}\r\nContent-Type: audio/wav\r\n\r\n{}\r\n",
+                disposition, content
+            ));
+        }
+        if closed {
+            body.push_str("--XyZ--\r\n");
+        }
+        body
+    }
+
+    /// The headers and content of a part.
+    type Part = (Vec<(String, String)>, Vec<u8>);
+
+    /// The parts of `body`, read `step` bytes at a time.
+    fn parts(body: &str, step: usize) -> std::io::Result<Vec<Part>> {
+        let mut parts = Multipart::new(
+            Trickle {
+                body: body.as_bytes(),
+                step,
+            },
+            "XyZ",
+        );
+        let mut more = parts.copy(&mut std::io::sink())?;
+        let mut read = Vec::new();
+        while more {
+            let headers = parts.headers()?;
+            let mut content = Vec::new();
+            more = parts.copy(&mut content)?;
+            read.push((headers, content));
+        }
+        Ok(read)
+    }
+
+    #[test]
+    fn delimiters_split_across_reads_are_found() {
+        let body = form(
+            "",
+            &[
+                ("name=\"file\"; filename=\"call.wav\"", "RIFF\r\n--Xy audio"),
+                ("name=\"note\"", "hello"),
+            ],
+            true,
+        );
+        for step in [1, 2, 3, 5, 7, 64 * 1024] {
+            let read = parts(&body, step).unwrap();
+            assert_eq!(read.len(), 2, "step {}", step);
+            assert_eq!(read[0].1, b"RIFF\r\n--Xy audio", "step {}", step);
+            assert_eq!(read[1].1, b"hello", "step {}", step);
+            assert_eq!(read[0].0[1], ("content-type".into(), "audio/wav".into()));
+        }
+    }
+
+    #[test]
+    fn preambles_are_skipped() {
+        let body = form(
+            "This is a multipart message.\r\n",
+            &[("name=\"file\"; filename=\"call.wav\"", "audio")],
+            true,
+        );
+        let read = parts(&body, 4).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].1, b"audio");
+    }
+
+    #[test]
+    fn bodies_without_a_closing_delimiter_are_truncated() {
+        let body = form(
+            "",
+            &[("name=\"file\"; filename=\"call.wav\"", "audio")],
+            false,
+        );
+        let error = parts(&body, 3).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        let error = parts("--XyZ\r\nContent-Disposition: form-data", 3).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    fn upload(test: &str, body: &str) -> (PathBuf, u16, String) {
+        let dir = std::env::temp_dir().join(format!("ingest-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let pipeline = Pipeline::new(TranscodeOptions::default(), Notifiers::default());
+        let endpoint = IngestEndpoint::new(&dir, pipeline.submitter(), 1 << 20);
+        let mut reader = body.as_bytes();
+        let mut request = Request::with_body("POST", "/ingest", &mut reader, body.len() as u64)
+            .header_set("Content-Type", "multipart/form-data; boundary=\"XyZ\"");
+        let response = endpoint.handle(&mut request).unwrap();
+        let Body::Bytes(answer) = response.body else {
+            panic!("streamed response");
+        };
+        (dir, response.status, String::from_utf8(answer).unwrap())
+    }
+
+    fn stored(dir: &Path) -> Vec<String> {
+        std::fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn the_first_file_field_is_stored() {
+        let body = form(
+            "",
+            &[
+                ("name=\"note\"", "hello"),
+                ("name=\"file\"; filename=\"call.wav\"", "audio"),
+                ("name=\"other\"; filename=\"other.wav\"", "more"),
+            ],
+            true,
+        );
+        let (dir, status, answer) = upload("first", &body);
+        assert_eq!(status, 202, "{}", answer);
+        let names = stored(&dir);
+        assert_eq!(names.len(), 1);
+        assert!(names[0].ends_with("-call.wav"), "{:?}", names);
+        assert_eq!(std::fs::read(dir.join(&names[0])).unwrap(), b"audio");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn forms_without_a_file_field_are_refused() {
+        let body = form("", &[("name=\"note\"", "hello")], true);
+        let (dir, status, answer) = upload("no-file", &body);
+        assert_eq!(status, 400);
+        assert!(answer.contains("no file field"), "{}", answer);
+        assert!(stored(&dir).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unfinished_forms_leave_nothing_behind() {
+        let body = form(
+            "",
+            &[("name=\"file\"; filename=\"call.wav\"", "audio")],
+            false,
+        );
+        let (dir, status, answer) = upload("unfinished", &body);
+        assert_eq!(status, 400);
+        assert!(answer.contains("ended early"), "{}", answer);
+        assert!(stored(&dir).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_names_lose_their_directories() {
+        for (name, kept) in [
+            ("../../etc/cron.d/call.wav", "-call.wav"),
+            ("C:\\Users\\me\\call.wav", "-call.wav"),
+            ("calls/..", "-upload"),
+        ] {
+            let disposition = format!("name=\"file\"; filename=\"{}\"", name);
+            let body = form("", &[(&disposition, "audio")], true);
+            let (dir, status, answer) = upload("names", &body);
+            assert_eq!(status, 202, "{}", answer);
+            let names = stored(&dir);
+            assert_eq!(names.len(), 1, "{}", name);
+            assert!(names[0].ends_with(kept), "{}: {:?}", name, names);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Keeping a second copy of the program, e.g. one started by cron, from
+//! doing what a running instance already does.
+//!
+//! `--pid-file` holds the process ID of the running instance, and a local
+//! input directory is locked while it is watched. Both are advisory locks
+//! that the operating system releases when the process dies, so what a
+//! crashed instance left behind is taken over instead of refused.
+use log::{debug, info};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The process ID of this instance in a locked file, removed when dropped.
+pub struct PidFile {
+    path: PathBuf,
+    _file: File,
+}
+
+impl PidFile {
+    /// Write the process ID to `path`, unless another running instance
+    /// holds it.
+    pub fn acquire(path: &Path) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                return Err(std::io::Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("another instance (pid {}) is running", pid.trim()),
+                ));
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        // Left by an instance that did not exit cleanly, if not empty
+        if file.metadata()?.len() > 0 {
+            info!("Taking over the stale pid file {}", path.display());
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+            _file: file,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A lock on a watched input directory, held until dropped.
+pub struct InputLock {
+    _dir: File,
+}
+
+impl InputLock {
+    /// Lock `dir`, unless another instance has; `None` where the file
+    /// system cannot lock directories, e.g. on NFS.
+    pub fn acquire(dir: &Path) -> std::io::Result<Option<Self>> {
+        let file = match File::open(dir) {
+            // Reported when the directory is watched
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            result => result?,
+        };
+        match file.try_lock() {
+            Ok(()) => Ok(Some(InputLock { _dir: file })),
+            Err(TryLockError::WouldBlock) => Err(std::io::Error::new(
+                ErrorKind::AddrInUse,
+                "another instance is already watching it",
+            )),
+            Err(TryLockError::Error(e)) => {
+                debug!("Cannot lock {}: {}", dir.display(), e);
+                Ok(None)
+            }
+        }
+    }
+}
@@ -0,0 +1,403 @@
+//! In-memory record of every job the pipeline has seen, for the API.
+//!
+//! Each job gets an ID when it is queued and moves through
+//! [`JobStatus::Queued`] and [`JobStatus::Running`] to a final status.
+//! Finished jobs are kept up to a fixed count, oldest dropped first; the
+//! store does not survive a restart, the audit log is the durable record.
+//! Every change is also published to the receivers from
+//! [`JobStore::subscribe`].
+use crate::JobResult;
+use crate::json;
+use crate::sha256::Sha256;
+use crate::wav;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Finished jobs kept before the oldest are forgotten.
+const MAX_FINISHED: usize = 10_000;
+
+/// A short unique ID for a new job.
+pub fn new_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut hasher = Sha256::default();
+    hasher.update(
+        format!(
+            "{}-{}-{}",
+            nanos,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        )
+        .as_bytes(),
+    );
+    hasher.hex()[..16].to_string()
+}
+
+/// Where a job is in its lifecycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    /// Not transcoded, e.g. routed away or no longer a regular file.
+    Skipped,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::Skipped => "skipped",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        [
+            JobStatus::Queued,
+            JobStatus::Running,
+            JobStatus::Succeeded,
+            JobStatus::Failed,
+            JobStatus::Skipped,
+            JobStatus::Cancelled,
+        ]
+        .into_iter()
+        .find(|status| status.as_str() == s)
+    }
+
+    /// Whether this is a final status.
+    pub fn finished(self) -> bool {
+        !matches!(self, JobStatus::Queued | JobStatus::Running)
+    }
+}
+
+/// What is known about one job.
+#[derive(Clone, Debug)]
+pub struct JobRecord {
+    pub id: String,
+    pub input: PathBuf,
+    pub status: JobStatus,
+    pub output: Option<PathBuf>,
+    /// Failure details, e.g. ffmpeg's diagnostics, or why it was skipped.
+    pub error: Option<String>,
+    pub error_class: Option<&'static str>,
+    pub queued_at: SystemTime,
+    pub started_at: Option<SystemTime>,
+    pub finished_at: Option<SystemTime>,
+    /// ID of the job that retried it, once it failed.
+    pub retried_as: Option<String>,
+    /// Whether it goes ahead of the other queued jobs.
+    pub urgent: bool,
+}
+
+impl JobRecord {
+    /// Seconds of audio a running job has written to its output so far.
+    pub fn written(&self) -> u64 {
+        let Some(output) = &self.output else {
+            return 0;
+        };
+        match (std::fs::metadata(output), wav::byte_rate(output)) {
+            (Ok(metadata), Some(rate)) if rate > 0 => {
+                metadata.len().saturating_sub(44) / u64::from(rate)
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let timestamp =
+            |t: Option<SystemTime>| t.map(|t| humantime::format_rfc3339_millis(t).to_string());
+        json::Object::new()
+            .str("id", &self.id)
+            .str("status", self.status.as_str())
+            .str("input", &self.input.to_string_lossy())
+            .opt_str(
+                "output",
+                self.output.as_ref().map(|p| p.to_string_lossy()).as_deref(),
+            )
+            .opt_str("error", self.error.as_deref())
+            .opt_str("error_class", self.error_class)
+            .opt_str("queued_at", timestamp(Some(self.queued_at)).as_deref())
+            .opt_str("started_at", timestamp(self.started_at).as_deref())
+            .opt_str("finished_at", timestamp(self.finished_at).as_deref())
+            .opt_str("retried_as", self.retried_as.as_deref())
+            .opt_str("priority", self.urgent.then_some("high"))
+            .finish()
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    records: HashMap<String, JobRecord>,
+    /// IDs of finished jobs, oldest first.
+    finished: VecDeque<String>,
+    /// Cancellation flags of the running jobs.
+    running: HashMap<String, Arc<AtomicBool>>,
+    /// Number of records with the queued status.
+    queued: usize,
+    /// IDs of the queued jobs that go first.
+    urgent: HashSet<String>,
+    watchers: Vec<Sender<JobRecord>>,
+}
+
+impl Inner {
+    fn publish(&mut self, id: &str) {
+        if let Some(record) = self.records.get(id) {
+            self.watchers.retain(|w| w.send(record.clone()).is_ok());
+        }
+    }
+
+    fn finish(&mut self, id: &str, update: impl FnOnce(&mut JobRecord)) {
+        let Some(record) = self.records.get_mut(id) else {
+            return;
+        };
+        if record.status.finished() {
+            return;
+        }
+        if record.status == JobStatus::Queued {
+            self.queued -= 1;
+        }
+        update(record);
+        record.finished_at = Some(SystemTime::now());
+        self.running.remove(id);
+        self.urgent.remove(id);
+        self.publish(id);
+        self.finished.push_back(id.to_string());
+        while self.finished.len() > MAX_FINISHED {
+            if let Some(old) = self.finished.pop_front() {
+                self.records.remove(&old);
+            }
+        }
+    }
+}
+
+/// Status of the pipeline's jobs, shared between the workers and the API.
+#[derive(Default)]
+pub struct JobStore {
+    inner: Mutex<Inner>,
+}
+
+impl JobStore {
+    /// A job was queued. A job recorded before its input was ready, e.g.
+    /// while it was downloaded, keeps its original record.
+    pub fn queued(&self, id: &str, input: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.records.contains_key(id) {
+            return;
+        }
+        let record = JobRecord {
+            id: id.to_string(),
+            input: input.to_path_buf(),
+            status: JobStatus::Queued,
+            output: None,
+            error: None,
+            error_class: None,
+            queued_at: SystemTime::now(),
+            started_at: None,
+            finished_at: None,
+            retried_as: None,
+            urgent: false,
+        };
+        inner.records.insert(id.to_string(), record);
+        inner.queued += 1;
+        inner.publish(id);
+    }
+
+    /// A worker picked the job up. Returns the flag that is set if the job
+    /// is cancelled, or `None` if it was cancelled while queued.
+    pub fn started(&self, id: &str) -> Option<Arc<AtomicBool>> {
+        let mut inner = self.inner.lock().unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        if let Some(record) = inner.records.get_mut(id) {
+            if record.status == JobStatus::Cancelled {
+                return None;
+            }
+            let was_queued = record.status == JobStatus::Queued;
+            record.status = JobStatus::Running;
+            record.started_at = Some(SystemTime::now());
+            if was_queued {
+                inner.queued -= 1;
+            }
+            inner.urgent.remove(id);
+            inner.running.insert(id.to_string(), cancel.clone());
+            inner.publish(id);
+        }
+        Some(cancel)
+    }
+
+    /// A running job is writing its output to `output`.
+    pub fn writing(&self, id: &str, output: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(record) = inner.records.get_mut(id) {
+            record.output = Some(output.to_path_buf());
+        }
+    }
+
+    /// Whether `path` is the output of a running job, or one of its
+    /// sidecars, and so may still be incomplete.
+    pub fn in_progress(&self, path: &Path) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .running
+            .keys()
+            .filter_map(|id| inner.records.get(id)?.output.as_ref())
+            .any(|output| {
+                path.as_os_str()
+                    .as_encoded_bytes()
+                    .starts_with(output.as_os_str().as_encoded_bytes())
+            })
+    }
+
+    pub fn finished(&self, id: &str, result: &JobResult) {
+        let mut inner = self.inner.lock().unwrap();
+        let cancelled = inner
+            .running
+            .get(id)
+            .is_some_and(|c| c.load(Ordering::SeqCst));
+        inner.finish(id, |record| {
+            record.status = match result.error {
+                None => JobStatus::Succeeded,
+                Some(_) if cancelled => JobStatus::Cancelled,
+                Some(_) => JobStatus::Failed,
+            };
+            record.output = Some(result.output.clone());
+            record.error = result.error.clone();
+            record.error_class = result.error_class();
+        });
+    }
+
+    /// The job failed outside the transcoder, e.g. its download failed.
+    pub fn failed(&self, id: &str, error: &str, class: &'static str) {
+        self.inner.lock().unwrap().finish(id, |record| {
+            record.status = JobStatus::Failed;
+            record.error = Some(error.to_string());
+            record.error_class = Some(class);
+        });
+    }
+
+    pub fn skipped(&self, id: &str, reason: &str) {
+        self.inner.lock().unwrap().finish(id, |record| {
+            record.status = JobStatus::Skipped;
+            record.error = Some(reason.to_string());
+        });
+    }
+
+    /// A failed job was queued again as job `retry`.
+    pub fn retried(&self, id: &str, retry: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(record) = inner.records.get_mut(id) {
+            record.retried_as = Some(retry.to_string());
+            inner.publish(id);
+        }
+    }
+
+    /// Cancel a job: a queued job is dropped when a worker reaches it, and
+    /// a running job's transcoder is killed. Returns the job's record, which
+    /// still shows a running job as running until it has stopped, or `None`
+    /// if the job is unknown.
+    pub fn cancel(&self, id: &str) -> Option<JobRecord> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.records.get(id)?.status {
+            JobStatus::Queued => inner.finish(id, |record| {
+                record.status = JobStatus::Cancelled;
+                record.error = Some("cancelled while queued".to_string());
+            }),
+            JobStatus::Running => {
+                if let Some(cancel) = inner.running.get(id) {
+                    cancel.store(true, Ordering::SeqCst);
+                }
+            }
+            _ => {}
+        }
+        inner.records.get(id).cloned()
+    }
+
+    /// Move a queued job ahead of the others that are not urgent. Returns
+    /// its record, or `None` if the job is unknown.
+    pub fn bump(&self, id: &str) -> Option<JobRecord> {
+        let mut inner = self.inner.lock().unwrap();
+        let record = inner.records.get_mut(id)?;
+        if record.status == JobStatus::Queued && !record.urgent {
+            record.urgent = true;
+            inner.urgent.insert(id.to_string());
+            inner.publish(id);
+        }
+        inner.records.get(id).cloned()
+    }
+
+    /// ID of the oldest queued job of `input`, if there is one.
+    pub fn queued_with_input(&self, input: &Path) -> Option<String> {
+        let input = std::path::absolute(input).ok()?;
+        let inner = self.inner.lock().unwrap();
+        inner
+            .records
+            .values()
+            .filter(|record| record.status == JobStatus::Queued)
+            .filter(|record| std::path::absolute(&record.input).is_ok_and(|path| path == input))
+            .min_by_key(|record| record.queued_at)
+            .map(|record| record.id.clone())
+    }
+
+    /// ID of a queued or running job of `input`, if there is one.
+    pub fn active_with_input(&self, input: &Path) -> Option<String> {
+        let input = std::path::absolute(input).ok()?;
+        let inner = self.inner.lock().unwrap();
+        inner
+            .records
+            .values()
+            .filter(|record| matches!(record.status, JobStatus::Queued | JobStatus::Running))
+            .find(|record| std::path::absolute(&record.input).is_ok_and(|path| path == input))
+            .map(|record| record.id.clone())
+    }
+
+    /// The position of the first of `ids` that is an urgent queued job.
+    pub(crate) fn first_urgent<'a>(&self, mut ids: impl Iterator<Item = &'a str>) -> Option<usize> {
+        let inner = self.inner.lock().unwrap();
+        if inner.urgent.is_empty() {
+            return None;
+        }
+        ids.position(|id| inner.urgent.contains(id))
+    }
+
+    /// Number of jobs waiting in the queue.
+    pub fn queued_count(&self) -> usize {
+        self.inner.lock().unwrap().queued
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobRecord> {
+        self.inner.lock().unwrap().records.get(id).cloned()
+    }
+
+    /// Every known job, optionally only those with one status, oldest first.
+    pub fn list(&self, status: Option<JobStatus>) -> Vec<JobRecord> {
+        let inner = self.inner.lock().unwrap();
+        let mut records: Vec<JobRecord> = inner
+            .records
+            .values()
+            .filter(|r| status.is_none_or(|s| r.status == s))
+            .cloned()
+            .collect();
+        records.sort_by_key(|r| r.queued_at);
+        records
+    }
+
+    /// Receive the updated record of every job that changes from now on.
+    /// The subscription ends when the receiver is dropped.
+    pub fn subscribe(&self) -> Receiver<JobRecord> {
+        let (tx, rx) = channel();
+        self.inner.lock().unwrap().watchers.push(tx);
+        rx
+    }
+}
@@ -0,0 +1,428 @@
+//! Minimal JSON helpers.
+//!
+//! Events, logs and sidecars are written with a small builder, and the few
+//! API responses that are read back are parsed into a [`Value`] tree, rather
+//! than pulling in a full serialization framework.
+use std::fmt::Write;
+
+/// Most arrays and objects that may be nested in a parsed document, so that
+/// a hostile body cannot run the parser out of stack.
+const MAX_DEPTH: usize = 64;
+
+/// Quote and escape a string as a JSON string literal.
+pub fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Builder for a single JSON object.
+#[derive(Default)]
+pub struct Object {
+    buf: String,
+}
+
+impl Object {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn key(&mut self, key: &str) {
+        if !self.buf.is_empty() {
+            self.buf.push(',');
+        }
+        self.buf.push_str(&string(key));
+        self.buf.push(':');
+    }
+
+    /// Add a string field.
+    pub fn str(mut self, key: &str, value: &str) -> Self {
+        self.key(key);
+        self.buf.push_str(&string(value));
+        self
+    }
+
+    /// Add a string field, or `null` if the value is missing.
+    pub fn opt_str(mut self, key: &str, value: Option<&str>) -> Self {
+        self.key(key);
+        match value {
+            Some(v) => self.buf.push_str(&string(v)),
+            None => self.buf.push_str("null"),
+        }
+        self
+    }
+
+    /// Add a numeric field.
+    pub fn num(mut self, key: &str, value: impl std::fmt::Display) -> Self {
+        self.key(key);
+        let _ = write!(self.buf, "{}", value);
+        self
+    }
+
+    /// Add a field whose value is already serialized JSON.
+    pub fn raw(mut self, key: &str, value: &str) -> Self {
+        self.key(key);
+        self.buf.push_str(value);
+        self
+    }
+
+    pub fn finish(self) -> String {
+        format!("{{{}}}", self.buf)
+    }
+}
+
+/// Join already serialized values into a JSON array.
+pub fn array(items: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", items.into_iter().collect::<Vec<_>>().join(","))
+}
+
+/// A parsed JSON value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Field of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// A number, or a string holding one, as APIs often encode 64-bit values.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(n) if *n >= 0.0 => Some(*n as u64),
+            Value::String(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "gcs", feature = "http")), allow(dead_code))]
+    pub fn as_array(&self) -> &[Value] {
+        match self {
+            Value::Array(items) => items,
+            _ => &[],
+        }
+    }
+}
+
+/// Parse a complete JSON document.
+pub fn parse(text: &str) -> Option<Value> {
+    let mut parser = Parser {
+        chars: text.chars().peekable(),
+        depth: 0,
+    };
+    let value = parser.value()?;
+    parser.whitespace();
+    parser.chars.peek().is_none().then_some(value)
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    /// Arrays and objects open around the value being parsed.
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Option<Value> {
+        word.chars()
+            .all(|c| self.chars.next() == Some(c))
+            .then_some(value)
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        self.whitespace();
+        match *self.chars.peek()? {
+            open @ ('{' | '[') => {
+                if self.depth == MAX_DEPTH {
+                    return None;
+                }
+                self.chars.next();
+                self.depth += 1;
+                let value = match open {
+                    '{' => self.object(),
+                    _ => self.array(),
+                };
+                self.depth -= 1;
+                value
+            }
+            '"' => self.string().map(Value::String),
+            't' => self.literal("true", Value::Bool(true)),
+            'f' => self.literal("false", Value::Bool(false)),
+            'n' => self.literal("null", Value::Null),
+            _ => {
+                let mut number = String::new();
+                while let Some(c) = self
+                    .chars
+                    .next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+                {
+                    number.push(c);
+                }
+                number.parse().ok().map(Value::Number)
+            }
+        }
+    }
+
+    /// The rest of an object, after its `{`.
+    fn object(&mut self) -> Option<Value> {
+        let mut fields = Vec::new();
+        self.whitespace();
+        if self.chars.next_if_eq(&'}').is_some() {
+            return Some(Value::Object(fields));
+        }
+        loop {
+            self.whitespace();
+            let key = self.string()?;
+            self.whitespace();
+            self.chars.next_if_eq(&':')?;
+            fields.push((key, self.value()?));
+            self.whitespace();
+            match self.chars.next()? {
+                ',' => {}
+                '}' => return Some(Value::Object(fields)),
+                _ => return None,
+            }
+        }
+    }
+
+    /// The rest of an array, after its `[`.
+    fn array(&mut self) -> Option<Value> {
+        let mut items = Vec::new();
+        self.whitespace();
+        if self.chars.next_if_eq(&']').is_some() {
+            return Some(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.chars.next()? {
+                ',' => {}
+                ']' => return Some(Value::Array(items)),
+                _ => return None,
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        (0..4).try_fold(0, |acc, _| {
+            Some(acc * 16 + self.chars.next()?.to_digit(16)?)
+        })
+    }
+
+    /// The low half of a surrogate pair, if a `\u` escape of one follows.
+    fn low_surrogate(&mut self) -> Option<u32> {
+        let mut ahead = Parser {
+            chars: self.chars.clone(),
+            depth: self.depth,
+        };
+        ahead.chars.next_if_eq(&'\\')?;
+        ahead.chars.next_if_eq(&'u')?;
+        let low = ahead.hex4().filter(|low| (0xDC00..0xE000).contains(low))?;
+        self.chars = ahead.chars;
+        Some(low)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.chars.next_if_eq(&'"')?;
+        let mut out = String::new();
+        loop {
+            match self.chars.next()? {
+                '"' => return Some(out),
+                '\\' => match self.chars.next()? {
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'u' => {
+                        let mut code = self.hex4()?;
+                        // Characters outside the BMP are escaped as surrogate
+                        // pairs; a lone half is replaced
+                        if (0xD800..0xDC00).contains(&code)
+                            && let Some(low) = self.low_surrogate()
+                        {
+                            code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                        }
+                        out.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    c => out.push(c),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(string(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(string(r"C:\out\call.wav"), r#""C:\\out\\call.wav""#);
+        assert_eq!(string("a\nb\rc\td"), r#""a\nb\rc\td""#);
+        assert_eq!(string("\u{0}\u{1b}\u{1f} "), r#""\u0000\u001b\u001f ""#);
+        assert_eq!(string("Ström 通話 🎵"), "\"Ström 通話 🎵\"");
+        assert_eq!(string(""), r#""""#);
+    }
+
+    #[test]
+    fn objects_are_built_in_order() {
+        let object = Object::new()
+            .str("input", "/srv/in/\"call\"\n.wav")
+            .opt_str("error", None)
+            .opt_str("class", Some("timeout"))
+            .num("seconds", 1.5)
+            .raw("tags", &array(["\"a\"".to_string(), "\"b\"".to_string()]))
+            .finish();
+        assert_eq!(
+            object,
+            r#"{"input":"/srv/in/\"call\"\n.wav","error":null,"class":"timeout","seconds":1.5,"tags":["a","b"]}"#
+        );
+        assert_eq!(Object::new().finish(), "{}");
+        assert_eq!(array(Vec::new()), "[]");
+    }
+
+    #[test]
+    fn escaped_strings_parse_back() {
+        let text: String = (0..0x80u8).map(char::from).chain("é€🎵".chars()).collect();
+        let object = Object::new().str(&text, &text).finish();
+        let value = parse(&object).unwrap();
+        assert_eq!(value.get(&text), Some(&Value::String(text.clone())));
+    }
+
+    #[test]
+    fn documents_parse_into_values() {
+        let value = parse(r#" {"a": [1, -2.5e3, true, null], "b": {"c": "d"}, "e": []} "#).unwrap();
+        assert_eq!(
+            value.get("a"),
+            Some(&Value::Array(vec![
+                Value::Number(1.0),
+                Value::Number(-2500.0),
+                Value::Bool(true),
+                Value::Null,
+            ]))
+        );
+        assert_eq!(
+            value.get("b").and_then(|b| b.get("c")),
+            Some(&Value::String("d".into()))
+        );
+        assert_eq!(value.get("e").map(Value::as_array), Some(&[][..]));
+        assert_eq!(parse("{}"), Some(Value::Object(Vec::new())));
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(parse(&nested(MAX_DEPTH)).is_some());
+        assert_eq!(parse(&nested(MAX_DEPTH + 1)), None);
+        let objects = r#"{"a":"#.repeat(MAX_DEPTH + 1) + "1" + &"}".repeat(MAX_DEPTH + 1);
+        assert_eq!(parse(&objects), None);
+        // Far deeper than the stack would take
+        assert_eq!(parse(&"[".repeat(65536)), None);
+        // Siblings do not add up
+        let wide = format!("[{}]", vec![nested(MAX_DEPTH - 1); 100].join(","));
+        assert!(parse(&wide).is_some());
+    }
+
+    #[test]
+    fn escapes_are_decoded() {
+        let text = r#""q\"b\\s\/n\nr\rt\tb\bf\f\u00e9\u20AC\u0001""#;
+        assert_eq!(
+            parse(text),
+            Some(Value::String("q\"b\\s/n\nr\rt\tb\u{8}f\u{c}é€\u{1}".into()))
+        );
+    }
+
+    #[test]
+    fn surrogate_pairs_make_one_character() {
+        assert_eq!(parse(r#""\ud83c\udfb5""#), Some(Value::String("🎵".into())));
+        assert_eq!(
+            parse(r#""\uD83C\uDFB5x""#),
+            Some(Value::String("🎵x".into()))
+        );
+        // Lone halves are replaced, and what follows them kept
+        assert_eq!(
+            parse(r#""\ud83cx""#),
+            Some(Value::String("\u{fffd}x".into()))
+        );
+        assert_eq!(
+            parse(r#""\ud83c\n""#),
+            Some(Value::String("\u{fffd}\n".into()))
+        );
+        assert_eq!(
+            parse(r#""\ud83cA""#),
+            Some(Value::String("\u{fffd}A".into()))
+        );
+        assert_eq!(parse(r#""\udfb5""#), Some(Value::String("\u{fffd}".into())));
+    }
+
+    #[test]
+    fn truncated_documents_are_refused() {
+        for text in [
+            "",
+            "{",
+            r#"{"a""#,
+            r#"{"a":"#,
+            r#"{"a":1"#,
+            r#"{"a":1,"#,
+            "[1,",
+            r#""abc"#,
+            r#""\u12"#,
+            r#""\"#,
+            "tru",
+            "nul",
+        ] {
+            assert_eq!(parse(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn trailing_text_is_refused() {
+        assert_eq!(parse("{} {}"), None);
+        assert_eq!(parse("[1]]"), None);
+        assert_eq!(parse("[1 2]"), None);
+        assert_eq!(parse("{\"a\" 1}"), None);
+    }
+}
@@ -0,0 +1,326 @@
+//! Kafka job input and result events, through the kcat executable.
+//!
+//! [`KafkaSource`] consumes a topic as a member of a consumer group, so the
+//! partitions are balanced across every instance using the same group.
+//! Each message names one input, as described in [`crate::message`]. The
+//! consumer commits offsets as messages are read, so the jobs in progress
+//! on an instance that dies are not redelivered.
+//!
+//! [`KafkaPublisher`] publishes the record of every finished job to a
+//! topic, as JSON keyed by the job ID.
+use crate::jobs::JobRecord;
+use crate::message;
+use crate::source::Source;
+use crate::{Error, Result, Submitter, shutdown};
+use log::{debug, error, info, warn};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Pause before restarting kcat after it exited.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// A topic, parsed from `kafka://host[:port][,host[:port]...]/topic`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KafkaLocation {
+    /// Bootstrap brokers, comma-separated.
+    pub brokers: String,
+    pub topic: String,
+}
+
+impl KafkaLocation {
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("kafka://")?;
+        let (brokers, topic) = rest.split_once('/')?;
+        let topic = topic.trim_end_matches('/');
+        if brokers.is_empty() || topic.is_empty() || topic.contains('/') {
+            return None;
+        }
+        Some(KafkaLocation {
+            brokers: brokers.to_string(),
+            topic: topic.to_string(),
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("kafka://{}/{}", self.brokers, self.topic)
+    }
+
+    /// A kcat command for the brokers, with the librdkafka settings from
+    /// `config`, e.g. the security protocol and credentials.
+    fn command(&self, config: Option<&PathBuf>) -> Command {
+        let mut command = Command::new("kcat");
+        command.arg("-b").arg(&self.brokers).arg("-q");
+        if let Some(config) = config {
+            command.arg("-F").arg(config);
+        }
+        command
+    }
+}
+
+/// How to consume a topic.
+#[derive(Clone, Debug)]
+pub struct KafkaConfig {
+    /// Consumer group shared by the instances the topic is balanced over.
+    pub group: String,
+    /// librdkafka properties file, e.g. with `security.protocol`.
+    pub config: Option<PathBuf>,
+    /// Scratch directory for inputs given as URLs.
+    pub work_dir: PathBuf,
+}
+
+/// Read the next message from kcat's output, formatted as `%S %s\n`: the
+/// payload length, a space, the payload and a newline. `None` at the end of
+/// the output; NULL payloads are returned as empty.
+fn read_message(reader: &mut impl BufRead) -> std::io::Result<Option<Vec<u8>>> {
+    let mut length = Vec::new();
+    if reader.read_until(b' ', &mut length)? == 0 {
+        return Ok(None);
+    }
+    let length = std::str::from_utf8(&length)
+        .ok()
+        .and_then(|s| s.trim().parse::<i64>().ok())
+        .ok_or_else(|| std::io::Error::other("unexpected output from kcat"))?;
+    let mut payload = Vec::new();
+    if length > 0 {
+        reader
+            .by_ref()
+            .take(length as u64)
+            .read_to_end(&mut payload)?;
+    }
+    let mut newline = [0u8];
+    reader.read_exact(&mut newline)?;
+    Ok(Some(payload))
+}
+
+/// State shared between a [`KafkaSource`] and its consumer thread.
+struct Consumer {
+    location: KafkaLocation,
+    config: KafkaConfig,
+    child: Mutex<Option<Child>>,
+    healthy: AtomicBool,
+    stop: AtomicBool,
+}
+
+impl Consumer {
+    fn stopping(&self) -> bool {
+        self.stop.load(Ordering::SeqCst) || shutdown::requested()
+    }
+
+    /// Run one kcat consumer until it exits.
+    fn session(&self, submitter: &Submitter) -> std::io::Result<()> {
+        let mut command = self.location.command(self.config.config.as_ref());
+        command
+            .args(["-G", &self.config.group, "-u", "-f", "%S %s\n"])
+            .arg(&self.location.topic)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped());
+        let mut child = command.spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        *self.child.lock().unwrap() = Some(child);
+        info!(
+            "Consuming {} as group {}",
+            self.location.describe(),
+            self.config.group
+        );
+        self.healthy.store(true, Ordering::SeqCst);
+
+        let mut reader = BufReader::new(stdout);
+        let result = loop {
+            match read_message(&mut reader) {
+                Ok(Some(body)) if body.is_empty() => debug!("Skipping empty Kafka message"),
+                Ok(Some(body)) => {
+                    message::submit(submitter, &self.config.work_dir, "Kafka", &body);
+                }
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        if let Some(mut child) = self.child.lock().unwrap().take() {
+            let _ = child.kill();
+            let status = child.wait()?;
+            if !self.stopping() {
+                return Err(std::io::Error::other(format!(
+                    "kcat exited with {}",
+                    status
+                )));
+            }
+        }
+        result
+    }
+}
+
+/// Consumes job messages from a Kafka topic.
+pub struct KafkaSource {
+    consumer: Arc<Consumer>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl KafkaSource {
+    pub fn new(location: KafkaLocation, config: KafkaConfig) -> Self {
+        KafkaSource {
+            consumer: Arc::new(Consumer {
+                location,
+                config,
+                child: Mutex::new(None),
+                healthy: AtomicBool::new(true),
+                stop: AtomicBool::new(false),
+            }),
+            thread: None,
+        }
+    }
+}
+
+impl Source for KafkaSource {
+    fn scan(&mut self, _submitter: &Submitter) -> Result<usize> {
+        Err(Error::Config(
+            "--batch is not supported for kafka:// inputs".to_string(),
+        ))
+    }
+
+    fn watch(&mut self, submitter: Submitter) -> Result<()> {
+        let consumer = self.consumer.clone();
+        self.thread = Some(std::thread::spawn(move || {
+            while !consumer.stopping() {
+                if let Err(e) = consumer.session(&submitter) {
+                    error!(
+                        "Kafka consumer for {} failed: {}",
+                        consumer.location.describe(),
+                        e
+                    );
+                    consumer.healthy.store(false, Ordering::SeqCst);
+                    let retry = Instant::now() + RESTART_DELAY;
+                    while Instant::now() < retry && !consumer.stopping() {
+                        std::thread::sleep(Duration::from_millis(200));
+                    }
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    fn healthy(&self) -> bool {
+        self.consumer.healthy.load(Ordering::SeqCst)
+    }
+
+    fn describe(&self) -> String {
+        self.consumer.location.describe()
+    }
+}
+
+impl Drop for KafkaSource {
+    fn drop(&mut self) {
+        self.consumer.stop.store(true, Ordering::SeqCst);
+        if let Some(child) = self.consumer.child.lock().unwrap().as_mut() {
+            let _ = child.kill();
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A running `kcat -P`.
+struct Producer {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+impl Producer {
+    fn start(location: &KafkaLocation, config: Option<&PathBuf>) -> std::io::Result<Self> {
+        let mut child = location
+            .command(config)
+            .args(["-P", "-K", "\t", "-t", &location.topic])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        Ok(Producer { child, stdin })
+    }
+
+    /// Close the input, so kcat flushes its queue and exits.
+    fn finish(self) {
+        let Producer { mut child, stdin } = self;
+        drop(stdin);
+        let _ = child.wait();
+    }
+}
+
+/// Publishes finished jobs to a Kafka topic until it is dropped.
+pub struct KafkaPublisher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl KafkaPublisher {
+    /// Publish the records received from `events`, e.g. a subscription to
+    /// the pipeline's job store.
+    pub fn start(
+        location: KafkaLocation,
+        config: Option<PathBuf>,
+        events: Receiver<JobRecord>,
+    ) -> std::io::Result<Self> {
+        let mut producer = Some(Producer::start(&location, config.as_ref())?);
+        info!("Publishing job results to {}", location.describe());
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                loop {
+                    let record = match events.recv_timeout(Duration::from_secs(1)) {
+                        Ok(record) => record,
+                        // Everything sent before the stop has been published
+                        Err(RecvTimeoutError::Timeout) if stop.load(Ordering::SeqCst) => break,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+                    if !record.status.finished() {
+                        continue;
+                    }
+                    let line = format!("{}\t{}\n", record.id, record.to_json());
+                    // Restart kcat once if it went away
+                    for _ in 0..2 {
+                        if producer.is_none() {
+                            match Producer::start(&location, config.as_ref()) {
+                                Ok(p) => producer = Some(p),
+                                Err(e) => {
+                                    warn!("Failed to start kcat: {}", e);
+                                    break;
+                                }
+                            }
+                        }
+                        let p = producer.as_mut().expect("producer is running");
+                        if p.stdin.write_all(line.as_bytes()).is_ok() {
+                            break;
+                        }
+                        warn!("kcat producer for {} exited", location.describe());
+                        if let Some(p) = producer.take() {
+                            p.finish();
+                        }
+                    }
+                }
+                if let Some(producer) = producer {
+                    producer.finish();
+                }
+            })
+        };
+        Ok(KafkaPublisher {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for KafkaPublisher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
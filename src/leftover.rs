@@ -0,0 +1,135 @@
+//! The jobs still queued in process when a run stops, e.g. at the end of
+//! `--max-runtime`, saved in the work directory so that the next run
+//! queues them again before anything else.
+//!
+//! Neither a watcher, which only sees the files that arrive, nor a polling
+//! source, which has marked the objects seen once it downloaded them, would
+//! find them otherwise. Jobs handed to a shared queue stay in that instead.
+use crate::Submitter;
+use crate::json::{self, Value};
+use crate::preset::Preset;
+use clap::ValueEnum;
+use log::warn;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A job left queued.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Leftover {
+    pub input: PathBuf,
+    /// A local copy, e.g. a download, deleted once its job is done.
+    pub fetched: bool,
+    pub urgent: bool,
+    /// The preset it was submitted with, if any.
+    pub preset: Option<Preset>,
+}
+
+impl Leftover {
+    fn line(&self) -> String {
+        json::Object::new()
+            .str("input", &self.input.to_string_lossy())
+            .raw("fetched", &self.fetched.to_string())
+            .raw("urgent", &self.urgent.to_string())
+            .opt_str("preset", self.preset.map(Preset::as_str))
+            .finish()
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let value = json::parse(line)?;
+        let flag = |key| value.get(key) == Some(&Value::Bool(true));
+        Some(Leftover {
+            input: PathBuf::from(value.get("input")?.as_str()?),
+            fetched: flag("fetched"),
+            urgent: flag("urgent"),
+            preset: match value.get("preset") {
+                Some(Value::String(preset)) => Some(Preset::from_str(preset, true).ok()?),
+                Some(Value::Null) | None => None,
+                Some(_) => return None,
+            },
+        })
+    }
+}
+
+/// Write the jobs left queued to `path`, in queue order, replacing what an
+/// earlier run left there.
+pub(crate) fn save(path: &Path, jobs: &[Leftover]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let partial = path.with_extension("partial");
+    let mut file = File::create(&partial)?;
+    for job in jobs {
+        writeln!(file, "{}", job.line())?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&partial, path)
+}
+
+/// Queue the jobs an earlier run left in `path` through `submitter`, and
+/// remove the file, returning how many were queued. Jobs whose input is
+/// gone meanwhile are left out.
+pub fn restore(path: &Path, submitter: &Submitter) -> std::io::Result<usize> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut queued = 0;
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let Some(job) = Leftover::parse(line) else {
+            warn!("Ignoring a job left queued that cannot be read: {}", line);
+            continue;
+        };
+        if !job.input.is_file() {
+            warn!("Not queueing {:?} again, it is gone", job.input);
+            continue;
+        }
+        match (job.fetched, job.preset, job.urgent) {
+            (true, _, _) => submitter.submit_fetched(&job.input),
+            (false, Some(preset), urgent) => {
+                submitter.submit_with_preset(&job.input, preset, urgent)
+            }
+            (false, None, true) => submitter.submit_urgent(&job.input),
+            (false, None, false) => submitter.submit(&job.input),
+        };
+        queued += 1;
+    }
+    std::fs::remove_file(path)?;
+    Ok(queued)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leftovers_survive_the_round_trip() {
+        let job = Leftover {
+            input: PathBuf::from("/srv/in/call \"7\".wav"),
+            fetched: true,
+            urgent: false,
+            preset: None,
+        };
+        assert_eq!(Leftover::parse(&job.line()), Some(job));
+    }
+
+    #[test]
+    fn leftovers_keep_their_preset() {
+        let job = Leftover {
+            input: PathBuf::from("/srv/in/call.wav"),
+            fetched: false,
+            urgent: true,
+            preset: Some(Preset::Telephony),
+        };
+        assert_eq!(Leftover::parse(&job.line()), Some(job));
+        let unknown = r#"{"input":"/srv/in/call.wav","preset":"vinyl"}"#;
+        assert_eq!(Leftover::parse(unknown), None);
+    }
+
+    #[test]
+    fn lines_without_an_input_are_refused() {
+        assert_eq!(Leftover::parse(r#"{"fetched": true}"#), None);
+        assert_eq!(Leftover::parse("call.wav"), None);
+    }
+}
@@ -0,0 +1,2042 @@
+//! Transcode audio files to 16kHz mono WAV format.
+//!
+//! The pipeline watches a directory for new audio files and transcodes them
+//! to 16kHz mono WAV format with ffmpeg, on one or more worker threads. It
+//! can be embedded in another program:
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use transcoderexpress::{Pipeline, TranscodeOptions};
+//! use transcoderexpress::notifications::Notifiers;
+//! use transcoderexpress::source::{DirectorySource, Source};
+//!
+//! let options = TranscodeOptions {
+//!     output_dir: "/path/to/output".to_string(),
+//!     ..Default::default()
+//! };
+//! let pipeline = Pipeline::new(options, Notifiers::default());
+//! pipeline.submitter().submit(Path::new("/path/to/input/call.opus"));
+//! DirectorySource::new("/path/to/more/input").scan(&pipeline.submitter())?;
+//! let stats = pipeline.run()?;
+//! stats.print();
+//! # Ok::<(), transcoderexpress::Error>(())
+//! ```
+//!
+//! The pipeline uses ffmpeg for transcoding, so make sure it is installed.
+//!
+pub mod affinity;
+#[cfg(feature = "amqp")]
+pub mod amqp;
+pub mod analysis;
+#[cfg(feature = "http")]
+pub mod api;
+pub mod audit;
+#[cfg(feature = "azure")]
+pub mod azure;
+pub mod backend;
+pub mod backpressure;
+pub mod bwf;
+pub mod cache;
+pub mod claim;
+pub mod concurrency;
+#[cfg(unix)]
+pub mod control;
+#[cfg(any(
+    feature = "fetch",
+    feature = "remote",
+    feature = "transcribe",
+    feature = "webdav"
+))]
+mod curl;
+#[cfg(feature = "dashboard")]
+pub mod dashboard;
+#[cfg(feature = "desktop")]
+pub mod desktop;
+#[cfg(feature = "email")]
+pub mod email;
+pub mod error;
+#[cfg(feature = "fetch")]
+mod fetch;
+#[cfg(feature = "http")]
+pub mod files;
+pub mod fingerprint;
+#[cfg(feature = "ftp")]
+pub mod ftp;
+#[cfg(feature = "gcs")]
+pub mod gcs;
+pub mod health;
+pub mod hooks;
+mod host;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "http")]
+pub mod ingest;
+pub mod instance;
+pub mod jobs;
+mod json;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod leftover;
+pub mod marker;
+#[cfg(feature = "messages")]
+mod message;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "nats")]
+pub mod nats;
+pub mod notifications;
+pub mod parts;
+pub mod pause;
+pub mod pipelines;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod prefetch;
+pub mod preset;
+pub mod probe;
+pub mod quarantine;
+pub mod queue;
+pub mod raw;
+#[cfg(feature = "redis")]
+pub mod redis;
+mod relocate;
+pub mod report;
+#[cfg(unix)]
+pub mod rescan;
+pub mod routing;
+#[cfg(feature = "rsync")]
+pub mod rsync;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod sandbox;
+pub mod schedule;
+#[cfg(feature = "sentry")]
+pub mod sentry;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+mod sha256;
+pub mod shutdown;
+pub mod sink;
+pub mod slots;
+pub mod source;
+pub mod space;
+pub mod split;
+pub mod stats;
+pub mod template;
+pub mod throttle;
+#[cfg(feature = "transcribe")]
+pub mod transcript;
+#[cfg(any(
+    feature = "amqp",
+    feature = "ftp",
+    feature = "http",
+    feature = "nats",
+    feature = "postgres",
+    feature = "redis"
+))]
+mod url;
+pub mod verify;
+mod wav;
+#[cfg(feature = "webdav")]
+pub mod webdav;
+#[cfg(feature = "http")]
+pub mod webhook;
+pub mod writers;
+
+pub use error::{Error, Result};
+
+use backend::{BackendKind, BackendOutput, TranscodeBackend, TranscoderMissing};
+use backpressure::Backpressure;
+use claim::{Claim, Claimed};
+use clap::ValueEnum;
+use fingerprint::{Duplicates, Fingerprint, Original};
+use hooks::Hooks;
+use jobs::{JobStatus, JobStore};
+use leftover::Leftover;
+use log::{debug, error, info, warn};
+use notifications::Notifiers;
+use preset::Preset;
+use queue::JobQueue;
+use routing::{Route, RouteRule, RouteScript, Routes, Variant};
+use sink::{DirectorySink, Sink};
+use stats::RunStats;
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError, channel, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the consumer wakes up while the queue is idle.
+const IDLE_TICK: Duration = Duration::from_secs(1);
+/// First and longest wait before trying again to start a transcoder that
+/// could not be started, e.g. while ffmpeg is being upgraded.
+const SPAWN_RETRY: Duration = Duration::from_secs(1);
+const SPAWN_RETRY_MAX: Duration = Duration::from_secs(60);
+/// Attempts in a row, among all workers, that could not start the
+/// transcoder before every job is held back or the run stops.
+const SPAWN_FAILURES: usize = 3;
+/// How often workers held back for a missing transcoder look for it.
+const TRANSCODER_PROBE: Duration = Duration::from_secs(10);
+/// How often the inputs of jobs set aside while being written are looked
+/// at again.
+const WRITERS_RECHECK: Duration = Duration::from_secs(2);
+/// How often the free space of `options.free_space` is looked at.
+const SPACE_CHECK: Duration = Duration::from_secs(5);
+
+/// Settings that apply to every transcoding job.
+#[derive(Clone, Debug, Default)]
+pub struct TranscodeOptions {
+    /// Directory of the default [`DirectorySink`].
+    pub output_dir: String,
+    /// Keep the full ffmpeg output of every job in this directory.
+    pub ffmpeg_log_dir: Option<PathBuf>,
+    /// Log per-stage timings and collect them for the run summary.
+    pub timings: bool,
+    /// Engine used when the pipeline is created.
+    pub backend: BackendKind,
+    /// Format of the outputs.
+    pub preset: preset::Preset,
+    /// Name of the pipeline among those of the process, whose jobs share
+    /// the slots of [`slots`] with them.
+    pub pipeline: Option<String>,
+    /// Number of files transcoded concurrently; zero is treated as one.
+    pub jobs: usize,
+    /// Kill the transcoder if a single file takes longer than this.
+    pub timeout: Option<Duration>,
+    /// Split ffmpeg inputs longer than twice this into segments of this
+    /// length, transcoded in parallel.
+    pub segment_length: Option<Duration>,
+    /// Inputs up to this many bytes are transcoded in batches of up to
+    /// [`TranscodeOptions::small_file_batch`], so that starting the
+    /// transcoder for each does not dominate.
+    pub small_file_size: Option<u64>,
+    /// Most small files in one batch; zero or one turns batching off.
+    pub small_file_batch: usize,
+    /// CPU and I/O priority of the transcoder processes.
+    pub priority: backend::Priority,
+    /// Memory and CPU limits of each transcoder process.
+    pub limits: backend::Limits,
+    /// Check every input before transcoding it, and move those that are
+    /// corrupted here.
+    pub quarantine: Option<quarantine::Quarantine>,
+    /// Skip or link inputs with the same audio as an earlier one.
+    pub fingerprints: Option<fingerprint::FingerprintIndex>,
+    /// Verify every output after it is written.
+    pub verify: Option<verify::Verify>,
+    /// Also write each channel of multichannel inputs to its own file.
+    pub split: Option<split::Split>,
+    /// Write a Broadcast WAV `bext` chunk into every output.
+    pub bwf: Option<bwf::Bwf>,
+    /// Checks of every finished output, written to its JSON sidecar.
+    pub checks: analysis::Checks,
+    /// How the ffmpeg backend turns multichannel inputs into mono;
+    /// ffmpeg's default mix if unset.
+    pub downmix: Option<backend::Downmix>,
+    /// Channel expression of the `pan` filter for the custom downmix.
+    pub downmix_pan: Option<String>,
+    /// Even out the level of the ffmpeg backend's outputs with a dynamic
+    /// normalization filter.
+    pub agc: bool,
+    /// Parameters of headerless PCM inputs for the ffmpeg backend.
+    pub raw_inputs: Vec<raw::RawInput>,
+    /// Hours in which the workers take jobs; outside them, files are
+    /// queued until a window opens.
+    pub active_hours: Option<schedule::ActiveHours>,
+    /// Queue lengths at which submitting new files is held back, and
+    /// resumed; only for the in-process queue.
+    pub queue_watermarks: Option<backpressure::Watermarks>,
+    /// Most jobs of each class of inputs that run at once; a job belongs
+    /// to the first class it matches.
+    pub classes: Vec<concurrency::ConcurrencyClass>,
+    /// Let one more worker take jobs every this often after the start, and
+    /// after being paused or outside the active hours, instead of all of
+    /// them at once.
+    pub ramp_up: Option<Duration>,
+    /// Set jobs aside while another process still has their input open
+    /// for writing.
+    pub skip_open: bool,
+    /// Hold the workers back while less than this is free where outputs
+    /// and scratch files are written.
+    pub free_space: Option<space::FreeSpace>,
+    /// What to do once the transcoder cannot be started for a while.
+    pub transcoder_missing: TranscoderMissing,
+    /// Cores the workers, and the processes they run, are pinned to.
+    pub cpu_set: Option<affinity::CpuSet>,
+    /// Commands run before and after every job.
+    pub hooks: Hooks,
+    /// Rules sending the inputs of subdirectories to outputs of their own.
+    pub routes: Option<Routes>,
+    /// Names of the outputs below their directory, instead of the sink's.
+    pub output_template: Option<template::OutputTemplate>,
+    /// Script deciding the output path of each file, or skipping it.
+    pub route_script: Option<RouteScript>,
+    /// Speech recognition run on every output before it is delivered.
+    #[cfg(feature = "transcribe")]
+    pub transcriber: Option<transcript::Transcriber>,
+    /// Copy queued inputs to local disk ahead of the workers, so that the
+    /// backend does not read from slow storage; only for the in-process
+    /// queue.
+    pub prefetch: Option<prefetch::Prefetch>,
+    /// Hard-link inputs that already are 16kHz mono 16-bit PCM WAV into
+    /// place instead of transcoding them.
+    pub link_compliant: bool,
+    /// Outputs kept for inputs with the same contents, to be reused.
+    pub cache: Option<cache::ResultCache>,
+    /// Claim directory shared with other instances watching the same
+    /// inputs, so that only one of them transcodes each file.
+    pub claims: Option<claim::Claims>,
+    /// Marker files that said the inputs were complete, removed with the
+    /// jobs done.
+    pub done_marker: Option<marker::DoneMarker>,
+}
+
+/// A file waiting in the queue.
+#[derive(Clone, Debug)]
+pub struct TranscodeJob {
+    /// Key of the job in the pipeline's [`JobStore`].
+    pub id: String,
+    pub path: PathBuf,
+    pub queued_at: Instant,
+    /// Delete the input once the job is done, for local copies made by a
+    /// remote source.
+    pub remove_input: bool,
+    /// Copy of the input on local disk that the backend reads instead,
+    /// made by the prefetcher and deleted once the job is done.
+    pub local: Option<PathBuf>,
+    /// Preset of its outputs, instead of that of its route or the options,
+    /// e.g. as given to `enqueue`.
+    pub preset: Option<preset::Preset>,
+}
+
+/// Result of a single transcoding job.
+pub struct JobResult {
+    pub command: Vec<String>,
+    pub started_at: SystemTime,
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub error: Option<String>,
+    /// Diagnostic output of the backend, e.g. ffmpeg's stderr.
+    pub stderr: String,
+    pub elapsed: Duration,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    pub audio: Option<Duration>,
+    /// Time spent in each stage of the job, in pipeline order.
+    pub stages: Vec<(&'static str, Duration)>,
+    /// Findings of the output checks worth a look, e.g. clipping.
+    pub warnings: Vec<String>,
+}
+
+impl JobResult {
+    /// Coarse classification of the failure, derived from ffmpeg's stderr.
+    pub fn error_class(&self) -> Option<&'static str> {
+        let stderr = self.error.as_deref()?;
+        if let Some(reason) = quarantine::Reason::of_error(stderr) {
+            return Some(reason.as_str());
+        }
+        let class = if stderr.contains("Transcoding timed out") {
+            "timeout"
+        } else if stderr.contains("Transcoding cancelled") {
+            "cancelled"
+        } else if stderr.starts_with("Delivery failed") {
+            "delivery_failed"
+        } else if stderr.starts_with("Pre-hook failed") || stderr.starts_with("Post-hook failed") {
+            "hook_failed"
+        } else if stderr.starts_with("Claiming failed") {
+            "claim_failed"
+        } else if stderr.starts_with("Route script") {
+            "route_failed"
+        } else if stderr.starts_with("Writing BWF metadata failed") {
+            "bwf_failed"
+        } else if stderr.starts_with("Splitting channels failed") {
+            "split_failed"
+        } else if stderr.starts_with("Verification failed") {
+            "verify_failed"
+        } else if stderr.starts_with("Transcription failed") {
+            "transcription_failed"
+        } else if stderr.contains("No such file or directory") {
+            "not_found"
+        } else if stderr.contains("Permission denied") {
+            "permission_denied"
+        } else if stderr.contains("does not contain any stream")
+            || stderr.contains("matches no streams")
+        {
+            "no_audio_stream"
+        } else if stderr.contains("Invalid data found") {
+            "invalid_data"
+        } else if stderr.contains("No space left on device") {
+            "disk_full"
+        } else if stderr.contains("Cannot allocate memory") || stderr.contains("Out of memory") {
+            "out_of_memory"
+        } else {
+            "ffmpeg_error"
+        };
+        Some(class)
+    }
+}
+
+/// Transcode a file to 16kHz mono WAV format with the given backend.
+///
+/// Fails only if the backend could not be run at all; a failed conversion
+/// is reported in the returned [`JobResult`].
+pub fn transcode(path: &Path, outfile: &Path, backend: &dyn TranscodeBackend) -> Result<JobResult> {
+    let started = Instant::now();
+    let started_at = SystemTime::now();
+
+    let output = backend.transcode(path, outfile).map_err(Error::Spawn)?;
+    Ok(outcome(
+        path,
+        outfile,
+        output,
+        started_at,
+        started.elapsed(),
+    ))
+}
+
+/// Transcode several files, each into its output, with the given backend
+/// at once, e.g. in one ffmpeg run for a batch of small files.
+///
+/// The time the batch took is shared evenly among its files.
+pub fn transcode_many(
+    files: &[(&Path, &Path)],
+    backend: &dyn TranscodeBackend,
+) -> Result<Vec<JobResult>> {
+    let started = Instant::now();
+    let started_at = SystemTime::now();
+
+    let outputs = backend.transcode_many(files).map_err(Error::Spawn)?;
+    let elapsed = started.elapsed() / files.len().max(1) as u32;
+    Ok(files
+        .iter()
+        .zip(outputs)
+        .map(|(&(path, outfile), output)| outcome(path, outfile, output, started_at, elapsed))
+        .collect())
+}
+
+/// Result of a file the backend was run on.
+fn outcome(
+    path: &Path,
+    outfile: &Path,
+    output: BackendOutput,
+    started_at: SystemTime,
+    elapsed: Duration,
+) -> JobResult {
+    let error = if output.success {
+        info!("Transcoding successful, saved to {}", outfile.display());
+        None
+    } else {
+        error!("Transcoding failed: {}", output.log);
+        Some(output.log.clone())
+    };
+
+    let size = |p: &Path| std::fs::metadata(p).map_or(0, |m| m.len());
+    let audio = match error {
+        None => wav::duration(outfile),
+        Some(_) => None,
+    };
+    JobResult {
+        command: output.command,
+        started_at,
+        input: path.to_path_buf(),
+        error,
+        stderr: output.log,
+        elapsed,
+        input_bytes: size(path),
+        output_bytes: size(outfile),
+        audio,
+        stages: vec![("transcode", elapsed)],
+        warnings: Vec::new(),
+        output: outfile.to_path_buf(),
+    }
+}
+
+/// Result of a job that was refused before transcoding started.
+fn rejected(path: &Path, output: &Path, error: String) -> JobResult {
+    error!("Not transcoding {:?}: {}", path, error);
+    let error = error + "\n";
+    JobResult {
+        command: Vec::new(),
+        started_at: SystemTime::now(),
+        input: path.to_path_buf(),
+        output: output.to_path_buf(),
+        stderr: error.clone(),
+        error: Some(error),
+        elapsed: Duration::ZERO,
+        input_bytes: std::fs::metadata(path).map_or(0, |m| m.len()),
+        output_bytes: 0,
+        audio: None,
+        stages: Vec::new(),
+        warnings: Vec::new(),
+    }
+}
+
+/// Result of a job whose output was put in place without transcoding, in
+/// `stage`, e.g. from the cache.
+fn reused(
+    path: &Path,
+    output: &Path,
+    elapsed: Duration,
+    stage: &'static str,
+    note: &str,
+) -> JobResult {
+    let size = |p: &Path| std::fs::metadata(p).map_or(0, |m| m.len());
+    JobResult {
+        command: Vec::new(),
+        started_at: SystemTime::now() - elapsed,
+        input: path.to_path_buf(),
+        output: output.to_path_buf(),
+        error: None,
+        stderr: format!("{}\n", note),
+        elapsed,
+        input_bytes: size(path),
+        output_bytes: size(output),
+        audio: wav::duration(output),
+        stages: vec![(stage, elapsed)],
+        warnings: Vec::new(),
+    }
+}
+
+/// The results in `done`, with the gaps filled from `rest` in order.
+fn fill(done: Vec<Option<JobResult>>, rest: Vec<JobResult>) -> Vec<JobResult> {
+    let mut rest = rest.into_iter();
+    done.into_iter()
+        .map(|result| result.unwrap_or_else(|| rest.next().expect("one result per file")))
+        .collect()
+}
+
+/// Append the ffmpeg output of a job to its log file.
+fn write_ffmpeg_log(dir: &Path, result: &JobResult) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let name = result.output.file_name().unwrap_or_default();
+    let mut log_name = name.to_os_string();
+    log_name.push(".log");
+    let path = dir.join(log_name);
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(
+        file,
+        "# {} {}",
+        humantime::format_rfc3339_seconds(result.started_at),
+        result.command.join(" ")
+    )?;
+    file.write_all(result.stderr.as_bytes())?;
+    writeln!(
+        file,
+        "# {}\n",
+        match &result.error {
+            None => "succeeded",
+            Some(_) => "failed",
+        }
+    )
+}
+
+/// What a worker got when asking for its next job.
+enum Next {
+    Job(TranscodeJob),
+    Idle,
+    /// No more jobs will be submitted.
+    Closed,
+}
+
+/// State shared by the workers of one pipeline run.
+struct Workers<'a> {
+    rx: Mutex<Receiver<TranscodeJob>>,
+    /// Shared queue the local submissions are forwarded to, if any.
+    queue: Option<&'a dyn JobQueue>,
+    /// Every local submitter is gone, so with a shared queue the run ends
+    /// once that is empty.
+    closed: AtomicBool,
+    /// Jobs taken off the channel so that urgent ones can go first, in
+    /// the order they were submitted.
+    pending: Mutex<VecDeque<TranscodeJob>>,
+    /// Jobs taken off the queue that have not finished yet.
+    busy: AtomicUsize,
+    /// Jobs set aside because their concurrency class was at its limit,
+    /// oldest first; each counts as busy.
+    deferred: Mutex<VecDeque<TranscodeJob>>,
+    /// Jobs set aside because their input was still open for writing, and
+    /// when they were last looked at; each counts as busy.
+    writing: Mutex<(VecDeque<TranscodeJob>, Instant)>,
+    /// Jobs held by the workers, per class of `options.classes`.
+    class_running: Mutex<Vec<usize>>,
+    /// A worker found the queue closed and empty, so those still waiting
+    /// for their turn in the ramp-up can stop too.
+    drained: AtomicBool,
+    /// Too little space is free for outputs and scratch files.
+    short_of_space: AtomicBool,
+    /// Attempts in a row that could not start the transcoder.
+    spawn_failures: AtomicUsize,
+    /// The transcoder cannot be started, so every job is held back; and
+    /// when a held worker last looked for it.
+    transcoder_missing: AtomicBool,
+    transcoder_probed: Mutex<Instant>,
+    /// When the workers last started, or resumed, taking jobs; with a
+    /// ramp-up, worker `n` joins in `n` steps after it.
+    ramp_from: Mutex<Instant>,
+    options: &'a TranscodeOptions,
+    backend: &'a dyn TranscodeBackend,
+    /// Backends of the variants of routes other than the options'.
+    variant_backends: Vec<(Variant, &'a dyn TranscodeBackend)>,
+    /// Rules of the jobs looked at for a batch, by job ID, so that their
+    /// inputs are only probed once.
+    routed: Mutex<HashMap<String, Option<RouteRule>>>,
+    sink: &'a dyn Sink,
+    notifiers: &'a Mutex<Notifiers>,
+    jobs: &'a JobStore,
+    stats: Mutex<RunStats>,
+    /// The first error that stopped a worker.
+    fatal: Mutex<Option<Error>>,
+}
+
+/// A job that is about to be transcoded, with what was settled before.
+struct Prepared {
+    id: String,
+    path: PathBuf,
+    /// What the backend reads: the input, or its prefetched copy.
+    read: PathBuf,
+    output: PathBuf,
+    variant: Variant,
+    cancel: Arc<AtomicBool>,
+    claim: Option<Claim>,
+    queue_wait: Duration,
+    pre_hook: Duration,
+    /// Why the job must not be transcoded, if it must not.
+    allowed: std::result::Result<(), String>,
+    /// Fingerprint of the input, for the index once the output is made.
+    fingerprint: Option<Fingerprint>,
+    /// An earlier input of the same audio, whose output to link.
+    original: Option<Original>,
+    fingerprinting: Duration,
+}
+
+impl Workers<'_> {
+    /// Transcode jobs, one or a batch of small files, and report the
+    /// outcome of each.
+    fn process(&self, jobs: Vec<TranscodeJob>) -> Result<()> {
+        let prepared: Vec<Prepared> = jobs
+            .into_iter()
+            .filter_map(|job| self.prepare(job))
+            .collect();
+        // Batches are of one variant
+        let variant = prepared
+            .first()
+            .map_or(Variant::of(self.options, None), |job| job.variant);
+        let allowed: Vec<&Prepared> = prepared.iter().filter(|job| job.allowed.is_ok()).collect();
+        let linked: Vec<Option<JobResult>> = allowed
+            .iter()
+            .map(|job| {
+                let original = job.original.as_ref()?;
+                self.link_duplicate(job, original)
+            })
+            .collect();
+        let files: Vec<(&Path, &Path)> = allowed
+            .iter()
+            .zip(&linked)
+            .filter(|(_, linked)| linked.is_none())
+            .map(|(job, _)| (job.read.as_path(), job.output.as_path()))
+            .collect();
+        let cancel = match prepared.as_slice() {
+            [job] => Some(job.cancel.clone()),
+            _ => None,
+        };
+        if files.len() > 1 {
+            info!("Transcoding a batch of {} small files", files.len());
+        }
+        let transcoded = match files.is_empty() {
+            true => Vec::new(),
+            false => self.transcode_cached(&files, cancel, variant)?,
+        };
+        let mut transcoded = fill(linked, transcoded).into_iter();
+        for job in prepared {
+            let result = match &job.allowed {
+                Ok(()) => JobResult {
+                    input: job.path.clone(),
+                    ..transcoded.next().expect("one result per file")
+                },
+                Err(e) => rejected(&job.path, &job.output, e.clone()),
+            };
+            self.finish(job, result);
+        }
+        Ok(())
+    }
+
+    /// Link the output of an earlier input of the same audio into place,
+    /// or `None` to transcode the job after all.
+    fn link_duplicate(&self, job: &Prepared, original: &Original) -> Option<JobResult> {
+        if !wav::compliant(&original.output, job.variant.preset) {
+            debug!(
+                "The output of {:?} is not of the {} preset, transcoding {:?}",
+                original.input,
+                job.variant.preset.as_str(),
+                job.path
+            );
+            return None;
+        }
+        let started = Instant::now();
+        if let Err(e) =
+            cache::detach(&job.output).and_then(|()| cache::place(&original.output, &job.output))
+        {
+            warn!(
+                "Failed to link the output of {:?} for {:?}, transcoding it: {}",
+                original.input, job.path, e
+            );
+            return None;
+        }
+        info!(
+            "{:?} has the same audio as {:?}, linked its output",
+            job.path, original.input
+        );
+        Some(reused(
+            &job.path,
+            &job.output,
+            started.elapsed(),
+            "duplicate",
+            &format!(
+                "Linked the output of {:?}, which has the same audio",
+                original.input
+            ),
+        ))
+    }
+
+    /// Take a job through the stages before transcoding, or skip it.
+    fn prepare(&self, job: TranscodeJob) -> Option<Prepared> {
+        let path = job.path;
+        if !path.is_file() {
+            debug!("Skipping {:?}, not a regular file", path);
+            self.jobs.skipped(&job.id, "not a regular file");
+            self.stats.lock().unwrap().skipped();
+            return None;
+        }
+
+        let Some(cancel) = self.jobs.started(&job.id) else {
+            info!("Skipping {:?}, job {} was cancelled", path, job.id);
+            self.stats.lock().unwrap().skipped();
+            return None;
+        };
+
+        let queue_wait = job.queued_at.elapsed();
+        let mut allowed = Ok(());
+        let route = match self.routed.lock().unwrap().remove(&job.id) {
+            Some(route) => route,
+            None => self.route_with(&path, job.preset),
+        };
+        let variant = Variant::of(self.options, route.as_ref());
+        let mut output = planned_output(
+            self.sink,
+            route.as_ref(),
+            self.options.output_template.as_ref(),
+            &path,
+        );
+        if (route.is_some() || self.options.output_template.is_some())
+            && let Some(dir) = output.parent()
+            && let Err(e) = std::fs::create_dir_all(dir)
+        {
+            allowed = Err(format!("Output {:?} unusable: {}", output, e));
+        }
+        let mut claim = None;
+        if let Some(claims) = &self.options.claims {
+            match claims.claim(&path) {
+                Ok(Claimed::Ours(ours)) => claim = Some(ours),
+                Ok(Claimed::Theirs(holder)) => {
+                    info!("Skipping {:?}, claimed by {}", path, holder);
+                    self.jobs
+                        .skipped(&job.id, &format!("claimed by {}", holder));
+                    self.stats.lock().unwrap().skipped();
+                    return None;
+                }
+                Ok(Claimed::Done(holder)) => {
+                    info!("Skipping {:?}, already transcoded by {}", path, holder);
+                    if let Some(marker) = &self.options.done_marker {
+                        marker.skipped(&path);
+                    }
+                    self.jobs
+                        .skipped(&job.id, &format!("already transcoded by {}", holder));
+                    self.stats.lock().unwrap().skipped();
+                    return None;
+                }
+                Err(e) => allowed = Err(format!("Claiming failed: {}", e)),
+            }
+        }
+        if let Some(script) = &self.options.route_script
+            && allowed.is_ok()
+        {
+            match script.route(&path, &output) {
+                Ok(Route::Default) => {}
+                Ok(Route::Output(routed)) => output = routed,
+                Ok(Route::Skip(reason)) => {
+                    info!("Skipping {:?} as routed: {}", path, reason);
+                    if let Some(marker) = &self.options.done_marker {
+                        marker.skipped(&path);
+                    }
+                    self.jobs.skipped(&job.id, &reason);
+                    self.stats.lock().unwrap().skipped();
+                    return None;
+                }
+                Err(e) => allowed = Err(e),
+            }
+        }
+
+        let read = job.local.unwrap_or_else(|| path.clone());
+        if let Some(quarantine) = &self.options.quarantine
+            && allowed.is_ok()
+            && let Err((reason, detail)) = quarantine::check(
+                &read,
+                &backend::input_options(&self.options.raw_inputs, &read),
+            )
+        {
+            let error = match quarantine.isolate(&path, &job.id, reason, &detail) {
+                Ok(moved) => format!(
+                    "Quarantined as {}, moved to {:?}: {}",
+                    reason.as_str(),
+                    moved,
+                    detail
+                ),
+                Err(e) => format!(
+                    "Quarantined as {}, but failed to move it ({}): {}",
+                    reason.as_str(),
+                    e,
+                    detail
+                ),
+            };
+            allowed = Err(error);
+        }
+        let started = Instant::now();
+        let mut fingerprint = None;
+        let mut original = None;
+        if let Some(index) = &self.options.fingerprints
+            && allowed.is_ok()
+        {
+            match Fingerprint::of(&read) {
+                Ok(print) => match index.find(&print) {
+                    Some(earlier) if index.duplicates == Duplicates::Skip => {
+                        info!("Skipping {:?}, same audio as {:?}", path, earlier.input);
+                        if let Some(marker) = &self.options.done_marker {
+                            marker.skipped(&path);
+                        }
+                        self.jobs.skipped(
+                            &job.id,
+                            &format!("same audio as {}", earlier.input.display()),
+                        );
+                        self.stats.lock().unwrap().skipped();
+                        return None;
+                    }
+                    Some(earlier) => original = Some(earlier),
+                    None => fingerprint = Some(print),
+                },
+                Err(e) => warn!("Cannot fingerprint {:?}: {}", path, e),
+            }
+        }
+        let fingerprinting = started.elapsed();
+
+        self.jobs.writing(&job.id, &output);
+        info!("Processing file: {:?}", path);
+        self.notifiers.lock().unwrap().started(&path);
+        let started = Instant::now();
+        let allowed = allowed.and_then(|()| self.options.hooks.before(&path, &output));
+        Some(Prepared {
+            id: job.id,
+            read,
+            variant,
+            path,
+            output,
+            cancel,
+            claim,
+            queue_wait,
+            pre_hook: started.elapsed(),
+            allowed,
+            fingerprint,
+            original,
+            fingerprinting,
+        })
+    }
+
+    /// Take a transcoded job through the stages after transcoding and
+    /// report the outcome.
+    fn finish(&self, job: Prepared, mut result: JobResult) {
+        let Prepared {
+            id,
+            path,
+            output,
+            variant,
+            claim,
+            queue_wait,
+            pre_hook,
+            fingerprint,
+            fingerprinting,
+            ..
+        } = job;
+        let mut before = vec![("queue_wait", queue_wait)];
+        if self.options.fingerprints.is_some() {
+            before.push(("fingerprint", fingerprinting));
+        }
+        if self.options.hooks.pre.is_some() {
+            before.push(("pre_hook", pre_hook));
+        }
+        result.stages.splice(0..0, before);
+        if let Some(verify) = self.options.verify
+            && result.error.is_none()
+        {
+            let started = Instant::now();
+            if let Err(e) = verify::check(&output, verify, Some(variant.preset)) {
+                error!("Verifying {:?} failed: {}", output, e);
+                // So that a rerun does not take it for done
+                let _ = std::fs::remove_file(&output);
+                result.error = Some(format!("Verification failed: {}\n", e));
+            }
+            result.stages.push(("verify", started.elapsed()));
+        }
+        if let Some(split) = &self.options.split
+            && result.error.is_none()
+        {
+            let started = Instant::now();
+            let split = backend::cancellable(job.cancel.clone(), || {
+                split.split(&path, &output, &self.options.raw_inputs, variant.preset)
+            });
+            match split {
+                Ok(files) if !files.is_empty() => {
+                    debug!("Split {:?} into {} channels", path, files.len())
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Splitting the channels of {:?} failed: {}", path, e);
+                    result.error = Some(format!("Splitting channels failed: {}\n", e));
+                }
+            }
+            result.stages.push(("split", started.elapsed()));
+        }
+        if self.options.checks.any() && result.error.is_none() {
+            let started = Instant::now();
+            match analysis::analyze(&self.options.checks, &path, &output) {
+                Ok(report) if report.silent => {
+                    match analysis::set_aside(&output) {
+                        Ok(moved) => info!("Moved silent output of {:?} to {:?}", path, moved),
+                        Err(e) => error!("Failed to set aside silent output {:?}: {}", output, e),
+                    }
+                    if let Some(claim) = claim {
+                        claim.complete(&path, &output);
+                    }
+                    if let Some(marker) = &self.options.done_marker {
+                        marker.skipped(&path);
+                    }
+                    self.jobs.skipped(&id, "silent");
+                    self.stats.lock().unwrap().skipped();
+                    return;
+                }
+                Ok(report) => {
+                    for warning in &report.warnings {
+                        warn!("{:?}: {}", path, warning);
+                    }
+                    result.warnings = report.warnings;
+                }
+                Err(e) => warn!("Failed to check {:?}: {}", output, e),
+            }
+            result.stages.push(("analyze", started.elapsed()));
+        }
+        // After the checks, which may write the output anew
+        if let Some(bwf) = &self.options.bwf
+            && result.error.is_none()
+        {
+            let started = Instant::now();
+            if let Err(e) = bwf.tag(&path, &output, &id, variant.preset) {
+                error!("Writing BWF metadata into {:?} failed: {}", output, e);
+                result.error = Some(format!("Writing BWF metadata failed: {}\n", e));
+            }
+            result.stages.push(("bwf", started.elapsed()));
+        }
+        #[cfg(feature = "transcribe")]
+        if let Some(transcriber) = &self.options.transcriber
+            && result.error.is_none()
+        {
+            let started = Instant::now();
+            if let Err(e) =
+                backend::cancellable(job.cancel.clone(), || transcriber.transcribe(&output))
+            {
+                error!("{}", e);
+                result.error = Some(e + "\n");
+            }
+            result.stages.push(("transcribe", started.elapsed()));
+        }
+        #[cfg(not(feature = "transcribe"))]
+        let _ = output;
+        if result.error.is_none() {
+            let started = Instant::now();
+            if let Err(e) = self.sink.deliver(&result) {
+                error!("Delivery of {:?} failed: {}", result.output, e);
+                result.error = Some(format!("Delivery failed: {}\n", e));
+            }
+            result.stages.push(("deliver", started.elapsed()));
+        }
+        if self.options.hooks.post.is_some() {
+            let started = Instant::now();
+            self.options.hooks.after(&mut result);
+            result.stages.push(("post_hook", started.elapsed()));
+        }
+        info!("Done processing file: {:?}", path);
+        if self.options.timings {
+            let stages: Vec<String> = result
+                .stages
+                .iter()
+                .map(|(stage, d)| format!("{}={:.1}ms", stage, d.as_secs_f64() * 1000.0))
+                .collect();
+            info!("Timings for {:?}: {}", path, stages.join(" "));
+        }
+        if let Some(dir) = &self.options.ffmpeg_log_dir
+            && let Err(e) = write_ffmpeg_log(dir, &result)
+        {
+            error!("Failed to write ffmpeg log: {}", e);
+        }
+        if let Some(claim) = claim
+            && result.error.is_none()
+        {
+            claim.complete(&path, &result.output);
+        }
+        if let (Some(index), Some(fingerprint)) = (&self.options.fingerprints, fingerprint)
+            && result.error.is_none()
+        {
+            index.add(fingerprint, &path, &result.output);
+        }
+        if let Some(marker) = &self.options.done_marker {
+            marker.finished(&path, &result);
+        }
+        self.jobs.finished(&id, &result);
+        self.notifiers.lock().unwrap().finished(&result);
+        self.stats.lock().unwrap().record(&result);
+    }
+
+    /// Transcode the files, or link those already in the target format
+    /// into place, or take the outputs of identical inputs from the cache.
+    /// Only a single file can be cancelled while it runs.
+    fn transcode_cached(
+        &self,
+        files: &[(&Path, &Path)],
+        cancel: Option<Arc<AtomicBool>>,
+        variant: Variant,
+    ) -> Result<Vec<JobResult>> {
+        if !self.options.link_compliant {
+            return self.transcode_or_reuse(files, cancel, variant);
+        }
+        let mut linked = Vec::with_capacity(files.len());
+        let mut rest = Vec::new();
+        for &(path, output) in files {
+            let result = self.link_compliant(path, output, variant.preset);
+            if result.is_none() {
+                // Written anew, not through a link to another input
+                if let Err(e) = cache::detach(output) {
+                    warn!("Failed to unlink {:?}: {}", output, e);
+                }
+                rest.push((path, output));
+            }
+            linked.push(result);
+        }
+        let transcoded = match rest.is_empty() {
+            true => Vec::new(),
+            false => self.transcode_or_reuse(&rest, cancel, variant)?,
+        };
+        Ok(fill(linked, transcoded))
+    }
+
+    /// Link, or copy, an input already in the target format to its output.
+    fn link_compliant(
+        &self,
+        path: &Path,
+        output: &Path,
+        preset: preset::Preset,
+    ) -> Option<JobResult> {
+        if !wav::compliant(path, preset) {
+            return None;
+        }
+        let started = Instant::now();
+        if let Err(e) = cache::detach(output).and_then(|()| cache::place(path, output)) {
+            warn!(
+                "Failed to link {:?} into place, transcoding it: {}",
+                path, e
+            );
+            return None;
+        }
+        info!(
+            "{:?} is already in the target format, linked into place",
+            path
+        );
+        Some(reused(
+            path,
+            output,
+            started.elapsed(),
+            "link",
+            "Linked into place, already in the target format",
+        ))
+    }
+
+    /// Transcode the files, or take the outputs of identical inputs from
+    /// the cache.
+    fn transcode_or_reuse(
+        &self,
+        files: &[(&Path, &Path)],
+        cancel: Option<Arc<AtomicBool>>,
+        variant: Variant,
+    ) -> Result<Vec<JobResult>> {
+        let backend = self.backend_of(&variant);
+        let run = |files: &[(&Path, &Path)]| match &cancel {
+            Some(cancel) => backend::cancellable(cancel.clone(), || {
+                transcode(files[0].0, files[0].1, backend).map(|result| vec![result])
+            }),
+            None => transcode_many(files, backend),
+        };
+        let run =
+            |files: &[(&Path, &Path)]| self.until_started(backend, files, cancel.as_deref(), run);
+        let Some(cache) = &self.options.cache else {
+            return run(files);
+        };
+        let mut results: Vec<Option<JobResult>> = Vec::with_capacity(files.len());
+        let mut missed = Vec::new();
+        let mut keys = Vec::new();
+        for &(path, output) in files {
+            let started = Instant::now();
+            let key = match cache.key(path, self.options, &variant) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    warn!("Cannot look up {:?} in the cache: {}", path, e);
+                    None
+                }
+            };
+            if let Some(key) = &key {
+                match cache.restore(key, output) {
+                    Ok(true) => {
+                        info!("Reused the output of an identical file for {:?}", path);
+                        results.push(Some(reused(
+                            path,
+                            output,
+                            started.elapsed(),
+                            "cache",
+                            "Reused the output of an identical file from the cache",
+                        )));
+                        continue;
+                    }
+                    Ok(false) => {}
+                    Err(e) => warn!("Failed to restore {:?} from the cache: {}", path, e),
+                }
+            }
+            if let Err(e) = cache::detach(output) {
+                warn!("Failed to unlink {:?} from the cache: {}", output, e);
+            }
+            results.push(None);
+            missed.push((path, output));
+            keys.push(key);
+        }
+        let mut transcoded = match missed.is_empty() {
+            true => Vec::new(),
+            false => run(&missed)?,
+        }
+        .into_iter();
+        let mut stored = missed.iter().zip(keys);
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    let result = transcoded.next().expect("one result per file");
+                    let ((path, output), key) = stored.next().expect("one key per file");
+                    if let Some(key) = &key
+                        && result.error.is_none()
+                        && let Err(e) = cache.store(key, output)
+                    {
+                        warn!("Failed to cache the output of {:?}: {}", path, e);
+                    }
+                    result
+                })
+            })
+            .collect())
+    }
+
+    /// The index of a job's concurrency class, if it has one.
+    fn class_of(&self, job: &TranscodeJob) -> Option<usize> {
+        (self.options.classes.iter()).position(|class| class.matches(&job.path))
+    }
+
+    /// Take a slot of the job's class, unless the class is at its limit.
+    fn acquire(&self, job: &TranscodeJob) -> bool {
+        let Some(class) = self.class_of(job) else {
+            return true;
+        };
+        let mut running = self.class_running.lock().unwrap();
+        if running[class] >= self.options.classes[class].limit {
+            return false;
+        }
+        running[class] += 1;
+        true
+    }
+
+    fn release(&self, class: Option<usize>) {
+        if let Some(class) = class {
+            self.class_running.lock().unwrap()[class] -= 1;
+        }
+    }
+
+    /// Take the next job whose class has a free slot, trying those set
+    /// aside first, and waiting up to `wait` for a new one.
+    fn take(&self, wait: Duration) -> Next {
+        if self.options.skip_open {
+            self.release_written();
+        }
+        if !self.options.classes.is_empty() || self.options.skip_open {
+            let mut deferred = self.deferred.lock().unwrap();
+            if let Some(i) = deferred.iter().position(|job| self.acquire(job)) {
+                // Counted by the worker that takes it from now on
+                self.busy.fetch_sub(1, Ordering::SeqCst);
+                return Next::Job(deferred.remove(i).expect("found in the deque"));
+            }
+        }
+        match self.next(wait) {
+            Next::Job(job)
+                if self.options.skip_open
+                    && !writers::open_for_writing(&[&job.path]).is_empty() =>
+            {
+                info!("Setting {:?} aside, it is still open for writing", job.path);
+                self.busy.fetch_add(1, Ordering::SeqCst);
+                self.writing.lock().unwrap().0.push_back(job);
+                Next::Idle
+            }
+            Next::Job(job) if self.acquire(&job) => Next::Job(job),
+            Next::Job(job) => {
+                debug!("Setting {:?} aside, its class is at its limit", job.path);
+                self.busy.fetch_add(1, Ordering::SeqCst);
+                self.deferred.lock().unwrap().push_back(job);
+                Next::Idle
+            }
+            // The jobs set aside still have to be done
+            Next::Closed
+                if !self.deferred.lock().unwrap().is_empty()
+                    || !self.writing.lock().unwrap().0.is_empty() =>
+            {
+                std::thread::sleep(wait);
+                Next::Idle
+            }
+            next => next,
+        }
+    }
+
+    /// Run `attempt` on `files` until the transcoder can be started,
+    /// waiting longer after each time it cannot, so that a transcoder
+    /// missing for a while holds up its jobs instead of failing them; failed
+    /// jobs count as not started while the transcoder does not run at all,
+    /// e.g. when broken by an upgrade. After [`SPAWN_FAILURES`] such
+    /// attempts in a row, every job is held back, or the run stops, as
+    /// `options.transcoder_missing` says. Gives up on shutdown; a job
+    /// cancelled meanwhile ends as cancelled.
+    fn until_started(
+        &self,
+        backend: &dyn TranscodeBackend,
+        files: &[(&Path, &Path)],
+        cancel: Option<&AtomicBool>,
+        attempt: impl Fn(&[(&Path, &Path)]) -> Result<Vec<JobResult>>,
+    ) -> Result<Vec<JobResult>> {
+        let cancelled = || cancel.is_some_and(|c| c.load(Ordering::SeqCst));
+        let mut wait = SPAWN_RETRY;
+        loop {
+            let result = attempt(files).and_then(|results| {
+                match results.iter().any(|result| result.error.is_some()) {
+                    true => backend.available().map_err(Error::Spawn).map(|()| results),
+                    false => Ok(results),
+                }
+            });
+            let e = match result {
+                Err(Error::Spawn(e)) => e,
+                result => {
+                    if result.is_ok() {
+                        self.transcoder_found();
+                    }
+                    return result;
+                }
+            };
+            if shutdown::requested() {
+                return Err(Error::Spawn(e));
+            }
+            health::set("transcoder", format!("cannot be started: {}", e));
+            if self.spawn_failures.fetch_add(1, Ordering::SeqCst) + 1 >= SPAWN_FAILURES
+                && !self.transcoder_missing.swap(true, Ordering::SeqCst)
+            {
+                let message = format!("The transcoder cannot be started: {}", e);
+                self.notifiers
+                    .lock()
+                    .unwrap()
+                    .alert("transcoder missing", &message);
+                if self.options.transcoder_missing == TranscoderMissing::Exit {
+                    return Err(Error::Spawn(e));
+                }
+                error!(
+                    "{}; holding back every job, and looking for it every {}s",
+                    message,
+                    TRANSCODER_PROBE.as_secs()
+                );
+            }
+            warn!(
+                "Failed to start the transcoder, trying again in {}: {}",
+                humantime::format_duration(wait),
+                e
+            );
+            let until = Instant::now() + wait;
+            while Instant::now() < until && !shutdown::requested() && !cancelled() {
+                std::thread::sleep(IDLE_TICK.min(until.saturating_duration_since(Instant::now())));
+            }
+            if cancelled() {
+                let output = || BackendOutput {
+                    command: Vec::new(),
+                    log: format!("Transcoding cancelled\n{}", e),
+                    success: false,
+                };
+                return Ok(files
+                    .iter()
+                    .map(|&(path, outfile)| {
+                        outcome(path, outfile, output(), SystemTime::now(), Duration::ZERO)
+                    })
+                    .collect());
+            }
+            wait = (wait * 2).min(SPAWN_RETRY_MAX);
+        }
+    }
+
+    /// Look for the missing transcoder, if no other worker has lately.
+    fn probe_transcoder(&self) {
+        let mut probed = self.transcoder_probed.lock().unwrap();
+        if probed.elapsed() < TRANSCODER_PROBE {
+            return;
+        }
+        *probed = Instant::now();
+        match self.backend.available() {
+            Ok(()) => self.transcoder_found(),
+            Err(e) => debug!("The transcoder still cannot be run: {}", e),
+        }
+    }
+
+    /// The transcoder ran, so the jobs held back for it can go on.
+    fn transcoder_found(&self) {
+        self.spawn_failures.store(0, Ordering::SeqCst);
+        health::clear("transcoder");
+        if self.transcoder_missing.swap(false, Ordering::SeqCst) {
+            let message = "The transcoder can be started again, taking jobs again";
+            info!("{}", message);
+            self.notifiers
+                .lock()
+                .unwrap()
+                .alert("transcoder found", message);
+        }
+    }
+
+    /// Hold the workers back while less than `space.min_free` is free, and
+    /// let them go on once a tenth more than that is, alerting both times.
+    fn watch_space(&self, space: &space::FreeSpace) {
+        let condition = format!(
+            "free space of {}",
+            space
+                .dirs
+                .iter()
+                .map(|dir| dir.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let mut checked: Option<Instant> = None;
+        while !shutdown::requested() && !self.drained.load(Ordering::SeqCst) {
+            if checked.is_some_and(|at| at.elapsed() < SPACE_CHECK) {
+                std::thread::sleep(IDLE_TICK);
+                continue;
+            }
+            checked = Some(Instant::now());
+            let short = self.short_of_space.load(Ordering::SeqCst);
+            let needed = match short {
+                true => space.min_free + space.min_free / 10,
+                false => space.min_free,
+            };
+            let found = space.short(needed);
+            let described = found
+                .iter()
+                .map(|(dir, free)| format!("{} has {} MiB free", dir.display(), free >> 20))
+                .collect::<Vec<_>>()
+                .join(", ");
+            match (short, found.is_empty()) {
+                (false, false) => {
+                    let message = format!(
+                        "Not taking new jobs, {} of the {} MiB needed",
+                        described,
+                        space.min_free >> 20
+                    );
+                    error!("{}", message);
+                    health::set(condition.clone(), described);
+                    self.short_of_space.store(true, Ordering::SeqCst);
+                    self.notifiers
+                        .lock()
+                        .unwrap()
+                        .alert("disk nearly full", &message);
+                }
+                (true, false) => health::set(condition.clone(), described),
+                (true, true) => {
+                    let message = "Taking new jobs again, enough space is free";
+                    info!("{}", message);
+                    health::clear(&condition);
+                    self.short_of_space.store(false, Ordering::SeqCst);
+                    self.notifiers
+                        .lock()
+                        .unwrap()
+                        .alert("disk space freed", message);
+                }
+                (false, true) => {}
+            }
+        }
+    }
+
+    /// Hand the jobs set aside while their inputs were being written to
+    /// those waiting for a free slot, once the inputs are closed.
+    fn release_written(&self) {
+        let mut writing = self.writing.lock().unwrap();
+        let (jobs, checked) = &mut *writing;
+        if jobs.is_empty() || checked.elapsed() < WRITERS_RECHECK {
+            return;
+        }
+        *checked = Instant::now();
+        let paths: Vec<&Path> = jobs.iter().map(|job| job.path.as_path()).collect();
+        let open = writers::open_for_writing(&paths);
+        let (still, closed): (VecDeque<_>, VecDeque<_>) =
+            jobs.drain(..).partition(|job| open.contains(&job.path));
+        *jobs = still;
+        drop(writing);
+        for job in closed {
+            debug!("{:?} is no longer open for writing", job.path);
+            self.deferred.lock().unwrap().push_back(job);
+        }
+    }
+
+    /// The rule of the route of `path`, if it has one.
+    fn route_of(&self, path: &Path) -> Option<RouteRule> {
+        self.options.routes.as_ref()?.find(path).cloned()
+    }
+
+    /// The rule of a job, with the preset it was submitted with in place
+    /// of that of its route, if any.
+    fn route_with(&self, path: &Path, preset: Option<Preset>) -> Option<RouteRule> {
+        let mut route = self.route_of(path);
+        if let Some(preset) = preset {
+            route.get_or_insert_with(RouteRule::default).preset = Some(preset);
+        }
+        route
+    }
+
+    /// The variant of a job looked at for a batch, keeping its rule for
+    /// when it is prepared.
+    fn variant_of(&self, job: &TranscodeJob) -> Variant {
+        let mut routed = self.routed.lock().unwrap();
+        let route = routed
+            .entry(job.id.clone())
+            .or_insert_with(|| self.route_with(&job.path, job.preset));
+        Variant::of(self.options, route.as_ref())
+    }
+
+    /// The backend making outputs of `variant`.
+    fn backend_of(&self, variant: &Variant) -> &dyn TranscodeBackend {
+        self.variant_backends
+            .iter()
+            .find(|(of, _)| of == variant)
+            .map_or(self.backend, |(_, backend)| *backend)
+    }
+
+    /// Whether a job is small enough to be batched with others.
+    fn small(&self, job: &TranscodeJob) -> bool {
+        self.options.small_file_size.is_some_and(|size| {
+            std::fs::metadata(&job.path).is_ok_and(|m| m.is_file() && m.len() <= size)
+        })
+    }
+
+    /// Take the next job, waiting up to `wait` for one.
+    fn next(&self, wait: Duration) -> Next {
+        let Some(queue) = self.queue else {
+            let rx = self.rx.lock().unwrap();
+            let mut pending = self.pending.lock().unwrap();
+            pending.extend(rx.try_iter());
+            let urgent = self
+                .jobs
+                .first_urgent(pending.iter().map(|job| job.id.as_str()));
+            let job = match urgent {
+                Some(i) => pending.remove(i),
+                None => pending.pop_front(),
+            };
+            if let Some(job) = job {
+                return Next::Job(job);
+            }
+            drop(pending);
+            let received = match wait.is_zero() {
+                true => rx.try_recv().map_err(|e| match e {
+                    TryRecvError::Empty => RecvTimeoutError::Timeout,
+                    TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+                }),
+                false => rx.recv_timeout(wait),
+            };
+            return match received {
+                Ok(job) => Next::Job(job),
+                Err(RecvTimeoutError::Timeout) => Next::Idle,
+                Err(RecvTimeoutError::Disconnected) => Next::Closed,
+            };
+        };
+        // Checked first, so every local job is in the queue when it is found
+        // empty after the forwarder has finished
+        let closed = self.closed.load(Ordering::SeqCst);
+        match queue.pop(wait) {
+            Ok(Some(job)) => {
+                // Jobs submitted by other instances are new to this one
+                self.jobs.queued(&job.id, &job.path);
+                Next::Job(job)
+            }
+            Ok(None) if closed => Next::Closed,
+            Ok(None) => Next::Idle,
+            Err(e) => {
+                error!("Failed to take a job from {}: {}", queue.describe(), e);
+                std::thread::sleep(wait.max(IDLE_TICK));
+                Next::Idle
+            }
+        }
+    }
+
+    /// Push the local submissions to the shared queue until every
+    /// submitter is gone or a shutdown is requested.
+    fn forward(&self, queue: &dyn JobQueue) {
+        let rx = self.rx.lock().unwrap();
+        let push = |job: TranscodeJob| {
+            if let Err(e) = queue.push(&job) {
+                error!(
+                    "Failed to queue {:?} on {}: {}",
+                    job.path,
+                    queue.describe(),
+                    e
+                );
+                self.jobs.failed(&job.id, &e.to_string(), "not_queued");
+            }
+        };
+        while !shutdown::requested() {
+            match rx.recv_timeout(IDLE_TICK) {
+                Ok(job) => push(job),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        // What was submitted before the shutdown is kept in the queue for
+        // the next run instead of being dropped
+        while let Ok(job) = rx.try_recv() {
+            push(job);
+        }
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    /// Worker loop that processes files from the queue until it is closed
+    /// or a shutdown is requested. A fatal error requests a shutdown, so
+    /// the other workers stop too.
+    fn run(&self, index: usize) {
+        if let Some(cpus) = &self.options.cpu_set
+            && let Err(e) = cpus.pin_current_thread()
+        {
+            warn!("Failed to pin a worker to CPUs {}: {}", cpus, e);
+        }
+        let mut next = None;
+        let mut held = false;
+        let mut ramping = false;
+        while !shutdown::requested() {
+            if self.transcoder_missing.load(Ordering::SeqCst) {
+                self.probe_transcoder();
+            }
+            if pause::paused()
+                || self.short_of_space.load(Ordering::SeqCst)
+                || self.transcoder_missing.load(Ordering::SeqCst)
+                || (self.options.active_hours.as_ref()).is_some_and(|hours| !hours.active_now())
+            {
+                held = true;
+                std::thread::sleep(IDLE_TICK);
+                continue;
+            }
+            if held {
+                held = false;
+                *self.ramp_from.lock().unwrap() = Instant::now();
+            }
+            if let Some(step) = self.options.ramp_up
+                && next.is_none()
+                && self.ramp_from.lock().unwrap().elapsed() < step * index as u32
+            {
+                if self.drained.load(Ordering::SeqCst) {
+                    break;
+                }
+                ramping = true;
+                std::thread::sleep(IDLE_TICK.min(step));
+                continue;
+            }
+            if ramping {
+                ramping = false;
+                debug!("Worker {} joins in after the ramp-up", index + 1);
+            }
+            let job = match next.take() {
+                Some(job) => job,
+                None => match self.take(IDLE_TICK) {
+                    Next::Job(job) => {
+                        self.busy.fetch_add(1, Ordering::SeqCst);
+                        job
+                    }
+                    Next::Idle => {
+                        self.notifiers.lock().unwrap().tick();
+                        continue;
+                    }
+                    Next::Closed => {
+                        self.drained.store(true, Ordering::SeqCst);
+                        break;
+                    }
+                },
+            };
+            // Held back while waiting for it
+            if self.short_of_space.load(Ordering::SeqCst)
+                || self.transcoder_missing.load(Ordering::SeqCst)
+            {
+                next = Some(job);
+                continue;
+            }
+
+            let mut batch = vec![job];
+            if self.small(&batch[0]) {
+                while batch.len() < self.options.small_file_batch {
+                    match self.take(Duration::ZERO) {
+                        Next::Job(job)
+                            if self.small(&job)
+                                && self.variant_of(&job) == self.variant_of(&batch[0]) =>
+                        {
+                            batch.push(job)
+                        }
+                        Next::Job(job) => {
+                            next = Some(job);
+                            break;
+                        }
+                        _ => break,
+                    }
+                }
+            }
+
+            let fetched: Vec<PathBuf> = batch
+                .iter()
+                .filter(|job| job.remove_input)
+                .map(|job| job.path.clone())
+                .chain(batch.iter().filter_map(|job| job.local.clone()))
+                .collect();
+            let ids: Vec<String> = batch.iter().map(|job| job.id.clone()).collect();
+            let classes: Vec<Option<usize>> = batch.iter().map(|job| self.class_of(job)).collect();
+            let slot = slots::acquire(self.options.pipeline.as_deref().unwrap_or_default());
+            let processed = self.process(batch);
+            drop(slot);
+            for class in classes {
+                self.release(class);
+            }
+            for path in fetched {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    error!("Failed to remove fetched input {:?}: {}", path, e);
+                }
+            }
+            if let Err(e) = processed {
+                // A shared queue keeps the jobs as in progress, so they are
+                // requeued when this instance restarts
+                error!("Stopping: {}", e);
+                for id in &ids {
+                    if self
+                        .jobs
+                        .get(id)
+                        .is_some_and(|record| !record.status.finished())
+                    {
+                        self.jobs.failed(id, &e.to_string(), "transcoder_failed");
+                    }
+                }
+                self.fatal.lock().unwrap().get_or_insert(e);
+                shutdown::request();
+                break;
+            }
+            if let Some(queue) = self.queue {
+                for record in ids.iter().filter_map(|id| self.jobs.get(id)) {
+                    queue.done(&record);
+                }
+            }
+
+            // Peek for more work, unless a job that did not fit the batch
+            // is next already; an empty queue with no other job in flight
+            // marks the end of a batch
+            if next.is_some() {
+                continue;
+            }
+            match self.take(Duration::ZERO) {
+                Next::Job(job) => next = Some(job),
+                _ => {
+                    if self.busy.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        self.notifiers.lock().unwrap().batch_done();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Where the output of `path` goes unless a routing script says otherwise:
+/// the sink's path, in the directory of its route if that has one, named
+/// by the template if there is one.
+fn planned_output(
+    sink: &dyn Sink,
+    route: Option<&RouteRule>,
+    template: Option<&template::OutputTemplate>,
+    path: &Path,
+) -> PathBuf {
+    let output = sink.output_path(path);
+    let output = route
+        .and_then(|rule| rule.output(&output))
+        .unwrap_or(output);
+    match template {
+        Some(template) => output
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(template.render(path)),
+        None => output,
+    }
+}
+
+/// Handle for adding files to a pipeline's queue.
+#[derive(Clone)]
+pub struct Submitter {
+    tx: Sender<TranscodeJob>,
+    notifiers: Arc<Mutex<Notifiers>>,
+    jobs: Arc<JobStore>,
+    backpressure: Option<Arc<Backpressure>>,
+    sink: Arc<dyn Sink>,
+    routes: Option<Routes>,
+    output_template: Option<template::OutputTemplate>,
+    route_script: Option<RouteScript>,
+}
+
+impl Submitter {
+    /// Where the job of `path` would write its output, following the
+    /// routes and asking the routing script if there is one, or `None` if
+    /// the script skips it.
+    pub fn output_path(&self, path: &Path) -> Option<PathBuf> {
+        let route = self.routes.as_ref().and_then(|routes| routes.find(path));
+        let output = planned_output(&*self.sink, route, self.output_template.as_ref(), path);
+        match self
+            .route_script
+            .as_ref()
+            .map(|script| script.route(path, &output))
+        {
+            Some(Ok(Route::Output(routed))) => Some(routed),
+            Some(Ok(Route::Skip(_))) => None,
+            // A failing script fails the job, which is worth a try
+            Some(Ok(Route::Default) | Err(_)) | None => Some(output),
+        }
+    }
+
+    /// Add a path to the queue, returning the job ID.
+    pub fn submit(&self, path: &Path) -> String {
+        let id = jobs::new_id();
+        self.send(&id, path, false);
+        id
+    }
+
+    /// Like [`Submitter::submit`], for a job that goes ahead of those
+    /// waiting, e.g. an urgent file among thousands of routine ones.
+    pub fn submit_urgent(&self, path: &Path) -> String {
+        let id = jobs::new_id();
+        self.jobs.queued(&id, path);
+        self.jobs.bump(&id);
+        self.send(&id, path, false);
+        id
+    }
+
+    /// Add a temporary local copy to the queue; it is deleted once the job
+    /// is done, whatever the outcome.
+    pub fn submit_fetched(&self, path: &Path) -> String {
+        let id = jobs::new_id();
+        self.send(&id, path, true);
+        id
+    }
+
+    /// Like [`Submitter::submit_fetched`], with an ID from
+    /// [`jobs::new_id`] that was handed out before the file was ready.
+    pub fn submit_fetched_as(&self, id: &str, path: &Path) {
+        self.send(id, path, true);
+    }
+
+    /// Like [`Submitter::submit`] or [`Submitter::submit_urgent`], for a
+    /// job whose outputs are made with `preset` rather than that of its
+    /// route or the options.
+    pub fn submit_with_preset(&self, path: &Path, preset: Preset, urgent: bool) -> String {
+        let id = jobs::new_id();
+        if urgent {
+            self.jobs.queued(&id, path);
+            self.jobs.bump(&id);
+        }
+        self.send_job(&id, path, false, Some(preset));
+        id
+    }
+
+    /// The status of this pipeline's jobs.
+    pub fn jobs(&self) -> &Arc<JobStore> {
+        &self.jobs
+    }
+
+    fn send(&self, id: &str, path: &Path, remove_input: bool) {
+        self.send_job(id, path, remove_input, None)
+    }
+
+    fn send_job(&self, id: &str, path: &Path, remove_input: bool, preset: Option<Preset>) {
+        if let Some(backpressure) = &self.backpressure {
+            backpressure.wait(&self.jobs);
+        }
+        let job = TranscodeJob {
+            id: id.to_string(),
+            path: path.to_path_buf(),
+            queued_at: Instant::now(),
+            remove_input,
+            local: None,
+            preset,
+        };
+        self.jobs.queued(id, path);
+        if let Err(e) = self.tx.send(job) {
+            error!("Error sending path: {}", e);
+            self.jobs
+                .failed(id, "the pipeline has stopped", "not_queued");
+        } else {
+            self.notifiers.lock().unwrap().queued(path);
+        }
+    }
+}
+
+/// A queue of transcoding jobs processed by a pool of workers.
+///
+/// Files are added through [`Submitter`] handles, usually by a
+/// [`source::Source`]; outputs go to a [`Sink`]. [`Pipeline::run`]
+/// processes the queue until every submitter is gone or a shutdown is
+/// requested.
+pub struct Pipeline {
+    options: TranscodeOptions,
+    backend: Box<dyn TranscodeBackend>,
+    variant_backends: Vec<(Variant, Box<dyn TranscodeBackend>)>,
+    sink: Arc<dyn Sink>,
+    notifiers: Arc<Mutex<Notifiers>>,
+    jobs: Arc<JobStore>,
+    queue: Option<Box<dyn JobQueue>>,
+    /// Whether to transcode at all, rather than only forward to the queue.
+    local_workers: bool,
+    backpressure: Option<Arc<Backpressure>>,
+    /// Where the jobs left queued at a shutdown are saved.
+    saved_queue: Option<PathBuf>,
+    tx: Sender<TranscodeJob>,
+    rx: Receiver<TranscodeJob>,
+}
+
+impl Pipeline {
+    pub fn new(options: TranscodeOptions, notifiers: Notifiers) -> Self {
+        let (tx, rx) = channel();
+        let backpressure = options
+            .queue_watermarks
+            .map(|marks| Arc::new(Backpressure::new(marks)));
+        let mut variant_backends: Vec<(Variant, Box<dyn TranscodeBackend>)> = Vec::new();
+        let default = Variant::of(&options, None);
+        // Jobs may be submitted with any preset, whatever their route
+        let rules = std::iter::once(None).chain(
+            options
+                .routes
+                .iter()
+                .flat_map(|routes| routes.rules())
+                .map(Some),
+        );
+        let variants = rules.flat_map(|rule| {
+            let routed = Variant::of(&options, rule);
+            Preset::value_variants()
+                .iter()
+                .map(move |&preset| Variant { preset, ..routed })
+        });
+        for variant in variants.collect::<Vec<_>>() {
+            if variant != default && variant_backends.iter().all(|(of, _)| *of != variant) {
+                let options = TranscodeOptions {
+                    preset: variant.preset,
+                    backend: variant.backend,
+                    segment_length: variant.segment_length,
+                    ..options.clone()
+                };
+                variant_backends.push((variant, variant.backend.create(&options)));
+            }
+        }
+        Pipeline {
+            backend: options.backend.create(&options),
+            variant_backends,
+            sink: Arc::new(DirectorySink::new(&options.output_dir)),
+            options,
+            notifiers: Arc::new(Mutex::new(notifiers)),
+            jobs: Arc::new(JobStore::default()),
+            queue: None,
+            local_workers: true,
+            backpressure,
+            saved_queue: None,
+            tx,
+            rx,
+        }
+    }
+
+    /// Use a custom backend instead of the one named in the options; routes
+    /// made differently keep backends of their own.
+    pub fn with_backend(mut self, backend: Box<dyn TranscodeBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Write outputs to a custom sink instead of the output directory.
+    pub fn with_sink(mut self, sink: Box<dyn Sink>) -> Self {
+        self.sink = sink.into();
+        self
+    }
+
+    /// Share the pending jobs with other instances through `queue`,
+    /// instead of queueing them in process.
+    pub fn with_queue(mut self, queue: Box<dyn JobQueue>) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Only forward the submissions to the shared queue, for the workers of
+    /// other instances, without transcoding anything here. Without a queue
+    /// this has no effect.
+    pub fn without_workers(mut self) -> Self {
+        self.local_workers = false;
+        self
+    }
+
+    /// Save the jobs still queued in process at a shutdown to `path`, for
+    /// [`leftover::restore`] in the next run.
+    pub fn with_saved_queue(mut self, path: impl Into<PathBuf>) -> Self {
+        self.saved_queue = Some(path.into());
+        self
+    }
+
+    /// A handle for submitting files from other threads.
+    pub fn submitter(&self) -> Submitter {
+        Submitter {
+            tx: self.tx.clone(),
+            notifiers: self.notifiers.clone(),
+            jobs: self.jobs.clone(),
+            backpressure: self.backpressure.clone(),
+            sink: self.sink.clone(),
+            routes: self.options.routes.clone(),
+            output_template: self.options.output_template.clone(),
+            route_script: self.options.route_script.clone(),
+        }
+    }
+
+    /// Process the queue until it is closed or a shutdown is requested.
+    ///
+    /// The current thread is one of the workers; `options.jobs - 1` more
+    /// are spawned and joined before returning. Returns the error that
+    /// stopped the run early, if any.
+    pub fn run(self) -> Result<RunStats> {
+        drop(self.tx);
+        // With a shared queue, the workers take their jobs from that instead
+        let (rx, prefetch) = match &self.options.prefetch {
+            Some(prefetch) if self.queue.is_none() => {
+                let (tx, rx) = sync_channel(prefetch.count.max(1) - 1);
+                (rx, Some((prefetch, self.rx, tx)))
+            }
+            _ => (self.rx, None),
+        };
+        let workers = Workers {
+            rx: Mutex::new(rx),
+            queue: self.queue.as_deref(),
+            closed: AtomicBool::new(false),
+            busy: AtomicUsize::new(0),
+            deferred: Mutex::new(VecDeque::new()),
+            writing: Mutex::new((VecDeque::new(), Instant::now())),
+            pending: Mutex::new(VecDeque::new()),
+            class_running: Mutex::new(vec![0; self.options.classes.len()]),
+            drained: AtomicBool::new(false),
+            short_of_space: AtomicBool::new(false),
+            spawn_failures: AtomicUsize::new(0),
+            transcoder_missing: AtomicBool::new(false),
+            transcoder_probed: Mutex::new(Instant::now()),
+            ramp_from: Mutex::new(Instant::now()),
+            options: &self.options,
+            backend: &*self.backend,
+            variant_backends: self
+                .variant_backends
+                .iter()
+                .map(|(variant, backend)| (*variant, &**backend))
+                .collect(),
+            routed: Mutex::new(HashMap::new()),
+            sink: &*self.sink,
+            notifiers: &self.notifiers,
+            jobs: &self.jobs,
+            stats: Mutex::new(RunStats::start(self.options.timings)),
+            fatal: Mutex::new(None),
+        };
+        std::thread::scope(|scope| {
+            if let Some((prefetch, rx, tx)) = prefetch {
+                scope.spawn(move || prefetch::run(prefetch, rx, tx));
+            }
+            if let Some(queue) = workers.queue {
+                if !self.local_workers {
+                    workers.forward(queue);
+                    return;
+                }
+                scope.spawn(|| workers.forward(queue));
+            }
+            if let Some(space) = &self.options.free_space {
+                scope.spawn(|| workers.watch_space(space));
+            }
+            for index in 1..self.options.jobs {
+                let workers = &workers;
+                scope.spawn(move || workers.run(index));
+            }
+            workers.run(0);
+        });
+        // Copies made for the jobs left queued at a shutdown
+        let mut pending: Vec<TranscodeJob> = workers.pending.lock().unwrap().drain(..).collect();
+        pending.extend(workers.rx.lock().unwrap().try_iter());
+        pending.extend(workers.deferred.lock().unwrap().drain(..));
+        pending.extend(workers.writing.lock().unwrap().0.drain(..));
+        for job in &pending {
+            if let Some(copy) = &job.local {
+                let _ = std::fs::remove_file(copy);
+            }
+        }
+        let left = self.jobs.list(Some(JobStatus::Queued));
+        if !left.is_empty() {
+            match (workers.queue, &self.saved_queue) {
+                (Some(queue), _) => {
+                    info!("Left {} jobs in {} for later", left.len(), queue.describe())
+                }
+                (None, Some(path)) => {
+                    // Those held by the prefetcher count as local inputs
+                    let leftovers: Vec<Leftover> = left
+                        .iter()
+                        .map(|record| Leftover {
+                            input: record.input.clone(),
+                            fetched: pending
+                                .iter()
+                                .any(|job| job.id == record.id && job.remove_input),
+                            urgent: record.urgent,
+                            preset: pending
+                                .iter()
+                                .find(|job| job.id == record.id)
+                                .and_then(|job| job.preset),
+                        })
+                        .collect();
+                    match leftover::save(path, &leftovers) {
+                        Ok(()) => info!(
+                            "Saved the {} jobs left queued to {:?}, for the next run",
+                            left.len(),
+                            path
+                        ),
+                        Err(e) => error!(
+                            "Failed to save the {} jobs left queued to {:?}: {}",
+                            left.len(),
+                            path,
+                            e
+                        ),
+                    }
+                }
+                (None, None) => warn!("Dropping the {} jobs left queued", left.len()),
+            }
+        }
+        match workers.fatal.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => Ok(workers.stats.into_inner().unwrap()),
+        }
+    }
+}
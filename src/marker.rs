@@ -0,0 +1,224 @@
+//! The marker file handshake of recorders that upload `call.wav` and then
+//! create an empty `call.wav.done` to say it is complete, so that inputs
+//! are only queued once their marker is there rather than on their own
+//! events.
+//!
+//! Marker files are never inputs themselves. Once the job of an input is
+//! done its marker is removed, unless the job failed with the input in
+//! place, so that a backfill or a retry still finds it ready. Jobs skipped
+//! for good, e.g. with a silent output, also have it removed.
+use crate::JobResult;
+use log::{debug, error};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The suffix that names the marker of an input, parsed from e.g. `.done`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DoneMarker {
+    suffix: String,
+}
+
+impl FromStr for DoneMarker {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.contains(['/', '\\']) {
+            return Err(format!("invalid marker suffix {:?}", s));
+        }
+        Ok(DoneMarker {
+            suffix: s.to_string(),
+        })
+    }
+}
+
+impl DoneMarker {
+    /// The marker of `input`, next to it.
+    pub fn of(&self, input: &Path) -> PathBuf {
+        let mut name = OsString::from(input.file_name().unwrap_or_default());
+        name.push(&self.suffix);
+        input.with_file_name(name)
+    }
+
+    /// The input that `path` is the marker of, if it is one.
+    fn input_of(&self, path: &Path) -> Option<PathBuf> {
+        let name = path.file_name()?.to_str()?;
+        let input = name.strip_suffix(&self.suffix)?;
+        (!input.is_empty()).then(|| path.with_file_name(input))
+    }
+
+    /// The input ready to be queued now that `path` has turned up, which is
+    /// the input of a marker whose input exists, or an input whose marker
+    /// does; inputs still waiting for their marker and the markers of
+    /// inputs yet to come give none.
+    pub fn ready(&self, path: &Path) -> Option<PathBuf> {
+        match self.input_of(path) {
+            Some(input) => input.is_file().then_some(input),
+            None => self.of(path).is_file().then(|| path.to_path_buf()),
+        }
+    }
+
+    /// Remove the marker of the input of a finished job, unless the job
+    /// failed and left the input where it was.
+    pub fn finished(&self, input: &Path, result: &JobResult) {
+        if result.error.is_some() && input.exists() {
+            return;
+        }
+        self.skipped(input);
+    }
+
+    /// Remove the marker of an input whose job was skipped for good, e.g.
+    /// with a silent output or by the routing script.
+    pub fn skipped(&self, input: &Path) {
+        let marker = self.of(input);
+        match std::fs::remove_file(&marker) {
+            Ok(()) => debug!("Removed the marker {:?}", marker),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!("Failed to remove the marker {:?}: {}", marker, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("marker-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn done() -> DoneMarker {
+        ".done".parse().unwrap()
+    }
+
+    fn result(input: &Path, error: Option<&str>) -> JobResult {
+        JobResult {
+            command: Vec::new(),
+            started_at: SystemTime::now(),
+            input: input.to_path_buf(),
+            output: input.with_extension("out.wav"),
+            error: error.map(str::to_string),
+            stderr: String::new(),
+            elapsed: Duration::ZERO,
+            input_bytes: 0,
+            output_bytes: 0,
+            audio: None,
+            stages: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn suffixes_are_names() {
+        assert!("".parse::<DoneMarker>().is_err());
+        assert!("/done".parse::<DoneMarker>().is_err());
+        assert!("\\done".parse::<DoneMarker>().is_err());
+        let marker = done().of(Path::new("in/call.wav"));
+        assert_eq!(marker, Path::new("in/call.wav.done"));
+        assert_eq!(done().input_of(Path::new("in/.done")), None);
+    }
+
+    #[test]
+    fn inputs_are_ready_once_their_marker_comes_last() {
+        let dir = dir("marker-last");
+        let input = dir.join("call.wav");
+        std::fs::write(&input, "audio").unwrap();
+        // Still waiting for the marker
+        assert_eq!(done().ready(&input), None);
+        std::fs::write(dir.join("call.wav.done"), "").unwrap();
+        assert_eq!(done().ready(&dir.join("call.wav.done")), Some(input));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn inputs_are_ready_once_they_come_last() {
+        let dir = dir("input-last");
+        let input = dir.join("call.wav");
+        let marker = dir.join("call.wav.done");
+        std::fs::write(&marker, "").unwrap();
+        // The marker of an input yet to come
+        assert_eq!(done().ready(&marker), None);
+        std::fs::write(&input, "audio").unwrap();
+        assert_eq!(done().ready(&input), Some(input.clone()));
+        // Nor any other input without a marker
+        assert_eq!(done().ready(&dir.join("other.wav")), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn markers_stay_for_failures_with_the_input_in_place() {
+        let dir = dir("failed");
+        let input = dir.join("call.wav");
+        let marker = dir.join("call.wav.done");
+        std::fs::write(&input, "audio").unwrap();
+        std::fs::write(&marker, "").unwrap();
+        done().finished(&input, &result(&input, Some("Invalid data")));
+        assert!(marker.is_file());
+        // Quarantined, so the input is gone
+        std::fs::remove_file(&input).unwrap();
+        done().finished(&input, &result(&input, Some("Invalid data")));
+        assert!(!marker.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn markers_go_with_successes() {
+        let dir = dir("succeeded");
+        let input = dir.join("call.wav");
+        let marker = dir.join("call.wav.done");
+        std::fs::write(&input, "audio").unwrap();
+        std::fs::write(&marker, "").unwrap();
+        done().finished(&input, &result(&input, None));
+        assert!(!marker.exists());
+        // Nothing to remove the second time
+        done().finished(&input, &result(&input, None));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn markers_go_with_silent_outputs() {
+        use crate::analysis::Checks;
+        use crate::backend::BackendKind;
+        use crate::notifications::Notifiers;
+        use crate::{Pipeline, TranscodeOptions};
+
+        let dir = dir("silent");
+        let input = dir.join("call.wav");
+        let marker = dir.join("call.wav.done");
+        // A second of silence, already 16 kHz mono
+        let data = vec![0u8; 32000];
+        let mut wav = b"RIFF".to_vec();
+        wav.extend((36 + data.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend([
+            16, 0, 0, 0, 1, 0, 1, 0, 0x80, 0x3e, 0, 0, 0, 0x7d, 0, 0, 2, 0, 16, 0,
+        ]);
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(data);
+        std::fs::write(&input, wav).unwrap();
+        std::fs::write(&marker, "").unwrap();
+        let options = TranscodeOptions {
+            output_dir: dir.join("out").to_string_lossy().into_owned(),
+            backend: BackendKind::Native,
+            checks: Checks {
+                silence: Some(-60.0),
+                ..Checks::default()
+            },
+            done_marker: Some(done()),
+            ..TranscodeOptions::default()
+        };
+        std::fs::create_dir_all(&options.output_dir).unwrap();
+        let pipeline = Pipeline::new(options, Notifiers::default());
+        pipeline.submitter().submit(&input);
+        pipeline.run().unwrap();
+        assert!(dir.join("out/silent/call_transcoded.wav").is_file());
+        assert!(!marker.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,52 @@
+//! Job messages of the message queue inputs.
+//!
+//! A message names one input, either as a JSON object with a `path` or
+//! `url` field or as a plain text body holding the path or URL; other
+//! fields are ignored. Paths must be absolute and readable by the daemon,
+//! URLs are downloaded into the work directory first.
+use crate::{Submitter, fetch, json};
+use log::error;
+use std::path::Path;
+
+/// Largest message body accepted.
+pub const MAX_MESSAGE: u64 = 64 * 1024;
+
+/// The input named by a message body.
+fn input(body: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(body).ok()?.trim();
+    if text.starts_with('{') {
+        let message = json::parse(text)?;
+        let field = |name| message.get(name).and_then(|v| v.as_str());
+        return field("path").or_else(|| field("url")).map(str::to_string);
+    }
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// Queue the input of a message, returning its job ID, or `None` if the
+/// message cannot be processed. `origin` names the queue in logs.
+pub fn submit(submitter: &Submitter, work_dir: &Path, origin: &str, body: &[u8]) -> Option<String> {
+    if body.len() as u64 > MAX_MESSAGE {
+        error!("Rejecting {} message of {} bytes", origin, body.len());
+        return None;
+    }
+    let Some(input) = input(body) else {
+        error!(
+            "Rejecting {} message without a path or URL: {:?}",
+            origin,
+            String::from_utf8_lossy(body)
+        );
+        return None;
+    };
+    if fetch::supported(&input) {
+        return Some(fetch::submit_url(submitter, work_dir, &input));
+    }
+    let path = Path::new(&input);
+    if !path.is_absolute() || !path.is_file() {
+        error!(
+            "Rejecting {} message for {:?}: not a regular file",
+            origin, path
+        );
+        return None;
+    }
+    Some(submitter.submit(path))
+}
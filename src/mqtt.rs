@@ -0,0 +1,157 @@
+//! MQTT job lifecycle events.
+//!
+//! A minimal MQTT 3.1.1 client that publishes QoS 0 messages, enough to feed
+//! a local broker without pulling in a full client library. Each event is
+//! published to `<topic>/<event>` with a small JSON payload.
+use crate::json;
+use log::{debug, warn};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Publishes job events to an MQTT broker.
+pub struct MqttPublisher {
+    host: String,
+    port: u16,
+    topic: String,
+    client_id: String,
+    stream: Option<TcpStream>,
+}
+
+/// Append an MQTT variable length integer.
+fn push_length(buf: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+/// Append a length-prefixed UTF-8 string.
+fn push_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Build a packet from its fixed header byte and body.
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = vec![header];
+    push_length(&mut buf, body.len());
+    buf.extend_from_slice(body);
+    buf
+}
+
+impl MqttPublisher {
+    pub fn new(host: String, port: u16, topic: String) -> Self {
+        MqttPublisher {
+            host,
+            port,
+            topic: topic.trim_end_matches('/').to_string(),
+            client_id: format!("transcoderexpress-{}", std::process::id()),
+            stream: None,
+        }
+    }
+
+    /// Open a connection and perform the CONNECT/CONNACK handshake.
+    fn connect(&self) -> std::io::Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+        let mut body = Vec::new();
+        push_str(&mut body, "MQTT");
+        body.push(4); // Protocol level 3.1.1
+        body.push(0x02); // Clean session
+        body.extend_from_slice(&0u16.to_be_bytes()); // No keep alive
+        push_str(&mut body, &self.client_id);
+        stream.write_all(&packet(0x10, &body))?;
+
+        let mut connack = [0u8; 4];
+        stream.read_exact(&mut connack)?;
+        if connack[0] != 0x20 || connack[3] != 0 {
+            return Err(std::io::Error::other(format!(
+                "broker refused connection (code {})",
+                connack[3]
+            )));
+        }
+        debug!("Connected to MQTT broker {}:{}", self.host, self.port);
+        Ok(stream)
+    }
+
+    /// Publish a raw payload, reconnecting once if the connection was lost.
+    fn publish_raw(&mut self, topic: &str, payload: &str) -> std::io::Result<()> {
+        let mut body = Vec::new();
+        push_str(&mut body, topic);
+        body.extend_from_slice(payload.as_bytes());
+        let pkt = packet(0x30, &body);
+
+        if let Some(stream) = self.stream.as_mut()
+            && stream.write_all(&pkt).is_ok()
+        {
+            return Ok(());
+        }
+        let mut stream = self.connect()?;
+        stream.write_all(&pkt)?;
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    /// Publish a lifecycle event for a job.
+    pub fn publish(
+        &mut self,
+        event: &str,
+        input: &Path,
+        output: Option<&Path>,
+        error: Option<&str>,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let payload = json::Object::new()
+            .str("event", event)
+            .str("input", &input.to_string_lossy())
+            .opt_str("output", output.map(|p| p.to_string_lossy()).as_deref())
+            .opt_str("error", error)
+            .num("timestamp", timestamp)
+            .finish();
+        let topic = format!("{}/{}", self.topic, event);
+        if let Err(e) = self.publish_raw(&topic, &payload) {
+            warn!("Failed to publish MQTT event to {}: {}", topic, e);
+            self.stream = None;
+        }
+    }
+
+    /// Publish an alert that is not about one job.
+    pub fn alert(&mut self, subject: &str, message: &str) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let payload = json::Object::new()
+            .str("event", "alert")
+            .str("subject", subject)
+            .str("message", message)
+            .num("timestamp", timestamp)
+            .finish();
+        let topic = format!("{}/alert", self.topic);
+        if let Err(e) = self.publish_raw(&topic, &payload) {
+            warn!("Failed to publish MQTT alert to {}: {}", topic, e);
+            self.stream = None;
+        }
+    }
+}
+
+impl Drop for MqttPublisher {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.as_mut() {
+            let _ = stream.write_all(&[0xE0, 0x00]);
+        }
+    }
+}
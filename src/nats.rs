@@ -0,0 +1,565 @@
+//! NATS JetStream work queue consumer.
+//!
+//! A minimal NATS client using a durable pull consumer on a JetStream
+//! stream, enough for edge deployments without pulling in a full client
+//! library. Each message names one input, as described in
+//! [`crate::message`]. Messages are fetched only while workers are free,
+//! and acknowledged once their job has succeeded, including delivery.
+//! Failed jobs are negatively acknowledged, so JetStream redelivers them
+//! until the consumer's maximum delivery count is reached; messages that
+//! cannot be processed at all are terminated.
+use crate::jobs::{JobRecord, JobStatus};
+use crate::source::Source;
+use crate::url::percent_decode;
+use crate::{Error, Result, Submitter, json, message, shutdown};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Pause before reconnecting after the connection was lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long a pull request waits for messages.
+const PULL_EXPIRY: Duration = Duration::from_secs(5);
+/// Time the server waits for an acknowledgement before redelivering.
+const ACK_WAIT: Duration = Duration::from_secs(60);
+/// How often jobs in progress are reported to the server, so their
+/// messages are not redelivered while they run.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(20);
+/// Ping interval; a connection silent for three intervals is dropped.
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+/// A stream, parsed from `nats://[user:password@|token@]host[:port]/stream`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NatsLocation {
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub host: String,
+    pub port: u16,
+    pub stream: String,
+}
+
+impl NatsLocation {
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("nats://")?;
+        let (authority, stream) = rest.split_once('/')?;
+        let stream = stream.trim_end_matches('/');
+        let (userinfo, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => (Some(userinfo), host),
+            None => (None, authority),
+        };
+        let (user, password) = match userinfo.map(|info| info.split_once(':')) {
+            Some(Some((user, password))) => (
+                Some(percent_decode(user, false)),
+                Some(percent_decode(password, false)),
+            ),
+            // A user without a password is a token
+            Some(None) => (Some(percent_decode(userinfo?, false)), None),
+            None => (None, None),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+            _ => (host_port, 4222),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        // Stream names cannot contain subject separators or wildcards
+        let invalid = |c: char| matches!(c, '.' | '*' | '>' | '/') || c.is_whitespace();
+        if host.is_empty() || stream.is_empty() || stream.contains(invalid) {
+            return None;
+        }
+        Some(NatsLocation {
+            user,
+            password,
+            host: host.to_string(),
+            port,
+            stream: stream.to_string(),
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("nats://{}:{}/{}", self.host, self.port, self.stream)
+    }
+}
+
+/// Which consumer to pull from and how it redelivers.
+#[derive(Clone, Debug)]
+pub struct NatsConfig {
+    /// Durable consumer shared by every instance, created if missing.
+    pub consumer: String,
+    /// Deliveries of a message before JetStream gives up on it.
+    pub max_deliver: u32,
+    /// Messages being processed at a time.
+    pub max_in_flight: usize,
+    /// Scratch directory for inputs given as URLs.
+    pub work_dir: PathBuf,
+}
+
+/// A protocol message from the server.
+enum Op {
+    Info(String),
+    Msg {
+        subject: String,
+        reply: Option<String>,
+        /// Status code of a message with headers, e.g. 408 when a pull
+        /// request expired.
+        status: Option<u16>,
+        payload: Vec<u8>,
+    },
+    Ping,
+    Pong,
+    Ok,
+    Err(String),
+}
+
+/// Status code from the header block of an `HMSG`, e.g. `NATS/1.0 404`.
+fn header_status(headers: &[u8]) -> Option<u16> {
+    let line = headers.split(|b| *b == b'\r').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    line.strip_prefix("NATS/1.0")?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// Bytes read but not yet parsed.
+    buf: Vec<u8>,
+    last_received: Instant,
+    last_ping: Instant,
+}
+
+impl Connection {
+    fn open(location: &NatsLocation, inbox: &str) -> std::io::Result<Self> {
+        let stream = TcpStream::connect((location.host.as_str(), location.port))?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut conn = Connection {
+            stream,
+            buf: Vec::new(),
+            last_received: Instant::now(),
+            last_ping: Instant::now(),
+        };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let info = loop {
+            match conn.read_op()? {
+                Some(Op::Info(info)) => break info,
+                _ if Instant::now() > deadline => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::TimedOut,
+                        "no INFO from the NATS server",
+                    ));
+                }
+                _ => {}
+            }
+        };
+        let info = json::parse(&info).unwrap_or(json::Value::Null);
+        if info.get("tls_required") == Some(&json::Value::Bool(true)) {
+            return Err(std::io::Error::other(
+                "the NATS server requires TLS, which is not supported",
+            ));
+        }
+        let mut connect = json::Object::new()
+            .raw("verbose", "false")
+            .raw("pedantic", "false")
+            .str("name", "transcoderexpress")
+            .str("lang", "rust")
+            .str("version", env!("CARGO_PKG_VERSION"))
+            .num("protocol", 1)
+            .raw("headers", "true");
+        connect = match (&location.user, &location.password) {
+            (Some(user), Some(password)) => connect.str("user", user).str("pass", password),
+            (Some(token), None) => connect.str("auth_token", token),
+            _ => connect,
+        };
+        let subscribe = format!("SUB {}.* 1\r\n", inbox);
+        conn.send(&format!(
+            "CONNECT {}\r\n{}PING\r\n",
+            connect.finish(),
+            subscribe
+        ))?;
+        loop {
+            match conn.read_op()? {
+                Some(Op::Pong) => return Ok(conn),
+                Some(Op::Err(e)) => {
+                    return Err(std::io::Error::other(format!("NATS server error: {}", e)));
+                }
+                _ if Instant::now() > deadline => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::TimedOut,
+                        "no answer from the NATS server",
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> std::io::Result<()> {
+        self.stream.write_all(data.as_bytes())
+    }
+
+    fn publish(
+        &mut self,
+        subject: &str,
+        reply: Option<&str>,
+        payload: &str,
+    ) -> std::io::Result<()> {
+        let reply = reply.map(|r| format!(" {}", r)).unwrap_or_default();
+        self.send(&format!(
+            "PUB {}{} {}\r\n{}\r\n",
+            subject,
+            reply,
+            payload.len(),
+            payload
+        ))
+    }
+
+    /// Parse one complete protocol message from the buffer.
+    fn parse_op(&mut self) -> std::io::Result<Option<Op>> {
+        let Some(end) = self.buf.windows(2).position(|w| w == b"\r\n") else {
+            return Ok(None);
+        };
+        let line = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+        let mut words = line.split_whitespace();
+        let verb = words.next().unwrap_or_default().to_ascii_uppercase();
+        let args: Vec<&str> = words.collect();
+        let invalid = || std::io::Error::new(ErrorKind::InvalidData, "malformed NATS message");
+        let op = match verb.as_str() {
+            "MSG" | "HMSG" => {
+                let headers = verb == "HMSG";
+                // MSG <subject> <sid> [reply] <size>
+                // HMSG <subject> <sid> [reply] <header size> <total size>
+                let fixed = if headers { 4 } else { 3 };
+                if args.len() < fixed {
+                    return Err(invalid());
+                }
+                let reply = (args.len() > fixed).then(|| args[2].to_string());
+                let size: usize = args
+                    .last()
+                    .ok_or_else(invalid)?
+                    .parse()
+                    .map_err(|_| invalid())?;
+                let header_size: usize = match headers {
+                    true => args[args.len() - 2].parse().map_err(|_| invalid())?,
+                    false => 0,
+                };
+                if header_size > size {
+                    return Err(invalid());
+                }
+                let start = end + 2;
+                if self.buf.len() < start + size + 2 {
+                    return Ok(None);
+                }
+                let body = &self.buf[start..start + size];
+                let op = Op::Msg {
+                    subject: args[0].to_string(),
+                    reply,
+                    status: match headers {
+                        true => header_status(&body[..header_size]),
+                        false => None,
+                    },
+                    payload: body[header_size..].to_vec(),
+                };
+                self.buf.drain(..start + size + 2);
+                return Ok(Some(op));
+            }
+            "INFO" => Op::Info(line[4..].trim().to_string()),
+            "PING" => Op::Ping,
+            "PONG" => Op::Pong,
+            "+OK" => Op::Ok,
+            "-ERR" => Op::Err(line[4..].trim().trim_matches('\'').to_string()),
+            _ => return Err(invalid()),
+        };
+        self.buf.drain(..end + 2);
+        Ok(Some(op))
+    }
+
+    /// The next message from the server, or `None` if nothing arrived
+    /// within the read timeout. Pings are answered here.
+    fn read_op(&mut self) -> std::io::Result<Option<Op>> {
+        loop {
+            if let Some(op) = self.parse_op()? {
+                self.last_received = Instant::now();
+                if let Op::Ping = op {
+                    self.send("PONG\r\n")?;
+                }
+                return Ok(Some(op));
+            }
+            let mut chunk = [0u8; 16 * 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Ping the server when due, and fail if it went quiet.
+    fn keep_alive(&mut self) -> std::io::Result<()> {
+        if self.last_received.elapsed() > PING_INTERVAL * 3 {
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "no answer from the NATS server",
+            ));
+        }
+        if self.last_ping.elapsed() >= PING_INTERVAL {
+            self.send("PING\r\n")?;
+            self.last_ping = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+/// State shared between a [`NatsSource`] and its consumer thread.
+struct Consumer {
+    location: NatsLocation,
+    config: NatsConfig,
+    healthy: AtomicBool,
+    stop: AtomicBool,
+}
+
+impl Consumer {
+    fn stopping(&self) -> bool {
+        self.stop.load(Ordering::SeqCst) || shutdown::requested()
+    }
+
+    /// Create the durable consumer, or keep the existing one.
+    fn create_consumer(&self, conn: &mut Connection, inbox: &str) -> std::io::Result<()> {
+        let config = json::Object::new()
+            .str("durable_name", &self.config.consumer)
+            .str("ack_policy", "explicit")
+            .num("ack_wait", ACK_WAIT.as_nanos())
+            .num("max_deliver", self.config.max_deliver)
+            .finish();
+        let request = json::Object::new()
+            .str("stream_name", &self.location.stream)
+            .raw("config", &config)
+            .finish();
+        let subject = format!(
+            "$JS.API.CONSUMER.DURABLE.CREATE.{}.{}",
+            self.location.stream, self.config.consumer
+        );
+        let reply = format!("{}.create", inbox);
+        conn.publish(&subject, Some(&reply), &request)?;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            match conn.read_op()? {
+                Some(Op::Msg {
+                    subject,
+                    status,
+                    payload,
+                    ..
+                }) if subject == reply => {
+                    if status == Some(503) {
+                        return Err(std::io::Error::other("JetStream is not enabled"));
+                    }
+                    let response = json::parse(&String::from_utf8_lossy(&payload));
+                    let error = response.as_ref().and_then(|r| r.get("error"));
+                    if let Some(error) = error {
+                        let description = error
+                            .get("description")
+                            .and_then(|d| d.as_str())
+                            .unwrap_or("unknown error");
+                        // A consumer with other settings is used as it is
+                        if description.contains("already") {
+                            warn!(
+                                "Using existing NATS consumer {}: {}",
+                                self.config.consumer, description
+                            );
+                            return Ok(());
+                        }
+                        return Err(std::io::Error::other(format!(
+                            "cannot create NATS consumer {}: {}",
+                            self.config.consumer, description
+                        )));
+                    }
+                    return Ok(());
+                }
+                Some(Op::Err(e)) => {
+                    return Err(std::io::Error::other(format!("NATS server error: {}", e)));
+                }
+                _ => {}
+            }
+        }
+        Err(std::io::Error::new(
+            ErrorKind::TimedOut,
+            "no answer from JetStream",
+        ))
+    }
+
+    /// Consume until the connection fails or the source is stopped.
+    /// `pending` maps the jobs in flight to the subjects acknowledging their
+    /// messages, which stay valid across connections.
+    fn session(
+        &self,
+        submitter: &Submitter,
+        events: &Receiver<JobRecord>,
+        pending: &mut HashMap<String, String>,
+    ) -> std::io::Result<()> {
+        let inbox = format!("_INBOX.transcoderexpress.{}", crate::jobs::new_id());
+        let mut conn = Connection::open(&self.location, &inbox)?;
+        self.create_consumer(&mut conn, &inbox)?;
+        info!(
+            "Pulling from {} as consumer {}",
+            self.location.describe(),
+            self.config.consumer
+        );
+        self.healthy.store(true, Ordering::SeqCst);
+
+        let next = format!(
+            "$JS.API.CONSUMER.MSG.NEXT.{}.{}",
+            self.location.stream, self.config.consumer
+        );
+        let pull_inbox = format!("{}.pull", inbox);
+        // Messages still expected for the open pull request
+        let mut pulling = 0;
+        let mut pull_deadline = Instant::now();
+        let mut last_progress = Instant::now();
+        while !self.stopping() {
+            while let Ok(record) = events.try_recv() {
+                if !record.status.finished() {
+                    continue;
+                }
+                let Some(ack) = pending.remove(&record.id) else {
+                    continue;
+                };
+                let reply = match record.status {
+                    JobStatus::Succeeded | JobStatus::Skipped => "+ACK",
+                    _ => "-NAK",
+                };
+                debug!("Answering NATS message {} with {}", ack, reply);
+                conn.publish(&ack, None, reply)?;
+            }
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                for ack in pending.values() {
+                    conn.publish(ack, None, "+WPI")?;
+                }
+                last_progress = Instant::now();
+            }
+            conn.keep_alive()?;
+
+            // Ask for as many messages as there are free workers
+            let free = self.config.max_in_flight.saturating_sub(pending.len());
+            if (pulling == 0 || Instant::now() > pull_deadline) && free > 0 {
+                let request = json::Object::new()
+                    .num("batch", free)
+                    .num("expires", PULL_EXPIRY.as_nanos())
+                    .finish();
+                conn.publish(&next, Some(&pull_inbox), &request)?;
+                pulling = free;
+                // Allow for the round trip before asking again
+                pull_deadline = Instant::now() + PULL_EXPIRY + Duration::from_secs(1);
+            }
+
+            match conn.read_op()? {
+                // Stream messages keep their subject and carry the subject
+                // acknowledging them as the reply
+                Some(Op::Msg {
+                    reply: Some(ack),
+                    payload,
+                    ..
+                }) if ack.starts_with("$JS.ACK.") => {
+                    pulling -= pulling.min(1);
+                    match message::submit(submitter, &self.config.work_dir, "NATS", &payload) {
+                        Some(id) => {
+                            pending.insert(id, ack);
+                        }
+                        None => conn.publish(&ack, None, "+TERM")?,
+                    }
+                }
+                // The pull request expired or was answered in full; 100 is
+                // an idle heartbeat
+                Some(Op::Msg {
+                    subject, status, ..
+                }) if subject == pull_inbox && status != Some(100) => pulling = 0,
+                Some(Op::Err(e)) => {
+                    return Err(std::io::Error::other(format!("NATS server error: {}", e)));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pulls job messages from a NATS JetStream stream.
+pub struct NatsSource {
+    consumer: Arc<Consumer>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NatsSource {
+    pub fn new(location: NatsLocation, config: NatsConfig) -> Self {
+        NatsSource {
+            consumer: Arc::new(Consumer {
+                location,
+                config,
+                healthy: AtomicBool::new(true),
+                stop: AtomicBool::new(false),
+            }),
+            thread: None,
+        }
+    }
+}
+
+impl Source for NatsSource {
+    fn scan(&mut self, _submitter: &Submitter) -> Result<usize> {
+        Err(Error::Config(
+            "--batch is not supported for nats:// inputs".to_string(),
+        ))
+    }
+
+    fn watch(&mut self, submitter: Submitter) -> Result<()> {
+        let consumer = self.consumer.clone();
+        let events = submitter.jobs().subscribe();
+        self.thread = Some(std::thread::spawn(move || {
+            let mut pending = HashMap::new();
+            while !consumer.stopping() {
+                if let Err(e) = consumer.session(&submitter, &events, &mut pending) {
+                    warn!(
+                        "NATS connection to {} failed: {}",
+                        consumer.location.describe(),
+                        e
+                    );
+                    consumer.healthy.store(false, Ordering::SeqCst);
+                    let retry = Instant::now() + RECONNECT_DELAY;
+                    while Instant::now() < retry && !consumer.stopping() {
+                        std::thread::sleep(Duration::from_millis(200));
+                    }
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    fn healthy(&self) -> bool {
+        self.consumer.healthy.load(Ordering::SeqCst)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} consumer {}",
+            self.consumer.location.describe(),
+            self.consumer.config.consumer
+        )
+    }
+}
+
+impl Drop for NatsSource {
+    fn drop(&mut self) {
+        self.consumer.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Fan-out of job lifecycle events to the configured notifiers and reports.
+use crate::JobResult;
+use crate::audit::AuditLog;
+#[cfg(feature = "desktop")]
+use crate::desktop;
+#[cfg(feature = "email")]
+use crate::email::EmailDigest;
+#[cfg(feature = "mqtt")]
+use crate::mqtt::MqttPublisher;
+use crate::report::DailyReport;
+#[cfg(feature = "sentry")]
+use crate::sentry::SentryReporter;
+use std::path::Path;
+
+/// All notifiers enabled on the command line.
+#[derive(Default)]
+pub struct Notifiers {
+    #[cfg(feature = "email")]
+    pub email: Option<EmailDigest>,
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<MqttPublisher>,
+    #[cfg(feature = "desktop")]
+    pub desktop: bool,
+    pub report: Option<DailyReport>,
+    pub audit: Option<AuditLog>,
+    #[cfg(feature = "sentry")]
+    pub sentry: Option<SentryReporter>,
+}
+
+impl Notifiers {
+    /// A file was added to the queue.
+    #[cfg_attr(not(feature = "mqtt"), allow(unused_variables))]
+    pub fn queued(&mut self, path: &Path) {
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = self.mqtt.as_mut() {
+            mqtt.publish("queued", path, None, None);
+        }
+    }
+
+    /// The consumer started working on a file.
+    #[cfg_attr(not(feature = "mqtt"), allow(unused_variables))]
+    pub fn started(&mut self, path: &Path) {
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = self.mqtt.as_mut() {
+            mqtt.publish("started", path, None, None);
+        }
+    }
+
+    /// A job finished, successfully or not.
+    pub fn finished(&mut self, outcome: &JobResult) {
+        #[cfg(feature = "email")]
+        if let Some(email) = self.email.as_mut() {
+            email.record(outcome);
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = self.mqtt.as_mut() {
+            let event = match outcome.error {
+                None => "succeeded",
+                Some(_) => "failed",
+            };
+            mqtt.publish(
+                event,
+                &outcome.input,
+                Some(&outcome.output),
+                outcome.error.as_deref(),
+            );
+        }
+        #[cfg(feature = "desktop")]
+        if self.desktop {
+            desktop::notify(outcome);
+        }
+        if let Some(report) = self.report.as_mut() {
+            report.record(outcome);
+        }
+        if let Some(audit) = self.audit.as_ref() {
+            audit.record(outcome);
+        }
+        #[cfg(feature = "sentry")]
+        if let Some(sentry) = self.sentry.as_mut() {
+            sentry.record(outcome);
+        }
+    }
+
+    /// The queue drained after processing one or more files.
+    pub fn batch_done(&mut self) {
+        #[cfg(feature = "email")]
+        if let Some(email) = self.email.as_mut() {
+            email.batch_done();
+        }
+    }
+
+    /// Something needs attention that is not about one job, e.g. the
+    /// workers holding back while the disk is full.
+    #[cfg_attr(
+        not(any(
+            feature = "email",
+            feature = "mqtt",
+            feature = "desktop",
+            feature = "sentry"
+        )),
+        allow(unused_variables)
+    )]
+    pub fn alert(&mut self, subject: &str, message: &str) {
+        #[cfg(feature = "email")]
+        if let Some(email) = self.email.as_ref() {
+            email.alert(subject, message);
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = self.mqtt.as_mut() {
+            mqtt.alert(subject, message);
+        }
+        #[cfg(feature = "desktop")]
+        if self.desktop {
+            desktop::alert(subject, message);
+        }
+        #[cfg(feature = "sentry")]
+        if let Some(sentry) = self.sentry.as_ref() {
+            sentry.alert(&format!("{}: {}", subject, message));
+        }
+    }
+
+    /// Periodic wake-up while the queue is idle.
+    pub fn tick(&mut self) {
+        #[cfg(feature = "email")]
+        if let Some(email) = self.email.as_mut() {
+            email.tick();
+        }
+    }
+}
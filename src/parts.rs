@@ -0,0 +1,329 @@
+//! Multi-part recordings, e.g. the long calls a PBX exports in chunks,
+//! joined into one output.
+//!
+//! Parts are grouped by name, `<base><marker><N>.<ext>` as in
+//! `call123_part1.wav` with the marker `_part`, or listed in an `.m3u`
+//! playlist dropped next to them. Once a group is complete and none of its
+//! parts has changed for the settle time, an ffmpeg concat list of the
+//! parts in order is written to the work directory and queued in their
+//! place, named after the group, so that it becomes one output, e.g.
+//! `call123_transcoded.wav`.
+use crate::{Submitter, backend, jobs};
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// ffmpeg's input options for a concat list: absolute paths are only
+/// allowed with `-safe 0`.
+const CONCAT_OPTIONS: [&str; 4] = ["-f", "concat", "-safe", "0"];
+
+/// A group of parts waiting to be complete.
+#[derive(Debug)]
+struct Group {
+    /// Name of the output, e.g. `call123`.
+    name: String,
+    /// Parts by number, for groups by name.
+    parts: BTreeMap<u64, PathBuf>,
+    /// The playlist listing the parts, for groups by playlist.
+    playlist: Option<PathBuf>,
+    /// When a file of the group last arrived.
+    touched: Instant,
+    /// Whether it was reported as incomplete already.
+    warned: bool,
+}
+
+impl Group {
+    /// The parts in order, if all are there.
+    fn complete(&self) -> Option<Vec<PathBuf>> {
+        match &self.playlist {
+            Some(playlist) => {
+                let parts = read_playlist(playlist).ok()?;
+                (!parts.is_empty() && parts.iter().all(|part| part.is_file())).then_some(parts)
+            }
+            None => {
+                // Numbered from 0 or 1, without gaps
+                let first = *self.parts.keys().next()?;
+                let contiguous = self
+                    .parts
+                    .keys()
+                    .zip(first..)
+                    .all(|(&number, expected)| number == expected);
+                (first <= 1 && contiguous).then(|| self.parts.values().cloned().collect())
+            }
+        }
+    }
+}
+
+/// The files listed in an `.m3u` playlist, relative to its directory
+/// unless absolute; comments and directives are skipped.
+fn read_playlist(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.trim().trim_start_matches('\u{feff}'))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| dir.join(line))
+        .collect())
+}
+
+pub(crate) fn is_playlist(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("m3u") || e.eq_ignore_ascii_case("m3u8"))
+}
+
+/// Whether `path` is a concat list written by [`Parts`].
+fn is_concat_list(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "ffconcat")
+}
+
+/// ffmpeg's and ffprobe's input options for `input`: those of a concat
+/// list, or none.
+pub fn input_options(input: &Path) -> &'static [&'static str] {
+    if is_concat_list(input) {
+        &CONCAT_OPTIONS
+    } else {
+        &[]
+    }
+}
+
+/// `path` quoted for a concat list.
+fn quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+/// The files the concat list at `path` references, or nothing if it is not
+/// one.
+pub(crate) fn list_entries(path: &Path) -> Vec<PathBuf> {
+    if !is_concat_list(path) {
+        return Vec::new();
+    }
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let Ok(list) = std::fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    list.lines()
+        .filter_map(|line| line.trim().strip_prefix("file "))
+        .map(|file| {
+            let file = file.trim();
+            let file = file
+                .strip_prefix('\'')
+                .and_then(|file| file.strip_suffix('\''))
+                .unwrap_or(file);
+            dir.join(file.replace("'\\''", "'"))
+        })
+        .collect()
+}
+
+/// Multi-part recordings being collected.
+#[derive(Debug)]
+pub struct Parts {
+    /// What comes between the base name and the number of a part, e.g.
+    /// `_part`, to group parts by name.
+    marker: Option<String>,
+    /// Group the files listed in `.m3u` playlists.
+    playlists: bool,
+    /// How long the parts of a complete group must be left alone before it
+    /// is queued.
+    settle: Duration,
+    /// Where the concat lists are written.
+    work_dir: PathBuf,
+    /// Groups by their directory and name, or playlist.
+    groups: HashMap<PathBuf, Group>,
+}
+
+impl Parts {
+    pub fn new(
+        marker: Option<String>,
+        playlists: bool,
+        settle: Duration,
+        work_dir: impl Into<PathBuf>,
+    ) -> Self {
+        Parts {
+            marker: marker.filter(|marker| !marker.is_empty()),
+            playlists,
+            settle,
+            work_dir: work_dir.into(),
+            groups: HashMap::new(),
+        }
+    }
+
+    /// The name of the group of `path`, and its number in it, if it is a
+    /// part.
+    fn part_of(&self, path: &Path) -> Option<(String, u64)> {
+        let marker = self.marker.as_deref()?;
+        let stem = path.file_stem()?.to_str()?;
+        let (base, number) = stem.rsplit_once(marker)?;
+        if base.is_empty() || number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        Some((base.to_string(), number.parse().ok()?))
+    }
+
+    /// Hold on to `path` if it is a part or a playlist, returning whether
+    /// it was taken.
+    pub fn take(&mut self, path: &Path) -> bool {
+        if self.playlists && is_playlist(path) {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            debug!("Waiting for the parts listed in {:?}", path);
+            self.groups.insert(
+                path.to_path_buf(),
+                Group {
+                    name: name.into_owned(),
+                    parts: BTreeMap::new(),
+                    playlist: Some(path.to_path_buf()),
+                    touched: Instant::now(),
+                    warned: false,
+                },
+            );
+            return true;
+        }
+        let Some((name, number)) = self.part_of(path) else {
+            return self.listed(path);
+        };
+        let key = path.with_file_name(&name);
+        let group = self.groups.entry(key).or_insert_with(|| Group {
+            name,
+            parts: BTreeMap::new(),
+            playlist: None,
+            touched: Instant::now(),
+            warned: false,
+        });
+        debug!("Part {} of {:?} arrived: {:?}", number, group.name, path);
+        group.parts.insert(number, path.to_path_buf());
+        group.touched = Instant::now();
+        true
+    }
+
+    /// Whether a playlist waiting for its parts lists `path`, which is
+    /// then taken with the playlist rather than transcoded on its own.
+    fn listed(&self, path: &Path) -> bool {
+        let Ok(path) = std::path::absolute(path) else {
+            return false;
+        };
+        self.groups
+            .values()
+            .filter_map(|group| group.playlist.as_deref())
+            .filter_map(|playlist| read_playlist(playlist).ok())
+            .flatten()
+            .any(|part| std::path::absolute(part).is_ok_and(|part| part == path))
+    }
+
+    /// Queue every complete group whose parts have settled, or, with
+    /// `present`, every complete group, as for files that were there before
+    /// the run, returning how many were queued.
+    pub fn submit_ready(&mut self, submitter: &Submitter, present: bool) -> usize {
+        let settle = self.settle;
+        let settled = |parts: &[PathBuf]| {
+            parts.iter().all(|part| {
+                std::fs::metadata(part)
+                    .and_then(|m| m.modified())
+                    .is_ok_and(|modified| {
+                        SystemTime::now()
+                            .duration_since(modified)
+                            .is_ok_and(|age| age >= settle)
+                    })
+            })
+        };
+        let mut ready = Vec::new();
+        for (key, group) in &mut self.groups {
+            match group.complete() {
+                Some(parts)
+                    if present || (group.touched.elapsed() >= settle && settled(&parts)) =>
+                {
+                    ready.push((key.clone(), parts))
+                }
+                Some(_) => {}
+                None if present => {
+                    warn!("{:?} is missing parts, waiting for them", group.name);
+                    group.warned = true;
+                }
+                None if !group.warned && group.touched.elapsed() >= settle => {
+                    warn!("{:?} is missing parts, still waiting for them", group.name);
+                    group.warned = true;
+                }
+                None => {}
+            }
+        }
+        let count = ready.len();
+        for (key, parts) in ready {
+            let group = self.groups.remove(&key).expect("ready group is pending");
+            match self.write_list(&group.name, &parts) {
+                Ok(list) => {
+                    info!(
+                        "All {} parts of {:?} are there, adding them to the queue as one",
+                        parts.len(),
+                        group.name
+                    );
+                    submitter.submit_fetched(&list);
+                }
+                Err(e) => warn!("Failed to write the concat list of {:?}: {}", group.name, e),
+            }
+        }
+        count
+    }
+
+    /// Write the concat list of `parts` as `<work dir>/<id>/<name>.ffconcat`,
+    /// a directory of its own so that groups of the same name elsewhere do
+    /// not clash.
+    fn write_list(&self, name: &str, parts: &[PathBuf]) -> std::io::Result<PathBuf> {
+        let mut list = String::from("ffconcat version 1.0\n");
+        for part in parts {
+            let part = std::path::absolute(part)?;
+            // A line break would end the entry, and the rest be read as
+            // directives of the list
+            if part.to_string_lossy().contains(['\n', '\r']) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{:?} has a line break in its name", part),
+                ));
+            }
+            list.push_str(&format!("file {}\n", quote(&backend::path_arg(&part))));
+        }
+        let dir = self.work_dir.join(jobs::new_id());
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.ffconcat", name));
+        std::fs::write(&path, list)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn work_dir(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("parts-{}-{}", test, std::process::id()))
+    }
+
+    #[test]
+    fn concat_lists_keep_hostile_names() {
+        let dir = work_dir("names");
+        let parts = Parts::new(None, false, Duration::ZERO, &dir);
+        let names: Vec<PathBuf> = [
+            "-y.wav",
+            "it's.wav",
+            "''\\'.wav",
+            "file 'x'.wav",
+            "a\"b.wav",
+        ]
+        .iter()
+        .map(|name| dir.join(name))
+        .collect();
+        let list = parts.write_list("group", &names).unwrap();
+        assert!(is_concat_list(&list));
+        assert_eq!(list_entries(&list), names);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concat_lists_refuse_line_breaks() {
+        let dir = work_dir("breaks");
+        let parts = Parts::new(None, false, Duration::ZERO, &dir);
+        for name in ["a\nfile '/etc/passwd'\n.wav", "a\r.wav"] {
+            let error = parts.write_list("group", &[dir.join(name)]).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        }
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,22 @@
+//! Pausing the workers without stopping the pipeline.
+//!
+//! While paused, workers finish the job in flight and then wait instead of
+//! taking more files off the queue; queued files are kept.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Stop taking new jobs until [`resume`] is called.
+pub fn pause() {
+    PAUSED.store(true, Ordering::SeqCst);
+}
+
+/// Take new jobs again.
+pub fn resume() {
+    PAUSED.store(false, Ordering::SeqCst);
+}
+
+/// Whether the workers are paused.
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
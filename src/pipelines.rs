@@ -0,0 +1,251 @@
+//! Several named pipelines in one process, e.g. one per customer, each
+//! defined by a section of a file with options of its own:
+//!
+//! ```text
+//! # For every pipeline that does not set them itself
+//! jobs = 2
+//! mqtt-host = mqtt.internal
+//! skip-open-files = true
+//!
+//! [acme]
+//! input-dir = /srv/acme/in
+//! output-dir = /srv/acme/out
+//! preset = telephony
+//! mqtt-topic = transcoder/acme
+//! email-to = ops@acme.example
+//! email-to = audio@acme.example
+//!
+//! [globex]
+//! input-dir = /srv/globex/in
+//! output-dir = /srv/globex/out
+//! jobs = 6
+//! mqtt-topic = transcoder/globex
+//! skip-open-files = false
+//! ```
+//!
+//! Keys are the long names of the command line options without the
+//! dashes, and values are as on the command line: `true` for a flag, and
+//! a line per value for options that take several. The options before the
+//! first section are the defaults of every pipeline; a pipeline that sets
+//! a key replaces all of its default values, and `false` leaves a flag of
+//! the defaults out. Flags given on the command line cannot be left out
+//! that way, and [`Definition::unset`] names those a pipeline tries to.
+use std::ffi::OsString;
+use std::path::Path;
+
+/// One pipeline of the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Definition {
+    pub name: String,
+    /// Keys and values in file order, with the defaults it does not
+    /// replace first.
+    pub options: Vec<(String, String)>,
+}
+
+impl Definition {
+    /// Whether the pipeline sets `key`, itself or by default.
+    pub fn sets(&self, key: &str) -> bool {
+        self.options.iter().any(|(k, _)| k == key)
+    }
+
+    /// The flags the pipeline sets to `false`, which are left out of its
+    /// [`Definition::args`].
+    pub fn unset(&self) -> impl Iterator<Item = &str> {
+        self.options
+            .iter()
+            .filter(|(_, value)| value == "false")
+            .map(|(key, _)| key.as_str())
+    }
+
+    /// The options as command line arguments.
+    pub fn args(&self) -> Vec<OsString> {
+        self.options
+            .iter()
+            .filter(|(_, value)| value != "false")
+            .map(|(key, value)| match value.as_str() {
+                "true" => format!("--{}", key).into(),
+                _ => format!("--{}={}", key, value).into(),
+            })
+            .collect()
+    }
+}
+
+/// Whether `name` is fit to name a pipeline, and its directories.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The pipelines defined in `text`, in file order.
+pub fn parse(text: &str) -> Result<Vec<Definition>, String> {
+    let mut defaults = Vec::new();
+    let mut sections: Vec<Definition> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim().trim_start_matches('\u{feff}');
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name
+                .strip_suffix(']')
+                .map(str::trim)
+                .filter(|name| valid_name(name))
+                .ok_or_else(|| {
+                    format!(
+                        "line {}: invalid section {:?}, expected [NAME] of letters, digits, - and _",
+                        number + 1,
+                        line
+                    )
+                })?;
+            if sections.iter().any(|section| section.name == name) {
+                return Err(format!(
+                    "line {}: pipeline {:?} is defined twice",
+                    number + 1,
+                    name
+                ));
+            }
+            sections.push(Definition {
+                name: name.to_string(),
+                options: Vec::new(),
+            });
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .filter(|(key, _)| !key.is_empty())
+            .ok_or_else(|| format!("line {}: expected KEY = VALUE", number + 1))?;
+        let key = key.trim_start_matches("--").to_string();
+        match sections.last_mut() {
+            Some(section) => section.options.push((key, value.to_string())),
+            None => defaults.push((key, value.to_string())),
+        }
+    }
+    if sections.is_empty() {
+        return Err("no pipelines defined, expected a [NAME] section".to_string());
+    }
+    for section in &mut sections {
+        let inherited = defaults
+            .iter()
+            .filter(|(key, _)| !section.sets(key))
+            .cloned()
+            .collect::<Vec<_>>();
+        section.options.splice(0..0, inherited);
+    }
+    Ok(sections)
+}
+
+/// The pipelines defined in the file at `path`.
+pub fn read(path: &Path) -> Result<Vec<Definition>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    parse(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example file of the module documentation.
+    fn example() -> String {
+        include_str!("pipelines.rs")
+            .lines()
+            .filter_map(|line| line.strip_prefix("//!"))
+            .map(|line| line.strip_prefix(' ').unwrap_or(line))
+            .skip_while(|line| *line != "```text")
+            .skip(1)
+            .take_while(|line| *line != "```")
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn options<'a>(definition: &'a Definition, key: &str) -> Vec<&'a str> {
+        definition
+            .options
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn the_example_defines_its_pipelines() {
+        let definitions = parse(&example()).unwrap();
+        let names: Vec<&str> = definitions.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["acme", "globex"]);
+        let (acme, globex) = (&definitions[0], &definitions[1]);
+        assert_eq!(options(acme, "jobs"), ["2"]);
+        assert_eq!(options(globex, "jobs"), ["6"]);
+        assert_eq!(options(acme, "mqtt-host"), ["mqtt.internal"]);
+        assert_eq!(
+            options(acme, "email-to"),
+            ["ops@acme.example", "audio@acme.example"]
+        );
+        assert!(options(globex, "email-to").is_empty());
+    }
+
+    #[test]
+    fn sections_replace_the_defaults() {
+        let text = "email-to = a@example.org\nemail-to = b@example.org\n[one]\n[two]\nemail-to = c@example.org\n";
+        let definitions = parse(text).unwrap();
+        assert_eq!(
+            options(&definitions[0], "email-to"),
+            ["a@example.org", "b@example.org"]
+        );
+        assert_eq!(options(&definitions[1], "email-to"), ["c@example.org"]);
+    }
+
+    #[test]
+    fn defaults_come_before_the_options_of_the_section() {
+        let definitions = parse("jobs = 2\n[one]\npreset = archive\n").unwrap();
+        let keys: Vec<&str> = definitions[0]
+            .options
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect();
+        assert_eq!(keys, ["jobs", "preset"]);
+    }
+
+    #[test]
+    fn flags_become_arguments() {
+        let definitions = parse(&example()).unwrap();
+        let args = |definition: &Definition| -> Vec<String> {
+            definition
+                .args()
+                .into_iter()
+                .map(|arg| arg.into_string().unwrap())
+                .collect()
+        };
+        assert!(args(&definitions[0]).contains(&"--skip-open-files".to_string()));
+        assert!(args(&definitions[0]).contains(&"--jobs=2".to_string()));
+        assert!(
+            !args(&definitions[1])
+                .iter()
+                .any(|arg| arg.starts_with("--skip-open-files"))
+        );
+        assert_eq!(
+            definitions[1].unset().collect::<Vec<_>>(),
+            ["skip-open-files"]
+        );
+        assert_eq!(definitions[0].unset().count(), 0);
+    }
+
+    #[test]
+    fn comments_dashes_and_blank_lines_are_allowed() {
+        let text = "\u{feff}; defaults\n\n--jobs = 3\n# the only one\n[only]\n";
+        let definitions = parse(text).unwrap();
+        assert_eq!(options(&definitions[0], "jobs"), ["3"]);
+    }
+
+    #[test]
+    fn malformed_files_are_refused() {
+        let error = |text| parse(text).unwrap_err();
+        assert!(error("jobs = 2\n").contains("no pipelines defined"));
+        assert!(error("[one]\n[one]\n").contains("line 2: pipeline \"one\" is defined twice"));
+        assert!(error("[one two]\n").contains("line 1: invalid section"));
+        assert!(error("[one\n").contains("line 1: invalid section"));
+        assert!(error("[one]\njobs 2\n").contains("line 2: expected KEY = VALUE"));
+        assert!(error("[one]\n= 2\n").contains("line 2: expected KEY = VALUE"));
+    }
+}
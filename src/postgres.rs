@@ -0,0 +1,315 @@
+//! PostgreSQL-backed shared job queue and job history, through psql.
+//!
+//! Every job is a row of the `<name>_jobs` table, created if missing, which
+//! keeps its status, output and error once it has finished. A worker claims
+//! a job by taking a session-level advisory lock on it and holds the lock
+//! on its own psql session until the job is done. If the instance dies, its
+//! sessions end and the locks are released, so a job left `running` whose
+//! lock is free is claimed again by another instance.
+use crate::jobs::JobRecord;
+use crate::queue::JobQueue;
+use crate::url::percent_decode;
+use crate::{TranscodeJob, host};
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Jobs considered at a time when looking for one to claim.
+const CANDIDATES: usize = 10;
+/// Line psql echoes after each statement, followed by its status.
+const END: &str = "__transcoderexpress_end__";
+
+/// A database, given as a `postgres://` or `postgresql://` URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PostgresLocation {
+    /// The URL without its password.
+    pub url: String,
+    pub password: Option<String>,
+}
+
+impl PostgresLocation {
+    pub fn parse(url: &str) -> Option<Self> {
+        let (scheme, rest) = url.split_once("://")?;
+        if scheme != "postgres" && scheme != "postgresql" {
+            return None;
+        }
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (url, password) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => match userinfo.split_once(':') {
+                Some((user, password)) => (
+                    format!("{}://{}@{}/{}", scheme, user, host, path),
+                    Some(percent_decode(password, false)),
+                ),
+                None => (url.to_string(), None),
+            },
+            None => (url.to_string(), None),
+        };
+        Some(PostgresLocation { url, password })
+    }
+}
+
+/// Quote a string as an SQL literal.
+fn literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// A long-running psql session.
+struct Session {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Session {
+    fn open(location: &PostgresLocation) -> std::io::Result<Self> {
+        let mut command = Command::new("psql");
+        command
+            .args(["-X", "-q", "-A", "-t", "-F", "\t", "-v", "ON_ERROR_STOP=0"])
+            .arg(&location.url)
+            .env("PGCONNECT_TIMEOUT", "10")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        if let Some(password) = &location.password {
+            command.env("PGPASSWORD", password);
+        }
+        let mut child = command.spawn()?;
+        let mut session = Session {
+            stdin: child.stdin.take().expect("stdin is piped"),
+            stdout: BufReader::new(child.stdout.take().expect("stdout is piped")),
+            child,
+        };
+        session.query("SET standard_conforming_strings = on")?;
+        Ok(session)
+    }
+
+    /// Run one statement and return its rows, as tab-separated fields.
+    fn query(&mut self, sql: &str) -> std::io::Result<Vec<Vec<String>>> {
+        debug!("psql: {}", sql);
+        writeln!(
+            self.stdin,
+            "{};\n\\echo {} :ERROR :LAST_ERROR_MESSAGE",
+            sql, END
+        )?;
+        self.stdin.flush()?;
+        let mut rows = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stdout.read_line(&mut line)? == 0 {
+                return Err(std::io::Error::other("psql exited"));
+            }
+            let line = line.trim_end_matches('\n');
+            if let Some(status) = line.strip_prefix(END) {
+                return match status.trim().split_once(' ') {
+                    Some(("true", message)) => Err(std::io::Error::other(format!(
+                        "PostgreSQL error: {}",
+                        message
+                    ))),
+                    _ => Ok(rows),
+                };
+            }
+            rows.push(line.split('\t').map(str::to_string).collect());
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = writeln!(self.stdin, "\\q");
+        let _ = self.child.wait();
+    }
+}
+
+/// A job queue in a PostgreSQL table, shared by every instance using the
+/// same database and queue name.
+pub struct PostgresQueue {
+    location: PostgresLocation,
+    table: String,
+    instance: String,
+    /// Sessions holding no lock.
+    idle: Mutex<Vec<Session>>,
+    /// Sessions holding the lock of a claimed job, by job ID.
+    claimed: Mutex<HashMap<String, Session>>,
+}
+
+impl PostgresQueue {
+    /// Connect to the queue `name`, as `instance` or else the host name,
+    /// and create its table if needed.
+    pub fn open(
+        location: PostgresLocation,
+        name: &str,
+        instance: Option<&str>,
+    ) -> std::io::Result<Self> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '_';
+        if name.is_empty()
+            || !name.chars().all(valid)
+            || name.starts_with(|c: char| c.is_ascii_digit())
+        {
+            return Err(std::io::Error::other(format!(
+                "{:?} is not usable as a table name",
+                name
+            )));
+        }
+        let queue = PostgresQueue {
+            table: format!("{}_jobs", name),
+            instance: instance.map_or_else(host::name, str::to_string),
+            location,
+            idle: Mutex::new(Vec::new()),
+            claimed: Mutex::new(HashMap::new()),
+        };
+        let mut session = Session::open(&queue.location)?;
+        session.query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (id text PRIMARY KEY, input text NOT NULL, \
+             remove_input boolean NOT NULL DEFAULT false, status text NOT NULL, \
+             instance text, output text, error text, error_class text, \
+             queued_at timestamptz NOT NULL, started_at timestamptz, finished_at timestamptz)",
+            queue.table
+        ))?;
+        session.query(&format!(
+            "CREATE INDEX IF NOT EXISTS {0}_pending ON {0} (queued_at) \
+             WHERE status IN ('queued', 'running')",
+            queue.table
+        ))?;
+        queue.idle.lock().unwrap().push(session);
+        info!(
+            "Sharing queue table {} as instance {}",
+            queue.table, queue.instance
+        );
+        Ok(queue)
+    }
+
+    fn session(&self) -> std::io::Result<Session> {
+        let idle = self.idle.lock().unwrap().pop();
+        match idle {
+            Some(session) => Ok(session),
+            None => Session::open(&self.location),
+        }
+    }
+
+    /// Arguments of the advisory lock of a job.
+    fn lock_key(&self, id: &str) -> String {
+        format!(
+            "hashtext({}), hashtext({})",
+            literal(&self.table),
+            literal(id)
+        )
+    }
+
+    /// Claim the oldest unclaimed job on `session`, if there is one.
+    fn claim(&self, session: &mut Session) -> std::io::Result<Option<TranscodeJob>> {
+        let candidates = session.query(&format!(
+            "SELECT id FROM {} WHERE status IN ('queued', 'running') ORDER BY queued_at LIMIT {}",
+            self.table, CANDIDATES
+        ))?;
+        for row in candidates {
+            let id = &row[0];
+            let locked = session.query(&format!(
+                "SELECT pg_try_advisory_lock({})",
+                self.lock_key(id)
+            ))?;
+            if locked.first().is_none_or(|row| row[0] != "t") {
+                continue;
+            }
+            // Another instance may have finished the job since it was listed
+            let claimed = session.query(&format!(
+                "UPDATE {} SET status = 'running', instance = {}, started_at = now() \
+                 WHERE id = {} AND status IN ('queued', 'running') \
+                 RETURNING input, remove_input, (extract(epoch FROM queued_at) * 1000)::bigint",
+                self.table,
+                literal(&self.instance),
+                literal(id)
+            ))?;
+            let Some(job) = claimed.first().filter(|row| row.len() == 3) else {
+                session.query(&format!("SELECT pg_advisory_unlock({})", self.lock_key(id)))?;
+                continue;
+            };
+            let queued_at = UNIX_EPOCH + Duration::from_millis(job[2].parse().unwrap_or(0));
+            let waited = SystemTime::now()
+                .duration_since(queued_at)
+                .unwrap_or_default();
+            return Ok(Some(TranscodeJob {
+                id: id.clone(),
+                path: PathBuf::from(&job[0]),
+                queued_at: Instant::now()
+                    .checked_sub(waited)
+                    .unwrap_or_else(Instant::now),
+                remove_input: job[1] == "t",
+                local: None,
+                // The table has no column for it
+                preset: None,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+impl JobQueue for PostgresQueue {
+    fn push(&self, job: &TranscodeJob) -> std::io::Result<()> {
+        let mut session = self.session()?;
+        let waited = job.queued_at.elapsed();
+        session.query(&format!(
+            "INSERT INTO {} (id, input, remove_input, status, queued_at) \
+             VALUES ({}, {}, {}, 'queued', now() - interval '{} milliseconds')",
+            self.table,
+            literal(&job.id),
+            literal(&job.path.to_string_lossy()),
+            job.remove_input,
+            waited.as_millis()
+        ))?;
+        self.idle.lock().unwrap().push(session);
+        Ok(())
+    }
+
+    fn pop(&self, wait: Duration) -> std::io::Result<Option<TranscodeJob>> {
+        let mut session = self.session()?;
+        match self.claim(&mut session)? {
+            Some(job) => {
+                self.claimed.lock().unwrap().insert(job.id.clone(), session);
+                Ok(Some(job))
+            }
+            None => {
+                self.idle.lock().unwrap().push(session);
+                // Poll, as psql cannot wait for notifications
+                std::thread::sleep(wait);
+                Ok(None)
+            }
+        }
+    }
+
+    fn done(&self, record: &JobRecord) {
+        let Some(mut session) = self.claimed.lock().unwrap().remove(&record.id) else {
+            return;
+        };
+        let optional = |value: Option<&str>| value.map_or_else(|| "NULL".to_string(), literal);
+        let output = record.output.as_ref().map(|p| p.to_string_lossy());
+        let updated = session
+            .query(&format!(
+                "UPDATE {} SET status = {}, output = {}, error = {}, error_class = {}, \
+                 finished_at = now() WHERE id = {}",
+                self.table,
+                literal(record.status.as_str()),
+                optional(output.as_deref()),
+                optional(record.error.as_deref()),
+                optional(record.error_class),
+                literal(&record.id)
+            ))
+            .and_then(|_| {
+                session.query(&format!(
+                    "SELECT pg_advisory_unlock({})",
+                    self.lock_key(&record.id)
+                ))
+            });
+        match updated {
+            Ok(_) => self.idle.lock().unwrap().push(session),
+            Err(e) => error!("Failed to record job {} as finished: {}", record.id, e),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("PostgreSQL table {}", self.table)
+    }
+}
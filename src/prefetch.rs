@@ -0,0 +1,72 @@
+//! Copying queued inputs from slow storage, e.g. NFS, to local scratch
+//! while the jobs before them run, so that ffmpeg reads from local disk.
+//!
+//! The prefetcher sits between the submitters and the workers: it takes
+//! each job, copies its input to the scratch directory and hands the job
+//! on, keeping at most the configured number of copies ahead. Jobs keep
+//! their input path for naming the output, routing and hooks; only the
+//! backend reads the copy, which is deleted when the job is done. Inputs
+//! that are local copies already, e.g. downloads, are handed on as they
+//! are.
+use crate::shutdown;
+use crate::{IDLE_TICK, TranscodeJob};
+use log::{debug, warn};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::Duration;
+
+/// How often a prefetcher waiting for a worker checks for a shutdown.
+const SEND_RETRY: Duration = Duration::from_millis(20);
+
+/// Where and how far ahead to prefetch, as given with `--prefetch`.
+#[derive(Clone, Debug)]
+pub struct Prefetch {
+    pub dir: PathBuf,
+    /// Most copies made ahead of the workers; at least one.
+    pub count: usize,
+}
+
+/// Copy the input of every job from `rx` and hand the job on to `tx`,
+/// until the submitters are gone or a shutdown is requested.
+pub(crate) fn run(prefetch: &Prefetch, rx: Receiver<TranscodeJob>, tx: SyncSender<TranscodeJob>) {
+    while !shutdown::requested() {
+        let mut job = match rx.recv_timeout(IDLE_TICK) {
+            Ok(job) => job,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if !job.remove_input {
+            let name = job.path.file_name().unwrap_or_default().to_string_lossy();
+            let copy = prefetch.dir.join(format!("{}-{}", job.id, name));
+            match std::fs::copy(&job.path, &copy) {
+                Ok(bytes) => {
+                    debug!("Prefetched {:?} ({} bytes)", job.path, bytes);
+                    job.local = Some(copy);
+                }
+                Err(e) => {
+                    let _ = std::fs::remove_file(&copy);
+                    warn!(
+                        "Failed to prefetch {:?}, reading it in place: {}",
+                        job.path, e
+                    );
+                }
+            }
+        }
+        // Blocks once enough copies are waiting
+        loop {
+            match tx.try_send(job) {
+                Ok(()) => break,
+                Err(TrySendError::Full(waiting)) if !shutdown::requested() => {
+                    job = waiting;
+                    std::thread::sleep(SEND_RETRY);
+                }
+                Err(TrySendError::Full(job) | TrySendError::Disconnected(job)) => {
+                    if let Some(copy) = &job.local {
+                        let _ = std::fs::remove_file(copy);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
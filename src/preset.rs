@@ -0,0 +1,51 @@
+//! Output formats, selectable with `--preset`.
+//!
+//! Every output is mono 16-bit PCM WAV; the preset sets its sample rate to
+//! suit what the audio is for.
+use clap::ValueEnum;
+
+/// What outputs are made for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Preset {
+    /// 16kHz, what speech recognition expects.
+    #[default]
+    Speech,
+    /// 8kHz, the narrow band telephone networks carry, for call recordings
+    /// headed back into telephony systems.
+    Telephony,
+    /// 48kHz, keeping the full band of the input for the archive.
+    Archive,
+}
+
+impl Preset {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Preset::Speech => "speech",
+            Preset::Telephony => "telephony",
+            Preset::Archive => "archive",
+        }
+    }
+
+    pub fn sample_rate(self) -> u32 {
+        match self {
+            Preset::Speech => 16_000,
+            Preset::Telephony => 8_000,
+            Preset::Archive => 48_000,
+        }
+    }
+
+    /// Bytes of output per second of audio.
+    pub fn byte_rate(self) -> u32 {
+        self.sample_rate() * 2
+    }
+
+    /// ffmpeg's output options for it.
+    pub(crate) fn output_options(self) -> [&'static str; 6] {
+        let rate = match self {
+            Preset::Speech => "16000",
+            Preset::Telephony => "8000",
+            Preset::Archive => "48000",
+        };
+        ["-ac", "1", "-ar", rate, "-sample_fmt", "s16"]
+    }
+}
@@ -0,0 +1,85 @@
+//! What ffprobe finds out about an input before it is transcoded, for
+//! routes and output names that depend on it.
+use crate::backend;
+use crate::parts;
+use crate::raw::{self, RawInput};
+use crate::sandbox;
+use std::path::Path;
+use std::process::Stdio;
+
+/// What is known of an input before it is transcoded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Properties {
+    /// In seconds.
+    pub duration: Option<f64>,
+    /// ffmpeg's name of the codec of the first audio stream, e.g. `opus`.
+    pub codec: Option<String>,
+    pub channels: Option<u32>,
+    pub sample_rate: Option<u32>,
+    /// Tags of the container, e.g. `artist` or `creation_time`, as ffprobe
+    /// names them.
+    pub tags: Vec<(String, String)>,
+}
+
+impl Properties {
+    /// The properties of `input`, as far as they can be found out.
+    pub fn probe(input: &Path, raw_inputs: &[RawInput]) -> Properties {
+        if let Some(raw) = raw::find(raw_inputs, input) {
+            return Properties {
+                duration: std::fs::metadata(input).ok().map(|m| raw.duration(m.len())),
+                codec: Some(raw.format.clone()),
+                channels: Some(u32::from(raw.channels)),
+                sample_rate: Some(raw.sample_rate),
+                tags: Vec::new(),
+            };
+        }
+        let Ok(output) = sandbox::command("ffprobe", &[input], &[])
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "a:0",
+                "-show_entries",
+                "stream=codec_name,channels,sample_rate:format=duration:format_tags",
+                "-of",
+                "default=noprint_wrappers=1",
+            ])
+            .args(parts::input_options(input))
+            .arg(backend::path_arg(input))
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+        else {
+            return Properties::default();
+        };
+        let mut properties = Properties::default();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if let Some((key, value)) = line
+                .trim()
+                .strip_prefix("TAG:")
+                .and_then(|tag| tag.split_once('='))
+            {
+                properties.tags.push((key.to_string(), value.to_string()));
+                continue;
+            }
+            match line.trim().split_once('=') {
+                Some(("duration", value)) => properties.duration = value.parse().ok(),
+                Some(("codec_name", value)) => {
+                    properties.codec = Some(value.to_string()).filter(|codec| codec != "N/A")
+                }
+                Some(("channels", value)) => properties.channels = value.parse().ok(),
+                Some(("sample_rate", value)) => properties.sample_rate = value.parse().ok(),
+                _ => {}
+            }
+        }
+        properties
+    }
+
+    /// The value of the tag `name`, whatever its case.
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
@@ -0,0 +1,202 @@
+//! Quarantine of corrupted inputs, so that garbage is set aside with the
+//! reason once instead of failing in ffmpeg again on every retry.
+//!
+//! Before an input is transcoded, ffprobe must be able to open it and find
+//! an audio stream, and ffmpeg must decode its first seconds without an
+//! error. An input that fails is moved into the quarantine directory, next
+//! to a `<name>.reason.json` that says why, and its job fails with the
+//! reason as the error class.
+use crate::{backend, json, relocate, sandbox};
+use log::warn;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::SystemTime;
+
+/// Seconds of the input the sanity check decodes.
+const DECODE_SECONDS: &str = "10";
+
+/// Why an input was quarantined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+    /// ffprobe cannot open it, e.g. it is truncated or not media at all.
+    Unreadable,
+    /// It is media, but without audio.
+    NoAudioStream,
+    /// Its audio does not decode cleanly.
+    DecodeError,
+}
+
+impl Reason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reason::Unreadable => "unreadable",
+            Reason::NoAudioStream => "no_audio_stream",
+            Reason::DecodeError => "decode_error",
+        }
+    }
+
+    /// The reason of a job error made by [`Quarantine::isolate`].
+    pub fn of_error(error: &str) -> Option<Reason> {
+        let reason = error.strip_prefix("Quarantined as ")?;
+        [
+            Reason::Unreadable,
+            Reason::NoAudioStream,
+            Reason::DecodeError,
+        ]
+        .into_iter()
+        .find(|r| reason.starts_with(r.as_str()))
+    }
+}
+
+/// Check that `input`, read with ffmpeg's input `options`, opens, has
+/// audio and decodes, or say why not, with the diagnostics of the tool
+/// that failed.
+pub(crate) fn check(input: &Path, options: &[String]) -> Result<(), (Reason, String)> {
+    let probe = sandbox::command("ffprobe", &[input], &[])
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "stream=codec_type",
+            "-of",
+            "csv=p=0",
+        ])
+        .args(probe_options(options))
+        .arg(backend::path_arg(input))
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| (Reason::Unreadable, format!("Cannot run ffprobe: {}", e)))?;
+    if !probe.status.success() {
+        let stderr = String::from_utf8_lossy(&probe.stderr).trim().to_string();
+        return Err((Reason::Unreadable, stderr));
+    }
+    if !String::from_utf8_lossy(&probe.stdout)
+        .lines()
+        .any(|line| line.trim() == "audio")
+    {
+        return Err((Reason::NoAudioStream, "no audio stream".to_string()));
+    }
+    let decode = sandbox::command("ffmpeg", &[input], &[])
+        .args([
+            "-hide_banner",
+            "-v",
+            "error",
+            "-xerror",
+            "-t",
+            DECODE_SECONDS,
+        ])
+        .args(options)
+        .arg("-i")
+        .arg(backend::path_arg(input))
+        .args(["-map", "0:a:0", "-f", "null", "-"])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| (Reason::DecodeError, format!("Cannot run ffmpeg: {}", e)))?;
+    if !decode.status.success() {
+        let stderr = String::from_utf8_lossy(&decode.stderr).trim().to_string();
+        return Err((Reason::DecodeError, stderr));
+    }
+    Ok(())
+}
+
+/// ffprobe's spelling of ffmpeg's raw input `options`: it takes the rate
+/// and channels as format options.
+fn probe_options(options: &[String]) -> Vec<String> {
+    options
+        .chunks(2)
+        .flat_map(|pair| match pair[0].as_str() {
+            "-ar" => ["-sample_rate".to_string(), pair[1].clone()],
+            "-ac" => ["-ch_layout".to_string(), format!("{}c", pair[1])],
+            _ => [pair[0].clone(), pair[1].clone()],
+        })
+        .collect()
+}
+
+/// The directory corrupted inputs are moved to.
+#[derive(Clone, Debug)]
+pub struct Quarantine {
+    dir: PathBuf,
+}
+
+impl Quarantine {
+    pub fn new(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Quarantine { dir })
+    }
+
+    /// Move `input` into quarantine with its reason, returning where it
+    /// went. Names taken already get the job id in front.
+    pub(crate) fn isolate(
+        &self,
+        input: &Path,
+        id: &str,
+        reason: Reason,
+        detail: &str,
+    ) -> std::io::Result<PathBuf> {
+        let name = input.file_name().unwrap_or_default().to_string_lossy();
+        let mut target = self.dir.join(&*name);
+        if target.exists() {
+            target = self.dir.join(format!("{}-{}", id, name));
+        }
+        relocate::move_file(input, &target)?;
+        let record = json::Object::new()
+            .str("input", &input.to_string_lossy())
+            .str("reason", reason.as_str())
+            .str("detail", detail)
+            .str(
+                "quarantined_at",
+                &humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            )
+            .finish();
+        let mut path = target.as_os_str().to_owned();
+        path.push(".reason.json");
+        std::fs::write(path, record + "\n")?;
+        Ok(target)
+    }
+
+    /// Move the quarantined inputs that `keep` accepts, by their reason and
+    /// when they were quarantined, back to where they came from, returning
+    /// those paths. Inputs whose place has been taken meanwhile stay.
+    pub(crate) fn release(
+        &self,
+        keep: impl Fn(&str, SystemTime) -> bool,
+    ) -> std::io::Result<Vec<PathBuf>> {
+        let mut released = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let Some(target) = name.strip_suffix(".reason.json") else {
+                continue;
+            };
+            let target = self.dir.join(target);
+            let Some(record) = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|text| json::parse(&text))
+            else {
+                warn!("Cannot read {:?}", path);
+                continue;
+            };
+            let field = |key| record.get(key).and_then(|v| v.as_str()).unwrap_or_default();
+            let at =
+                humantime::parse_rfc3339(field("quarantined_at")).unwrap_or(SystemTime::UNIX_EPOCH);
+            let input = PathBuf::from(field("input"));
+            if !target.is_file() || input.as_os_str().is_empty() || !keep(field("reason"), at) {
+                continue;
+            }
+            if input.exists() {
+                warn!(
+                    "Not releasing {:?} from quarantine, {:?} exists",
+                    target, input
+                );
+                continue;
+            }
+            relocate::move_file(&target, &input)?;
+            std::fs::remove_file(&path)?;
+            released.push(input);
+        }
+        Ok(released)
+    }
+}
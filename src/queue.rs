@@ -0,0 +1,95 @@
+//! Pending-job queues shared between instances.
+//!
+//! By default each pipeline queues its jobs in process. With a
+//! [`JobQueue`], every job submitted locally is pushed to the shared queue
+//! instead, and the workers of every instance sharing it take their jobs
+//! from there. Paths must then be readable on every instance, e.g. on
+//! shared storage.
+use crate::jobs::JobRecord;
+use crate::preset::Preset;
+use crate::{TranscodeJob, json};
+use clap::ValueEnum;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A queue of pending jobs shared by the pipelines of several instances.
+pub trait JobQueue: Send + Sync {
+    /// Add a job to the end of the queue.
+    fn push(&self, job: &TranscodeJob) -> std::io::Result<()>;
+
+    /// Take the next job, waiting up to `wait` for one; a zero wait does
+    /// not block. The job is recorded as in progress on this instance
+    /// until [`JobQueue::done`], so it is not lost if the instance dies.
+    fn pop(&self, wait: Duration) -> std::io::Result<Option<TranscodeJob>>;
+
+    /// A job taken with [`JobQueue::pop`] has finished, as `record` shows.
+    fn done(&self, record: &JobRecord);
+
+    /// Human-readable location, for logs.
+    fn describe(&self) -> String;
+}
+
+/// Serialize a job for a shared queue.
+pub fn encode(job: &TranscodeJob) -> String {
+    let waited = job.queued_at.elapsed();
+    let queued_at = SystemTime::now()
+        .checked_sub(waited)
+        .unwrap_or(UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    json::Object::new()
+        .str("id", &job.id)
+        .str("path", &job.path.to_string_lossy())
+        .raw("remove_input", &job.remove_input.to_string())
+        .num("queued_at", queued_at.as_millis())
+        .opt_str("preset", job.preset.map(Preset::as_str))
+        .finish()
+}
+
+/// Parse a job serialized by [`encode`], possibly on another host.
+pub fn decode(payload: &str) -> Option<TranscodeJob> {
+    let value = json::parse(payload)?;
+    let queued_at = value.get("queued_at").and_then(|v| v.as_u64()).unwrap_or(0);
+    let waited = SystemTime::now()
+        .duration_since(UNIX_EPOCH + Duration::from_millis(queued_at))
+        .unwrap_or_default();
+    Some(TranscodeJob {
+        id: value.get("id")?.as_str()?.to_string(),
+        path: PathBuf::from(value.get("path")?.as_str()?),
+        queued_at: Instant::now()
+            .checked_sub(waited)
+            .unwrap_or_else(Instant::now),
+        remove_input: value.get("remove_input") == Some(&json::Value::Bool(true)),
+        local: None,
+        preset: value
+            .get("preset")
+            .and_then(|preset| Preset::from_str(preset.as_str()?, true).ok()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(preset: Option<Preset>) -> TranscodeJob {
+        TranscodeJob {
+            id: "0123456789abcdef".to_string(),
+            path: PathBuf::from("/srv/in/call.wav"),
+            queued_at: Instant::now(),
+            remove_input: true,
+            local: None,
+            preset,
+        }
+    }
+
+    #[test]
+    fn jobs_keep_their_preset_through_the_queue() {
+        for preset in [None, Some(Preset::Archive)] {
+            let decoded = decode(&encode(&job(preset))).unwrap();
+            assert_eq!(decoded.id, "0123456789abcdef");
+            assert_eq!(decoded.path, PathBuf::from("/srv/in/call.wav"));
+            assert!(decoded.remove_input);
+            assert_eq!(decoded.preset, preset);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Headerless PCM inputs, e.g. the captures of telephony systems, whose
+//! sample format, rate and channel count ffmpeg cannot guess and so must
+//! be told.
+//!
+//! Each declaration applies to the inputs it matches, by extension or by
+//! a glob on the file name, and is turned into ffmpeg's `-f`, `-ar` and
+//! `-ac` input options for them.
+use std::path::Path;
+use std::str::FromStr;
+
+/// Extensions matched by a declaration without a pattern.
+const DEFAULT_EXTENSIONS: &[&str] = &["pcm", "raw"];
+
+/// ffmpeg's raw PCM formats and the bytes of each sample.
+const FORMATS: &[(&str, u32)] = &[
+    ("alaw", 1),
+    ("mulaw", 1),
+    ("s8", 1),
+    ("u8", 1),
+    ("s16le", 2),
+    ("s16be", 2),
+    ("u16le", 2),
+    ("u16be", 2),
+    ("s24le", 3),
+    ("s24be", 3),
+    ("u24le", 3),
+    ("u24be", 3),
+    ("s32le", 4),
+    ("s32be", 4),
+    ("u32le", 4),
+    ("u32be", 4),
+    ("f32le", 4),
+    ("f32be", 4),
+    ("f64le", 8),
+    ("f64be", 8),
+];
+
+/// Which inputs a declaration applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Pattern {
+    /// Lowercase extensions without the dot.
+    Extensions(Vec<String>),
+    /// A glob on the file name, with `*` and `?`.
+    Glob(String),
+}
+
+/// Whether `name` matches `glob`, where `*` stands for any run of
+/// characters and `?` for any one.
+pub(crate) fn glob_matches(glob: &[char], name: &[char]) -> bool {
+    match glob.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| glob_matches(rest, &name[skip..])),
+        Some((&c, rest)) => name
+            .split_first()
+            .is_some_and(|(&n, name)| (c == '?' || c == n) && glob_matches(rest, name)),
+    }
+}
+
+/// The parameters of headerless PCM inputs, parsed from e.g.
+/// `s16le:8000:1`, `al=alaw:8000:1` or `'line*.bin=s16be:16000:2'`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawInput {
+    pattern: Pattern,
+    /// ffmpeg's name of the sample format, e.g. `s16le`.
+    pub format: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl FromStr for RawInput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, params) = match s.rsplit_once('=') {
+            Some((pattern, params)) => (Some(pattern.trim()), params),
+            None => (None, s),
+        };
+        let pattern = match pattern {
+            None => Pattern::Extensions(DEFAULT_EXTENSIONS.iter().map(|e| e.to_string()).collect()),
+            Some(glob) if glob.contains(['*', '?']) => Pattern::Glob(glob.to_string()),
+            Some(list) => {
+                let extensions: Vec<String> = list
+                    .split(',')
+                    .map(|e| e.trim().trim_start_matches('.').to_ascii_lowercase())
+                    .filter(|e| !e.is_empty())
+                    .collect();
+                if extensions.is_empty() {
+                    return Err(format!("no extensions or glob in {:?}", s));
+                }
+                Pattern::Extensions(extensions)
+            }
+        };
+        let fields: Vec<&str> = params.split(':').map(str::trim).collect();
+        let [format, rate, channels] = fields[..] else {
+            return Err(format!(
+                "invalid raw input {:?}, expected [PATTERN=]FORMAT:RATE:CHANNELS",
+                s
+            ));
+        };
+        if !FORMATS.iter().any(|(name, _)| *name == format) {
+            return Err(format!(
+                "unknown sample format {:?}, expected one of {}",
+                format,
+                FORMATS
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        // parse would also take a sign
+        let digits = |n: &str| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit());
+        let sample_rate = Some(rate)
+            .filter(|rate| digits(rate))
+            .and_then(|rate| rate.parse::<u32>().ok())
+            .filter(|&rate| rate > 0)
+            .ok_or_else(|| format!("invalid sample rate {:?}", rate))?;
+        let channels = Some(channels)
+            .filter(|channels| digits(channels))
+            .and_then(|channels| channels.parse::<u16>().ok())
+            .filter(|&channels| channels > 0)
+            .ok_or_else(|| format!("invalid channel count {:?}", channels))?;
+        Ok(RawInput {
+            pattern,
+            format: format.to_string(),
+            sample_rate,
+            channels,
+        })
+    }
+}
+
+impl RawInput {
+    /// Whether this declaration applies to `path`.
+    pub fn matches(&self, path: &Path) -> bool {
+        match &self.pattern {
+            Pattern::Extensions(extensions) => path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| extensions.iter().any(|x| x.eq_ignore_ascii_case(e))),
+            Pattern::Glob(glob) => {
+                let name: Vec<char> = path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .chars()
+                    .collect();
+                glob_matches(&glob.chars().collect::<Vec<_>>(), &name)
+            }
+        }
+    }
+
+    /// ffmpeg's input options for it, to go before `-i`.
+    pub fn input_options(&self) -> [String; 6] {
+        [
+            "-f".to_string(),
+            self.format.clone(),
+            "-ar".to_string(),
+            self.sample_rate.to_string(),
+            "-ac".to_string(),
+            self.channels.to_string(),
+        ]
+    }
+
+    /// Duration in seconds of an input of `size` bytes.
+    pub fn duration(&self, size: u64) -> f64 {
+        let bytes = FORMATS
+            .iter()
+            .find(|(name, _)| *name == self.format)
+            .map_or(1, |(_, bytes)| *bytes);
+        // In floating point, as the product may not fit in 32 bits
+        size as f64 / (f64::from(bytes) * f64::from(self.sample_rate) * f64::from(self.channels))
+    }
+}
+
+/// The first of `declarations` that applies to `path`.
+pub fn find<'a>(declarations: &'a [RawInput], path: &Path) -> Option<&'a RawInput> {
+    declarations.iter().find(|raw| raw.matches(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(s: &str) -> RawInput {
+        s.parse().unwrap_or_else(|e| panic!("{}: {}", s, e))
+    }
+
+    #[test]
+    fn declarations_without_a_pattern_take_pcm_and_raw() {
+        let raw = raw("s16le:8000:1");
+        assert_eq!(raw.format, "s16le");
+        assert_eq!(raw.sample_rate, 8000);
+        assert_eq!(raw.channels, 1);
+        assert!(raw.matches(Path::new("in/call.pcm")));
+        assert!(raw.matches(Path::new("in/call.RAW")));
+        assert!(!raw.matches(Path::new("in/call.wav")));
+        assert_eq!(
+            raw.input_options(),
+            ["-f", "s16le", "-ar", "8000", "-ac", "1"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn declarations_match_extensions_or_globs() {
+        let alaw = raw("al, .ALW=alaw:8000:1");
+        assert!(alaw.matches(Path::new("call.al")));
+        assert!(alaw.matches(Path::new("call.alw")));
+        assert!(!alaw.matches(Path::new("call.pcm")));
+        let lines = raw("line*.bin=s16be:16000:2");
+        assert!(lines.matches(Path::new("in/line7.bin")));
+        assert!(lines.matches(Path::new("in/line.bin")));
+        assert!(!lines.matches(Path::new("in/trunk7.bin")));
+        assert!(!lines.matches(Path::new("line7.bin/call.wav")));
+        let one = raw("line?.bin=s16be:16000:2");
+        assert!(one.matches(Path::new("line7.bin")));
+        assert!(!one.matches(Path::new("line17.bin")));
+    }
+
+    #[test]
+    fn the_first_matching_declaration_applies() {
+        let declarations = [raw("a*.pcm=alaw:8000:1"), raw("s16le:16000:1")];
+        assert_eq!(
+            find(&declarations, Path::new("a1.pcm")).unwrap().format,
+            "alaw"
+        );
+        assert_eq!(
+            find(&declarations, Path::new("b1.pcm")).unwrap().format,
+            "s16le"
+        );
+        assert!(find(&declarations, Path::new("b1.wav")).is_none());
+    }
+
+    #[test]
+    fn durations_follow_from_the_size() {
+        assert_eq!(raw("s16le:8000:1").duration(16000), 1.0);
+        assert_eq!(raw("s24le:48000:2").duration(288000), 1.0);
+        assert_eq!(raw("mulaw:8000:1").duration(4000), 0.5);
+        // More bytes a second than fit in 32 bits
+        assert_eq!(raw("f64le:4000000000:2").duration(64_000_000_000), 1.0);
+    }
+
+    #[test]
+    fn invalid_declarations_are_refused() {
+        for (s, error) in [
+            ("s16le:8000", "expected [PATTERN=]FORMAT:RATE:CHANNELS"),
+            ("s16le:8000:1:2", "expected [PATTERN=]FORMAT:RATE:CHANNELS"),
+            ("wav:8000:1", "unknown sample format \"wav\""),
+            ("s16le:0:1", "invalid sample rate"),
+            ("s16le:+8000:1", "invalid sample rate"),
+            ("s16le:8k:1", "invalid sample rate"),
+            ("s16le:8000:0", "invalid channel count"),
+            ("s16le:8000:70000", "invalid channel count"),
+            (" , =s16le:8000:1", "no extensions or glob"),
+        ] {
+            let refusal = s.parse::<RawInput>().unwrap_err();
+            assert!(refusal.contains(error), "{}: {}", s, refusal);
+        }
+    }
+}
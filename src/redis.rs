@@ -0,0 +1,281 @@
+//! Redis-backed shared job queue.
+//!
+//! A minimal RESP client, enough for a list-based work queue without
+//! pulling in a full client library. Jobs are pushed onto the
+//! `<name>:pending` list and moved atomically onto the taking instance's
+//! `<name>:processing:<instance>` list, where they stay until they have
+//! finished. When an instance starts, whatever is left in its processing
+//! list, i.e. the jobs it was running when it died, goes back to pending.
+use crate::jobs::JobRecord;
+use crate::queue::{self, JobQueue};
+use crate::url::percent_decode;
+use crate::{TranscodeJob, host};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Time allowed for a reply, on top of any blocking wait.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server, parsed from `redis://[[user]:password@]host[:port][/db]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RedisLocation {
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub host: String,
+    pub port: u16,
+    pub db: u32,
+}
+
+impl RedisLocation {
+    /// Parse a URL. Without a password in it, `REDIS_PASSWORD` is used.
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("redis://")?;
+        let (authority, db) = rest.split_once('/').unwrap_or((rest, ""));
+        let (userinfo, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => (Some(userinfo), host),
+            None => (None, authority),
+        };
+        let (user, password) = match userinfo.map(|info| info.split_once(':')) {
+            Some(Some((user, password))) => (
+                Some(percent_decode(user, false)).filter(|u| !u.is_empty()),
+                Some(percent_decode(password, false)),
+            ),
+            Some(None) => (None, Some(percent_decode(userinfo?, false))),
+            None => (None, std::env::var("REDIS_PASSWORD").ok()),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+            _ => (host_port, 6379),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return None;
+        }
+        let db = match db.trim_end_matches('/') {
+            "" => 0,
+            db => db.parse().ok()?,
+        };
+        Some(RedisLocation {
+            user,
+            password,
+            host: host.to_string(),
+            port,
+            db,
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("redis://{}:{}/{}", self.host, self.port, self.db)
+    }
+}
+
+/// A reply from the server; error replies are returned as errors.
+enum Reply {
+    Status,
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array,
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn open(location: &RedisLocation) -> std::io::Result<Self> {
+        let stream = TcpStream::connect((location.host.as_str(), location.port))?;
+        let mut conn = Connection {
+            reader: BufReader::new(stream),
+        };
+        if let Some(password) = &location.password {
+            match &location.user {
+                Some(user) => conn.command(&["AUTH", user, password], Duration::ZERO)?,
+                None => conn.command(&["AUTH", password], Duration::ZERO)?,
+            };
+        }
+        if location.db != 0 {
+            conn.command(&["SELECT", &location.db.to_string()], Duration::ZERO)?;
+        }
+        Ok(conn)
+    }
+
+    /// Send a command and read its reply, allowing `wait` extra time for
+    /// blocking commands.
+    fn command(&mut self, args: &[&str], wait: Duration) -> std::io::Result<Reply> {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        let stream = self.reader.get_mut();
+        stream.set_read_timeout(Some(REPLY_TIMEOUT + wait))?;
+        stream.write_all(request.as_bytes())?;
+        self.reply()
+    }
+
+    fn line(&mut self) -> std::io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+
+    fn reply(&mut self) -> std::io::Result<Reply> {
+        let line = self.line()?;
+        let invalid = || std::io::Error::new(ErrorKind::InvalidData, "malformed Redis reply");
+        let (kind, rest) = line.split_at_checked(1).ok_or_else(invalid)?;
+        match kind {
+            "+" => Ok(Reply::Status),
+            "-" => Err(std::io::Error::other(format!("Redis error: {}", rest))),
+            ":" => Ok(Reply::Integer(rest.parse().map_err(|_| invalid())?)),
+            "$" => {
+                let len: i64 = rest.parse().map_err(|_| invalid())?;
+                if len < 0 {
+                    return Ok(Reply::Bulk(None));
+                }
+                let mut data = vec![0; len as usize + 2];
+                self.reader.read_exact(&mut data)?;
+                data.truncate(len as usize);
+                Ok(Reply::Bulk(Some(data)))
+            }
+            "*" => {
+                let len: i64 = rest.parse().map_err(|_| invalid())?;
+                for _ in 0..len.max(0) {
+                    self.reply()?;
+                }
+                Ok(Reply::Array)
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A job queue in Redis lists, shared by every instance using the same
+/// server and queue name.
+pub struct RedisQueue {
+    location: RedisLocation,
+    pending: String,
+    processing: String,
+    /// Connections not in use; each blocking pop needs its own.
+    idle: Mutex<Vec<Connection>>,
+    /// Serialized jobs taken by this instance, by job ID.
+    taken: Mutex<HashMap<String, String>>,
+}
+
+impl RedisQueue {
+    /// Connect to the queue `name`, as `instance` or else the host name,
+    /// and requeue the jobs this instance did not finish last time.
+    pub fn open(
+        location: RedisLocation,
+        name: &str,
+        instance: Option<&str>,
+    ) -> std::io::Result<Self> {
+        let instance = instance.map_or_else(host::name, str::to_string);
+        let queue = RedisQueue {
+            pending: format!("{}:pending", name),
+            processing: format!("{}:processing:{}", name, instance),
+            location,
+            idle: Mutex::new(Vec::new()),
+            taken: Mutex::new(HashMap::new()),
+        };
+        let mut requeued = 0;
+        queue.with_connection(|conn| {
+            while let Reply::Bulk(Some(_)) = conn.command(
+                &["RPOPLPUSH", &queue.processing, &queue.pending],
+                Duration::ZERO,
+            )? {
+                requeued += 1;
+            }
+            Ok(())
+        })?;
+        if requeued > 0 {
+            warn!(
+                "Requeued {} jobs left unfinished by instance {}",
+                requeued, instance
+            );
+        }
+        info!(
+            "Sharing queue {} on {} as instance {}",
+            name,
+            queue.location.describe(),
+            instance
+        );
+        Ok(queue)
+    }
+
+    /// Run `f` on an idle connection, or a new one. A connection that
+    /// failed is dropped rather than reused.
+    fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut conn = match idle {
+            Some(conn) => conn,
+            None => Connection::open(&self.location)?,
+        };
+        let result = f(&mut conn)?;
+        self.idle.lock().unwrap().push(conn);
+        Ok(result)
+    }
+}
+
+impl JobQueue for RedisQueue {
+    fn push(&self, job: &TranscodeJob) -> std::io::Result<()> {
+        let payload = queue::encode(job);
+        self.with_connection(|conn| {
+            conn.command(&["LPUSH", &self.pending, &payload], Duration::ZERO)
+        })?;
+        Ok(())
+    }
+
+    fn pop(&self, wait: Duration) -> std::io::Result<Option<TranscodeJob>> {
+        let reply = self.with_connection(|conn| {
+            if wait.is_zero() {
+                conn.command(&["RPOPLPUSH", &self.pending, &self.processing], wait)
+            } else {
+                let timeout = format!("{:.3}", wait.as_secs_f64());
+                conn.command(
+                    &["BRPOPLPUSH", &self.pending, &self.processing, &timeout],
+                    wait,
+                )
+            }
+        })?;
+        let Reply::Bulk(Some(payload)) = reply else {
+            return Ok(None);
+        };
+        let payload = String::from_utf8_lossy(&payload).into_owned();
+        let Some(job) = queue::decode(&payload) else {
+            error!("Dropping malformed job from the Redis queue: {}", payload);
+            self.with_connection(|conn| {
+                conn.command(&["LREM", &self.processing, "1", &payload], Duration::ZERO)
+            })?;
+            return Ok(None);
+        };
+        self.taken.lock().unwrap().insert(job.id.clone(), payload);
+        Ok(Some(job))
+    }
+
+    fn done(&self, record: &JobRecord) {
+        let id = &record.id;
+        let Some(payload) = self.taken.lock().unwrap().remove(id) else {
+            return;
+        };
+        let removed = self.with_connection(|conn| {
+            conn.command(&["LREM", &self.processing, "1", &payload], Duration::ZERO)
+        });
+        match removed {
+            Ok(Reply::Integer(1)) => {}
+            Ok(_) => warn!("Job {} was no longer in the Redis processing list", id),
+            Err(e) => error!("Failed to remove job {} from the Redis queue: {}", id, e),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("{} list {}", self.location.describe(), self.pending)
+    }
+}
@@ -0,0 +1,95 @@
+//! Moving files between directories that may be on different file
+//! systems, e.g. into a quarantine directory on a mount of its own, where
+//! a rename cannot do.
+use crate::jobs;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+/// Bytes compared at a time when checking a copy.
+const CHUNK: usize = 64 * 1024;
+
+/// Move `from` to `to`. Within one file system this is a rename; across
+/// file systems `from` is copied next to `to`, synced to disk, compared
+/// with the original and only then put in place, and `from` is removed
+/// last, so that a crash or a full disk on the way leaves it where it was.
+pub(crate) fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {}
+        moved => return moved,
+    }
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let partial = to.with_file_name(format!(".{}.{}.part", name, jobs::new_id()));
+    let placed = copy_synced(from, &partial)
+        .and_then(|()| compare(from, &partial))
+        .and_then(|()| std::fs::rename(&partial, to));
+    if let Err(e) = placed {
+        let _ = std::fs::remove_file(&partial);
+        return Err(e);
+    }
+    sync_dir(to)?;
+    std::fs::remove_file(from)?;
+    sync_dir(from)
+}
+
+/// Copy `from` to the new file `to` with its permissions and modification
+/// time, and wait for it to be on disk.
+fn copy_synced(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut source = File::open(from)?;
+    let metadata = source.metadata()?;
+    let mut target = File::create_new(to)?;
+    std::io::copy(&mut source, &mut target)?;
+    target.set_permissions(metadata.permissions())?;
+    target.set_modified(metadata.modified()?)?;
+    target.sync_all()
+}
+
+/// Fail unless `a` and `b` have the same contents.
+fn compare(a: &Path, b: &Path) -> std::io::Result<()> {
+    let (mut a_file, mut b_file) = (File::open(a)?, File::open(b)?);
+    let (mut a_chunk, mut b_chunk) = (vec![0; CHUNK], vec![0; CHUNK]);
+    loop {
+        let a_read = fill(&mut a_file, &mut a_chunk)?;
+        let b_read = fill(&mut b_file, &mut b_chunk)?;
+        if a_chunk[..a_read] != b_chunk[..b_read] {
+            return Err(std::io::Error::other(format!(
+                "the copy of {:?} at {:?} differs from it",
+                a, b
+            )));
+        }
+        if a_read == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Read into `buf` until it is full or the file ends, returning how much
+/// was read.
+fn fill(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Wait for the entry of `path` in its directory to be on disk.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Directories cannot be opened to sync them on Windows, where the rename
+/// and removal are written through anyway.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,120 @@
+//! Rolling daily processing reports.
+//!
+//! One JSON file per UTC day, `report-YYYY-MM-DD.json`, rewritten after every
+//! job with the day's throughput, failures grouped by error class and the
+//! slowest files. Counters cover jobs handled by this process only.
+use crate::JobResult;
+use crate::json;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// Number of slowest files kept in the report.
+const SLOWEST: usize = 10;
+
+/// Accumulates one day's worth of job outcomes.
+pub struct DailyReport {
+    dir: PathBuf,
+    date: String,
+    processed: u64,
+    failed: u64,
+    input_bytes: u64,
+    audio: Duration,
+    transcoding: Duration,
+    failures: BTreeMap<&'static str, u64>,
+    slowest: Vec<(Duration, String)>,
+}
+
+/// Today's UTC date as `YYYY-MM-DD`.
+fn today() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()[..10].to_string()
+}
+
+impl DailyReport {
+    pub fn new(dir: PathBuf) -> std::io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self::empty(dir, today()))
+    }
+
+    fn empty(dir: PathBuf, date: String) -> Self {
+        DailyReport {
+            dir,
+            date,
+            processed: 0,
+            failed: 0,
+            input_bytes: 0,
+            audio: Duration::ZERO,
+            transcoding: Duration::ZERO,
+            failures: BTreeMap::new(),
+            slowest: Vec::new(),
+        }
+    }
+
+    /// Add a finished job and rewrite the report file.
+    pub fn record(&mut self, outcome: &JobResult) {
+        let date = today();
+        if date != self.date {
+            info!("Starting daily report for {}", date);
+            *self = Self::empty(std::mem::take(&mut self.dir), date);
+        }
+
+        match outcome.error_class() {
+            None => {
+                self.processed += 1;
+                self.input_bytes += outcome.input_bytes;
+                self.audio += outcome.audio.unwrap_or_default();
+                self.transcoding += outcome.elapsed;
+            }
+            Some(class) => {
+                self.failed += 1;
+                *self.failures.entry(class).or_default() += 1;
+            }
+        }
+
+        self.slowest
+            .push((outcome.elapsed, outcome.input.display().to_string()));
+        self.slowest.sort_by_key(|s| std::cmp::Reverse(s.0));
+        self.slowest.truncate(SLOWEST);
+
+        if let Err(e) = self.write() {
+            warn!("Failed to write daily report: {}", e);
+        }
+    }
+
+    /// Write the report atomically, via a temporary file and rename.
+    fn write(&self) -> std::io::Result<()> {
+        let failures = self
+            .failures
+            .iter()
+            .fold(json::Object::new(), |obj, (class, count)| {
+                obj.num(class, count)
+            })
+            .finish();
+        let slowest = json::array(self.slowest.iter().map(|(elapsed, input)| {
+            json::Object::new()
+                .str("input", input)
+                .num("seconds", format!("{:.3}", elapsed.as_secs_f64()))
+                .finish()
+        }));
+        let report = json::Object::new()
+            .str("date", &self.date)
+            .num("processed", self.processed)
+            .num("failed", self.failed)
+            .num("input_bytes", self.input_bytes)
+            .num("audio_seconds", format!("{:.3}", self.audio.as_secs_f64()))
+            .num(
+                "transcode_seconds",
+                format!("{:.3}", self.transcoding.as_secs_f64()),
+            )
+            .raw("failures", &failures)
+            .raw("slowest", &slowest)
+            .finish();
+
+        let path = self.dir.join(format!("report-{}.json", self.date));
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, report + "\n")?;
+        fs::rename(&tmp, &path)
+    }
+}
@@ -0,0 +1,26 @@
+//! Backfills on SIGHUP, for operators without the control socket, e.g. to
+//! recover from inotify events lost in an overflow without a restart.
+//!
+//! As with the shutdown signals, the handler only counts the signals, and
+//! the main loop walks the input when the count changes.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static SIGNALS: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn handler(_signal: libc::c_int) {
+    SIGNALS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Install the SIGHUP handler.
+pub fn install() {
+    let handler = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only touches an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGHUP, handler);
+    }
+}
+
+/// How many rescans have been requested so far.
+pub fn requested() -> u64 {
+    SIGNALS.load(Ordering::SeqCst)
+}
@@ -0,0 +1,635 @@
+//! Per-job routing, by rules on where inputs are in the input directory,
+//! or decided by a user script.
+//!
+//! A rule of `--route` sends the inputs below a subdirectory, or those of
+//! some duration, codec, channel count or sample rate, to an output
+//! directory of their own, in a preset of their own or with a backend of
+//! their own, e.g. `callcenter/**=/srv/calls:telephony` or
+//! `[duration>1h]=:segment-length=10m`. The first rule matching an input
+//! applies; the others are made as without rules.
+//!
+//! The script runs through the shell once per file with the job described
+//! in `TRANSCODER_*` environment variables, and answers on stdout:
+//!
+//! - nothing: use the default output path
+//! - `output <PATH>`: write to `PATH`, relative to the output directory
+//! - `skip [REASON]`: do not transcode the file
+//!
+//! A script that exits with a non-zero status fails the job.
+use crate::backend::BackendKind;
+use crate::preset::Preset;
+use crate::probe::Properties;
+use crate::raw::RawInput;
+use crate::{TranscodeOptions, wav};
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Whether `path` matches `glob`, with `/` separators: `**` stands for
+/// any run of characters, `*` for any run within a directory or file name
+/// and `?` for any one character but `/`.
+fn glob_matches(glob: &[char], path: &[char]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|skip| glob_matches(rest, &path[skip..])),
+        ['*', rest @ ..] => (0..=path.len())
+            .take_while(|&skip| skip == 0 || path[skip - 1] != '/')
+            .any(|skip| glob_matches(rest, &path[skip..])),
+        [c, rest @ ..] => path.split_first().is_some_and(|(&p, path)| {
+            (*c == p || (*c == '?' && p != '/')) && glob_matches(rest, path)
+        }),
+    }
+}
+
+/// A property of inputs that routes can depend on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Property {
+    Duration,
+    Codec,
+    Channels,
+    Rate,
+}
+
+/// How a property is compared with the value of a condition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    fn holds(self, ordering: std::cmp::Ordering) -> bool {
+        use std::cmp::Ordering::*;
+        match self {
+            Comparison::Less => ordering == Less,
+            Comparison::LessOrEqual => ordering != Greater,
+            Comparison::Greater => ordering == Greater,
+            Comparison::GreaterOrEqual => ordering != Less,
+            Comparison::Equal => ordering == Equal,
+            Comparison::NotEqual => ordering != Equal,
+        }
+    }
+}
+
+/// A condition on a property of the input, e.g. `duration>1h`,
+/// `channels==1` or `codec!=opus`.
+#[derive(Clone, Debug, PartialEq)]
+struct Condition {
+    property: Property,
+    comparison: Comparison,
+    /// Seconds, channels or Hz, for the numeric properties.
+    number: f64,
+    /// The codec, for `codec`.
+    text: String,
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let at = s
+            .find(['<', '>', '=', '!'])
+            .ok_or_else(|| format!("invalid condition {:?}, expected e.g. duration>1h", s))?;
+        let (name, rest) = s.split_at(at);
+        let (comparison, value) = [
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("==", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+        ]
+        .into_iter()
+        .find_map(|(operator, comparison)| Some((comparison, rest.strip_prefix(operator)?)))
+        .ok_or_else(|| {
+            format!(
+                "invalid comparison in {:?}, expected <, <=, >, >=, == or !=",
+                s
+            )
+        })?;
+        let value = value.trim();
+        let property = match name.trim() {
+            "duration" => Property::Duration,
+            "codec" => Property::Codec,
+            "channels" => Property::Channels,
+            "rate" => Property::Rate,
+            other => {
+                return Err(format!(
+                    "unknown property {:?}, expected duration, codec, channels or rate",
+                    other
+                ));
+            }
+        };
+        let number = match property {
+            Property::Duration => value
+                .parse::<f64>()
+                .ok()
+                .or_else(|| Some(humantime::parse_duration(value).ok()?.as_secs_f64()))
+                .ok_or_else(|| format!("invalid duration {:?}, expected e.g. 90s or 1h", value))?,
+            Property::Channels | Property::Rate => value
+                .parse::<u32>()
+                .map(f64::from)
+                .map_err(|_| format!("invalid number {:?} in {:?}", value, s))?,
+            Property::Codec
+                if matches!(comparison, Comparison::Equal | Comparison::NotEqual)
+                    && !value.is_empty() =>
+            {
+                0.0
+            }
+            Property::Codec => {
+                return Err(format!(
+                    "a codec can only be compared with == or !=, in {:?}",
+                    s
+                ));
+            }
+        };
+        Ok(Condition {
+            property,
+            comparison,
+            number,
+            text: value.to_string(),
+        })
+    }
+}
+
+impl Condition {
+    /// Whether it holds for an input of `properties`; never if the
+    /// property is not known.
+    fn holds(&self, properties: &Properties) -> bool {
+        let value = match self.property {
+            Property::Codec => {
+                return properties.codec.as_deref().is_some_and(|codec| {
+                    codec.eq_ignore_ascii_case(&self.text) == (self.comparison == Comparison::Equal)
+                });
+            }
+            Property::Duration => properties.duration,
+            Property::Channels => properties.channels.map(f64::from),
+            Property::Rate => properties.sample_rate.map(f64::from),
+        };
+        value
+            .and_then(|value| value.partial_cmp(&self.number))
+            .is_some_and(|ordering| self.comparison.holds(ordering))
+    }
+}
+
+/// Where the inputs matching a pattern go, and how they are made, parsed
+/// from `GLOB[CONDITION,...]=[DIR][:OPTION,...]`: e.g.
+/// `callcenter/**=/srv/calls:telephony`, `podcasts/**=:archive`,
+/// `[duration>1h]=:segment-length=10m` or
+/// `**.wav[channels==1]=:backend=native`. An option is a preset, or
+/// `preset=`, `backend=` or `segment-length=` with a value.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouteRule {
+    /// Glob on the path of an input below the input directory.
+    pattern: String,
+    /// What must hold for the input, all of it.
+    conditions: Vec<Condition>,
+    /// Directory of the outputs, instead of the output directory.
+    pub output_dir: Option<PathBuf>,
+    /// Preset of the outputs, instead of `--preset`.
+    pub preset: Option<Preset>,
+    /// Engine, instead of `--backend`.
+    pub backend: Option<BackendKind>,
+    /// Segments transcoded in parallel, instead of `--segment-length`.
+    pub segment_length: Option<Duration>,
+}
+
+impl RouteRule {
+    /// Set `option` of the target, returning whether it is one.
+    fn set(&mut self, option: &str) -> Result<bool, String> {
+        let (key, value) = match option.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => ("preset", option.trim()),
+        };
+        match key {
+            "preset" => match Preset::from_str(value, true) {
+                Ok(preset) => self.preset = Some(preset),
+                Err(_) if option.contains('=') => {
+                    return Err(format!("unknown preset {:?}", value));
+                }
+                Err(_) => return Ok(false),
+            },
+            "backend" => {
+                self.backend = Some(
+                    BackendKind::from_str(value, true)
+                        .map_err(|_| format!("unknown backend {:?}", value))?,
+                )
+            }
+            "segment-length" => {
+                self.segment_length = Some(
+                    humantime::parse_duration(value)
+                        .ok()
+                        .filter(|length| !length.is_zero())
+                        .ok_or_else(|| format!("invalid segment length {:?}", value))?,
+                )
+            }
+            _ => return Err(format!("unknown route option {:?}", key)),
+        }
+        Ok(true)
+    }
+
+    /// Whether it applies to an input at `relative` below the input
+    /// directory, with its properties found out by `probe` if needed.
+    fn matches(&self, relative: &[char], probe: &mut impl FnMut() -> Properties) -> bool {
+        glob_matches(&self.pattern.chars().collect::<Vec<_>>(), relative)
+            && (self.conditions.is_empty() || {
+                let properties = probe();
+                self.conditions
+                    .iter()
+                    .all(|condition| condition.holds(&properties))
+            })
+    }
+
+    /// Where it sends the output that would be at `output`, if elsewhere.
+    pub fn output(&self, output: &Path) -> Option<PathBuf> {
+        Some(self.output_dir.as_ref()?.join(output.file_name()?))
+    }
+}
+
+impl FromStr for RouteRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid route {:?}, expected GLOB[CONDITION,...]=[DIR][:OPTION,...]",
+                s
+            )
+        };
+        // The conditions may compare with =, the target starts after them
+        let split = match s.find('[') {
+            Some(open) => {
+                let close = open + s[open..].find(']').ok_or_else(invalid)?;
+                close + s[close..].find('=').ok_or_else(invalid)?
+            }
+            None => s.find('=').ok_or_else(invalid)?,
+        };
+        let (matching, target) = (s[..split].trim(), s[split + 1..].trim());
+        let (pattern, conditions) = match matching.split_once('[') {
+            Some((pattern, conditions)) => {
+                let conditions = conditions
+                    .strip_suffix(']')
+                    .ok_or_else(invalid)?
+                    .split(',')
+                    .map(|condition| condition.trim().parse())
+                    .collect::<Result<Vec<Condition>, String>>()?;
+                (pattern.trim(), conditions)
+            }
+            None => (matching, Vec::new()),
+        };
+        let pattern = match pattern.trim_start_matches("./") {
+            "" if !conditions.is_empty() => "**",
+            "" => return Err(invalid()),
+            pattern => pattern,
+        };
+        let mut rule = RouteRule {
+            pattern: pattern.replace('\\', "/"),
+            conditions,
+            output_dir: None,
+            preset: None,
+            backend: None,
+            segment_length: None,
+        };
+        // Options only after the last colon, so that C:\out stays a directory
+        let mut dir = target;
+        if let Some((before, options)) = target.rsplit_once(':') {
+            let mut options = options.split(',');
+            if let Some(first) = options.next()
+                && rule.set(first)?
+            {
+                for option in options {
+                    if !rule.set(option)? {
+                        return Err(format!("unknown preset {:?}", option.trim()));
+                    }
+                }
+                dir = before;
+            }
+        }
+        rule.output_dir = (!dir.is_empty()).then(|| PathBuf::from(dir));
+        if rule.output_dir.is_none()
+            && rule.preset.is_none()
+            && rule.backend.is_none()
+            && rule.segment_length.is_none()
+        {
+            return Err(format!("route {:?} changes nothing", s));
+        }
+        Ok(rule)
+    }
+}
+
+/// How the outputs of a job are made, which routes may change from the
+/// options.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Variant {
+    pub preset: Preset,
+    pub backend: BackendKind,
+    pub segment_length: Option<Duration>,
+}
+
+impl Variant {
+    /// That of the inputs `rule` applies to, or of those without a route.
+    pub fn of(options: &TranscodeOptions, rule: Option<&RouteRule>) -> Variant {
+        Variant {
+            preset: rule.and_then(|rule| rule.preset).unwrap_or(options.preset),
+            backend: rule
+                .and_then(|rule| rule.backend)
+                .unwrap_or(options.backend),
+            segment_length: rule
+                .and_then(|rule| rule.segment_length)
+                .or(options.segment_length),
+        }
+    }
+}
+
+/// The rules of `--route`, on the inputs of a local input directory.
+#[derive(Clone, Debug)]
+pub struct Routes {
+    input_dir: PathBuf,
+    rules: Vec<RouteRule>,
+    /// Declarations the properties of headerless inputs are taken from.
+    raw_inputs: Vec<RawInput>,
+}
+
+impl Routes {
+    pub fn new(
+        input_dir: impl Into<PathBuf>,
+        rules: Vec<RouteRule>,
+        raw_inputs: Vec<RawInput>,
+    ) -> Self {
+        let input_dir = input_dir.into();
+        Routes {
+            input_dir: std::path::absolute(&input_dir).unwrap_or(input_dir),
+            rules,
+            raw_inputs,
+        }
+    }
+
+    /// The first rule matching `input`, if it is in the input directory;
+    /// its properties are probed at most once, and only if a rule whose
+    /// glob matches has conditions.
+    pub fn find(&self, input: &Path) -> Option<&RouteRule> {
+        let absolute = std::path::absolute(input).ok()?;
+        let relative: Vec<char> = absolute
+            .strip_prefix(&self.input_dir)
+            .ok()?
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+            .chars()
+            .collect();
+        let mut probed = None;
+        let mut probe = || {
+            probed
+                .get_or_insert_with(|| Properties::probe(input, &self.raw_inputs))
+                .clone()
+        };
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(&relative, &mut probe))
+    }
+
+    pub fn rules(&self) -> &[RouteRule] {
+        &self.rules
+    }
+}
+
+/// What the routing script decided for a file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Route {
+    Default,
+    Output(PathBuf),
+    Skip(String),
+}
+
+/// A routing script given with `--route-script`.
+#[derive(Clone, Debug)]
+pub struct RouteScript {
+    script: String,
+}
+
+impl RouteScript {
+    pub fn new(script: impl Into<String>) -> Self {
+        RouteScript {
+            script: script.into(),
+        }
+    }
+
+    /// Ask the script where `input` should go; `output` is the default path.
+    pub fn route(&self, input: &Path, output: &Path) -> Result<Route, String> {
+        let mut command = if cfg!(windows) {
+            let mut command = Command::new("cmd");
+            command.args(["/C", &self.script]);
+            command
+        } else {
+            let mut command = Command::new("sh");
+            command.args(["-c", &self.script]);
+            command
+        };
+        let size = std::fs::metadata(input).map_or(0, |m| m.len());
+        let extension = input.extension().unwrap_or_default();
+        let duration =
+            wav::duration(input).map_or(String::new(), |d| format!("{:.3}", d.as_secs_f64()));
+        command
+            .env("TRANSCODER_INPUT", input)
+            .env("TRANSCODER_OUTPUT", output)
+            .env("TRANSCODER_SIZE", size.to_string())
+            .env("TRANSCODER_EXTENSION", extension)
+            .env("TRANSCODER_DURATION", duration);
+
+        let result = crate::backend::run(&mut command, None)
+            .map_err(|e| format!("Route script failed to run: {}", e))?;
+        if !result.status.success() {
+            return Err(format!(
+                "Route script failed ({}): {}",
+                result.status,
+                result.stderr.trim_end()
+            ));
+        }
+
+        let answer = result.stdout.trim();
+        if answer.is_empty() {
+            return Ok(Route::Default);
+        }
+        let (verb, argument) = answer.split_once(' ').unwrap_or((answer, ""));
+        match verb {
+            "skip" => Ok(Route::Skip(argument.trim().to_string())),
+            "output" if !argument.trim().is_empty() => {
+                let dir = output.parent().unwrap_or(Path::new(""));
+                let routed = dir.join(argument.trim());
+                if let Some(parent) = routed.parent() {
+                    std::fs::create_dir_all(parent)
+                        .map_err(|e| format!("Route script output {:?} unusable: {}", routed, e))?;
+                }
+                Ok(Route::Output(routed))
+            }
+            _ => Err(format!("Route script gave an invalid answer: {}", answer)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(s: &str) -> RouteRule {
+        s.parse().unwrap_or_else(|e| panic!("{}: {}", s, e))
+    }
+
+    fn matches(glob: &str, path: &str) -> bool {
+        let glob: Vec<char> = glob.chars().collect();
+        let path: Vec<char> = path.chars().collect();
+        glob_matches(&glob, &path)
+    }
+
+    #[test]
+    fn globs_keep_single_stars_within_a_name() {
+        assert!(matches("callcenter/**", "callcenter/2024/05/call.wav"));
+        assert!(matches("**.wav", "a/b/call.wav"));
+        assert!(matches("*.wav", "call.wav"));
+        assert!(!matches("*.wav", "a/call.wav"));
+        assert!(matches("a/*/call.wav", "a/b/call.wav"));
+        assert!(!matches("a/*/call.wav", "a/b/c/call.wav"));
+        assert!(matches("call?.wav", "call7.wav"));
+        assert!(!matches("a?b", "a/b"));
+        assert!(!matches("callcenter/**", "podcasts/call.wav"));
+    }
+
+    #[test]
+    fn the_documented_routes_parse() {
+        let rule = route("callcenter/**=/srv/calls:telephony");
+        assert_eq!(rule.pattern, "callcenter/**");
+        assert_eq!(rule.output_dir, Some(PathBuf::from("/srv/calls")));
+        assert_eq!(rule.preset, Some(Preset::Telephony));
+
+        let rule = route("podcasts/**=:archive");
+        assert_eq!(rule.output_dir, None);
+        assert_eq!(rule.preset, Some(Preset::Archive));
+
+        let rule = route("[duration>1h]=:segment-length=10m");
+        assert_eq!(rule.pattern, "**");
+        assert_eq!(rule.conditions, vec!["duration>1h".parse().unwrap()]);
+        assert_eq!(rule.segment_length, Some(Duration::from_secs(600)));
+
+        let rule = route("./in\\calls/**=out:preset=speech,backend=ffmpeg");
+        assert_eq!(rule.pattern, "in/calls/**");
+        assert_eq!(rule.output_dir, Some(PathBuf::from("out")));
+        assert_eq!(rule.preset, Some(Preset::Speech));
+        assert_eq!(rule.backend, Some(BackendKind::Ffmpeg));
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn routes_can_choose_the_backend() {
+        let rule = route("**.wav[channels==1]=:backend=native");
+        assert_eq!(rule.pattern, "**.wav");
+        assert_eq!(rule.backend, Some(BackendKind::Native));
+    }
+
+    #[test]
+    fn options_are_only_after_the_last_colon() {
+        let rule = route("calls/**=C:\\out");
+        assert_eq!(rule.output_dir, Some(PathBuf::from("C:\\out")));
+        assert_eq!(rule.preset, None);
+
+        let rule = route("calls/**=C:\\out:telephony");
+        assert_eq!(rule.output_dir, Some(PathBuf::from("C:\\out")));
+        assert_eq!(rule.preset, Some(Preset::Telephony));
+
+        let rule = route("calls/**=C:\\out:segment-length=5m,archive");
+        assert_eq!(rule.output_dir, Some(PathBuf::from("C:\\out")));
+        assert_eq!(rule.segment_length, Some(Duration::from_secs(300)));
+        assert_eq!(rule.preset, Some(Preset::Archive));
+    }
+
+    #[test]
+    fn conditions_may_compare_with_equals_signs() {
+        let rule = route("[codec==opus,rate!=8000]=/srv/opus");
+        assert_eq!(rule.conditions.len(), 2);
+        assert_eq!(rule.output_dir, Some(PathBuf::from("/srv/opus")));
+    }
+
+    #[test]
+    fn invalid_routes_are_refused() {
+        for (s, error) in [
+            ("calls/**", "expected GLOB"),
+            ("=/srv/calls", "expected GLOB"),
+            ("[duration>1h=/srv/long", "expected GLOB"),
+            ("calls/**=", "changes nothing"),
+            ("calls/**=:preset=vinyl", "unknown preset \"vinyl\""),
+            ("calls/**=:telephony,vinyl", "unknown preset \"vinyl\""),
+            ("calls/**=:backend=sox", "unknown backend"),
+            ("calls/**=:segment-length=0s", "invalid segment length"),
+            ("calls/**=:bitrate=64k", "unknown route option"),
+            ("[loudness>-20]=/srv/loud", "unknown property"),
+        ] {
+            let refusal = s.parse::<RouteRule>().unwrap_err();
+            assert!(refusal.contains(error), "{}: {}", s, refusal);
+        }
+    }
+
+    #[test]
+    fn conditions_parse_their_values() {
+        let condition: Condition = "duration > 90s".parse().unwrap();
+        assert_eq!(condition.comparison, Comparison::Greater);
+        assert_eq!(condition.number, 90.0);
+        let condition: Condition = "duration<=120".parse().unwrap();
+        assert_eq!(condition.comparison, Comparison::LessOrEqual);
+        assert_eq!(condition.number, 120.0);
+        let condition: Condition = "channels==1".parse().unwrap();
+        assert_eq!(condition.property, Property::Channels);
+        assert_eq!(condition.number, 1.0);
+        let condition: Condition = "codec!=opus".parse().unwrap();
+        assert_eq!(condition.comparison, Comparison::NotEqual);
+        assert_eq!(condition.text, "opus");
+    }
+
+    #[test]
+    fn invalid_conditions_are_refused() {
+        for (s, error) in [
+            ("duration", "expected e.g. duration>1h"),
+            ("duration=1h", "invalid comparison"),
+            ("duration>long", "invalid duration"),
+            ("channels>=mono", "invalid number"),
+            ("rate>-1", "invalid number"),
+            ("codec>opus", "only be compared with == or !="),
+            ("codec==", "only be compared with == or !="),
+            ("bitrate>64000", "unknown property"),
+        ] {
+            let refusal = s.parse::<Condition>().unwrap_err();
+            assert!(refusal.contains(error), "{}: {}", s, refusal);
+        }
+    }
+
+    #[test]
+    fn conditions_hold_on_known_properties_only() {
+        let properties = Properties {
+            duration: Some(4000.0),
+            codec: Some("Opus".to_string()),
+            channels: Some(2),
+            ..Properties::default()
+        };
+        let holds = |s: &str| s.parse::<Condition>().unwrap().holds(&properties);
+        assert!(holds("duration>1h"));
+        assert!(!holds("duration<1h"));
+        assert!(holds("codec==opus"));
+        assert!(!holds("codec!=opus"));
+        assert!(holds("channels>=2"));
+        assert!(!holds("channels==1"));
+        // The rate is not known
+        assert!(!holds("rate==8000"));
+        assert!(!holds("rate!=8000"));
+        assert!(
+            !"codec!=opus"
+                .parse::<Condition>()
+                .unwrap()
+                .holds(&Properties::default())
+        );
+    }
+}
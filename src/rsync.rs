@@ -0,0 +1,333 @@
+//! Remote directories mirrored with the rsync executable.
+//!
+//! For servers where nothing can be installed beyond ssh and rsync: the
+//! remote directory is copied into a local mirror on every poll, and only
+//! the files rsync transferred, i.e. new or changed ones, are queued. The
+//! mirror is kept, so rsync skips what it already holds after a restart;
+//! files deleted on the server stay in it. Over ssh, prompts fail the sync
+//! instead of hanging it.
+use crate::source::Source;
+use crate::{Result, Submitter, shutdown, throttle};
+use log::{debug, info, warn};
+#[cfg(unix)]
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Partially transferred files are kept here, in each directory of the
+/// mirror, so an interrupted sync resumes them.
+const PARTIAL_DIR: &str = ".rsync-partial";
+
+/// A remote directory, parsed from `rsync+ssh://[user@]host[:port]/path`
+/// or `rsync://host[:port]/module/path` for an rsync daemon.
+///
+/// As with sftp:// locations, `/~/` at the start of an ssh path refers to
+/// the login directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RsyncLocation {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    /// Remote directory, ending in `/`; relative to the login directory
+    /// over ssh, or starting with the module for a daemon.
+    pub path: String,
+    /// Whether to connect through ssh rather than to an rsync daemon.
+    pub ssh: bool,
+}
+
+impl RsyncLocation {
+    pub fn parse(url: &str) -> Option<Self> {
+        let (ssh, rest) = match url.split_once("://")? {
+            ("rsync+ssh", rest) => (true, rest),
+            ("rsync", rest) => (false, rest),
+            _ => return None,
+        };
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, authority),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, Some(port.parse().ok()?)),
+            _ => (host_port, None),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let path = path.trim_end_matches('/');
+        if host.is_empty() || (!ssh && path.is_empty()) {
+            return None;
+        }
+        let mut path = match path.strip_prefix('~') {
+            Some(home) if ssh => home.trim_start_matches('/').to_string(),
+            _ if ssh => format!("/{}", path),
+            _ => path.to_string(),
+        };
+        if !path.is_empty() {
+            path.push('/');
+        }
+        Some(RsyncLocation {
+            user: user.filter(|u| !u.is_empty()),
+            host: host.to_string(),
+            port,
+            path,
+            ssh,
+        })
+    }
+
+    /// The source argument of rsync.
+    fn source(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        let user = self.user.as_ref().map(|u| format!("{}@", u));
+        let user = user.as_deref().unwrap_or_default();
+        match (self.ssh, self.port) {
+            // An empty path is the login directory
+            (true, _) => format!("{}{}:{}", user, host, self.path),
+            (false, Some(port)) => format!("rsync://{}{}:{}/{}", user, host, port, self.path),
+            (false, None) => format!("rsync://{}{}/{}", user, host, self.path),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match (self.ssh, self.path.strip_prefix('/')) {
+            (true, Some(path)) => format!("rsync+ssh://{}/{}", self.host, path),
+            (true, None) => format!("rsync+ssh://{}/~/{}", self.host, self.path),
+            (false, _) => format!("rsync://{}/{}", self.host, self.path),
+        }
+    }
+}
+
+/// Settings of the ssh connection.
+#[derive(Clone, Debug, Default)]
+pub struct RsyncConfig {
+    /// Private key; defaults to ssh's own configuration and agent.
+    pub identity: Option<PathBuf>,
+}
+
+/// Quote an argument of the remote shell command given to rsync `-e`.
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Undo rsync's `\#ooo` escaping of unprintable characters in file names.
+fn unescape(name: &str) -> Vec<u8> {
+    let bytes = name.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 2..i + 5)
+            // from_str_radix would also take a sign, as in `\#+12`
+            .filter(|digits| digits.iter().all(|d| matches!(d, b'0'..=b'7')))
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match octal {
+            Some(byte) if bytes[i] == b'\\' && bytes[i + 1] == b'#' => {
+                out.push(byte);
+                i += 5;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// State shared between an [`RsyncSource`] and its polling thread.
+struct Mirror {
+    location: RsyncLocation,
+    config: RsyncConfig,
+    dir: PathBuf,
+    healthy: AtomicBool,
+    stop: AtomicBool,
+}
+
+impl Mirror {
+    /// Bring the mirror up to date, returning the files transferred.
+    fn sync(&self) -> std::io::Result<Vec<PathBuf>> {
+        std::fs::create_dir_all(&self.dir)?;
+        let mut command = Command::new("rsync");
+        command.args([
+            "--recursive",
+            "--times",
+            "--protect-args",
+            "--timeout=300",
+            "--partial-dir",
+            PARTIAL_DIR,
+            "--exclude",
+            PARTIAL_DIR,
+            // Item changes, then the name; received files start with ">f"
+            "--out-format=%i %n",
+        ]);
+        if self.location.ssh {
+            let mut ssh = "ssh -o BatchMode=yes -o ConnectTimeout=30".to_string();
+            if let Some(port) = self.location.port {
+                ssh.push_str(&format!(" -p {}", port));
+            }
+            if let Some(identity) = &self.config.identity {
+                ssh.push_str(&format!(" -i {}", quote(&identity.to_string_lossy())));
+            }
+            command.arg("-e").arg(ssh);
+        }
+        if let Some(limit) = throttle::read_limit() {
+            // In KiB/s
+            command.arg(format!("--bwlimit={}", (limit / 1024).max(1)));
+        }
+        let mut dest = self.dir.clone().into_os_string();
+        dest.push("/");
+        debug!("Syncing {} to {:?}", self.location.describe(), self.dir);
+        let output = command
+            .arg(self.location.source())
+            .arg(dest)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()?;
+        if !output.status.success() {
+            return Err(std::io::Error::other(format!(
+                "rsync failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let mut received = Vec::new();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let Some((changes, name)) = line.split_once(' ') else {
+                continue;
+            };
+            if changes.starts_with(">f") {
+                #[cfg(unix)]
+                let name = OsStr::from_bytes(&unescape(name)).to_owned();
+                #[cfg(not(unix))]
+                let name = String::from_utf8_lossy(&unescape(name)).into_owned();
+                received.push(self.dir.join(name));
+            }
+        }
+        Ok(received)
+    }
+
+    /// Sync and queue the files transferred, returning how many.
+    fn poll(&self, submitter: &Submitter) -> std::io::Result<usize> {
+        let received = self.sync()?;
+        for path in &received {
+            info!("Received {:?} from {}", path, self.location.describe());
+            submitter.submit(path);
+        }
+        Ok(received.len())
+    }
+}
+
+/// Periodically mirrors a remote directory with rsync, queueing the files
+/// that arrive.
+pub struct RsyncSource {
+    mirror: Arc<Mirror>,
+    interval: Duration,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RsyncSource {
+    /// Mirror `location` into `dir`, every `interval` while watching.
+    pub fn new(
+        location: RsyncLocation,
+        config: RsyncConfig,
+        dir: &Path,
+        interval: Duration,
+    ) -> Self {
+        RsyncSource {
+            mirror: Arc::new(Mirror {
+                location,
+                config,
+                dir: dir.to_path_buf(),
+                healthy: AtomicBool::new(true),
+                stop: AtomicBool::new(false),
+            }),
+            interval,
+            thread: None,
+        }
+    }
+}
+
+impl Source for RsyncSource {
+    fn scan(&mut self, submitter: &Submitter) -> Result<usize> {
+        Ok(self.mirror.poll(submitter)?)
+    }
+
+    fn watch(&mut self, submitter: Submitter) -> Result<()> {
+        // Like the directory watcher, a new mirror only transcodes files
+        // arriving from now on; the first sync is taken as a baseline
+        if !self.mirror.dir.exists() {
+            let baseline = self.mirror.sync()?;
+            info!(
+                "Mirrored {} existing files from {}",
+                baseline.len(),
+                self.mirror.location.describe()
+            );
+        }
+
+        let mirror = self.mirror.clone();
+        let interval = self.interval;
+        self.thread = Some(std::thread::spawn(move || {
+            let mut last = Instant::now();
+            while !mirror.stop.load(Ordering::SeqCst) && !shutdown::requested() {
+                std::thread::sleep(Duration::from_secs(1).min(interval));
+                if last.elapsed() < interval || shutdown::requested() {
+                    continue;
+                }
+                last = Instant::now();
+                match mirror.poll(&submitter) {
+                    Ok(_) => mirror.healthy.store(true, Ordering::SeqCst),
+                    Err(e) => {
+                        warn!("Syncing {} failed: {}", mirror.location.describe(), e);
+                        mirror.healthy.store(false, Ordering::SeqCst);
+                    }
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    fn healthy(&self) -> bool {
+        self.mirror.healthy.load(Ordering::SeqCst)
+    }
+
+    fn describe(&self) -> String {
+        self.mirror.location.describe()
+    }
+}
+
+impl Drop for RsyncSource {
+    fn drop(&mut self) {
+        self.mirror.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unescape;
+
+    #[test]
+    fn octal_escapes_are_undone() {
+        assert_eq!(unescape("call\\#012.wav"), b"call\n.wav");
+        assert_eq!(unescape("caf\\#303\\#251.wav"), "café.wav".as_bytes());
+    }
+
+    #[test]
+    fn other_backslashes_are_kept() {
+        assert_eq!(unescape("a\\#+12"), b"a\\#+12");
+        assert_eq!(unescape("a\\#89"), b"a\\#89");
+        assert_eq!(unescape("a\\#01"), b"a\\#01");
+        assert_eq!(unescape("a\\b"), b"a\\b");
+    }
+}
@@ -0,0 +1,371 @@
+//! Amazon S3 and S3-compatible object storage, through the curl executable.
+//!
+//! Requests are signed with curl's built-in SigV4 support (curl 7.75 or
+//! later), so no AWS SDK or TLS stack is linked into the binary. Credentials
+//! come from the standard `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+//! optional `AWS_SESSION_TOKEN` environment variables, and are handed to
+//! curl in a private config file rather than on its command line.
+use crate::curl::{self, response_header, scratch_path, uri_encode, xml_elements};
+use crate::sink::RemoteTarget;
+use crate::source::{RemoteObject, RemoteStore};
+use log::debug;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Files larger than this are uploaded in parts.
+const MULTIPART_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Most parts S3 accepts in one multipart upload.
+const MAX_PARTS: u64 = 10_000;
+
+/// A bucket and key prefix, parsed from `s3://bucket/prefix`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct S3Location {
+    pub bucket: String,
+    /// Key prefix, empty or ending in `/`.
+    pub prefix: String,
+}
+
+impl S3Location {
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("s3://")?;
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return None;
+        }
+        let mut prefix = prefix.trim_matches('/').to_string();
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        Some(S3Location {
+            bucket: bucket.to_string(),
+            prefix,
+        })
+    }
+}
+
+/// Connection settings shared by the S3 source and sink.
+#[derive(Clone, Debug, Default)]
+pub struct S3Config {
+    /// Region used for signing; defaults to `AWS_REGION`, then `us-east-1`.
+    pub region: Option<String>,
+    /// Custom endpoint for S3-compatible stores, e.g. `http://minio:9000`.
+    /// Buckets are then addressed path-style.
+    pub endpoint: Option<String>,
+}
+
+/// Signed requests against one bucket.
+pub struct S3Client {
+    /// URL of the bucket, without a trailing slash.
+    base: String,
+    region: String,
+    /// curl config file holding the credentials.
+    credentials: PathBuf,
+    /// Directory for temporary request bodies.
+    work_dir: PathBuf,
+}
+
+impl S3Client {
+    /// Set up a client, keeping the credentials file in `work_dir`.
+    pub fn new(bucket: &str, config: &S3Config, work_dir: &Path) -> std::io::Result<Self> {
+        let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let (Some(key), Some(secret)) = (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY"))
+        else {
+            return Err(std::io::Error::other(
+                "AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY must be set",
+            ));
+        };
+        let region = config
+            .region
+            .clone()
+            .or_else(|| env("AWS_REGION"))
+            .or_else(|| env("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let base = match &config.endpoint {
+            Some(endpoint) => format!("{}/{}", endpoint.trim_end_matches('/'), bucket),
+            None => format!("https://{}.s3.{}.amazonaws.com", bucket, region),
+        };
+
+        std::fs::create_dir_all(work_dir)?;
+        let credentials = scratch_path(work_dir, "s3", "curlrc");
+        let mut config = format!("user = {}\n", curl::quote(&format!("{}:{}", key, secret)));
+        if let Some(token) = env("AWS_SESSION_TOKEN") {
+            config.push_str(&format!(
+                "header = {}\n",
+                curl::quote(&format!("x-amz-security-token: {}", token))
+            ));
+        }
+        curl::private_file(&credentials, &config)?;
+
+        Ok(S3Client {
+            base,
+            region,
+            credentials,
+            work_dir: work_dir.to_path_buf(),
+        })
+    }
+
+    /// URL of an object.
+    pub fn object_url(&self, key: &str) -> String {
+        format!("{}/{}", self.base, uri_encode(key, true))
+    }
+
+    /// Run one signed curl request, returning the response body.
+    pub(crate) fn request<S: AsRef<OsStr>>(
+        &self,
+        args: impl IntoIterator<Item = S>,
+    ) -> std::io::Result<Vec<u8>> {
+        let sigv4 = format!("aws:amz:{}:s3", self.region);
+        let mut all = vec![
+            OsStr::new("--aws-sigv4").to_os_string(),
+            sigv4.into(),
+            "-K".into(),
+            self.credentials.clone().into(),
+        ];
+        all.extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        curl::run("S3", all, None)
+    }
+
+    /// Every object whose key starts with `prefix`.
+    pub fn list(&self, prefix: &str) -> std::io::Result<Vec<RemoteObject>> {
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut url = format!(
+                "{}/?list-type=2&prefix={}",
+                self.base,
+                uri_encode(prefix, false)
+            );
+            if let Some(token) = &token {
+                url.push_str(&format!("&continuation-token={}", uri_encode(token, false)));
+            }
+            let body = self.request([url])?;
+            let body = String::from_utf8_lossy(&body);
+            for contents in xml_elements(&body, "Contents") {
+                let field = |tag| xml_elements(&contents, tag).pop().unwrap_or_default();
+                let key = field("Key");
+                objects.push(RemoteObject {
+                    key: key.strip_prefix(prefix).unwrap_or(&key).to_string(),
+                    version: field("ETag"),
+                    size: field("Size").parse().unwrap_or(0),
+                });
+            }
+            token = xml_elements(&body, "NextContinuationToken").pop();
+            if token.is_none()
+                || xml_elements(&body, "IsTruncated").pop().as_deref() != Some("true")
+            {
+                return Ok(objects);
+            }
+        }
+    }
+
+    /// Download an object to `dest`, via a temporary file next to it.
+    pub fn get(&self, key: &str, dest: &Path) -> std::io::Result<()> {
+        let mut partial = dest.as_os_str().to_os_string();
+        partial.push(".part");
+        let result = self.request([
+            OsStr::new(&self.object_url(key)),
+            OsStr::new("-o"),
+            &partial,
+        ]);
+        match result {
+            Ok(_) => std::fs::rename(&partial, dest),
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl S3Client {
+    /// Upload a file to `key`, in parts if it is large. `headers` are sent
+    /// with the upload, e.g. `Content-Type: audio/wav`.
+    pub fn put(&self, key: &str, file: &Path, headers: &[String]) -> std::io::Result<()> {
+        let size = std::fs::metadata(file)?.len();
+        if size > MULTIPART_THRESHOLD {
+            return self.put_multipart(key, file, size, headers);
+        }
+        let mut args = vec![OsStr::new("-T"), file.as_os_str()];
+        for header in headers {
+            args.extend([OsStr::new("-H"), OsStr::new(header)]);
+        }
+        let url = self.object_url(key);
+        args.push(OsStr::new(&url));
+        self.request(args)?;
+        Ok(())
+    }
+
+    fn put_multipart(
+        &self,
+        key: &str,
+        file: &Path,
+        size: u64,
+        headers: &[String],
+    ) -> std::io::Result<()> {
+        let url = self.object_url(key);
+        let mut args = vec!["-X".to_string(), "POST".to_string()];
+        for header in headers {
+            args.extend(["-H".to_string(), header.clone()]);
+        }
+        args.push(format!("{}?uploads", url));
+        let response = self.request(args)?;
+        let upload_id = xml_elements(&String::from_utf8_lossy(&response), "UploadId")
+            .pop()
+            .ok_or_else(|| std::io::Error::other("S3 did not return an upload ID"))?;
+        let upload_url = format!("{}?uploadId={}", url, uri_encode(&upload_id, false));
+
+        let result = self
+            .upload_parts(&url, &upload_id, file, size)
+            .and_then(|etags| {
+                let mut body = String::from("<CompleteMultipartUpload>");
+                for (i, etag) in etags.iter().enumerate() {
+                    body.push_str(&format!(
+                        "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                        i + 1,
+                        etag.replace('&', "&amp;").replace('"', "&quot;")
+                    ));
+                }
+                body.push_str("</CompleteMultipartUpload>");
+                let body_file = scratch_path(&self.work_dir, "s3", "xml");
+                std::fs::write(&body_file, body)?;
+                let mut data = OsString::from("@");
+                data.push(&body_file);
+                let result = self.request([
+                    OsStr::new("-H"),
+                    OsStr::new("Content-Type: application/xml"),
+                    OsStr::new("--data-binary"),
+                    &data,
+                    OsStr::new(&upload_url),
+                ]);
+                let _ = std::fs::remove_file(&body_file);
+                // Completion can fail with a 200 response and an error body
+                let response = String::from_utf8_lossy(&result?).into_owned();
+                match xml_elements(&response, "Message").pop() {
+                    Some(message) if response.contains("<Error>") => Err(std::io::Error::other(
+                        format!("S3 upload failed: {}", message),
+                    )),
+                    _ => Ok(()),
+                }
+            });
+        if result.is_err() {
+            let _ = self.request(["-X", "DELETE", &upload_url]);
+        }
+        result
+    }
+
+    /// Upload every part of a file, returning their ETags in order.
+    fn upload_parts(
+        &self,
+        url: &str,
+        upload_id: &str,
+        file: &Path,
+        size: u64,
+    ) -> std::io::Result<Vec<String>> {
+        let part_size = MULTIPART_THRESHOLD.max(size.div_ceil(MAX_PARTS));
+        let mut input = File::open(file)?;
+        let part_file = scratch_path(&self.work_dir, "s3", "part");
+        let mut etags = Vec::new();
+        let result = (|| {
+            for number in 1..=size.div_ceil(part_size) {
+                let mut part = File::create(&part_file)?;
+                std::io::copy(&mut (&mut input).take(part_size), &mut part)?;
+                drop(part);
+                debug!("Uploading part {} of {:?}", number, file);
+                let response = self.request([
+                    OsStr::new("-i"),
+                    OsStr::new("-T"),
+                    part_file.as_os_str(),
+                    OsStr::new(&format!(
+                        "{}?partNumber={}&uploadId={}",
+                        url,
+                        number,
+                        uri_encode(upload_id, false)
+                    )),
+                ])?;
+                etags.push(
+                    response_header(&response, "ETag")
+                        .ok_or_else(|| std::io::Error::other("S3 did not return a part ETag"))?,
+                );
+            }
+            Ok(etags)
+        })();
+        let _ = std::fs::remove_file(&part_file);
+        result
+    }
+}
+
+impl Drop for S3Client {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.credentials);
+    }
+}
+
+/// Objects below a prefix of a bucket, for a polling source.
+pub struct S3Store {
+    client: S3Client,
+    location: S3Location,
+}
+
+impl S3Store {
+    pub fn new(location: S3Location, config: &S3Config, work_dir: &Path) -> std::io::Result<Self> {
+        Ok(S3Store {
+            client: S3Client::new(&location.bucket, config, work_dir)?,
+            location,
+        })
+    }
+}
+
+impl RemoteStore for S3Store {
+    fn list(&self) -> std::io::Result<Vec<RemoteObject>> {
+        self.client.list(&self.location.prefix)
+    }
+
+    fn fetch(&self, key: &str, dest: &Path) -> std::io::Result<()> {
+        self.client
+            .get(&format!("{}{}", self.location.prefix, key), dest)
+    }
+
+    fn describe(&self) -> String {
+        format!("s3://{}/{}", self.location.bucket, self.location.prefix)
+    }
+}
+
+/// Uploads outputs below a prefix of a bucket.
+pub struct S3Target {
+    client: S3Client,
+    location: S3Location,
+    storage_class: Option<String>,
+}
+
+impl S3Target {
+    pub fn new(
+        location: S3Location,
+        config: &S3Config,
+        storage_class: Option<String>,
+        work_dir: &Path,
+    ) -> std::io::Result<Self> {
+        Ok(S3Target {
+            client: S3Client::new(&location.bucket, config, work_dir)?,
+            location,
+            storage_class,
+        })
+    }
+}
+
+impl RemoteTarget for S3Target {
+    fn upload(&self, file: &Path, name: &str, content_type: &str) -> std::io::Result<()> {
+        let mut headers = vec![format!("Content-Type: {}", content_type)];
+        if let Some(class) = &self.storage_class {
+            headers.push(format!("x-amz-storage-class: {}", class));
+        }
+        self.client
+            .put(&format!("{}{}", self.location.prefix, name), file, &headers)
+    }
+
+    fn describe(&self) -> String {
+        format!("s3://{}/{}", self.location.bucket, self.location.prefix)
+    }
+}
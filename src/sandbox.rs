@@ -0,0 +1,133 @@
+//! Running ffmpeg and ffprobe confined with `--sandbox`, as they parse
+//! untrusted uploads, and a flaw in one of their demuxers or decoders
+//! should not hand over the host.
+//!
+//! Under bubblewrap a child sees only the system directories, read-only,
+//! its inputs, read-only, and the directories of its outputs, e.g. the
+//! work directory; it has no network and none of the host's processes.
+//! Bubblewrap is Linux only, and needs unprivileged user namespaces unless
+//! the process runs as root.
+use crate::parts;
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+/// Directories of the system that the children may read, for the
+/// executables and the libraries they load; those that are symlinks, as
+/// on systems with a merged `/usr`, are recreated as such.
+const SYSTEM_DIRS: [&str; 6] = ["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64"];
+/// Files of `/etc` the dynamic loader and the `alternatives` of some
+/// distributions need.
+const SYSTEM_FILES: [&str; 3] = ["/etc/ld.so.cache", "/etc/ld.so.conf", "/etc/alternatives"];
+
+/// How the transcoder processes are confined, selectable with `--sandbox`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Sandbox {
+    /// Run them through `bwrap`, the sandbox of Flatpak.
+    Bubblewrap,
+}
+
+static SANDBOX: Mutex<Option<Sandbox>> = Mutex::new(None);
+
+/// Confine the children of the whole process, or not.
+pub fn set(sandbox: Option<Sandbox>) {
+    *SANDBOX.lock().unwrap() = sandbox;
+}
+
+/// Check that a child can be started in the sandbox, if one is set.
+pub fn check() -> std::io::Result<()> {
+    if SANDBOX.lock().unwrap().is_none() {
+        return Ok(());
+    }
+    let status = command("true", &[], &[])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("cannot run bwrap: {}", e)))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(std::io::Error::other(format!(
+            "bwrap cannot start a sandbox: {}",
+            status
+        ))),
+    }
+}
+
+/// A command running `program`, found on `PATH`, that may read `reads`,
+/// and the files the concat lists among them reference, and write in the
+/// directories of `writes`; without a sandbox, it may do anything.
+pub(crate) fn command(program: &str, reads: &[&Path], writes: &[&Path]) -> Command {
+    let sandbox = *SANDBOX.lock().unwrap();
+    match sandbox {
+        None => Command::new(program),
+        Some(Sandbox::Bubblewrap) => bubblewrap(program, reads, writes),
+    }
+}
+
+fn bubblewrap(program: &str, reads: &[&Path], writes: &[&Path]) -> Command {
+    let mut command = Command::new("bwrap");
+    command.args([
+        "--die-with-parent",
+        "--new-session",
+        "--unshare-all",
+        "--cap-drop",
+        "ALL",
+    ]);
+    for dir in SYSTEM_DIRS.map(Path::new) {
+        match std::fs::read_link(dir) {
+            Ok(target) => command.arg("--symlink").arg(target).arg(dir),
+            Err(_) if dir.is_dir() => command.arg("--ro-bind").arg(dir).arg(dir),
+            Err(_) => continue,
+        };
+    }
+    for file in SYSTEM_FILES {
+        command.args(["--ro-bind-try", file, file]);
+    }
+    command.args(["--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"]);
+    // Relative paths are resolved in the same directory inside
+    if let Ok(dir) = std::env::current_dir() {
+        command.arg("--dir").arg(&dir).arg("--chdir").arg(&dir);
+    }
+    let mut dirs: Vec<PathBuf> = writes
+        .iter()
+        .filter_map(|write| {
+            std::path::absolute(write)
+                .ok()?
+                .parent()
+                .map(Path::to_path_buf)
+        })
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in &dirs {
+        command.arg("--bind").arg(dir).arg(dir);
+    }
+    // After the writable directories, so that inputs among them stay
+    // read-only
+    let files = reads
+        .iter()
+        .flat_map(|read| std::iter::once(read.to_path_buf()).chain(parts::list_entries(read)));
+    for file in files.filter_map(|file| std::path::absolute(file).ok()) {
+        // An input that is missing is for the child to report
+        command.arg("--ro-bind-try").arg(&file).arg(&file);
+    }
+    match find(program) {
+        Some(path) => {
+            if !SYSTEM_DIRS.iter().any(|dir| path.starts_with(dir)) {
+                command.arg("--ro-bind").arg(&path).arg(&path);
+            }
+            command.arg("--").arg(path)
+        }
+        None => command.arg("--").arg(program),
+    };
+    command
+}
+
+/// Where `program` is on `PATH`.
+fn find(program: &str) -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+        .and_then(|path| std::path::absolute(path).ok())
+}
@@ -0,0 +1,212 @@
+//! Processing windows, so that heavy transcoding is kept to the hours set
+//! aside for it, e.g. the night, without stopping ingestion.
+//!
+//! Outside the windows, workers finish the job in flight and then wait, as
+//! when paused; files keep being found and queued. Times are local, in the
+//! time zone given with the windows or else the system's.
+use log::info;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Whether the last check found the time inside a window: 0 before the
+/// first check, 1 inside, 2 outside. Changes are logged once.
+static LAST: AtomicU8 = AtomicU8::new(0);
+
+/// Windows parsed from e.g. `22:00-06:00` or `12:00-13:00,20:00-23:00`,
+/// optionally followed by a time zone, as in `22:00-06:00 Europe/Stockholm`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ActiveHours {
+    /// Start and end, in minutes since midnight; an end before the start
+    /// wraps past midnight, and an end equal to it spans the whole day.
+    windows: Vec<(u32, u32)>,
+    /// Name of a zone in the system's time zone database.
+    pub zone: Option<String>,
+}
+
+/// Minutes since midnight of `HH:MM`.
+fn parse_time(s: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid time {:?}, expected HH:MM", s);
+    let (hours, minutes) = s.trim().split_once(':').ok_or_else(invalid)?;
+    // parse would also take a sign
+    if ![hours, minutes]
+        .iter()
+        .all(|part| part.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return Err(invalid());
+    }
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    // 24:00 is the end of the day
+    if minutes >= 60 || hours > 24 || (hours == 24 && minutes > 0) {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl FromStr for ActiveHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let windows = parts
+            .next()
+            .ok_or("no windows given")?
+            .split(',')
+            .map(|window| {
+                let (start, end) = window
+                    .split_once('-')
+                    .ok_or_else(|| format!("invalid window {:?}, expected HH:MM-HH:MM", window))?;
+                let start = parse_time(start)?;
+                // 00:00-24:00 is the whole day, as is 00:00-00:00
+                let end = parse_time(end)? % (24 * 60);
+                Ok((start % (24 * 60), end))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let zone = parts.next().map(str::to_string);
+        if let Some(extra) = parts.next() {
+            return Err(format!("unexpected {:?} after the time zone", extra));
+        }
+        Ok(ActiveHours { windows, zone })
+    }
+}
+
+impl fmt::Display for ActiveHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = |m: u32| format!("{:02}:{:02}", m / 60, m % 60);
+        let windows: Vec<String> = self
+            .windows
+            .iter()
+            .map(|&(start, end)| format!("{}-{}", time(start), time(end)))
+            .collect();
+        f.write_str(&windows.join(","))?;
+        if let Some(zone) = &self.zone {
+            write!(f, " {}", zone)?;
+        }
+        Ok(())
+    }
+}
+
+impl ActiveHours {
+    /// Whether `minute` since midnight falls in a window.
+    pub fn contains(&self, minute: u32) -> bool {
+        self.windows
+            .iter()
+            .any(|&(start, end)| match start.cmp(&end) {
+                std::cmp::Ordering::Less => (start..end).contains(&minute),
+                std::cmp::Ordering::Greater => minute >= start || minute < end,
+                std::cmp::Ordering::Equal => true,
+            })
+    }
+
+    /// Whether jobs may be taken now, logging when that changes.
+    pub fn active_now(&self) -> bool {
+        let active = self.contains(local_minute());
+        let state = if active { 1 } else { 2 };
+        if LAST.swap(state, Ordering::SeqCst) != state {
+            match active {
+                true => info!("Inside the active hours {}, processing jobs", self),
+                false => info!(
+                    "Outside the active hours {}, queueing files until the next window",
+                    self
+                ),
+            }
+        }
+        active
+    }
+}
+
+/// Minutes since local midnight.
+#[cfg(unix)]
+fn local_minute() -> u32 {
+    // SAFETY: `time` accepts a null pointer, and `localtime_r` writes only
+    // to the `tm` it is given
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return 0;
+        }
+        (tm.tm_hour * 60 + tm.tm_min) as u32
+    }
+}
+
+/// Minutes since midnight UTC, for lack of the local time zone.
+#[cfg(not(unix))]
+fn local_minute() -> u32 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    ((now.as_secs() / 60) % (24 * 60)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hours(s: &str) -> ActiveHours {
+        s.parse().unwrap_or_else(|e| panic!("{}: {}", s, e))
+    }
+
+    fn at(time: &str) -> u32 {
+        parse_time(time).unwrap()
+    }
+
+    #[test]
+    fn windows_wrap_past_midnight() {
+        let night = hours("22:00-06:00");
+        assert!(night.contains(at("23:00")));
+        assert!(night.contains(at("00:00")));
+        assert!(night.contains(at("05:59")));
+        assert!(!night.contains(at("06:00")));
+        assert!(!night.contains(at("21:59")));
+        assert!(night.contains(at("22:00")));
+    }
+
+    #[test]
+    fn windows_end_before_their_end() {
+        let lunch = hours("12:00-13:00,20:00-23:00");
+        assert!(lunch.contains(at("12:00")));
+        assert!(!lunch.contains(at("13:00")));
+        assert!(lunch.contains(at("22:59")));
+        assert!(!lunch.contains(at("23:00")));
+        assert!(!lunch.contains(at("16:00")));
+    }
+
+    #[test]
+    fn whole_days() {
+        for day in ["00:00-24:00", "00:00-00:00", "08:00-08:00"] {
+            let day = hours(day);
+            assert!(day.contains(at("00:00")));
+            assert!(day.contains(at("12:00")));
+            assert!(day.contains(at("23:59")));
+        }
+        let evening = hours("18:00-24:00");
+        assert!(evening.contains(at("23:59")));
+        assert!(!evening.contains(at("00:00")));
+    }
+
+    #[test]
+    fn zones_follow_the_windows() {
+        let night = hours("22:00-06:00 Europe/Stockholm");
+        assert_eq!(night.zone.as_deref(), Some("Europe/Stockholm"));
+        assert_eq!(night.to_string(), "22:00-06:00 Europe/Stockholm");
+        assert!(
+            "22:00-06:00 Europe/Stockholm now"
+                .parse::<ActiveHours>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn invalid_times_are_refused() {
+        for time in [
+            "24:01", "12:60", "25:00", "12", "12:", "ab:00", "+1:00", "12:-5",
+        ] {
+            assert!(parse_time(time).is_err(), "{}", time);
+        }
+        for s in ["", "22:00", "22:00-", "22:00-06:00,", "22:00-24:01"] {
+            assert!(s.parse::<ActiveHours>().is_err(), "{:?}", s);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Crash and failure reporting to Sentry.
+//!
+//! Events are posted to the project's store endpoint with curl, so no TLS
+//! stack is linked into the binary. Panics are reported from a panic hook;
+//! job failures are reported once a run of consecutive failures reaches the
+//! configured threshold.
+use crate::JobResult;
+use crate::host;
+use crate::json;
+use crate::sha256::Sha256;
+use log::{debug, warn};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of trailing ffmpeg stderr lines attached to failure events.
+const STDERR_TAIL: usize = 20;
+
+/// Parsed DSN and reporting state.
+#[derive(Clone)]
+pub struct SentryReporter {
+    store_url: String,
+    auth: String,
+    threshold: u32,
+    consecutive_failures: u32,
+}
+
+/// Random-looking 32 character hex event ID.
+fn event_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut hasher = Sha256::default();
+    hasher.update(&nanos.to_le_bytes());
+    hasher.update(&std::process::id().to_le_bytes());
+    hasher.update(&COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    hasher.hex()[..32].to_string()
+}
+
+impl SentryReporter {
+    /// Parse a DSN of the form `https://<key>@<host>[/<path>]/<project>`.
+    pub fn new(dsn: &str, threshold: u32) -> Result<Self, String> {
+        let invalid = || format!("invalid Sentry DSN: {}", dsn);
+        let (scheme, rest) = dsn.split_once("://").ok_or_else(invalid)?;
+        let (key, rest) = rest.split_once('@').ok_or_else(invalid)?;
+        let key = key.split(':').next().unwrap_or_default();
+        let (host_path, project) = rest
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .ok_or_else(invalid)?;
+        if key.is_empty() || project.is_empty() {
+            return Err(invalid());
+        }
+        Ok(SentryReporter {
+            store_url: format!("{}://{}/api/{}/store/", scheme, host_path, project),
+            auth: format!(
+                "Sentry sentry_version=7, sentry_key={}, sentry_client=transcoderexpress/{}",
+                key,
+                env!("CARGO_PKG_VERSION")
+            ),
+            threshold,
+            consecutive_failures: 0,
+        })
+    }
+
+    /// Send one event; `extra` is an already serialized JSON object.
+    fn send(&self, level: &str, message: &str, extra: &str) {
+        let event = json::Object::new()
+            .str("event_id", &event_id())
+            .str(
+                "timestamp",
+                &humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            )
+            .str("platform", "other")
+            .str("level", level)
+            .str("logger", "transcoderexpress")
+            .str("message", message)
+            .str("server_name", &host::name())
+            .str(
+                "release",
+                concat!("transcoderexpress@", env!("CARGO_PKG_VERSION")),
+            )
+            .raw("extra", extra)
+            .finish();
+
+        let child = Command::new("curl")
+            .args(["-sS", "--fail", "--max-time", "10", "-X", "POST"])
+            .args(["-H", "Content-Type: application/json"])
+            .args(["-H", &format!("X-Sentry-Auth: {}", self.auth)])
+            .args(["--data-binary", "@-", &self.store_url])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn();
+        let result = child.and_then(|mut child| {
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(event.as_bytes())?;
+            }
+            child.wait_with_output()
+        });
+        match result {
+            Ok(output) if output.status.success() => debug!("Reported to Sentry: {}", message),
+            Ok(output) => warn!(
+                "Sentry rejected event: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => warn!("Failed to run curl for Sentry: {}", e),
+        }
+    }
+
+    /// Track a finished job and report when failures keep repeating.
+    pub fn record(&mut self, outcome: &JobResult) {
+        let Some(stderr) = &outcome.error else {
+            self.consecutive_failures = 0;
+            return;
+        };
+        self.consecutive_failures += 1;
+        if self.consecutive_failures != self.threshold {
+            return;
+        }
+
+        let lines: Vec<&str> = stderr.lines().collect();
+        let tail = lines[lines.len().saturating_sub(STDERR_TAIL)..].join("\n");
+        let extra = json::Object::new()
+            .str("input", &outcome.input.to_string_lossy())
+            .str("output", &outcome.output.to_string_lossy())
+            .opt_str("error_class", outcome.error_class())
+            .num("consecutive_failures", self.consecutive_failures)
+            .str("ffmpeg_stderr_tail", &tail)
+            .finish();
+        let message = format!(
+            "{} consecutive transcoding failures, last: {}",
+            self.consecutive_failures,
+            outcome.input.display()
+        );
+        self.send("error", &message, &extra);
+    }
+
+    /// Report a condition that needs attention.
+    pub fn alert(&self, message: &str) {
+        self.send("warning", message, "{}");
+    }
+
+    /// Report panics before running the default panic hook.
+    pub fn install_panic_hook(&self) {
+        let reporter = self.clone();
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let location = info
+                .location()
+                .map(|l| format!("{}:{}", l.file(), l.line()))
+                .unwrap_or_default();
+            let thread = std::thread::current()
+                .name()
+                .unwrap_or("unnamed")
+                .to_string();
+            let extra = json::Object::new()
+                .str("location", &location)
+                .str("thread", &thread)
+                .finish();
+            reporter.send(
+                "fatal",
+                &format!("panic: {}", info.payload_as_str().unwrap_or("unknown")),
+                &extra,
+            );
+            default_hook(info);
+        }));
+    }
+}
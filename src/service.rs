@@ -0,0 +1,280 @@
+//! Running as a Windows service.
+//!
+//! With `--service` the process expects to be started by the service control
+//! manager: stop and shutdown requests trigger a graceful shutdown, pause and
+//! continue pause the workers, and log records go to the Application event
+//! log instead of a console. `--install-service` registers the current
+//! command line with `sc.exe`.
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::ffi::c_void;
+use std::process::Command;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicIsize, AtomicU8, AtomicU32, Ordering};
+use transcoderexpress::{pause, shutdown};
+
+/// Service and event source name.
+pub const NAME: &str = "transcoderexpress";
+
+const SERVICE_WIN32_OWN_PROCESS: u32 = 0x10;
+const SERVICE_STOPPED: u32 = 1;
+const SERVICE_START_PENDING: u32 = 2;
+const SERVICE_STOP_PENDING: u32 = 3;
+const SERVICE_RUNNING: u32 = 4;
+const SERVICE_PAUSED: u32 = 7;
+const SERVICE_ACCEPT_STOP: u32 = 0x1;
+const SERVICE_ACCEPT_PAUSE_CONTINUE: u32 = 0x2;
+const SERVICE_ACCEPT_SHUTDOWN: u32 = 0x4;
+const SERVICE_CONTROL_STOP: u32 = 1;
+const SERVICE_CONTROL_PAUSE: u32 = 2;
+const SERVICE_CONTROL_CONTINUE: u32 = 3;
+const SERVICE_CONTROL_INTERROGATE: u32 = 4;
+const SERVICE_CONTROL_SHUTDOWN: u32 = 5;
+const NO_ERROR: u32 = 0;
+const ERROR_CALL_NOT_IMPLEMENTED: u32 = 120;
+const ERROR_SERVICE_SPECIFIC_ERROR: u32 = 1066;
+const EVENTLOG_ERROR_TYPE: u16 = 0x1;
+const EVENTLOG_WARNING_TYPE: u16 = 0x2;
+const EVENTLOG_INFORMATION_TYPE: u16 = 0x4;
+
+/// `SERVICE_STATUS`
+#[repr(C)]
+struct ServiceStatus {
+    service_type: u32,
+    current_state: u32,
+    controls_accepted: u32,
+    win32_exit_code: u32,
+    service_specific_exit_code: u32,
+    check_point: u32,
+    wait_hint: u32,
+}
+
+/// `SERVICE_TABLE_ENTRYW`
+#[repr(C)]
+struct ServiceTableEntry {
+    name: *mut u16,
+    service_main: Option<unsafe extern "system" fn(u32, *mut *mut u16)>,
+}
+
+type HandlerEx = unsafe extern "system" fn(u32, u32, *mut c_void, *mut c_void) -> u32;
+
+#[link(name = "advapi32")]
+unsafe extern "system" {
+    fn StartServiceCtrlDispatcherW(table: *const ServiceTableEntry) -> i32;
+    fn RegisterServiceCtrlHandlerExW(
+        name: *const u16,
+        handler: HandlerEx,
+        context: *mut c_void,
+    ) -> isize;
+    fn SetServiceStatus(handle: isize, status: *const ServiceStatus) -> i32;
+    fn RegisterEventSourceW(server: *const u16, source: *const u16) -> isize;
+    fn ReportEventW(
+        log: isize,
+        kind: u16,
+        category: u16,
+        event_id: u32,
+        sid: *mut c_void,
+        num_strings: u16,
+        data_size: u32,
+        strings: *const *const u16,
+        data: *mut c_void,
+    ) -> i32;
+}
+
+/// Handle returned by `RegisterServiceCtrlHandlerExW`.
+static STATUS_HANDLE: AtomicIsize = AtomicIsize::new(0);
+/// Last state reported to the service control manager.
+static STATE: AtomicU32 = AtomicU32::new(SERVICE_STOPPED);
+/// Exit code of the program run by the service.
+static EXIT_CODE: AtomicU8 = AtomicU8::new(0);
+/// The program to run once the service control manager starts the service.
+static PROGRAM: Mutex<Option<Box<dyn FnOnce() -> u8 + Send>>> = Mutex::new(None);
+
+/// NUL-terminated UTF-16 copy of a string.
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+/// Report a state change to the service control manager.
+fn set_state(state: u32, exit_code: u8) {
+    let controls_accepted = match state {
+        SERVICE_RUNNING | SERVICE_PAUSED => {
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_PAUSE_CONTINUE | SERVICE_ACCEPT_SHUTDOWN
+        }
+        _ => 0,
+    };
+    let pending = matches!(state, SERVICE_START_PENDING | SERVICE_STOP_PENDING);
+    let status = ServiceStatus {
+        service_type: SERVICE_WIN32_OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        win32_exit_code: match exit_code {
+            0 => NO_ERROR,
+            _ => ERROR_SERVICE_SPECIFIC_ERROR,
+        },
+        service_specific_exit_code: u32::from(exit_code),
+        check_point: 0,
+        wait_hint: if pending { 30_000 } else { 0 },
+    };
+    STATE.store(state, Ordering::SeqCst);
+    // SAFETY: the handle came from RegisterServiceCtrlHandlerExW and the
+    // status struct outlives the call
+    unsafe {
+        SetServiceStatus(STATUS_HANDLE.load(Ordering::SeqCst), &status);
+    }
+}
+
+unsafe extern "system" fn handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    _context: *mut c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_state(SERVICE_STOP_PENDING, 0);
+            shutdown::request();
+        }
+        SERVICE_CONTROL_PAUSE => {
+            pause::pause();
+            set_state(SERVICE_PAUSED, 0);
+        }
+        SERVICE_CONTROL_CONTINUE => {
+            pause::resume();
+            set_state(SERVICE_RUNNING, 0);
+        }
+        SERVICE_CONTROL_INTERROGATE => set_state(STATE.load(Ordering::SeqCst), 0),
+        _ => return ERROR_CALL_NOT_IMPLEMENTED,
+    }
+    NO_ERROR
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let name = wide(NAME);
+    // SAFETY: name is NUL-terminated and the handler has the HandlerEx ABI
+    let handle =
+        unsafe { RegisterServiceCtrlHandlerExW(name.as_ptr(), handler, std::ptr::null_mut()) };
+    if handle == 0 {
+        return;
+    }
+    STATUS_HANDLE.store(handle, Ordering::SeqCst);
+    set_state(SERVICE_START_PENDING, 0);
+
+    let program = PROGRAM.lock().unwrap().take();
+    set_state(SERVICE_RUNNING, 0);
+    let code = program.map_or(0, |program| program());
+    EXIT_CODE.store(code, Ordering::SeqCst);
+    set_state(SERVICE_STOPPED, code);
+}
+
+/// Hand the process over to the service control manager and run `program`
+/// as the service, returning its exit code once the service has stopped.
+pub fn run(program: impl FnOnce() -> u8 + Send + 'static) -> std::io::Result<u8> {
+    *PROGRAM.lock().unwrap() = Some(Box::new(program));
+    let mut name = wide(NAME);
+    let table = [
+        ServiceTableEntry {
+            name: name.as_mut_ptr(),
+            service_main: Some(service_main),
+        },
+        ServiceTableEntry {
+            name: std::ptr::null_mut(),
+            service_main: None,
+        },
+    ];
+    // SAFETY: the table is terminated by a null entry and outlives the call,
+    // which only returns once the service has stopped
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(EXIT_CODE.load(Ordering::SeqCst))
+}
+
+/// Logger writing to the Windows event log.
+pub struct EventLog {
+    source: isize,
+}
+
+impl EventLog {
+    /// Install as the global logger, at info level.
+    pub fn install() {
+        let name = wide(NAME);
+        // SAFETY: a null server name means the local computer
+        let source = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+        if log::set_boxed_logger(Box::new(EventLog { source })).is_ok() {
+            log::set_max_level(LevelFilter::Info);
+        }
+    }
+}
+
+impl Log for EventLog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.source != 0 && metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let kind = match record.level() {
+            Level::Error => EVENTLOG_ERROR_TYPE,
+            Level::Warn => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = wide(&record.args().to_string());
+        let strings = [message.as_ptr()];
+        // SAFETY: one NUL-terminated string is passed and no raw data
+        unsafe {
+            ReportEventW(
+                self.source,
+                kind,
+                0,
+                0,
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                std::ptr::null_mut(),
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn sc(args: &[&str]) -> std::io::Result<()> {
+    let status = Command::new("sc.exe").args(args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!(
+            "sc.exe {} failed: {}",
+            args[0], status
+        )))
+    }
+}
+
+/// Register a service that runs this executable with the current arguments.
+pub fn install() -> std::io::Result<()> {
+    let exe = std::env::current_exe()?;
+    let mut command_line = format!("\"{}\" --service", exe.display());
+    for arg in std::env::args().skip(1) {
+        if arg != "--install-service" {
+            command_line.push_str(&format!(" \"{}\"", arg));
+        }
+    }
+    sc(&[
+        "create",
+        NAME,
+        "binPath=",
+        &command_line,
+        "start=",
+        "auto",
+        "DisplayName=",
+        "transcoderexpress",
+    ])?;
+    sc(&[
+        "description",
+        NAME,
+        "Transcodes audio files to 16kHz mono WAV",
+    ])
+}
@@ -0,0 +1,210 @@
+//! SFTP delivery through the OpenSSH sftp executable.
+//!
+//! Authentication is by key only: sftp runs in batch mode, so password and
+//! host key prompts fail the upload instead of hanging it. Each file is
+//! uploaded under a hidden temporary name in its destination directory and
+//! renamed into place, so the receiving side never sees a partial file.
+use crate::sink::RemoteTarget;
+use crate::throttle;
+use log::debug;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// A server and directory, parsed from `sftp://[user@]host[:port]/path`.
+///
+/// As with curl, `/~/` at the start of the path refers to the login
+/// directory; any other path is absolute.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SftpLocation {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    /// Remote directory, empty for the login directory, otherwise ending
+    /// in `/`.
+    pub path: String,
+}
+
+impl SftpLocation {
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("sftp://")?;
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, authority),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            // Bracketed IPv6 addresses contain colons of their own
+            Some((host, port)) if !port.contains(']') => (host, Some(port.parse().ok()?)),
+            _ => (host_port, None),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return None;
+        }
+        let path = path.trim_end_matches('/');
+        let mut path = match path.strip_prefix('~') {
+            Some(home) => home.trim_start_matches('/').to_string(),
+            None => format!("/{}", path),
+        };
+        if !path.is_empty() && !path.ends_with('/') {
+            path.push('/');
+        }
+        Some(SftpLocation {
+            user: user.filter(|u| !u.is_empty()),
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+
+    fn destination(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        match &self.user {
+            Some(user) => format!("{}@{}", user, host),
+            None => host,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.path.strip_prefix('/') {
+            Some(path) => format!("sftp://{}/{}", self.destination(), path),
+            None => format!("sftp://{}/~/{}", self.destination(), self.path),
+        }
+    }
+}
+
+/// Authentication settings for the ssh connection.
+#[derive(Clone, Debug, Default)]
+pub struct SftpConfig {
+    /// Private key; defaults to ssh's own configuration and agent.
+    pub identity: Option<PathBuf>,
+    /// Known hosts file to check the server key against.
+    pub known_hosts: Option<PathBuf>,
+}
+
+/// Quote a path for an sftp batch file.
+fn quote(path: &str) -> std::io::Result<String> {
+    if path.contains(['\n', '\r']) {
+        return Err(std::io::Error::other(format!(
+            "cannot upload {:?} over SFTP: line break in name",
+            path
+        )));
+    }
+    Ok(format!(
+        "\"{}\"",
+        path.replace('\\', "\\\\").replace('"', "\\\"")
+    ))
+}
+
+/// Uploads outputs into a directory on an SFTP server.
+pub struct SftpTarget {
+    location: SftpLocation,
+    config: SftpConfig,
+}
+
+impl SftpTarget {
+    pub fn new(location: SftpLocation, config: SftpConfig) -> Self {
+        SftpTarget { location, config }
+    }
+
+    /// Run sftp with a batch of commands on its standard input.
+    fn batch(&self, commands: &str) -> std::io::Result<()> {
+        let mut command = Command::new("sftp");
+        command.args(["-q", "-b", "-"]).args([
+            "-o",
+            "BatchMode=yes",
+            "-o",
+            "ConnectTimeout=30",
+            "-o",
+            "ServerAliveInterval=15",
+        ]);
+        if let Some(identity) = &self.config.identity {
+            command.arg("-i").arg(identity);
+        }
+        if let Some(known_hosts) = &self.config.known_hosts {
+            let mut option = std::ffi::OsString::from("UserKnownHostsFile=");
+            option.push(known_hosts);
+            command.arg("-o").arg(option);
+        }
+        if let Some(port) = self.location.port {
+            command.arg("-P").arg(port.to_string());
+        }
+        if let Some(limit) = throttle::write_limit() {
+            // In Kbit/s
+            command.arg("-l").arg((limit * 8 / 1000).max(1).to_string());
+        }
+        let mut child = command
+            .arg(self.location.destination())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(commands.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(std::io::Error::other(format!(
+                "SFTP upload failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl RemoteTarget for SftpTarget {
+    fn upload(&self, file: &Path, name: &str, _content_type: &str) -> std::io::Result<()> {
+        let local = file.to_str().ok_or_else(|| {
+            std::io::Error::other(format!(
+                "cannot upload {:?} over SFTP: name is not valid UTF-8",
+                file
+            ))
+        })?;
+        let (dir, file_name) = match name.rsplit_once('/') {
+            Some((dir, file_name)) => (format!("{}{}/", self.location.path, dir), file_name),
+            None => (self.location.path.clone(), name),
+        };
+        let remote = format!("{}{}", dir, file_name);
+        let partial = format!("{}.{}.part", dir, file_name);
+
+        // Create missing directories below the target; failures are
+        // ignored since most of them already exist
+        let mut commands = String::new();
+        let mut parent = self.location.path.clone();
+        if let Some((dirs, _)) = name.rsplit_once('/') {
+            for part in dirs.split('/') {
+                parent.push_str(part);
+                commands.push_str(&format!("-mkdir {}\n", quote(&parent)?));
+                parent.push('/');
+            }
+        }
+        commands.push_str(&format!("put {} {}\n", quote(local)?, quote(&partial)?));
+        commands.push_str(&format!(
+            "rename {} {}\n",
+            quote(&partial)?,
+            quote(&remote)?
+        ));
+
+        debug!(
+            "Uploading {:?} to {}{}",
+            file,
+            self.location.describe(),
+            name
+        );
+        let result = self.batch(&commands);
+        if result.is_err() {
+            let _ = self.batch(&format!("-rm {}\n", quote(&partial)?));
+        }
+        result
+    }
+
+    fn describe(&self) -> String {
+        self.location.describe()
+    }
+}
@@ -0,0 +1,31 @@
+//! Graceful shutdown on SIGINT/SIGTERM.
+//!
+//! The signal handler only sets a flag; the main loop and the consumer poll
+//! it, so the job in flight finishes before the process exits.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handler(_signal: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Install the shutdown signal handlers.
+pub fn install() {
+    let handler = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only touches an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Whether a shutdown has been requested.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Request a shutdown, as if a signal had been received.
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
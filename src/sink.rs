@@ -0,0 +1,169 @@
+//! Where finished outputs go.
+//!
+//! A [`Sink`] chooses the path the backend writes to and receives the
+//! output once the job succeeds. The local output directory is the default;
+//! remote storage integrations implement the same trait, typically writing
+//! to a scratch directory and uploading in [`Sink::deliver`].
+use crate::JobResult;
+use clap::ValueEnum;
+use log::info;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+
+/// A destination for transcoded files.
+pub trait Sink: Send + Sync {
+    /// Local path the backend should write the output for `input` to.
+    fn output_path(&self, input: &Path) -> PathBuf;
+
+    /// Take ownership of a successful output. An error fails the job.
+    fn deliver(&self, result: &JobResult) -> std::io::Result<()>;
+}
+
+/// Writes outputs directly into a local directory.
+pub struct DirectorySink {
+    dir: PathBuf,
+}
+
+impl DirectorySink {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        DirectorySink { dir: dir.into() }
+    }
+}
+
+impl Sink for DirectorySink {
+    /// `<dir>/<input stem>_transcoded.wav`; only the last extension of the
+    /// input is replaced, and names need not be valid UTF-8.
+    fn output_path(&self, input: &Path) -> PathBuf {
+        let mut name = OsString::from(input.file_stem().unwrap_or_default());
+        name.push("_transcoded.wav");
+        self.dir.join(name)
+    }
+
+    fn deliver(&self, _result: &JobResult) -> std::io::Result<()> {
+        // The backend already wrote the file in place
+        Ok(())
+    }
+}
+
+/// What happens to the local copy of an output once it has been uploaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LocalCopy {
+    #[default]
+    Keep,
+    Delete,
+}
+
+/// Remote storage that finished files are uploaded to.
+pub trait RemoteTarget: Send + Sync {
+    /// Upload a local file under `name`, relative to the target's prefix
+    /// and with `/` separators.
+    fn upload(&self, file: &Path, name: &str, content_type: &str) -> std::io::Result<()>;
+
+    /// Human-readable location, for logs.
+    fn describe(&self) -> String;
+}
+
+/// Content type for a sidecar file, guessed from its extension.
+fn sidecar_content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => "application/json",
+        Some("txt" | "log") => "text/plain",
+        Some("csv") => "text/csv",
+        Some("xml") => "application/xml",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Writes outputs into a local directory like [`DirectorySink`], then
+/// uploads each output and its sidecars (files named `<output>.*` next to
+/// it) to a [`RemoteTarget`].
+pub struct UploadSink {
+    local: DirectorySink,
+    target: Box<dyn RemoteTarget>,
+    content_type: String,
+    local_copy: LocalCopy,
+}
+
+impl UploadSink {
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        target: Box<dyn RemoteTarget>,
+        content_type: impl Into<String>,
+        local_copy: LocalCopy,
+    ) -> Self {
+        UploadSink {
+            local: DirectorySink::new(dir),
+            target,
+            content_type: content_type.into(),
+            local_copy,
+        }
+    }
+
+    /// Remote name of a local file: its path below the output directory,
+    /// or just its file name if it was routed elsewhere.
+    fn remote_name(&self, path: &Path) -> String {
+        let relative = path
+            .strip_prefix(&self.local.dir)
+            .unwrap_or_else(|_| Path::new(path.file_name().unwrap_or_default()));
+        relative
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => Some(part.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Files next to `output` whose names start with `<output name>.`.
+    fn sidecars(output: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let (Some(dir), Some(name)) = (output.parent(), output.file_name()) else {
+            return Ok(Vec::new());
+        };
+        let mut prefix = name.to_os_string();
+        prefix.push(".");
+        let mut sidecars: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .as_encoded_bytes()
+                    .starts_with(prefix.as_encoded_bytes())
+            })
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect();
+        sidecars.sort();
+        Ok(sidecars)
+    }
+}
+
+impl Sink for UploadSink {
+    fn output_path(&self, input: &Path) -> PathBuf {
+        self.local.output_path(input)
+    }
+
+    fn deliver(&self, result: &JobResult) -> std::io::Result<()> {
+        let sidecars = Self::sidecars(&result.output)?;
+        self.target.upload(
+            &result.output,
+            &self.remote_name(&result.output),
+            &self.content_type,
+        )?;
+        for sidecar in &sidecars {
+            self.target.upload(
+                sidecar,
+                &self.remote_name(sidecar),
+                sidecar_content_type(sidecar),
+            )?;
+        }
+        info!("Uploaded {:?} to {}", result.output, self.target.describe());
+
+        if self.local_copy == LocalCopy::Delete {
+            for path in std::iter::once(&result.output).chain(&sidecars) {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
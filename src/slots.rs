@@ -0,0 +1,99 @@
+//! A limit on the jobs transcoded at once by all pipelines of a process,
+//! e.g. those of `--pipelines`, so that together they do not overload the
+//! machine.
+//!
+//! Slots are shared fairly: while other pipelines wait for one, a
+//! pipeline only gets another if it holds fewer than its share of the
+//! limit, so that a flood of files in one pipeline cannot keep the others
+//! waiting. A pipeline on its own may use every slot.
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex, OnceLock};
+
+static SLOTS: OnceLock<Slots> = OnceLock::new();
+
+/// Workers holding and waiting for slots, by pipeline.
+#[derive(Default)]
+struct State {
+    held: HashMap<String, usize>,
+    waiting: HashMap<String, usize>,
+}
+
+impl State {
+    /// Whether `pipeline` may take a slot now.
+    fn may_take(&self, pipeline: &str, limit: usize) -> bool {
+        if self.held.values().sum::<usize>() >= limit {
+            return false;
+        }
+        let others_waiting = self
+            .waiting
+            .iter()
+            .any(|(other, &count)| other != pipeline && count > 0);
+        if !others_waiting {
+            return true;
+        }
+        let mut pipelines: Vec<&String> = self
+            .held
+            .iter()
+            .chain(&self.waiting)
+            .filter(|(_, count)| **count > 0)
+            .map(|(name, _)| name)
+            .collect();
+        pipelines.sort();
+        pipelines.dedup();
+        let share = (limit / pipelines.len().max(1)).max(1);
+        self.held.get(pipeline).copied().unwrap_or_default() < share
+    }
+}
+
+struct Slots {
+    limit: usize,
+    state: Mutex<State>,
+    freed: Condvar,
+}
+
+/// Limit the jobs of all pipelines to `limit` at once; only the first
+/// call has an effect.
+pub fn set_limit(limit: usize) {
+    let _ = SLOTS.set(Slots {
+        limit: limit.max(1),
+        state: Mutex::new(State::default()),
+        freed: Condvar::new(),
+    });
+}
+
+/// A slot held by a job of a pipeline, given back when dropped.
+pub struct Slot {
+    pipeline: String,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let Some(slots) = SLOTS.get() else {
+            return;
+        };
+        let mut state = slots.state.lock().unwrap();
+        if let Some(held) = state.held.get_mut(&self.pipeline) {
+            *held = held.saturating_sub(1);
+        }
+        slots.freed.notify_all();
+    }
+}
+
+/// Wait for a slot for a job of `pipeline`, or `None` without a limit.
+pub fn acquire(pipeline: &str) -> Option<Slot> {
+    let slots = SLOTS.get()?;
+    let mut state = slots.state.lock().unwrap();
+    *state.waiting.entry(pipeline.to_string()).or_default() += 1;
+    while !state.may_take(pipeline, slots.limit) {
+        state = slots.freed.wait(state).unwrap();
+    }
+    if let Some(waiting) = state.waiting.get_mut(pipeline) {
+        *waiting -= 1;
+    }
+    *state.held.entry(pipeline.to_string()).or_default() += 1;
+    // With one fewer waiting, others may be in for their share
+    slots.freed.notify_all();
+    Some(Slot {
+        pipeline: pipeline.to_string(),
+    })
+}
@@ -0,0 +1,1015 @@
+//! Where input files come from.
+//!
+//! A [`Source`] feeds files into a pipeline through a [`Submitter`]. The
+//! local directory watcher is the default; remote storage integrations
+//! implement [`RemoteStore`] and are polled by a [`PollingSource`].
+#[cfg(feature = "fetch")]
+use crate::fetch;
+use crate::json;
+use crate::marker::DoneMarker;
+use crate::parts::Parts;
+use crate::sha256::Sha256;
+use crate::{Result, Submitter, health, shutdown};
+use log::{debug, error, info, warn};
+use notify::event::CreateKind;
+#[cfg(feature = "fetch")]
+use notify::event::{AccessKind, AccessMode};
+use notify::{
+    Event, EventKind, EventKind::Create, RecommendedWatcher, RecursiveMode, Watcher,
+    recommended_watcher,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+/// First and longest wait before trying again to watch a directory.
+const WATCH_RETRY: Duration = Duration::from_secs(5);
+const WATCH_RETRY_MAX: Duration = Duration::from_secs(300);
+/// How often the directories that cannot be watched are polled.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// Whether directories are watched one by one, as inotify does, so that
+/// running out of watches leaves only some of them unwatched.
+const PER_DIRECTORY: bool = cfg!(target_os = "linux");
+/// Names of the files that platforms and transfers leave behind, matched
+/// without regard to case: Finder metadata and AppleDouble files, Explorer
+/// thumbnails and folder settings, Office lock files, the partial
+/// downloads of browsers and curl, and rsync's temporary copies.
+const JUNK: [&str; 8] = [
+    ".DS_Store",
+    "._*",
+    "Thumbs.db",
+    "desktop.ini",
+    "~$*",
+    "*.part",
+    "*.crdownload",
+    ".*.*.??????",
+];
+/// rsync's directory of updates held back by `--delay-updates`.
+const RSYNC_DELAYED: &str = ".~tmp~";
+
+/// A place that yields files to transcode.
+pub trait Source: Send {
+    /// Queue the files that are already present, returning the count.
+    fn scan(&mut self, submitter: &Submitter) -> Result<usize>;
+
+    /// Keep queueing new files as they arrive, until the source is dropped.
+    fn watch(&mut self, submitter: Submitter) -> Result<()>;
+
+    /// Whether the source can still deliver files, for health checks.
+    fn healthy(&self) -> bool {
+        true
+    }
+
+    /// Human-readable location, for logs.
+    fn describe(&self) -> String;
+}
+
+/// A local directory, scanned recursively and watched for created files.
+pub struct DirectorySource {
+    dir: PathBuf,
+    #[cfg(feature = "fetch")]
+    urls: Option<Arc<Mutex<UrlFiles>>>,
+    parts: Option<Arc<Mutex<Parts>>>,
+    /// How long a file must be left alone after an event before it is
+    /// queued, so that a burst of events queues it once.
+    coalesce: Duration,
+    /// Leave out the [`JUNK`] files.
+    filter_junk: bool,
+    /// Queue inputs only once their marker is there.
+    done_marker: Option<DoneMarker>,
+    watcher: Arc<Mutex<Option<RecommendedWatcher>>>,
+    /// Queues the groups of parts as they complete.
+    assembler: Option<JoinHandle<()>>,
+    /// Watches directories as they are created and sets up the watcher
+    /// again while it cannot be, e.g. with the inotify limits of the
+    /// system exhausted.
+    watching: Option<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl DirectorySource {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        DirectorySource {
+            dir: dir.into(),
+            #[cfg(feature = "fetch")]
+            urls: None,
+            parts: None,
+            coalesce: Duration::ZERO,
+            filter_junk: true,
+            done_marker: None,
+            watcher: Arc::new(Mutex::new(None)),
+            assembler: None,
+            watching: None,
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Collect the parts of multi-part recordings and queue each group as
+    /// one job once it is complete, instead of the parts.
+    pub fn with_parts(mut self, parts: Parts) -> Self {
+        self.parts = Some(Arc::new(Mutex::new(parts)));
+        self
+    }
+
+    /// Queue a file only once `window` has passed without another event
+    /// for it, e.g. of an upload tool that creates, truncates and rewrites
+    /// it, rather than on its first.
+    pub fn with_coalescing(mut self, window: Duration) -> Self {
+        self.coalesce = window;
+        self
+    }
+
+    /// Leave out the files that platforms and transfers leave behind, e.g.
+    /// `.DS_Store` and `*.part`, as by default, or not.
+    pub fn with_junk_filter(mut self, filter: bool) -> Self {
+        self.filter_junk = filter;
+        self
+    }
+
+    /// Queue an input only once its marker, e.g. `call.wav.done` for
+    /// `call.wav`, is there too, and never the markers themselves.
+    pub fn with_done_marker(mut self, marker: DoneMarker) -> Self {
+        self.done_marker = Some(marker);
+        self
+    }
+
+    /// Treat `.url` files as links: download the HTTP(S) URL each one
+    /// holds into `work_dir` and transcode that, instead of the file.
+    #[cfg(feature = "fetch")]
+    pub fn with_url_files(mut self, work_dir: impl Into<PathBuf>) -> Self {
+        self.urls = Some(Arc::new(Mutex::new(UrlFiles {
+            work_dir: work_dir.into(),
+            queued: HashMap::new(),
+        })));
+        self
+    }
+}
+
+impl Source for DirectorySource {
+    fn scan(&mut self, submitter: &Submitter) -> Result<usize> {
+        let mut files = Vec::new();
+        scan_dir(&self.dir, &mut files)?;
+        if self.parts.is_some() {
+            // Playlists first, so that they take the parts they list
+            files.sort_by_key(|path| !crate::parts::is_playlist(path));
+        }
+        if self.filter_junk {
+            files.retain(|path| !is_junk(path));
+        }
+        if let Some(marker) = &self.done_marker {
+            files = files.iter().filter_map(|path| marker.ready(path)).collect();
+            files.sort();
+            files.dedup();
+        }
+        for path in &files {
+            if let Some(parts) = &self.parts
+                && parts.lock().unwrap().take(path)
+            {
+                continue;
+            }
+            #[cfg(feature = "fetch")]
+            if let Some(urls) = &self.urls
+                && is_url_file(path)
+            {
+                urls.lock().unwrap().submit(submitter, path);
+                continue;
+            }
+            // E.g. left queued by the last run
+            if let Some(id) = submitter.jobs().active_with_input(path) {
+                debug!("Not queueing {:?} again, job {} has it already", path, id);
+                continue;
+            }
+            submitter.submit(path);
+        }
+        // What is there already is as complete as it gets
+        if let Some(parts) = &self.parts {
+            parts.lock().unwrap().submit_ready(submitter, true);
+        }
+        Ok(files.len())
+    }
+
+    fn watch(&mut self, submitter: Submitter) -> Result<()> {
+        #[cfg(feature = "fetch")]
+        let urls = self.urls.clone();
+        if let Some(parts) = self.parts.clone() {
+            let submitter = submitter.clone();
+            let stop = self.stop.clone();
+            self.assembler = Some(std::thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) && !shutdown::requested() {
+                    std::thread::sleep(Duration::from_secs(1));
+                    parts.lock().unwrap().submit_ready(&submitter, false);
+                }
+            }));
+        }
+        let queue = Arrivals {
+            submitter,
+            #[cfg(feature = "fetch")]
+            urls,
+            parts: self.parts.clone(),
+            coalesce: self.coalesce,
+            filter_junk: self.filter_junk,
+            done_marker: self.done_marker.clone(),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let (created_dirs, new_dirs) = channel();
+        let dir = self.dir.clone();
+        let handler = queue.clone();
+        let start = move || -> notify::Result<RecommendedWatcher> {
+            let queue = handler.clone();
+            let created_dirs = created_dirs.clone();
+            let mut watcher = recommended_watcher(move |res: notify::Result<Event>| match res {
+                Ok(event) => {
+                    if PER_DIRECTORY && event.kind == Create(CreateKind::Folder) {
+                        for path in &event.paths {
+                            let _ = created_dirs.send(path.clone());
+                        }
+                    }
+                    queue.handle(&event)
+                }
+                Err(e) => error!("Watch error: {:?}", e),
+            })?;
+            let mode = match PER_DIRECTORY {
+                true => RecursiveMode::NonRecursive,
+                false => RecursiveMode::Recursive,
+            };
+            watcher.watch(&dir, mode)?;
+            Ok(watcher)
+        };
+        let mut tree = Tree {
+            dir: self.dir.clone(),
+            watcher: self.watcher.clone(),
+            unwatched: BTreeSet::new(),
+            seen: HashSet::new(),
+        };
+        // Keep the rest of the pipeline running, and the files queued so
+        // far, until the watcher can be set up
+        let mut failed_at = None;
+        match start() {
+            Ok(watcher) => {
+                *self.watcher.lock().unwrap() = Some(watcher);
+                if PER_DIRECTORY {
+                    tree.watch_below(&self.dir);
+                }
+            }
+            Err(e) => {
+                error!(
+                    "Failed to watch {}, trying again in {}: {}",
+                    self.dir.display(),
+                    humantime::format_duration(WATCH_RETRY),
+                    e
+                );
+                health::set(
+                    tree.condition(),
+                    format!("cannot watch {}: {}", self.dir.display(), e),
+                );
+                failed_at = Some(SystemTime::now());
+            }
+        }
+        let stop = self.stop.clone();
+        // Often enough for the coalescing window to be kept to within a
+        // quarter of it
+        let tick = match self.coalesce.is_zero() {
+            true => Duration::from_secs(1),
+            false => (self.coalesce / 4).clamp(Duration::from_millis(10), Duration::from_secs(1)),
+        };
+        self.watching = Some(std::thread::spawn(move || {
+            let mut wait = WATCH_RETRY;
+            let mut last = Instant::now();
+            while !stop.load(Ordering::SeqCst) && !shutdown::requested() {
+                queue.flush();
+                let Some(since) = failed_at else {
+                    if let Ok(dir) = new_dirs.recv_timeout(tick) {
+                        tree.watch_tree(dir);
+                    }
+                    if last.elapsed() >= POLL_INTERVAL {
+                        last = Instant::now();
+                        tree.poll(&queue);
+                    }
+                    continue;
+                };
+                std::thread::sleep(Duration::from_secs(1));
+                if last.elapsed() < wait {
+                    continue;
+                }
+                last = Instant::now();
+                match start() {
+                    Ok(started) => {
+                        info!("Watching {} again", tree.dir.display());
+                        *tree.watcher.lock().unwrap() = Some(started);
+                        health::clear(&tree.condition());
+                        failed_at = None;
+                        if PER_DIRECTORY {
+                            tree.watch_below(&tree.dir.clone());
+                        }
+                        // Files that arrived while nothing watched, as if
+                        // just created
+                        let mut files = Vec::new();
+                        if let Err(e) = scan_dir(&tree.dir, &mut files) {
+                            warn!("Failed to look for files created in {:?}: {}", tree.dir, e);
+                        }
+                        for path in files {
+                            let created = std::fs::metadata(&path)
+                                .and_then(|m| m.modified())
+                                .is_ok_and(|modified| modified >= since);
+                            if created {
+                                queue.found(path);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        wait = (wait * 2).min(WATCH_RETRY_MAX);
+                        warn!(
+                            "Still failing to watch {}, trying again in {}: {}",
+                            tree.dir.display(),
+                            humantime::format_duration(wait),
+                            e
+                        );
+                    }
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    fn healthy(&self) -> bool {
+        self.dir.is_dir()
+    }
+
+    fn describe(&self) -> String {
+        self.dir.display().to_string()
+    }
+}
+
+impl Drop for DirectorySource {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.assembler.take() {
+            let _ = thread.join();
+        }
+        if let Some(thread) = self.watching.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Queues the files of a [`DirectorySource`] as they arrive.
+#[derive(Clone)]
+struct Arrivals {
+    submitter: Submitter,
+    #[cfg(feature = "fetch")]
+    urls: Option<Arc<Mutex<UrlFiles>>>,
+    parts: Option<Arc<Mutex<Parts>>>,
+    coalesce: Duration,
+    filter_junk: bool,
+    done_marker: Option<DoneMarker>,
+    /// Files created within the coalescing window, by when their last
+    /// event came.
+    pending: Arc<Mutex<HashMap<PathBuf, Instant>>>,
+}
+
+impl Arrivals {
+    fn handle(&self, event: &Event) {
+        #[cfg(feature = "fetch")]
+        let is_url = self.urls.is_some() && event.paths.iter().any(|path| is_url_file(path));
+        #[cfg(not(feature = "fetch"))]
+        let is_url = false;
+        // Created directories go straight through
+        let file = !matches!(event.kind, Create(CreateKind::Folder));
+        if self.coalesce.is_zero() || is_url || !file {
+            return self.queue(event);
+        }
+        let mut pending = self.pending.lock().unwrap();
+        for path in &event.paths {
+            match event.kind {
+                Create(_) => {
+                    pending.insert(path.clone(), Instant::now());
+                }
+                EventKind::Remove(_) => {
+                    pending.remove(path);
+                }
+                // Writes to a file queued already are not its arrival
+                _ => {
+                    if let Some(last) = pending.get_mut(path) {
+                        *last = Instant::now();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queue the files whose coalescing window has passed.
+    fn flush(&self) {
+        let mut ready: Vec<PathBuf> = {
+            let mut pending = self.pending.lock().unwrap();
+            let ready: Vec<PathBuf> = pending
+                .iter()
+                .filter(|(_, last)| last.elapsed() >= self.coalesce)
+                .map(|(path, _)| path.clone())
+                .collect();
+            for path in &ready {
+                pending.remove(path);
+            }
+            ready
+        };
+        ready.sort();
+        for path in ready {
+            self.queue(&Event::new(Create(CreateKind::File)).add_path(path));
+        }
+    }
+
+    fn queue(&self, event: &Event) {
+        handle_event(
+            &self.submitter,
+            event,
+            #[cfg(feature = "fetch")]
+            self.urls.as_deref(),
+            self.parts.as_deref(),
+            self.filter_junk,
+            self.done_marker.as_ref(),
+        )
+    }
+
+    /// Queue a file found other than by an event, as if just created.
+    fn found(&self, path: PathBuf) {
+        self.handle(&Event::new(Create(CreateKind::File)).add_path(path))
+    }
+}
+
+/// The directories of a [`DirectorySource`] that are watched, one by one
+/// where the platform watches them so, and those below that could not be,
+/// e.g. with `fs.inotify.max_user_watches` exhausted, and are polled
+/// instead.
+struct Tree {
+    dir: PathBuf,
+    watcher: Arc<Mutex<Option<RecommendedWatcher>>>,
+    /// Directories that could not be watched, with all below them.
+    unwatched: BTreeSet<PathBuf>,
+    /// Files found below them so far.
+    seen: HashSet<PathBuf>,
+}
+
+impl Tree {
+    /// Name of the health condition of the directory.
+    fn condition(&self) -> String {
+        format!("watcher {}", self.dir.display())
+    }
+
+    /// Watch the directories below `dir`.
+    fn watch_below(&mut self, dir: &Path) {
+        let mut subdirs: Vec<PathBuf> = match std::fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+                .map(|entry| entry.path())
+                .collect(),
+            Err(e) => {
+                warn!("Failed to list {:?} to watch it: {}", dir, e);
+                return;
+            }
+        };
+        subdirs.sort();
+        for subdir in subdirs {
+            self.watch_tree(subdir);
+        }
+    }
+
+    /// Watch `dir` and the directories below it, polling those below the
+    /// first that cannot be watched.
+    fn watch_tree(&mut self, dir: PathBuf) {
+        let watched = match self.watcher.lock().unwrap().as_mut() {
+            Some(watcher) => watcher.watch(&dir, RecursiveMode::NonRecursive),
+            None => return,
+        };
+        match watched {
+            Ok(()) => self.watch_below(&dir),
+            Err(e) if matches!(e.kind, notify::ErrorKind::MaxFilesWatch) => {
+                if self.unwatched.is_empty() {
+                    error!(
+                        "Out of inotify watches at {:?}, polling it and any other directory that \
+                         cannot be watched every {}s instead; raise the limit, e.g. with \
+                         `sysctl fs.inotify.max_user_watches=524288`",
+                        dir,
+                        POLL_INTERVAL.as_secs()
+                    );
+                }
+                // What is there already is not new
+                let mut files = Vec::new();
+                let _ = scan_dir(&dir, &mut files);
+                self.seen.extend(files);
+                self.unwatched.insert(dir);
+                self.report();
+            }
+            // E.g. removed again in the meantime
+            Err(e) => debug!("Failed to watch {:?}: {}", dir, e),
+        }
+    }
+
+    /// Queue the files new below the unwatched directories, and watch the
+    /// directories that can be watched by now.
+    fn poll(&mut self, queue: &Arrivals) {
+        if self.unwatched.is_empty() {
+            return;
+        }
+        let mut found = HashSet::new();
+        for dir in &self.unwatched {
+            let mut files = Vec::new();
+            if let Err(e) = scan_dir(dir, &mut files) {
+                debug!("Failed to poll {:?}: {}", dir, e);
+            }
+            for path in files {
+                if !self.seen.contains(&path) {
+                    queue.found(path.clone());
+                }
+                found.insert(path);
+            }
+        }
+        self.seen = found;
+        for dir in std::mem::take(&mut self.unwatched) {
+            let watched = match self.watcher.lock().unwrap().as_mut() {
+                Some(watcher) => watcher.watch(&dir, RecursiveMode::NonRecursive),
+                None => return,
+            };
+            match watched {
+                Ok(()) => {
+                    info!("Watching {:?} again", dir);
+                    self.seen.retain(|path| !path.starts_with(&dir));
+                    self.watch_below(&dir);
+                }
+                Err(e) if matches!(e.kind, notify::ErrorKind::MaxFilesWatch) => {
+                    self.unwatched.insert(dir);
+                }
+                Err(e) => {
+                    debug!("Failed to watch {:?}: {}", dir, e);
+                    self.seen.retain(|path| !path.starts_with(&dir));
+                }
+            }
+        }
+        self.report();
+    }
+
+    /// Report the directories polled as degrading health, if any.
+    fn report(&self) {
+        match self.unwatched.len() {
+            0 => health::clear(&self.condition()),
+            count => health::set(
+                self.condition(),
+                format!(
+                    "{} directories polled, out of inotify watches (fs.inotify.max_user_watches)",
+                    count
+                ),
+            ),
+        }
+    }
+}
+
+/// An object listed by a [`RemoteStore`].
+#[derive(Clone, Debug)]
+pub struct RemoteObject {
+    /// Name relative to the store's prefix, with `/` separators.
+    pub key: String,
+    /// Changes whenever the object's contents change, e.g. an ETag.
+    pub version: String,
+    pub size: u64,
+}
+
+/// Remote storage that can be listed and downloaded from.
+pub trait RemoteStore: Send + Sync {
+    /// Every object below the configured prefix.
+    fn list(&self) -> std::io::Result<Vec<RemoteObject>>;
+
+    /// Download one object to a local path.
+    fn fetch(&self, key: &str, dest: &Path) -> std::io::Result<()>;
+
+    /// Human-readable location, for logs.
+    fn describe(&self) -> String;
+}
+
+/// Version of every object already queued or deliberately ignored, kept
+/// in an append-only JSON lines file so that nothing is processed twice
+/// across restarts.
+struct SeenFiles {
+    versions: HashMap<String, String>,
+    file: File,
+    /// Whether the file was just created, i.e. this is the first run.
+    fresh: bool,
+}
+
+impl SeenFiles {
+    /// Load the state file, compacting it to one line per object.
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let (versions, fresh) = match std::fs::read_to_string(path) {
+            Ok(text) => {
+                let versions = text
+                    .lines()
+                    .filter_map(json::parse)
+                    .filter_map(|entry| {
+                        let field = |name| Some(entry.get(name)?.as_str()?.to_string());
+                        Some((field("key")?, field("version")?))
+                    })
+                    .collect();
+                (versions, false)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (HashMap::new(), true),
+            Err(e) => return Err(e),
+        };
+        let mut seen = SeenFiles {
+            versions: HashMap::new(),
+            file: File::create(path.with_extension("tmp"))?,
+            fresh,
+        };
+        for (key, version) in versions {
+            seen.insert(key, version)?;
+        }
+        std::fs::rename(path.with_extension("tmp"), path)?;
+        seen.file = OpenOptions::new().append(true).open(path)?;
+        Ok(seen)
+    }
+
+    fn contains(&self, object: &RemoteObject) -> bool {
+        self.versions.get(&object.key) == Some(&object.version)
+    }
+
+    fn insert(&mut self, key: String, version: String) -> std::io::Result<()> {
+        let line = json::Object::new()
+            .str("key", &key)
+            .str("version", &version)
+            .finish();
+        writeln!(self.file, "{}", line)?;
+        self.versions.insert(key, version);
+        Ok(())
+    }
+}
+
+/// State shared between a [`PollingSource`] and its polling thread.
+struct Poller {
+    store: Box<dyn RemoteStore>,
+    work_dir: PathBuf,
+    seen: Mutex<SeenFiles>,
+    healthy: AtomicBool,
+    stop: AtomicBool,
+}
+
+impl Poller {
+    /// Local path for a downloaded object, never outside the work dir.
+    fn local_path(&self, key: &str) -> PathBuf {
+        let relative: PathBuf = Path::new(key)
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect();
+        self.work_dir.join(relative)
+    }
+
+    /// List the store, returning the objects not seen before.
+    fn new_objects(&self) -> std::io::Result<Vec<RemoteObject>> {
+        let objects = self.store.list()?;
+        let seen = self.seen.lock().unwrap();
+        Ok(objects
+            .into_iter()
+            .filter(|o| !o.key.ends_with('/'))
+            .filter(|o| !seen.contains(o))
+            .collect())
+    }
+
+    /// Download and queue new objects, returning how many were queued.
+    fn poll(&self, submitter: &Submitter) -> std::io::Result<usize> {
+        let mut queued = 0;
+        for object in self.new_objects()? {
+            let dest = self.local_path(&object.key);
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            debug!("Fetching {} ({} bytes)", object.key, object.size);
+            match self.store.fetch(&object.key, &dest) {
+                Ok(()) => {
+                    info!("Fetched {} from {}", object.key, self.store.describe());
+                    submitter.submit_fetched(&dest);
+                    queued += 1;
+                }
+                // Not marked as seen, so it is retried on the next poll
+                Err(e) => {
+                    error!("Failed to fetch {}: {}", object.key, e);
+                    continue;
+                }
+            }
+            self.seen
+                .lock()
+                .unwrap()
+                .insert(object.key, object.version)?;
+        }
+        Ok(queued)
+    }
+}
+
+/// Periodically lists a [`RemoteStore`], downloading new and changed
+/// objects into a work directory and queueing the local copies.
+///
+/// Queued objects are recorded in a state file in the work directory, so a
+/// restart or a later batch run only picks up what it has not seen yet.
+pub struct PollingSource {
+    poller: Arc<Poller>,
+    interval: Duration,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PollingSource {
+    pub fn new(
+        store: Box<dyn RemoteStore>,
+        work_dir: PathBuf,
+        interval: Duration,
+    ) -> std::io::Result<Self> {
+        // Several locations can share a work dir, e.g. prefixes of a bucket
+        let mut hasher = Sha256::default();
+        hasher.update(store.describe().as_bytes());
+        std::fs::create_dir_all(&work_dir)?;
+        let state = work_dir.join(format!(".seen-{}.jsonl", &hasher.hex()[..16]));
+        let seen = SeenFiles::open(&state)?;
+        Ok(PollingSource {
+            poller: Arc::new(Poller {
+                store,
+                work_dir,
+                seen: Mutex::new(seen),
+                healthy: AtomicBool::new(true),
+                stop: AtomicBool::new(false),
+            }),
+            interval,
+            thread: None,
+        })
+    }
+}
+
+impl Source for PollingSource {
+    fn scan(&mut self, submitter: &Submitter) -> Result<usize> {
+        Ok(self.poller.poll(submitter)?)
+    }
+
+    fn watch(&mut self, submitter: Submitter) -> Result<()> {
+        // Like the directory watcher, the first run only transcodes objects
+        // arriving from now on; anything already there is taken as a
+        // baseline. Later runs catch up on what arrived in between.
+        if self.poller.seen.lock().unwrap().fresh {
+            let baseline = self.poller.new_objects()?;
+            let mut seen = self.poller.seen.lock().unwrap();
+            for object in baseline {
+                seen.insert(object.key, object.version)?;
+            }
+        }
+
+        let poller = self.poller.clone();
+        let interval = self.interval;
+        self.thread = Some(std::thread::spawn(move || {
+            let mut last = Instant::now();
+            while !poller.stop.load(Ordering::SeqCst) && !shutdown::requested() {
+                std::thread::sleep(Duration::from_secs(1).min(interval));
+                if last.elapsed() < interval {
+                    continue;
+                }
+                last = Instant::now();
+                match poller.poll(&submitter) {
+                    Ok(_) => poller.healthy.store(true, Ordering::SeqCst),
+                    Err(e) => {
+                        warn!("Polling {} failed: {}", poller.store.describe(), e);
+                        poller.healthy.store(false, Ordering::SeqCst);
+                    }
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    fn healthy(&self) -> bool {
+        self.poller.healthy.load(Ordering::SeqCst)
+    }
+
+    fn describe(&self) -> String {
+        self.poller.store.describe()
+    }
+}
+
+impl Drop for PollingSource {
+    fn drop(&mut self) {
+        self.poller.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// `.url` drop files being followed.
+#[cfg(feature = "fetch")]
+struct UrlFiles {
+    /// Scratch directory for the downloads.
+    work_dir: PathBuf,
+    /// URL last queued for each file, which is seen both when it is created
+    /// and when its writer closes it.
+    queued: HashMap<PathBuf, String>,
+}
+
+#[cfg(feature = "fetch")]
+impl UrlFiles {
+    /// Queue the download of the URL in `path`, if it holds one yet.
+    fn submit(&mut self, submitter: &Submitter, path: &Path) {
+        match fetch::url_file(path) {
+            Ok(Some(url)) => {
+                info!("Found {} in {:?}, downloading it", url, path);
+                fetch::submit_url(submitter, &self.work_dir, &url);
+                self.queued.insert(path.to_path_buf(), url);
+            }
+            Ok(None) => debug!("No URL in {:?} yet", path),
+            Err(e) => warn!("Failed to read {:?}: {}", path, e),
+        }
+    }
+
+    /// Like [`UrlFiles::submit`], unless the same URL was already queued
+    /// for the file.
+    fn submit_changed(&mut self, submitter: &Submitter, path: &Path) {
+        if let Ok(Some(url)) = fetch::url_file(path)
+            && self.queued.get(path) == Some(&url)
+        {
+            return;
+        }
+        self.submit(submitter, path);
+    }
+}
+
+#[cfg(feature = "fetch")]
+fn is_url_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("url"))
+}
+
+/// Whether `path` is one of the [`JUNK`] files, or below rsync's
+/// directory of delayed updates.
+fn is_junk(path: &Path) -> bool {
+    if path
+        .components()
+        .any(|c| c.as_os_str() == std::ffi::OsStr::new(RSYNC_DELAYED))
+    {
+        return true;
+    }
+    let Some(name) = path.file_name() else {
+        return false;
+    };
+    let name: Vec<char> = name.to_string_lossy().to_lowercase().chars().collect();
+    JUNK.iter().any(|junk| {
+        let junk: Vec<char> = junk.to_lowercase().chars().collect();
+        crate::raw::glob_matches(&junk, &name)
+    })
+}
+
+/// Handle file creation events, and for `.url` files also the writes,
+/// leaving out the junk files with `filter_junk` and, with `done_marker`,
+/// queueing inputs once their marker is there.
+fn handle_event(
+    submitter: &Submitter,
+    event: &Event,
+    #[cfg(feature = "fetch")] urls: Option<&Mutex<UrlFiles>>,
+    parts: Option<&Mutex<Parts>>,
+    filter_junk: bool,
+    done_marker: Option<&DoneMarker>,
+) {
+    #[cfg(feature = "fetch")]
+    if let Some(urls) = urls
+        && event.paths.iter().any(|path| is_url_file(path))
+    {
+        let written = matches!(
+            event.kind,
+            Create(_) | EventKind::Access(AccessKind::Close(AccessMode::Write))
+        );
+        if written {
+            for path in event.paths.iter().filter(|p| is_url_file(p) && p.is_file()) {
+                urls.lock().unwrap().submit_changed(submitter, path);
+            }
+        }
+        return;
+    }
+    if let notify::Event {
+        kind: Create(_),
+        paths,
+        ..
+    } = event
+    {
+        for path in paths {
+            if filter_junk && is_junk(path) {
+                debug!("Ignoring {:?}, left behind by a platform or transfer", path);
+                continue;
+            }
+            let path = match done_marker.map(|marker| marker.ready(path)) {
+                None => path.clone(),
+                Some(Some(input)) => input,
+                Some(None) => {
+                    debug!(
+                        "Not queueing {:?} before its marker and input are there",
+                        path
+                    );
+                    continue;
+                }
+            };
+            let path = &path;
+            if let Some(parts) = parts
+                && parts.lock().unwrap().take(path)
+            {
+                continue;
+            }
+            if let Some(id) = submitter.jobs().active_with_input(path) {
+                debug!("Not queueing {:?} again, job {} has it already", path, id);
+                continue;
+            }
+            info!("File created, adding to queue: {:?}", path);
+            submitter.submit(path);
+        }
+    }
+}
+
+/// The files below `dir` that have no output, and no job queued or running
+/// for them, e.g. as their events were missed, each with the output it
+/// would get; with `filter_junk` without the junk files, and with
+/// `done_marker` only the inputs whose marker is there.
+pub fn missing_outputs(
+    submitter: &Submitter,
+    dir: &Path,
+    filter_junk: bool,
+    done_marker: Option<&DoneMarker>,
+) -> std::io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut inputs = Vec::new();
+    scan_dir(dir, &mut inputs)?;
+    let pending: HashSet<PathBuf> = submitter
+        .jobs()
+        .list(None)
+        .into_iter()
+        .filter(|record| !record.status.finished())
+        .map(|record| record.input)
+        .collect();
+    Ok(inputs
+        .into_iter()
+        .filter(|input| !(filter_junk && is_junk(input)))
+        .filter(|input| {
+            done_marker.is_none_or(|marker| marker.ready(input).as_ref() == Some(input))
+        })
+        .filter(|input| !pending.contains(input))
+        .filter_map(|input| {
+            let output = submitter.output_path(&input)?;
+            (!output.exists()).then_some((input, output))
+        })
+        .collect())
+}
+
+/// Recursively collect the regular files below a directory, in sorted order.
+pub(crate) fn scan_dir(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            scan_dir(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_junk;
+    use std::path::Path;
+
+    #[test]
+    fn platform_and_transfer_leftovers_are_junk() {
+        for path in [
+            "in/.DS_Store",
+            "in/._call.wav",
+            "in/Thumbs.db",
+            "in/thumbs.db",
+            "in/desktop.ini",
+            "in/~$notes.docx",
+            "in/call.wav.part",
+            "in/call.wav.crdownload",
+            "in/.call.wav.Ab12Cd",
+            "in/.~tmp~/call.wav",
+        ] {
+            assert!(is_junk(Path::new(path)), "{}", path);
+        }
+    }
+
+    #[test]
+    fn inputs_like_junk_are_not() {
+        for path in [
+            "in/call.wav",
+            "in/.call.wav",
+            "in/.profile.backup",
+            "in/.call.wav.Ab12C",
+            "in/call.partial",
+            "in/my~$call.wav",
+            "in/tmp/call.wav",
+        ] {
+            assert!(!is_junk(Path::new(path)), "{}", path);
+        }
+    }
+}
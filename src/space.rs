@@ -0,0 +1,72 @@
+//! Holding the workers back while the output or work filesystem is nearly
+//! full, as every job started then would only fail, or leave a truncated
+//! output behind.
+use std::path::{Path, PathBuf};
+
+/// Bytes that must be free on the filesystems of some directories.
+#[derive(Clone, Debug)]
+pub struct FreeSpace {
+    pub min_free: u64,
+    /// E.g. the output and work directories.
+    pub dirs: Vec<PathBuf>,
+}
+
+impl FreeSpace {
+    /// The directories with less than `min_free` bytes free, with how much
+    /// is; those that cannot be looked at are left out.
+    pub fn short(&self, min_free: u64) -> Vec<(&Path, u64)> {
+        self.dirs
+            .iter()
+            .filter_map(|dir| Some((dir.as_path(), available(dir).ok()?)))
+            .filter(|&(_, free)| free < min_free)
+            .collect()
+    }
+}
+
+/// Bytes free for unprivileged use on the filesystem of `dir`.
+#[cfg(unix)]
+pub fn available(dir: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `path` is NUL-terminated and `stat` is written by the call
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+unsafe extern "system" {
+    fn GetDiskFreeSpaceExW(
+        directory: *const u16,
+        free_to_caller: *mut u64,
+        total: *mut u64,
+        total_free: *mut u64,
+    ) -> i32;
+}
+
+/// Bytes free for this user on the volume of `dir`.
+#[cfg(windows)]
+pub fn available(dir: &Path) -> std::io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    let wide: Vec<u16> = dir.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut free = 0;
+    // SAFETY: `wide` is NUL-terminated and the totals may be null
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            wide.as_ptr(),
+            &mut free,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    match ok {
+        0 => Err(std::io::Error::last_os_error()),
+        _ => Ok(free),
+    }
+}
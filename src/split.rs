@@ -0,0 +1,135 @@
+//! Splitting of multichannel inputs, e.g. call recordings with one party
+//! on each channel, into a mono file per channel next to the output.
+//!
+//! Each channel goes to `<output>.<name>.wav`, named by its role where
+//! given, so that downstream diarization does not have to guess which file
+//! is which speaker, and `<output>.channels.json` maps the channels to
+//! their files. Both are sidecars of the output, delivered with it.
+use crate::backend;
+use crate::json;
+use crate::preset::Preset;
+use crate::raw::{self, RawInput};
+use crate::sandbox;
+use log::debug;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How multichannel inputs are split.
+#[derive(Clone, Debug, Default)]
+pub struct Split {
+    /// Names of the channels in order, e.g. `agent` and `customer`;
+    /// channels without one are called `ch<N>`, counting from one.
+    pub names: Vec<String>,
+    /// Even out the level of each channel, as of the output.
+    pub agc: bool,
+    /// Kill ffmpeg if splitting takes longer than this.
+    pub timeout: Option<Duration>,
+}
+
+impl Split {
+    /// The name of channel `index`, counting from zero.
+    fn name(&self, index: usize) -> String {
+        self.names
+            .get(index)
+            .cloned()
+            .unwrap_or_else(|| format!("ch{}", index + 1))
+    }
+
+    /// Write each channel of `input` to its own file next to `output`, in
+    /// the format of `preset`, returning the files; none for mono inputs.
+    pub(crate) fn split(
+        &self,
+        input: &Path,
+        output: &Path,
+        raw_inputs: &[RawInput],
+        preset: Preset,
+    ) -> Result<Vec<PathBuf>, String> {
+        let raw = raw::find(raw_inputs, input);
+        let channels = match raw {
+            Some(raw) => usize::from(raw.channels),
+            None => backend::probe_channels(input)
+                .map(|(channels, _)| channels)
+                .ok_or_else(|| format!("ffprobe found no channels in {:?}", input))?,
+        };
+        if channels < 2 {
+            debug!("Not splitting {:?}, which has one channel", input);
+            return Ok(Vec::new());
+        }
+        let files: Vec<PathBuf> = (0..channels)
+            .map(|i| sidecar(output, &format!("{}.wav", self.name(i))))
+            .collect();
+        let partials: Vec<PathBuf> = files
+            .iter()
+            .map(|file| file.with_extension("wav.partial"))
+            .collect();
+
+        let outputs: Vec<&Path> = partials.iter().map(PathBuf::as_path).collect();
+        let mut command = sandbox::command("ffmpeg", &[input], &outputs);
+        command.args(["-hide_banner", "-nostdin", "-y"]);
+        command.args(backend::input_options(raw_inputs, input));
+        command.arg("-i").arg(backend::path_arg(input));
+        for (i, partial) in partials.iter().enumerate() {
+            command
+                .args(["-map", "0:a:0", "-af"])
+                .arg(if self.agc {
+                    format!("pan=mono|c0=c{},{}", i, backend::AGC_FILTER)
+                } else {
+                    format!("pan=mono|c0=c{}", i)
+                })
+                .args(preset.output_options())
+                .args(["-f", "wav"])
+                .arg(backend::path_arg(partial));
+        }
+        let result = backend::run(&mut command, self.timeout).map_err(|e| e.to_string());
+        let result = match result {
+            Ok(result) if result.status.success() && !result.timed_out => Ok(()),
+            Ok(result) if result.timed_out => Err("ffmpeg timed out".to_string()),
+            Ok(result) => Err(result
+                .stderr
+                .lines()
+                .last()
+                .unwrap_or("ffmpeg failed")
+                .to_string()),
+            Err(e) => Err(e),
+        }
+        .and_then(|()| {
+            for (partial, file) in partials.iter().zip(&files) {
+                std::fs::rename(partial, file).map_err(|e| e.to_string())?;
+            }
+            let record = json::Object::new()
+                .str("input", &input.to_string_lossy())
+                .str("output", &output.to_string_lossy())
+                .raw(
+                    "channels",
+                    &json::array(files.iter().enumerate().map(|(i, file)| {
+                        json::Object::new()
+                            .num("index", i)
+                            .str("name", &self.name(i))
+                            .str("file", &file.to_string_lossy())
+                            .finish()
+                    })),
+                )
+                .finish();
+            let map = sidecar(output, "channels.json");
+            let partial = map.with_extension("json.partial");
+            std::fs::write(&partial, record + "\n")
+                .and_then(|()| std::fs::rename(&partial, &map))
+                .map_err(|e| e.to_string())
+        });
+        if let Err(e) = result {
+            for path in partials.iter().chain(&files) {
+                let _ = std::fs::remove_file(path);
+            }
+            return Err(e);
+        }
+        Ok(files)
+    }
+}
+
+/// `<output>.<suffix>`, e.g. `call_transcoded.wav.agent.wav`.
+fn sidecar(output: &Path, suffix: &str) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
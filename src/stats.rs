@@ -0,0 +1,448 @@
+//! Run summary statistics, and the same over the history in an audit log.
+use crate::audit::Entry;
+use crate::{JobResult, json};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Error classes listed by [`History::print`], the most frequent.
+const TOP_ERRORS: usize = 5;
+
+/// Counters accumulated over one run of the program.
+pub struct RunStats {
+    processed: u64,
+    skipped: u64,
+    failed: u64,
+    /// Outputs the checks warned about.
+    warned: u64,
+    input_bytes: u64,
+    output_bytes: u64,
+    audio: Duration,
+    transcoding: Duration,
+    started: Instant,
+    /// Per-stage durations of every job, when timings are enabled.
+    stages: Option<Vec<(&'static str, Vec<Duration>)>>,
+}
+
+/// Format a byte count with a binary unit.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Format a duration as `h:mm:ss.s`.
+fn format_duration(duration: Duration) -> String {
+    // Rounded first, so that 59.96s carries into the minutes
+    let tenths = (duration.as_secs_f64() * 10.0).round() as u64;
+    format!(
+        "{}:{:02}:{:04.1}",
+        tenths / 36000,
+        tenths / 600 % 60,
+        (tenths % 600) as f64 / 10.0
+    )
+}
+
+/// Nearest-rank percentile of a sorted, non-empty slice.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+impl RunStats {
+    /// Start counting; wall-clock time is measured from here.
+    pub fn start(timings: bool) -> Self {
+        RunStats {
+            processed: 0,
+            skipped: 0,
+            failed: 0,
+            warned: 0,
+            input_bytes: 0,
+            output_bytes: 0,
+            audio: Duration::ZERO,
+            transcoding: Duration::ZERO,
+            started: Instant::now(),
+            stages: timings.then(Vec::new),
+        }
+    }
+
+    /// Count a path that was not transcoded.
+    pub fn skipped(&mut self) {
+        self.skipped += 1;
+    }
+
+    /// Add a finished job to the totals.
+    pub fn record(&mut self, outcome: &JobResult) {
+        if let Some(stages) = self.stages.as_mut() {
+            for &(stage, duration) in &outcome.stages {
+                match stages.iter_mut().find(|(s, _)| *s == stage) {
+                    Some((_, durations)) => durations.push(duration),
+                    None => stages.push((stage, vec![duration])),
+                }
+            }
+        }
+        if outcome.error.is_some() {
+            self.failed += 1;
+            return;
+        }
+        self.processed += 1;
+        if !outcome.warnings.is_empty() {
+            self.warned += 1;
+        }
+        self.transcoding += outcome.elapsed;
+        self.input_bytes += outcome.input_bytes;
+        self.output_bytes += outcome.output_bytes;
+        self.audio += outcome.audio.unwrap_or_default();
+    }
+
+    /// Jobs that were transcoded.
+    pub fn processed(&self) -> u64 {
+        self.processed
+    }
+
+    /// Jobs that failed.
+    pub fn failed(&self) -> u64 {
+        self.failed
+    }
+
+    /// Print the summary table to stdout.
+    pub fn print(&self) {
+        let realtime = if self.transcoding.is_zero() {
+            0.0
+        } else {
+            self.audio.as_secs_f64() / self.transcoding.as_secs_f64()
+        };
+        let rows = [
+            ("Files processed", self.processed.to_string()),
+            ("Files skipped", self.skipped.to_string()),
+            ("Files failed", self.failed.to_string()),
+            ("Files flagged", self.warned.to_string()),
+            ("Input size", format_bytes(self.input_bytes)),
+            ("Output size", format_bytes(self.output_bytes)),
+            ("Audio duration", format_duration(self.audio)),
+            ("Wall-clock time", format_duration(self.started.elapsed())),
+            ("Realtime factor", format!("{:.1}x", realtime)),
+        ];
+        println!("Run summary");
+        for (label, value) in rows {
+            println!("  {:<18}{:>14}", label, value);
+        }
+
+        if let Some(stages) = &self.stages {
+            println!("Stage timings (ms)");
+            println!(
+                "  {:<18}{:>10}{:>10}{:>10}{:>10}",
+                "Stage", "p50", "p90", "p99", "max"
+            );
+            for (stage, durations) in stages {
+                let mut sorted = durations.clone();
+                sorted.sort();
+                let ms = |p: f64| percentile(&sorted, p).as_secs_f64() * 1000.0;
+                println!(
+                    "  {:<18}{:>10.1}{:>10.1}{:>10.1}{:>10.1}",
+                    stage,
+                    ms(50.0),
+                    ms(90.0),
+                    ms(99.0),
+                    ms(100.0)
+                );
+            }
+        }
+    }
+}
+
+/// Totals over the jobs of an audit log that finished in a time window.
+pub struct History {
+    from: Option<SystemTime>,
+    to: Option<SystemTime>,
+    succeeded: u64,
+    failed: u64,
+    audio: Duration,
+    /// Time from start to finish of the jobs that succeeded.
+    busy: Duration,
+    input_bytes: u64,
+    /// Failures by error class, most frequent first.
+    errors: Vec<(String, u64)>,
+}
+
+impl History {
+    /// Sum up the `entries` that finished from `since` until `until`, or
+    /// now; without `since` the window starts with the first entry, and
+    /// without either it ends with the last.
+    pub fn of(entries: &[Entry], since: Option<SystemTime>, until: Option<SystemTime>) -> Self {
+        let entries: Vec<&Entry> = entries
+            .iter()
+            .filter(|e| since.is_none_or(|since| e.finished >= since))
+            .filter(|e| until.is_none_or(|until| e.finished <= until))
+            .collect();
+        let mut history = History {
+            from: since.or_else(|| entries.iter().map(|e| e.started).min()),
+            to: match (since, until) {
+                (_, Some(until)) => Some(until),
+                (Some(_), None) => Some(SystemTime::now()),
+                (None, None) => entries.iter().map(|e| e.finished).max(),
+            },
+            succeeded: 0,
+            failed: 0,
+            audio: Duration::ZERO,
+            busy: Duration::ZERO,
+            input_bytes: 0,
+            errors: Vec::new(),
+        };
+        for entry in entries {
+            if !entry.succeeded {
+                history.failed += 1;
+                let class = entry.error_class.as_deref().unwrap_or("unknown");
+                match history.errors.iter_mut().find(|(c, _)| c == class) {
+                    Some((_, count)) => *count += 1,
+                    None => history.errors.push((class.to_string(), 1)),
+                }
+                continue;
+            }
+            history.succeeded += 1;
+            history.input_bytes += entry.input_bytes;
+            history.audio += Duration::from_secs_f64(entry.audio_seconds.unwrap_or(0.0).max(0.0));
+            history.busy += entry
+                .finished
+                .duration_since(entry.started)
+                .unwrap_or_default();
+        }
+        history
+            .errors
+            .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        history
+    }
+
+    fn window(&self) -> Duration {
+        match (self.from, self.to) {
+            (Some(from), Some(to)) => to.duration_since(from).unwrap_or_default(),
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn jobs_per_hour(&self) -> f64 {
+        let hours = self.window().as_secs_f64() / 3600.0;
+        if hours > 0.0 {
+            (self.succeeded + self.failed) as f64 / hours
+        } else {
+            0.0
+        }
+    }
+
+    fn failure_rate(&self) -> f64 {
+        match self.succeeded + self.failed {
+            0 => 0.0,
+            jobs => self.failed as f64 / jobs as f64,
+        }
+    }
+
+    fn realtime(&self) -> f64 {
+        if self.busy.is_zero() {
+            0.0
+        } else {
+            self.audio.as_secs_f64() / self.busy.as_secs_f64()
+        }
+    }
+
+    /// Print the summary table to stdout.
+    pub fn print(&self) {
+        let timestamp = |t: Option<SystemTime>| {
+            t.map_or_else(
+                || "-".to_string(),
+                |t| humantime::format_rfc3339_seconds(t).to_string(),
+            )
+        };
+        let rows = [
+            ("From", timestamp(self.from)),
+            ("Until", timestamp(self.to)),
+            ("Jobs succeeded", self.succeeded.to_string()),
+            ("Jobs failed", self.failed.to_string()),
+            (
+                "Failure rate",
+                format!("{:.1}%", self.failure_rate() * 100.0),
+            ),
+            ("Jobs per hour", format!("{:.1}", self.jobs_per_hour())),
+            ("Input size", format_bytes(self.input_bytes)),
+            ("Audio duration", format_duration(self.audio)),
+            ("Realtime factor", format!("{:.1}x", self.realtime())),
+        ];
+        println!("Job history");
+        for (label, value) in rows {
+            println!("  {:<18}{:>24}", label, value);
+        }
+        if !self.errors.is_empty() {
+            println!("Top error classes");
+            for (class, count) in self.errors.iter().take(TOP_ERRORS) {
+                println!("  {:<30}{:>12}", class, count);
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let timestamp =
+            |t: Option<SystemTime>| t.map(|t| humantime::format_rfc3339_seconds(t).to_string());
+        json::Object::new()
+            .opt_str("from", timestamp(self.from).as_deref())
+            .opt_str("until", timestamp(self.to).as_deref())
+            .num("succeeded", self.succeeded)
+            .num("failed", self.failed)
+            .num("failure_rate", self.failure_rate())
+            .num("jobs_per_hour", self.jobs_per_hour())
+            .num("input_bytes", self.input_bytes)
+            .num("audio_seconds", self.audio.as_secs_f64())
+            .num("realtime_factor", self.realtime())
+            .raw(
+                "error_classes",
+                &json::array(self.errors.iter().map(|(class, count)| {
+                    json::Object::new()
+                        .str("class", class)
+                        .num("count", count)
+                        .finish()
+                })),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn percentiles_take_the_nearest_rank() {
+        let sorted: Vec<Duration> = (1..=10).map(|i| ms(i * 10)).collect();
+        assert_eq!(percentile(&sorted, 50.0), ms(50));
+        assert_eq!(percentile(&sorted, 90.0), ms(90));
+        assert_eq!(percentile(&sorted, 99.0), ms(100));
+        assert_eq!(percentile(&sorted, 100.0), ms(100));
+        assert_eq!(percentile(&sorted, 0.0), ms(10));
+        assert_eq!(percentile(&[ms(7)], 50.0), ms(7));
+        assert_eq!(percentile(&[ms(1), ms(2)], 50.0), ms(1));
+        assert_eq!(percentile(&[ms(1), ms(2)], 51.0), ms(2));
+    }
+
+    #[test]
+    fn sizes_and_durations_are_formatted() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 << 30), "5.0 GiB");
+        assert_eq!(format_bytes(u64::MAX), "16777216.0 TiB");
+        assert_eq!(format_duration(Duration::ZERO), "0:00:00.0");
+        assert_eq!(format_duration(ms(61_500)), "0:01:01.5");
+        assert_eq!(format_duration(ms(3_723_400)), "1:02:03.4");
+        assert_eq!(format_duration(ms(59_960)), "0:01:00.0");
+        assert_eq!(format_duration(ms(3_599_960)), "1:00:00.0");
+    }
+
+    fn result(error: Option<&str>, warnings: usize) -> JobResult {
+        JobResult {
+            command: Vec::new(),
+            started_at: SystemTime::now(),
+            input: PathBuf::from("in.wav"),
+            output: PathBuf::from("out.wav"),
+            error: error.map(str::to_string),
+            stderr: String::new(),
+            elapsed: ms(2000),
+            input_bytes: 100,
+            output_bytes: 40,
+            audio: Some(ms(10_000)),
+            stages: vec![("transcode", ms(1500)), ("deliver", ms(500))],
+            warnings: vec!["clipping".to_string(); warnings],
+        }
+    }
+
+    #[test]
+    fn runs_count_what_succeeded() {
+        let mut stats = RunStats::start(true);
+        stats.record(&result(None, 0));
+        stats.record(&result(None, 2));
+        stats.record(&result(Some("Invalid data"), 0));
+        stats.skipped();
+        assert_eq!(stats.processed(), 2);
+        assert_eq!(stats.failed(), 1);
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(stats.warned, 1);
+        // Failures add nothing to the totals
+        assert_eq!(stats.input_bytes, 200);
+        assert_eq!(stats.output_bytes, 80);
+        assert_eq!(stats.audio, ms(20_000));
+        assert_eq!(stats.transcoding, ms(4000));
+        // But their stages are timed
+        let stages = stats.stages.unwrap();
+        assert_eq!(stages.len(), 2);
+        assert_eq!(stages[0], ("transcode", vec![ms(1500); 3]));
+        assert!(RunStats::start(false).stages.is_none());
+    }
+
+    fn entry(started: u64, finished: u64, error_class: Option<&str>) -> Entry {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        Entry {
+            started: at(started),
+            finished: at(finished),
+            input: PathBuf::from("in.wav"),
+            input_sha256: None,
+            input_bytes: 1000,
+            output: PathBuf::from("out.wav"),
+            output_sha256: None,
+            output_bytes: 100,
+            audio_seconds: Some(60.0),
+            succeeded: error_class.is_none(),
+            error_class: error_class.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn histories_sum_up_their_window() {
+        let entries = [
+            entry(0, 30, None),
+            entry(600, 630, None),
+            entry(1200, 1210, Some("timeout")),
+            entry(1800, 1805, Some("corrupt_input")),
+            entry(3000, 3005, Some("timeout")),
+            entry(3570, 3600, None),
+        ];
+        let history = History::of(&entries, None, None);
+        assert_eq!(history.window(), Duration::from_secs(3600));
+        assert_eq!(history.succeeded, 3);
+        assert_eq!(history.failed, 3);
+        assert_eq!(history.failure_rate(), 0.5);
+        assert_eq!(history.jobs_per_hour(), 6.0);
+        assert_eq!(history.input_bytes, 3000);
+        // 180 seconds of audio in 90 seconds of work
+        assert_eq!(history.realtime(), 2.0);
+        assert_eq!(
+            history.errors,
+            [("timeout".to_string(), 2), ("corrupt_input".to_string(), 1)]
+        );
+
+        let at = |secs| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        let later = History::of(&entries, at(1800), at(3600));
+        assert_eq!(later.succeeded, 1);
+        assert_eq!(later.failed, 2);
+        assert_eq!(later.window(), Duration::from_secs(1800));
+        assert_eq!(later.jobs_per_hour(), 6.0);
+    }
+
+    #[test]
+    fn empty_histories_divide_by_nothing() {
+        let history = History::of(&[], None, None);
+        assert_eq!(history.window(), Duration::ZERO);
+        assert_eq!(history.jobs_per_hour(), 0.0);
+        assert_eq!(history.failure_rate(), 0.0);
+        assert_eq!(history.realtime(), 0.0);
+        assert!(history.to_json().contains("\"from\":null"));
+    }
+}
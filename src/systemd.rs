@@ -0,0 +1,83 @@
+//! systemd integration for `Type=notify` units.
+//!
+//! Readiness, status and stopping notifications are sent to the datagram
+//! socket named by `NOTIFY_SOCKET`, and the watchdog is pinged while the
+//! process is healthy when the unit sets `WatchdogSec=`. Outside systemd
+//! the variables are unset and every call is a no-op.
+use log::{debug, warn};
+use std::os::unix::net::UnixDatagram;
+use std::time::{Duration, Instant};
+
+pub struct Systemd {
+    socket: Option<(UnixDatagram, String)>,
+    /// Ping interval, half the watchdog timeout set by systemd.
+    watchdog: Option<Duration>,
+    last_ping: Option<Instant>,
+}
+
+impl Systemd {
+    /// Pick up the notification socket and watchdog settings from systemd.
+    pub fn from_env() -> Self {
+        let socket = std::env::var("NOTIFY_SOCKET").ok().and_then(|path| {
+            UnixDatagram::unbound()
+                .inspect_err(|e| warn!("Cannot create systemd notify socket: {}", e))
+                .ok()
+                .map(|socket| (socket, path))
+        });
+        let for_us =
+            std::env::var("WATCHDOG_PID").map_or(true, |pid| pid == std::process::id().to_string());
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|&usec| usec > 0 && for_us)
+            .map(|usec| Duration::from_micros(usec) / 2);
+        Systemd {
+            socket,
+            watchdog,
+            last_ping: None,
+        }
+    }
+
+    /// Send a raw notification, e.g. `READY=1`.
+    fn notify(&self, state: &str) {
+        let Some((socket, path)) = &self.socket else {
+            return;
+        };
+        let sent = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                std::os::unix::net::SocketAddr::from_abstract_name(name)
+                    .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+            }
+            _ => socket.send_to(state.as_bytes(), path),
+        };
+        match sent {
+            Ok(_) => debug!("Notified systemd: {}", state.replace('\n', " ")),
+            Err(e) => warn!("Failed to notify systemd: {}", e),
+        }
+    }
+
+    /// Tell systemd that startup has finished.
+    pub fn ready(&self, status: &str) {
+        self.notify(&format!("READY=1\nSTATUS={}", status));
+    }
+
+    /// Tell systemd that a graceful shutdown has started.
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1\nSTATUS=Finishing jobs in progress");
+    }
+
+    /// Called from the main loop; pings the watchdog while healthy, so a
+    /// hung or broken process gets restarted.
+    pub fn watchdog(&mut self, healthy: bool) {
+        let Some(interval) = self.watchdog else {
+            return;
+        };
+        if !healthy || self.last_ping.is_some_and(|t| t.elapsed() < interval) {
+            return;
+        }
+        self.last_ping = Some(Instant::now());
+        self.notify("WATCHDOG=1");
+    }
+}
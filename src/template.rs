@@ -0,0 +1,318 @@
+//! Output names made from a template given with `--output-template`, e.g.
+//! `{recorded_date}/{tag:artist}/{stem}.wav`, below the output directory,
+//! so that outputs can be organized by what they are rather than by when
+//! they were made.
+//!
+//! - `{stem}` and `{ext}`: the name of the input without its extension,
+//!   and the extension
+//! - `{dir}`: the directory of the input below the input directory
+//! - `{duration_s}`: whole seconds of audio
+//! - `{codec}`, `{channels}` and `{rate}`: those of the input's audio
+//! - `{tag:NAME}`: the tag `NAME` of the input's container
+//! - `{recorded_date}`: the day of the recording, from the input's tags,
+//!   or else the day it was last modified
+//!
+//! Values that are not known are `unknown`, and path separators in them
+//! are replaced, so that `/` in the template is the only one. The name
+//! ends in `.wav` unless the template ends in it already.
+//!
+//! There is no variable for the day the output is made: names are made
+//! again whenever an input's output is looked for, e.g. by a backfill or
+//! the scan at startup, and one that changed from day to day would have
+//! them transcode inputs of earlier days again.
+use crate::probe::Properties;
+use crate::raw::RawInput;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// Stands in for values that are not known.
+const UNKNOWN: &str = "unknown";
+/// Tags that may hold the day of the recording, in order of preference:
+/// MP4 and Matroska, ID3 and Vorbis comments, RIFF INFO, BWF.
+const DATE_TAGS: &[&str] = &[
+    "creation_time",
+    "date",
+    "ICRD",
+    "date_recorded",
+    "origination_date",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Variable {
+    Stem,
+    Ext,
+    Dir,
+    DurationSeconds,
+    Codec,
+    Channels,
+    Rate,
+    Tag(String),
+    RecordedDate,
+}
+
+impl Variable {
+    /// Whether its value needs the input probed.
+    fn probed(&self) -> bool {
+        matches!(
+            self,
+            Variable::DurationSeconds
+                | Variable::Codec
+                | Variable::Channels
+                | Variable::Rate
+                | Variable::Tag(_)
+                | Variable::RecordedDate
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Variable(Variable),
+}
+
+/// A template of output names.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+    /// What `{dir}` is relative to, if the inputs are in a local directory.
+    input_dir: Option<PathBuf>,
+    /// Declarations the properties of headerless inputs are taken from.
+    raw_inputs: Vec<RawInput>,
+}
+
+impl FromStr for OutputTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Err("empty output template".to_string());
+        }
+        let path = Path::new(s);
+        if path.has_root()
+            || path
+                .components()
+                .any(|c| matches!(c, Component::ParentDir | Component::Prefix(_)))
+        {
+            return Err(format!(
+                "output template {:?} must stay below the output directory",
+                s
+            ));
+        }
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let text_end = rest.find(['{', '}']).unwrap_or(rest.len());
+            if text_end > 0 {
+                parts.push(Part::Text(rest[..text_end].to_string()));
+            }
+            rest = &rest[text_end..];
+            if rest.starts_with('}') {
+                return Err(format!("unmatched }} in output template {:?}", s));
+            }
+            let Some(open) = rest.strip_prefix('{') else {
+                continue;
+            };
+            let close = open
+                .find('}')
+                .ok_or_else(|| format!("unmatched {{ in output template {:?}", s))?;
+            let variable = match &open[..close] {
+                "stem" => Variable::Stem,
+                "ext" => Variable::Ext,
+                "dir" => Variable::Dir,
+                "date" => {
+                    return Err(format!(
+                        "output template {:?} uses {{date}}, the day the output is made, which \
+                         would name the outputs of earlier days anew; use {{recorded_date}}",
+                        s
+                    ));
+                }
+                "duration_s" => Variable::DurationSeconds,
+                "codec" => Variable::Codec,
+                "channels" => Variable::Channels,
+                "rate" => Variable::Rate,
+                "recorded_date" => Variable::RecordedDate,
+                name => match name.strip_prefix("tag:") {
+                    Some(tag) if !tag.is_empty() => Variable::Tag(tag.to_string()),
+                    _ => {
+                        return Err(format!(
+                            "unknown variable {{{}}} in output template {:?}",
+                            name, s
+                        ));
+                    }
+                },
+            };
+            parts.push(Part::Variable(variable));
+            rest = &open[close + 1..];
+        }
+        Ok(OutputTemplate {
+            parts,
+            input_dir: None,
+            raw_inputs: Vec::new(),
+        })
+    }
+}
+
+/// `value` fit to be one name in a path.
+fn sanitize(value: &str) -> String {
+    let value: String = value
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    match value.as_str() {
+        "" | "." | ".." => UNKNOWN.to_string(),
+        _ => value,
+    }
+}
+
+/// The `YYYY-MM-DD` at the start of `value`, e.g. of
+/// `2024-05-01T10:00:00.000000Z`, or of the `2024:05:01` of some tools.
+fn day(value: &str) -> Option<String> {
+    let day: String = value.trim().chars().take(10).collect();
+    let bytes = day.as_bytes();
+    let digits = [0, 1, 2, 3, 5, 6, 8, 9]
+        .iter()
+        .all(|&i| bytes.get(i).is_some_and(u8::is_ascii_digit));
+    let separated = bytes.len() == 10 && bytes[4] == bytes[7] && matches!(bytes[4], b'-' | b':');
+    (digits && separated).then(|| day.replace(':', "-"))
+}
+
+/// The day of `time`, in UTC.
+fn day_of(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()[..10].to_string()
+}
+
+impl OutputTemplate {
+    /// Take `{dir}` relative to `input_dir`, and the properties of
+    /// headerless inputs from `raw_inputs`.
+    pub fn for_inputs(mut self, input_dir: Option<PathBuf>, raw_inputs: Vec<RawInput>) -> Self {
+        self.input_dir = input_dir.map(|dir| std::path::absolute(&dir).unwrap_or(dir));
+        self.raw_inputs = raw_inputs;
+        self
+    }
+
+    /// The name of the output of `input`, relative to the output directory.
+    pub fn render(&self, input: &Path) -> PathBuf {
+        let probed = self
+            .parts
+            .iter()
+            .any(|part| matches!(part, Part::Variable(variable) if variable.probed()));
+        let properties = if probed {
+            Properties::probe(input, &self.raw_inputs)
+        } else {
+            Properties::default()
+        };
+        let known = |value: Option<String>| value.map_or(UNKNOWN.to_string(), |v| sanitize(&v));
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => name.push_str(text),
+                Part::Variable(variable) => name.push_str(&match variable {
+                    Variable::Stem => known(
+                        input
+                            .file_stem()
+                            .map(|stem| stem.to_string_lossy().into_owned()),
+                    ),
+                    Variable::Ext => known(
+                        input
+                            .extension()
+                            .map(|ext| ext.to_string_lossy().into_owned()),
+                    ),
+                    Variable::Dir => self.dir(input),
+                    Variable::DurationSeconds => {
+                        known(properties.duration.map(|d| (d as u64).to_string()))
+                    }
+                    Variable::Codec => known(properties.codec.clone()),
+                    Variable::Channels => known(properties.channels.map(|c| c.to_string())),
+                    Variable::Rate => known(properties.sample_rate.map(|r| r.to_string())),
+                    Variable::Tag(tag) => known(properties.tag(tag).map(str::to_string)),
+                    Variable::RecordedDate => DATE_TAGS
+                        .iter()
+                        .find_map(|tag| day(properties.tag(tag)?))
+                        .or_else(|| {
+                            let modified = std::fs::metadata(input).ok()?.modified().ok()?;
+                            Some(day_of(modified))
+                        })
+                        .unwrap_or_else(|| UNKNOWN.to_string()),
+                }),
+            }
+        }
+        if !name.to_ascii_lowercase().ends_with(".wav") {
+            name.push_str(".wav");
+        }
+        // Without the `.` of an empty `{dir}`
+        Path::new(&name)
+            .components()
+            .filter(|c| !matches!(c, Component::CurDir))
+            .collect()
+    }
+
+    /// The directory of `input` below the input directory, with `/`
+    /// separators, or `.` for the input directory itself.
+    fn dir(&self, input: &Path) -> String {
+        let relative = self.input_dir.as_ref().and_then(|dir| {
+            let input = std::path::absolute(input).ok()?;
+            let parent = input.parent()?.strip_prefix(dir).ok()?.to_path_buf();
+            Some(parent)
+        });
+        let names: Vec<String> = relative
+            .iter()
+            .flat_map(|relative| relative.components())
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(sanitize(&name.to_string_lossy())),
+                _ => None,
+            })
+            .collect();
+        match names.is_empty() {
+            true => ".".to_string(),
+            false => names.join("/"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_made_from_the_input() {
+        let template: OutputTemplate = "{dir}/{stem}-{ext}".parse().unwrap();
+        let template = template.for_inputs(Some(PathBuf::from("/srv/in")), Vec::new());
+        assert_eq!(
+            template.render(Path::new("/srv/in/acme/call.mp3")),
+            PathBuf::from("acme/call-mp3.wav")
+        );
+        assert_eq!(
+            template.render(Path::new("/srv/in/call.mp3")),
+            PathBuf::from("call-mp3.wav")
+        );
+    }
+
+    #[test]
+    fn the_day_the_output_is_made_is_refused() {
+        let refusal = "{date}/{stem}.wav".parse::<OutputTemplate>().unwrap_err();
+        assert!(refusal.contains("{recorded_date}"), "{}", refusal);
+        assert!(
+            "{recorded_date}/{stem}.wav"
+                .parse::<OutputTemplate>()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn days_are_read_from_tags() {
+        assert_eq!(
+            day("2024-05-01T10:00:00.000000Z"),
+            Some("2024-05-01".into())
+        );
+        assert_eq!(day("2024:05:01 10:00:00"), Some("2024-05-01".into()));
+        assert_eq!(day("2024-05:01"), None);
+        assert_eq!(day("May 2024"), None);
+    }
+}
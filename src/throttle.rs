@@ -0,0 +1,33 @@
+//! Bandwidth limits, so that bulk transcoding does not saturate a link
+//! shared with other users, e.g. the one to a NAS.
+//!
+//! Reads are what comes in: the inputs ffmpeg reads and the files that are
+//! downloaded. Writes are what goes out: the outputs ffmpeg writes and the
+//! files that are uploaded. Each limit applies to every transfer on its
+//! own, so with several jobs at once, their sum can be higher.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Limits in bytes per second; zero is unlimited.
+static READ: AtomicU64 = AtomicU64::new(0);
+static WRITE: AtomicU64 = AtomicU64::new(0);
+
+/// Set the limits, in bytes per second.
+pub fn set_limits(read: Option<u64>, write: Option<u64>) {
+    READ.store(read.unwrap_or(0), Ordering::SeqCst);
+    WRITE.store(write.unwrap_or(0), Ordering::SeqCst);
+}
+
+/// Most bytes per second read by one transfer, if limited.
+pub fn read_limit() -> Option<u64> {
+    Some(READ.load(Ordering::SeqCst)).filter(|&limit| limit > 0)
+}
+
+/// Most bytes per second written by one transfer, if limited.
+pub fn write_limit() -> Option<u64> {
+    Some(WRITE.load(Ordering::SeqCst)).filter(|&limit| limit > 0)
+}
+
+/// Bytes per second of a rate given in megabits per second.
+pub fn from_mbps(mbps: f64) -> u64 {
+    (mbps * 1_000_000.0 / 8.0).round().max(1.0) as u64
+}
@@ -0,0 +1,224 @@
+//! Speech recognition on the transcoded output.
+//!
+//! After a successful transcode, the 16kHz mono WAV is handed to whisper.cpp
+//! or to an ASR service speaking the OpenAI transcription API (faster-whisper
+//! and whisper.cpp servers, among others), and each requested transcript is
+//! written next to the output as `<output>.txt`, `.srt` or `.json`. Being
+//! sidecars, they are delivered together with the output.
+use crate::curl;
+use clap::ValueEnum;
+use log::{debug, info};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+/// A transcript written for each output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TranscriptFormat {
+    /// Plain text.
+    Txt,
+    /// SubRip subtitles, with timestamps.
+    Srt,
+    /// The engine's full JSON output, with segments and timestamps.
+    Json,
+}
+
+impl TranscriptFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            TranscriptFormat::Txt => "txt",
+            TranscriptFormat::Srt => "srt",
+            TranscriptFormat::Json => "json",
+        }
+    }
+
+    /// Value of `response_format` in the transcription API.
+    fn response_format(self) -> &'static str {
+        match self {
+            TranscriptFormat::Txt => "text",
+            TranscriptFormat::Srt => "srt",
+            TranscriptFormat::Json => "verbose_json",
+        }
+    }
+}
+
+/// What recognizes the speech.
+#[derive(Clone, Debug)]
+pub enum Engine {
+    /// The whisper.cpp command line program with a local model file.
+    WhisperCpp { program: String, model: PathBuf },
+    /// `POST` to a `/v1/audio/transcriptions` style endpoint.
+    Http {
+        url: String,
+        /// Model name sent with each request, if the service wants one.
+        model: Option<String>,
+        /// Bearer token, kept off curl's command line.
+        token: Option<String>,
+    },
+}
+
+/// Transcribes outputs, configured with `--transcribe-model` or
+/// `--transcribe-url`.
+#[derive(Clone, Debug)]
+pub struct Transcriber {
+    pub engine: Engine,
+    pub formats: Vec<TranscriptFormat>,
+    /// Spoken language, e.g. `en`; detected by the engine if unset.
+    pub language: Option<String>,
+    /// Kill the engine if a single file takes longer than this.
+    pub timeout: Option<Duration>,
+}
+
+impl Transcriber {
+    /// Write the transcripts of `output`, returning their paths.
+    pub fn transcribe(&self, output: &Path) -> Result<Vec<PathBuf>, String> {
+        let written = match &self.engine {
+            Engine::WhisperCpp { program, model } => self.whisper_cpp(program, model, output)?,
+            Engine::Http { url, model, token } => {
+                let mut written = Vec::new();
+                for format in &self.formats {
+                    written.push(self.request(
+                        url,
+                        model.as_deref(),
+                        token.as_deref(),
+                        output,
+                        *format,
+                    )?);
+                }
+                written
+            }
+        };
+        info!(
+            "Transcribed {} into {} files",
+            output.display(),
+            written.len()
+        );
+        Ok(written)
+    }
+
+    fn whisper_cpp(
+        &self,
+        program: &str,
+        model: &Path,
+        output: &Path,
+    ) -> Result<Vec<PathBuf>, String> {
+        let mut command = Command::new(program);
+        command
+            .arg("--no-prints")
+            .arg("--model")
+            .arg(model)
+            .arg("--language")
+            .arg(self.language.as_deref().unwrap_or("auto"))
+            // whisper.cpp appends the extension of each format itself
+            .arg("--output-file")
+            .arg(output)
+            .args(self.formats.iter().map(|format| match format {
+                TranscriptFormat::Txt => "--output-txt",
+                TranscriptFormat::Srt => "--output-srt",
+                TranscriptFormat::Json => "--output-json-full",
+            }))
+            .arg("--file")
+            .arg(output);
+        debug!("Running {:?}", command);
+        let result = crate::backend::run(&mut command, self.timeout)
+            .map_err(|e| format!("Transcription failed to run {}: {}", program, e))?;
+        if !result.status.success() {
+            return Err(format!(
+                "Transcription failed ({}): {}",
+                result.status,
+                result.stderr.trim_end()
+            ));
+        }
+        let written: Vec<PathBuf> = self
+            .formats
+            .iter()
+            .map(|format| sidecar(output, format.extension()))
+            .collect();
+        match written.iter().find(|path| !path.is_file()) {
+            Some(missing) => Err(format!(
+                "Transcription failed: {} did not write {}",
+                program,
+                missing.display()
+            )),
+            None => Ok(written),
+        }
+    }
+
+    fn request(
+        &self,
+        url: &str,
+        model: Option<&str>,
+        token: Option<&str>,
+        output: &Path,
+        format: TranscriptFormat,
+    ) -> Result<PathBuf, String> {
+        let path = sidecar(output, format.extension());
+        let dir = output.parent().unwrap_or(Path::new("."));
+        let partial = curl::scratch_path(dir, "transcript", "part");
+        let config = curl::scratch_path(&std::env::temp_dir(), "transcript", "conf");
+        let mut command = Command::new("curl");
+        command
+            .args(["-sS", "--fail-with-body", "--connect-timeout", "30"])
+            .arg("-o")
+            .arg(&partial)
+            .arg("-F")
+            .arg(format!("file=@{}", curl::quote(&output.to_string_lossy())))
+            .arg("-F")
+            .arg(format!("response_format={}", format.response_format()));
+        if let Some(model) = model {
+            command.arg("--form-string").arg(format!("model={}", model));
+        }
+        if let Some(language) = &self.language {
+            command
+                .arg("--form-string")
+                .arg(format!("language={}", language));
+        }
+        if let Some(token) = token {
+            curl::private_file(
+                &config,
+                &format!(
+                    "header = {}\n",
+                    curl::quote(&format!("Authorization: Bearer {}", token))
+                ),
+            )
+            .map_err(|e| format!("Transcription failed to write the curl config: {}", e))?;
+            command.arg("-K").arg(&config);
+        }
+        command.arg(url);
+        let result = crate::backend::run(&mut command, self.timeout);
+        let _ = std::fs::remove_file(&config);
+        let failure = match result {
+            Ok(result) if result.status.success() => {
+                return match std::fs::rename(&partial, &path) {
+                    Ok(()) => Ok(path),
+                    Err(e) => Err(format!(
+                        "Transcription failed to write {}: {}",
+                        path.display(),
+                        e
+                    )),
+                };
+            }
+            Ok(result) => {
+                // With --fail-with-body, the service's error message
+                let body = std::fs::read_to_string(&partial).unwrap_or_default();
+                format!(
+                    "Transcription failed ({}): {} {}",
+                    result.status,
+                    result.stderr.trim_end(),
+                    body.trim()
+                )
+            }
+            Err(e) => format!("Transcription failed to run curl: {}", e),
+        };
+        let _ = std::fs::remove_file(&partial);
+        Err(failure)
+    }
+}
+
+/// `<output>.<extension>`, e.g. `call_transcoded.wav.srt`.
+fn sidecar(output: &Path, extension: &str) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
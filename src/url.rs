@@ -0,0 +1,56 @@
+//! URL parsing helpers shared by the integrations that take URLs.
+
+/// Decode `%XX` escapes, and `+` as a space if `plus` is set, as in query
+/// strings. Invalid escapes are kept as they are.
+pub fn percent_decode(s: &str, plus: bool) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            // from_str_radix would also take a sign, as in `%+f`
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+            (b'+', _) if plus => out.push(b' '),
+            (b, _) => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::percent_decode;
+
+    #[test]
+    fn escapes_are_decoded() {
+        assert_eq!(percent_decode("a%2Fb%2fc", false), "a/b/c");
+        assert_eq!(percent_decode("caf%C3%A9", false), "café");
+        assert_eq!(percent_decode("%25", false), "%");
+    }
+
+    #[test]
+    fn plus_is_a_space_only_in_queries() {
+        assert_eq!(percent_decode("a+b%2B", true), "a b+");
+        assert_eq!(percent_decode("a+b%2B", false), "a+b+");
+    }
+
+    #[test]
+    fn invalid_escapes_are_kept() {
+        assert_eq!(percent_decode("%4", false), "%4");
+        assert_eq!(percent_decode("100%", false), "100%");
+        assert_eq!(percent_decode("%+f", false), "%+f");
+        assert_eq!(percent_decode("%-1", false), "%-1");
+        assert_eq!(percent_decode("%zz", false), "%zz");
+        assert_eq!(percent_decode("%%41", false), "%A");
+    }
+}
@@ -0,0 +1,148 @@
+//! Verification of outputs after they are written, for archives where a
+//! truncated output found years later cannot be made again, and of the
+//! outputs already archived, in periodic audits.
+use crate::audit::Entry;
+use crate::preset::Preset;
+use crate::wav::{self, WavReader};
+use crate::{sha256, source};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Frames read at a time when decoding a whole output.
+const BLOCK: usize = 16_384;
+
+/// How thoroughly each output is verified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Verify {
+    /// Check that the header is mono 16-bit PCM at the preset's rate and
+    /// that the file holds all the data it declares.
+    Header,
+    /// Also decode every sample.
+    Full,
+}
+
+/// Most that an output's duration may differ from the one recorded, in
+/// seconds.
+const DURATION_TOLERANCE: f64 = 0.001;
+
+/// An archived output that failed verification.
+#[derive(Debug)]
+pub struct Failure {
+    pub output: PathBuf,
+    /// The input it was made from, if the audit log knows.
+    pub input: Option<PathBuf>,
+    pub reason: String,
+}
+
+/// Verify every WAV file below `dir` as the verification stage would and,
+/// with the audit log's `entries`, also its duration, and with `checksum`
+/// its SHA-256, against the latest successful job that wrote it; outputs
+/// the log records below `dir` that are gone fail too. Returns how many
+/// files were checked and the failures, in path order.
+pub fn audit(
+    dir: &Path,
+    verify: Verify,
+    entries: &[Entry],
+    checksum: bool,
+) -> std::io::Result<(usize, Vec<Failure>)> {
+    let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut recorded: HashMap<PathBuf, &Entry> = HashMap::new();
+    for entry in entries.iter().filter(|entry| entry.succeeded) {
+        recorded.insert(absolute(&entry.output), entry);
+    }
+    let mut files = Vec::new();
+    source::scan_dir(dir, &mut files)?;
+    files.retain(|file| {
+        file.extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("wav"))
+    });
+
+    let mut failures = Vec::new();
+    for file in &files {
+        let entry = recorded.remove(&absolute(file));
+        let reason = check(file, verify, None).err().or_else(|| {
+            let entry = entry?;
+            let duration = wav::duration(file).map_or(0.0, |d| d.as_secs_f64());
+            if let Some(recorded) = entry.audio_seconds
+                && (duration - recorded).abs() > DURATION_TOLERANCE
+            {
+                return Some(format!(
+                    "output holds {:.3}s of audio, the audit log recorded {:.3}s",
+                    duration, recorded
+                ));
+            }
+            let expected = entry.output_sha256.as_deref().filter(|_| checksum)?;
+            match sha256::file(file) {
+                Ok(actual) if actual == expected => None,
+                Ok(_) => Some("SHA-256 differs from the audit log".to_string()),
+                Err(e) => Some(format!("cannot hash the output: {}", e)),
+            }
+        });
+        if let Some(reason) = reason {
+            failures.push(Failure {
+                output: file.clone(),
+                input: entry.map(|entry| entry.input.clone()),
+                reason,
+            });
+        }
+    }
+    let dir = absolute(dir);
+    for (output, entry) in recorded {
+        if output.starts_with(&dir) && !output.exists() {
+            failures.push(Failure {
+                output: entry.output.clone(),
+                input: Some(entry.input.clone()),
+                reason: "output is gone".to_string(),
+            });
+        }
+    }
+    failures.sort_by(|a, b| a.output.cmp(&b.output));
+    Ok((files.len(), failures))
+}
+
+/// Verify `output`, made with `preset` or, if unknown, with any of them,
+/// or say what is wrong with it.
+pub(crate) fn check(output: &Path, verify: Verify, preset: Option<Preset>) -> Result<(), String> {
+    match preset {
+        Some(preset) if !wav::compliant(output, preset) => {
+            return Err(format!(
+                "output is not complete {}kHz mono 16-bit PCM WAV",
+                preset.sample_rate() / 1000
+            ));
+        }
+        Some(_) => {}
+        None => {
+            let rate = wav::compliant_rate(output);
+            if !Preset::value_variants()
+                .iter()
+                .any(|preset| Some(preset.sample_rate()) == rate)
+            {
+                return Err("output is not complete mono 16-bit PCM WAV of a preset".to_string());
+            }
+        }
+    }
+    if verify == Verify::Header {
+        return Ok(());
+    }
+    let decode = || -> std::io::Result<(u64, Option<u64>)> {
+        let mut reader = WavReader::open(output)?;
+        let mut samples = Vec::with_capacity(BLOCK);
+        let mut frames = 0;
+        loop {
+            samples.clear();
+            match reader.read_frames(BLOCK, &mut samples)? {
+                0 => return Ok((frames, reader.remaining())),
+                n => frames += n as u64,
+            }
+        }
+    };
+    match decode() {
+        Ok((_, None | Some(0))) => Ok(()),
+        Ok((frames, Some(missing))) => Err(format!(
+            "output ends after {} samples, {} bytes short of its data",
+            frames, missing
+        )),
+        Err(e) => Err(format!("output does not decode: {}", e)),
+    }
+}
@@ -0,0 +1,504 @@
+//! WAV header inspection, reading and writing.
+use crate::preset::Preset;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Sample encoding of a WAV file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    Int,
+    Float,
+}
+
+/// Contents of the `fmt ` chunk.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(feature = "native"), allow(dead_code))]
+pub struct WavFormat {
+    pub sample_format: SampleFormat,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub byte_rate: u32,
+    pub bits: u16,
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+fn u16_at(b: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([b[i], b[i + 1]])
+}
+
+fn u32_at(b: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+}
+
+/// Parse the header up to the start of the `data` chunk, returning the format
+/// and the declared data size.
+fn read_header(file: &mut (impl Read + Seek)) -> std::io::Result<(Option<WavFormat>, u32)> {
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err(invalid("not a RIFF/WAVE file"));
+    }
+
+    let mut format = None;
+    loop {
+        let mut chunk = [0u8; 8];
+        file.read_exact(&mut chunk)?;
+        let size = u32_at(&chunk, 4);
+        // Chunks are padded to an even length
+        let padded = i64::from(size) + i64::from(size % 2);
+        match &chunk[0..4] {
+            b"fmt " if size >= 16 => {
+                let mut fmt = vec![0u8; size as usize];
+                file.read_exact(&mut fmt)?;
+                file.seek(SeekFrom::Current(padded - i64::from(size)))?;
+                let mut tag = u16_at(&fmt, 0);
+                // WAVE_FORMAT_EXTENSIBLE keeps the real tag in the sub-format GUID
+                if tag == 0xFFFE && fmt.len() >= 26 {
+                    tag = u16_at(&fmt, 24);
+                }
+                let sample_format = match tag {
+                    1 => SampleFormat::Int,
+                    3 => SampleFormat::Float,
+                    _ => return Err(invalid("unsupported WAV encoding (not PCM or float)")),
+                };
+                format = Some(WavFormat {
+                    sample_format,
+                    channels: u16_at(&fmt, 2),
+                    sample_rate: u32_at(&fmt, 4),
+                    byte_rate: u32_at(&fmt, 8),
+                    bits: u16_at(&fmt, 14),
+                });
+            }
+            b"data" => return Ok((format, size)),
+            _ => {
+                file.seek(SeekFrom::Current(padded))?;
+            }
+        }
+    }
+}
+
+/// Audio duration of a PCM WAV file, computed from its `fmt ` and `data` chunks.
+pub fn duration(path: &Path) -> Option<Duration> {
+    let mut file = BufReader::new(File::open(path).ok()?);
+    let (format, size) = read_header(&mut file).ok()?;
+    let byte_rate = format?.byte_rate;
+    if byte_rate == 0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(
+        f64::from(size) / f64::from(byte_rate),
+    ))
+}
+
+/// The sample rate of a file that is complete mono 16-bit PCM WAV, the
+/// format the transcoder makes at any rate.
+pub fn compliant_rate(path: &Path) -> Option<u32> {
+    let file = File::open(path).ok()?;
+    let len = file.metadata().map_or(0, |m| m.len());
+    let mut file = BufReader::new(file);
+    let (Some(format), size) = read_header(&mut file).ok()? else {
+        return None;
+    };
+    let start = file.stream_position().ok()?;
+    // Streaming writers leave the size at 0 or u32::MAX; an extra chunk
+    // after the data is fine
+    let complete = size != u32::MAX && (size > 0 || start == len) && start + u64::from(size) <= len;
+    (format.sample_format == SampleFormat::Int
+        && format.channels == 1
+        && format.bits == 16
+        && complete)
+        .then_some(format.sample_rate)
+}
+
+/// Whether a file is already what the transcoder makes of it with
+/// `preset`: complete mono 16-bit PCM WAV at its rate.
+pub fn compliant(path: &Path, preset: Preset) -> bool {
+    compliant_rate(path) == Some(preset.sample_rate())
+}
+
+/// Bytes of audio a second of the WAV file at `path` holds, as its header
+/// declares, also while it is being written.
+pub fn byte_rate(path: &Path) -> Option<u32> {
+    let mut file = BufReader::new(File::open(path).ok()?);
+    read_header(&mut file)
+        .ok()?
+        .0
+        .map(|format| format.byte_rate)
+}
+
+/// Put a chunk of `id` and `body` right after the `fmt ` chunk of the WAV
+/// file at `path`, in place of any chunk of that id, into a new file in its
+/// place, as it may be linked elsewhere.
+pub fn set_chunk(path: &Path, id: &[u8; 4], body: &[u8]) -> std::io::Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0u8; 12];
+    reader.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err(invalid("not a RIFF/WAVE file"));
+    }
+    let partial = path.with_extension("wav.chunk.partial");
+    let mut writer = BufWriter::new(File::create(&partial)?);
+    writer.write_all(&header)?;
+    let mut written: u64 = 12;
+    let mut inserted = false;
+    loop {
+        let mut chunk = [0u8; 8];
+        match reader.read_exact(&mut chunk) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let size = u32_at(&chunk, 4);
+        let padded = u64::from(size) + u64::from(size % 2);
+        if &chunk[0..4] == id {
+            std::io::copy(&mut reader.by_ref().take(padded), &mut std::io::sink())?;
+            continue;
+        }
+        writer.write_all(&chunk)?;
+        written += 8;
+        if &chunk[0..4] == b"data" && size == u32::MAX {
+            // Left unfinished by a streaming writer: the data runs to the end
+            written += std::io::copy(&mut reader, &mut writer)?;
+            break;
+        }
+        written += std::io::copy(&mut reader.by_ref().take(padded), &mut writer)?;
+        if &chunk[0..4] == b"fmt " && !inserted {
+            writer.write_all(id)?;
+            writer.write_all(&(body.len() as u32).to_le_bytes())?;
+            writer.write_all(body)?;
+            if body.len() % 2 == 1 {
+                writer.write_all(&[0])?;
+            }
+            written += 8 + body.len() as u64 + body.len() as u64 % 2;
+            inserted = true;
+        }
+    }
+    if !inserted {
+        let _ = std::fs::remove_file(&partial);
+        return Err(invalid("missing fmt chunk"));
+    }
+    writer.seek(SeekFrom::Start(4))?;
+    writer.write_all(&((written - 8).min(u64::from(u32::MAX)) as u32).to_le_bytes())?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    std::fs::rename(&partial, path)
+}
+
+/// Join PCM WAV files of the same format into `output`, sample for sample.
+pub fn concat(parts: &[PathBuf], output: &Path) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(output)?);
+    let mut first: Option<WavFormat> = None;
+    let mut data_bytes: u64 = 0;
+    for part in parts {
+        let mut reader = BufReader::new(File::open(part)?);
+        let (format, size) = read_header(&mut reader)?;
+        let format = format.ok_or_else(|| invalid("missing fmt chunk"))?;
+        let Some(first) = first else {
+            let block_align = format.channels * (format.bits / 8);
+            writer.write_all(b"RIFF\0\0\0\0WAVEfmt ")?;
+            writer.write_all(&16u32.to_le_bytes())?;
+            writer.write_all(
+                &(if format.sample_format == SampleFormat::Float {
+                    3u16
+                } else {
+                    1
+                })
+                .to_le_bytes(),
+            )?;
+            writer.write_all(&format.channels.to_le_bytes())?;
+            writer.write_all(&format.sample_rate.to_le_bytes())?;
+            writer.write_all(&(format.sample_rate * u32::from(block_align)).to_le_bytes())?;
+            writer.write_all(&block_align.to_le_bytes())?;
+            writer.write_all(&format.bits.to_le_bytes())?;
+            writer.write_all(b"data\0\0\0\0")?;
+            data_bytes += copy_data(&mut reader, size, &mut writer)?;
+            first = Some(format);
+            continue;
+        };
+        if (
+            format.sample_format,
+            format.channels,
+            format.sample_rate,
+            format.bits,
+        ) != (
+            first.sample_format,
+            first.channels,
+            first.sample_rate,
+            first.bits,
+        ) {
+            return Err(invalid("parts have different sample formats"));
+        }
+        data_bytes += copy_data(&mut reader, size, &mut writer)?;
+    }
+    let data_bytes = u32::try_from(data_bytes).map_err(|_| invalid("joined file exceeds 4 GiB"))?;
+    writer.seek(SeekFrom::Start(4))?;
+    writer.write_all(&36u32.saturating_add(data_bytes).to_le_bytes())?;
+    writer.seek(SeekFrom::Start(40))?;
+    writer.write_all(&data_bytes.to_le_bytes())?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+/// Copy a data chunk of the declared `size`, or up to the end of the file
+/// for a streamed one, returning the bytes copied.
+fn copy_data(reader: &mut impl Read, size: u32, writer: &mut impl Write) -> std::io::Result<u64> {
+    match size {
+        // Streaming writers leave the size at 0 or u32::MAX
+        0 | u32::MAX => std::io::copy(reader, writer),
+        n => std::io::copy(&mut reader.take(u64::from(n)), writer),
+    }
+}
+
+/// Streaming reader that decodes samples to `f32` in `[-1.0, 1.0]`.
+pub struct WavReader {
+    reader: BufReader<File>,
+    format: WavFormat,
+    /// Bytes left in the data chunk, or `None` to read until end of file.
+    remaining: Option<u64>,
+}
+
+impl WavReader {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let (format, size) = read_header(&mut reader)?;
+        let format = format.ok_or_else(|| invalid("missing fmt chunk"))?;
+        let supported = matches!(
+            (format.sample_format, format.bits),
+            (SampleFormat::Int, 8 | 16 | 24 | 32) | (SampleFormat::Float, 32 | 64)
+        );
+        if !supported || format.channels == 0 || format.sample_rate == 0 {
+            return Err(invalid("unsupported WAV sample format"));
+        }
+        // Streaming writers leave the size at 0 or u32::MAX
+        let remaining = match size {
+            0 | u32::MAX => None,
+            n => Some(u64::from(n)),
+        };
+        Ok(WavReader {
+            reader,
+            format,
+            remaining,
+        })
+    }
+
+    pub fn format(&self) -> &WavFormat {
+        &self.format
+    }
+
+    /// Bytes of the data chunk not read yet, unless it runs to the end of
+    /// the file.
+    pub fn remaining(&self) -> Option<u64> {
+        self.remaining
+    }
+
+    /// Append up to `frames` interleaved frames to `out`, returning the
+    /// number of frames read; zero means the end of the data.
+    pub fn read_frames(&mut self, frames: usize, out: &mut Vec<f32>) -> std::io::Result<usize> {
+        let width = usize::from(self.format.bits / 8);
+        let frame_bytes = width * usize::from(self.format.channels);
+        let mut want = frames * frame_bytes;
+        if let Some(remaining) = self.remaining {
+            want = want.min(remaining as usize);
+        }
+        let mut buf = vec![0u8; want];
+        let mut got = 0;
+        while got < want {
+            match self.reader.read(&mut buf[got..])? {
+                0 => break,
+                n => got += n,
+            }
+        }
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= got as u64;
+        }
+
+        let frames = got / frame_bytes;
+        for s in buf[..frames * frame_bytes].chunks_exact(width) {
+            let sample = match (self.format.sample_format, width) {
+                (SampleFormat::Int, 1) => (f32::from(s[0]) - 128.0) / 128.0,
+                (SampleFormat::Int, 2) => f32::from(i16::from_le_bytes([s[0], s[1]])) / 32768.0,
+                (SampleFormat::Int, 3) => {
+                    (i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8) as f32 / 8_388_608.0
+                }
+                (SampleFormat::Int, _) => {
+                    i32::from_le_bytes([s[0], s[1], s[2], s[3]]) as f32 / 2_147_483_648.0
+                }
+                (SampleFormat::Float, 4) => f32::from_le_bytes([s[0], s[1], s[2], s[3]]),
+                (SampleFormat::Float, _) => {
+                    f64::from_le_bytes([s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]]) as f32
+                }
+            };
+            out.push(sample);
+        }
+        Ok(frames)
+    }
+}
+
+/// Writer for 16-bit PCM WAV files; sizes are patched in by [`WavWriter::finish`].
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32, channels: u16) -> std::io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let block_align = channels * 2;
+        writer.write_all(b"RIFF\0\0\0\0WAVEfmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * u32::from(block_align)).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&16u16.to_le_bytes())?;
+        writer.write_all(b"data\0\0\0\0")?;
+        Ok(WavWriter {
+            writer,
+            data_bytes: 0,
+        })
+    }
+
+    /// Write samples in `[-1.0, 1.0]`, clipping anything outside.
+    pub fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        for &s in samples {
+            let v = (s * 32768.0).round().clamp(-32768.0, 32767.0) as i16;
+            self.writer.write_all(&v.to_le_bytes())?;
+        }
+        self.data_bytes = self.data_bytes.saturating_add((samples.len() * 2) as u32);
+        Ok(())
+    }
+
+    /// Fill in the chunk sizes and flush the file to disk.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&(36u32.saturating_add(self.data_bytes)).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_bytes.to_le_bytes())?;
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((body.len() as u32).to_le_bytes());
+        chunk.extend(body);
+        if body.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn wav(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = chunks.concat();
+        let mut file = b"RIFF".to_vec();
+        file.extend((body.len() as u32 + 4).to_le_bytes());
+        file.extend(b"WAVE");
+        file.extend(body);
+        file
+    }
+
+    /// The ids and bodies of the chunks of `file`, checking its RIFF size.
+    fn chunks(file: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(u32_at(file, 4) as usize, file.len() - 8);
+        let mut chunks = Vec::new();
+        let mut at = 12;
+        while at < file.len() {
+            let size = u32_at(file, at + 4) as usize;
+            let id = String::from_utf8_lossy(&file[at..at + 4]).into_owned();
+            chunks.push((id, file[at + 8..at + 8 + size].to_vec()));
+            at += 8 + size + size % 2;
+        }
+        chunks
+    }
+
+    fn file(test: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wav-{}-{}.wav", test, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn fmt() -> Vec<u8> {
+        chunk(
+            b"fmt ",
+            &[1, 0, 1, 0, 0x80, 0x3e, 0, 0, 0, 0x7d, 0, 0, 2, 0, 16, 0],
+        )
+    }
+
+    #[test]
+    fn chunks_go_right_after_fmt() {
+        let path = file(
+            "after-fmt",
+            &wav(&[
+                fmt(),
+                chunk(b"LIST", b"INFO"),
+                chunk(b"data", &[1, 2, 3, 4]),
+            ]),
+        );
+        set_chunk(&path, b"bext", b"odd").unwrap();
+        let chunks = chunks(&std::fs::read(&path).unwrap());
+        let names: Vec<&str> = chunks.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(names, ["fmt ", "bext", "LIST", "data"]);
+        assert_eq!(chunks[1].1, b"odd");
+        assert_eq!(chunks[3].1, [1, 2, 3, 4]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn chunks_of_the_id_are_replaced_wherever_they_are() {
+        let path = file(
+            "replaced",
+            &wav(&[
+                chunk(b"bext", b"before"),
+                fmt(),
+                chunk(b"data", &[1, 2]),
+                chunk(b"bext", b"after"),
+            ]),
+        );
+        set_chunk(&path, b"bext", b"new").unwrap();
+        let chunks = chunks(&std::fs::read(&path).unwrap());
+        let names: Vec<&str> = chunks.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(names, ["fmt ", "bext", "data"]);
+        assert_eq!(chunks[1].1, b"new");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unfinished_data_keeps_running_to_the_end() {
+        let mut contents = wav(&[fmt()]);
+        contents.extend(b"data");
+        contents.extend(u32::MAX.to_le_bytes());
+        contents.extend([5, 6, 7]);
+        let path = file("streamed", &contents);
+        set_chunk(&path, b"bext", b"ab").unwrap();
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(&written[36..44], b"bext\x02\0\0\0");
+        assert_eq!(&written[46..50], b"data");
+        assert_eq!(&written[54..], [5, 6, 7]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn files_without_fmt_are_left_as_they_are() {
+        let contents = wav(&[chunk(b"data", &[1, 2])]);
+        let path = file("no-fmt", &contents);
+        assert!(set_chunk(&path, b"bext", b"ab").is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), contents);
+        assert!(!path.with_extension("wav.chunk.partial").exists());
+        std::fs::remove_file(&path).unwrap();
+
+        let path = file("not-riff", b"OggS\0\0\0\0WAVE");
+        assert!(set_chunk(&path, b"bext", b"ab").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+//! WebDAV delivery, e.g. to Nextcloud, ownCloud or SharePoint, through the
+//! curl executable.
+//!
+//! `dav://` locations are plain HTTP and `davs://` ones HTTPS. Requests are
+//! authorized with the bearer token in `WEBDAV_TOKEN` or, for the user in
+//! the URL, the password or app password in `WEBDAV_PASSWORD`; both reach
+//! curl on its standard input. Each file is written under a hidden
+//! `.<name>.part` name and moved into place once complete. Large files on
+//! Nextcloud go through its chunked upload protocol instead, so that no
+//! single request has to carry the whole file.
+use crate::curl::{self, scratch_path, uri_encode};
+use crate::jobs;
+use crate::sink::RemoteTarget;
+use log::debug;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Files larger than this are uploaded in chunks where the server allows.
+const CHUNK_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Smallest chunk size; Nextcloud wants at least 5 MiB per chunk.
+const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Most chunks a Nextcloud upload can be assembled from.
+const MAX_CHUNKS: u64 = 10_000;
+
+/// A collection on a WebDAV server, parsed from
+/// `dav[s]://[user@]host[:port]/path`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebdavLocation {
+    pub secure: bool,
+    pub user: Option<String>,
+    /// Host, with the port if one was given.
+    pub host: String,
+    /// Decoded path of the collection, starting and ending with `/`.
+    pub path: String,
+}
+
+impl WebdavLocation {
+    pub fn parse(url: &str) -> Option<Self> {
+        let (secure, rest) = match url.split_once("://")? {
+            ("dav", rest) => (false, rest),
+            ("davs", rest) => (true, rest),
+            _ => return None,
+        };
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (user, host) = match authority.rsplit_once('@') {
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, authority),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let path = path.trim_matches('/');
+        Some(WebdavLocation {
+            secure,
+            user: user.filter(|u| !u.is_empty()),
+            host: host.to_string(),
+            path: if path.is_empty() {
+                "/".to_string()
+            } else {
+                format!("/{}/", path)
+            },
+        })
+    }
+
+    /// URL of `path` on the server, which is encoded here.
+    fn url(&self, path: &str) -> String {
+        let scheme = if self.secure { "https" } else { "http" };
+        format!("{}://{}{}", scheme, self.host, uri_encode(path, true))
+    }
+
+    /// The uploads collection of a Nextcloud user, if the location is
+    /// inside that user's files.
+    fn nextcloud_uploads(&self) -> Option<String> {
+        let (root, rest) = self.path.split_once("/remote.php/dav/files/")?;
+        let (user, _) = rest.split_once('/')?;
+        Some(format!("{}/remote.php/dav/uploads/{}/", root, user))
+    }
+
+    fn describe(&self) -> String {
+        let scheme = if self.secure { "davs" } else { "dav" };
+        format!("{}://{}{}", scheme, self.host, self.path)
+    }
+}
+
+enum Credentials {
+    Anonymous,
+    Bearer(String),
+    /// `user:password` for basic authentication.
+    Basic(String),
+}
+
+/// Uploads outputs below a WebDAV collection.
+pub struct WebdavTarget {
+    location: WebdavLocation,
+    credentials: Credentials,
+    /// Collections already created below the location, by path.
+    created: Mutex<HashSet<String>>,
+    /// Directory for the chunks of chunked uploads.
+    work_dir: PathBuf,
+}
+
+impl WebdavTarget {
+    pub fn new(location: WebdavLocation, work_dir: &Path) -> std::io::Result<Self> {
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        let credentials = match (var("WEBDAV_TOKEN"), var("WEBDAV_PASSWORD"), &location.user) {
+            (Some(token), _, _) => Credentials::Bearer(token),
+            (None, Some(password), Some(user)) => {
+                Credentials::Basic(format!("{}:{}", user, password))
+            }
+            (None, Some(_), None) => {
+                return Err(std::io::Error::other(
+                    "WEBDAV_PASSWORD is set, but the location names no user",
+                ));
+            }
+            (None, None, _) => Credentials::Anonymous,
+        };
+        std::fs::create_dir_all(work_dir)?;
+        let target = WebdavTarget {
+            location,
+            credentials,
+            created: Mutex::new(HashSet::new()),
+            work_dir: work_dir.to_path_buf(),
+        };
+        // Fail at startup rather than on the first job
+        target
+            .request(&target.location.path, ["-X", "PROPFIND", "-H", "Depth: 0"])
+            .map_err(|e| {
+                std::io::Error::other(format!("cannot open {}: {}", target.location.describe(), e))
+            })?;
+        Ok(target)
+    }
+
+    /// Run one authorized request for `path` on the server.
+    fn request<S: AsRef<OsStr>>(
+        &self,
+        path: &str,
+        args: impl IntoIterator<Item = S>,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut config = format!("url = {}\n", curl::quote(&self.location.url(path)));
+        match &self.credentials {
+            Credentials::Anonymous => {}
+            Credentials::Bearer(token) => config.push_str(&format!(
+                "header = {}\n",
+                curl::quote(&format!("Authorization: Bearer {}", token))
+            )),
+            Credentials::Basic(user) => config.push_str(&format!("user = {}\n", curl::quote(user))),
+        }
+        let mut all: Vec<OsString> = vec!["-K".into(), "-".into()];
+        all.extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        curl::run("WebDAV", all, Some(config.as_bytes()))
+    }
+
+    /// Create the collections leading to `path`, below the location.
+    fn create_parents(&self, path: &str) {
+        let mut created = self.created.lock().unwrap();
+        let mut end = self.location.path.len();
+        while let Some(slash) = path[end..].find('/') {
+            end += slash + 1;
+            let dir = &path[..end];
+            if created.contains(dir) {
+                continue;
+            }
+            // An existing collection answers 405; a real problem shows up
+            // in the upload that follows
+            if let Err(e) = self.request(dir, ["-X", "MKCOL"]) {
+                debug!("MKCOL {} failed: {}", dir, e);
+            }
+            created.insert(dir.to_string());
+        }
+    }
+
+    fn put(&self, path: &str, file: &Path, content_type: &str) -> std::io::Result<()> {
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let partial = format!("{}/.{}.part", dir, name);
+        self.request(
+            &partial,
+            [
+                OsStr::new("-T"),
+                file.as_os_str(),
+                OsStr::new("-H"),
+                OsStr::new(&format!("Content-Type: {}", content_type)),
+            ],
+        )?;
+        self.request(
+            &partial,
+            [
+                "-X",
+                "MOVE",
+                "-H",
+                &format!("Destination: {}", self.location.url(path)),
+                "-H",
+                "Overwrite: T",
+            ],
+        )
+        .map(drop)
+    }
+
+    /// Upload through Nextcloud's chunked upload protocol (v2): the chunks
+    /// go into a temporary upload collection, which is then moved onto the
+    /// destination and assembled by the server.
+    fn put_chunked(&self, uploads: &str, path: &str, file: &Path) -> std::io::Result<()> {
+        let size = std::fs::metadata(file)?.len();
+        let upload = format!("{}transcoderexpress-{}/", uploads, jobs::new_id());
+        let destination = format!("Destination: {}", self.location.url(path));
+        let chunk_size = CHUNK_SIZE.max(size.div_ceil(MAX_CHUNKS));
+        let chunk_file = scratch_path(&self.work_dir, "webdav", "part");
+        let mut input = File::open(file)?;
+        let result = (|| {
+            self.request(&upload, ["-X", "MKCOL", "-H", &destination])?;
+            for number in 1..=size.div_ceil(chunk_size) {
+                let mut chunk = File::create(&chunk_file)?;
+                std::io::copy(&mut (&mut input).take(chunk_size), &mut chunk)?;
+                drop(chunk);
+                debug!("Uploading chunk {} of {:?}", number, file);
+                self.request(
+                    &format!("{}{:05}", upload, number),
+                    [
+                        OsStr::new("-T"),
+                        chunk_file.as_os_str(),
+                        OsStr::new("-H"),
+                        OsStr::new(&destination),
+                    ],
+                )?;
+            }
+            self.request(
+                &format!("{}.file", upload),
+                [
+                    "-X",
+                    "MOVE",
+                    "-H",
+                    &destination,
+                    "-H",
+                    &format!("OC-Total-Length: {}", size),
+                    "-H",
+                    "Overwrite: T",
+                ],
+            )
+            .map(drop)
+        })();
+        let _ = std::fs::remove_file(&chunk_file);
+        if result.is_err() {
+            let _ = self.request(&upload, ["-X", "DELETE"]);
+        }
+        result
+    }
+}
+
+impl RemoteTarget for WebdavTarget {
+    fn upload(&self, file: &Path, name: &str, content_type: &str) -> std::io::Result<()> {
+        let path = format!("{}{}", self.location.path, name);
+        self.create_parents(&path);
+        match self.location.nextcloud_uploads() {
+            Some(uploads) if std::fs::metadata(file)?.len() > CHUNK_THRESHOLD => {
+                self.put_chunked(&uploads, &path, file)
+            }
+            _ => self.put(&path, file, content_type),
+        }
+    }
+
+    fn describe(&self) -> String {
+        self.location.describe()
+    }
+}
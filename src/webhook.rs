@@ -0,0 +1,195 @@
+//! Inbound "file ready" webhooks, for upstream systems that announce files
+//! instead of dropping them where they can be watched.
+//!
+//! `POST /webhook` takes a JSON notification naming the file by `"path"`
+//! or `"url"`, an array of such notifications, or an object with a
+//! `"files"` array of them or of plain path and URL strings. Relative paths
+//! are resolved against the webhook root, and with a root, paths outside it
+//! are refused. Either every file of a request is queued, or none is.
+use crate::http::{Endpoint, Request, Response};
+use crate::json::{self, Value};
+use crate::{Submitter, fetch};
+use log::info;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Largest `POST /webhook` body accepted, in bytes.
+const MAX_BODY: u64 = 256 * 1024;
+
+/// An input announced by a notification.
+enum Input {
+    File(PathBuf),
+    Url(String),
+}
+
+/// The `/webhook` resource.
+pub struct WebhookEndpoint {
+    submitter: Submitter,
+    /// Where URL inputs are downloaded to.
+    dir: PathBuf,
+    /// The directory relative paths are resolved against, and the only one
+    /// files are accepted from.
+    root: Option<PathBuf>,
+}
+
+impl WebhookEndpoint {
+    pub fn new(submitter: Submitter, dir: impl Into<PathBuf>, root: Option<PathBuf>) -> Self {
+        WebhookEndpoint {
+            submitter,
+            dir: dir.into(),
+            root,
+        }
+    }
+
+    /// The file a notification names by path, checked to be a regular file
+    /// inside the root.
+    fn file(&self, path: &str) -> Result<PathBuf, String> {
+        let path = match &self.root {
+            Some(root) => root.join(path),
+            None if Path::new(path).is_absolute() => PathBuf::from(path),
+            None => return Err(format!("{:?} is not an absolute path", path)),
+        };
+        if !path.is_file() {
+            return Err(format!("{:?} is not a regular file", path));
+        }
+        if let Some(root) = &self.root {
+            // Resolving links and `..` so neither can reach outside the root
+            let inside = path
+                .canonicalize()
+                .ok()
+                .zip(root.canonicalize().ok())
+                .is_some_and(|(path, root)| path.starts_with(root));
+            if !inside {
+                return Err(format!("{:?} is outside {:?}", path, root));
+            }
+        }
+        Ok(path)
+    }
+
+    fn input(&self, notification: &Value) -> Result<Input, String> {
+        let (path, url) = match notification {
+            Value::String(s) if fetch::supported(s) => (None, Some(s.as_str())),
+            Value::String(s) => (Some(s.as_str()), None),
+            _ => (
+                notification.get("path").and_then(Value::as_str),
+                notification.get("url").and_then(Value::as_str),
+            ),
+        };
+        match (path, url) {
+            (Some(path), None) => self.file(path).map(Input::File),
+            (None, Some(url)) if fetch::supported(url) => Ok(Input::Url(url.to_string())),
+            (None, Some(url)) => Err(format!("{:?} is not an http:// or https:// URL", url)),
+            _ => Err("each file needs either a \"path\" or a \"url\"".to_string()),
+        }
+    }
+
+    fn notify(&self, request: &mut Request) -> Response {
+        if request.content_length().is_none_or(|n| n > MAX_BODY) {
+            return Response::error(411, "a JSON body of at most 256 KiB is required");
+        }
+        let mut body = String::new();
+        if request.body.read_to_string(&mut body).is_err() {
+            return Response::error(400, "body is not valid UTF-8");
+        }
+        let Some(payload) = json::parse(&body) else {
+            return Response::error(400, "body is not valid JSON");
+        };
+        let notifications = match (&payload, payload.get("files")) {
+            (Value::Array(items), _) => items.as_slice(),
+            (_, Some(files)) => files.as_array(),
+            _ => std::slice::from_ref(&payload),
+        };
+        if notifications.is_empty() {
+            return Response::error(400, "no files in the notification");
+        }
+        let inputs = match notifications
+            .iter()
+            .map(|n| self.input(n))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(inputs) => inputs,
+            Err(message) => return Response::error(400, &message),
+        };
+
+        let mut jobs = Vec::new();
+        for input in inputs {
+            let (id, name) = match input {
+                Input::File(path) => {
+                    let id = self.submitter.submit(&path);
+                    info!("Queued {:?} from a webhook as job {}", path, id);
+                    (id, path.to_string_lossy().into_owned())
+                }
+                Input::Url(url) => (fetch::submit_url(&self.submitter, &self.dir, &url), url),
+            };
+            jobs.push(
+                json::Object::new()
+                    .str("id", &id)
+                    .str("input", &name)
+                    .finish(),
+            );
+        }
+        Response::json(
+            202,
+            json::Object::new().raw("jobs", &json::array(jobs)).finish(),
+        )
+    }
+}
+
+impl Endpoint for WebhookEndpoint {
+    fn handle(&self, request: &mut Request) -> Option<Response> {
+        if request.path != "/webhook" {
+            return None;
+        }
+        Some(match request.method.as_str() {
+            "POST" => self.notify(request),
+            _ => Response::error(405, "use POST"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Body;
+    use crate::{Notifiers, Pipeline, TranscodeOptions};
+
+    fn post(endpoint: &WebhookEndpoint, body: &str) -> (u16, String) {
+        let mut reader = body.as_bytes();
+        let mut request = Request::with_body("POST", "/webhook", &mut reader, body.len() as u64);
+        let response = endpoint.handle(&mut request).unwrap();
+        let Body::Bytes(body) = response.body else {
+            panic!("streamed response");
+        };
+        (response.status, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn deeply_nested_bodies_are_refused() {
+        let pipeline = Pipeline::new(TranscodeOptions::default(), Notifiers::default());
+        let endpoint = WebhookEndpoint::new(pipeline.submitter(), std::env::temp_dir(), None);
+        let nested = "[".repeat(MAX_BODY as usize);
+        let (status, body) = post(&endpoint, &nested);
+        assert_eq!(status, 400);
+        assert!(body.contains("not valid JSON"), "{}", body);
+        let nested = format!(
+            "{}{{\"path\":\"/srv/in/call.wav\"}}{}",
+            "[".repeat(100),
+            "]".repeat(100)
+        );
+        assert_eq!(post(&endpoint, &nested).0, 400);
+    }
+
+    #[test]
+    fn files_are_queued() {
+        let dir = std::env::temp_dir().join(format!("webhook-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("call.wav"), "audio").unwrap();
+        let pipeline = Pipeline::new(TranscodeOptions::default(), Notifiers::default());
+        let endpoint = WebhookEndpoint::new(pipeline.submitter(), &dir, Some(dir.clone()));
+        let (status, body) = post(&endpoint, r#"{"files": ["call.wav"]}"#);
+        assert_eq!(status, 202, "{}", body);
+        assert!(body.contains("call.wav"), "{}", body);
+        assert_eq!(post(&endpoint, r#"{"path": "../call.wav"}"#).0, 400);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
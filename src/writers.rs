@@ -0,0 +1,98 @@
+//! Whether input files are still being written, e.g. by an upload in
+//! progress, so that they are not transcoded half-way.
+//!
+//! On Linux the open files of every process the user may look at are read
+//! from `/proc`; other Unix systems only see writers holding a `flock` on
+//! the file, and Windows writers that do not share it for writing.
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Those of `paths` that some process has open for writing.
+#[cfg(target_os = "linux")]
+pub fn open_for_writing(paths: &[&Path]) -> HashSet<PathBuf> {
+    use std::collections::HashMap;
+
+    let wanted: HashMap<PathBuf, &Path> = paths
+        .iter()
+        .filter_map(|path| Some((std::fs::canonicalize(path).ok()?, *path)))
+        .collect();
+    let mut open = HashSet::new();
+    if wanted.is_empty() {
+        return open;
+    }
+    let Ok(processes) = std::fs::read_dir("/proc") else {
+        return open;
+    };
+    for process in processes.flatten() {
+        let is_pid = process
+            .file_name()
+            .to_str()
+            .is_some_and(|name| !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit()));
+        // Those of other users cannot be read without privileges
+        let fds = match is_pid {
+            true => std::fs::read_dir(process.path().join("fd")),
+            false => continue,
+        };
+        for fd in fds.into_iter().flatten().flatten() {
+            let Some(path) = std::fs::read_link(fd.path())
+                .ok()
+                .and_then(|target| wanted.get(&target))
+            else {
+                continue;
+            };
+            let info = process.path().join("fdinfo").join(fd.file_name());
+            if std::fs::read_to_string(info).is_ok_and(|info| writable(&info)) {
+                open.insert(path.to_path_buf());
+            }
+        }
+    }
+    open
+}
+
+/// Whether the `/proc/<pid>/fdinfo/<fd>` in `info` is of a descriptor
+/// opened for writing.
+#[cfg(target_os = "linux")]
+fn writable(info: &str) -> bool {
+    info.lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .and_then(|flags| u32::from_str_radix(flags.trim(), 8).ok())
+        .is_some_and(|flags| flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32)
+}
+
+/// Those of `paths` that some process has open for writing.
+#[cfg(not(target_os = "linux"))]
+pub fn open_for_writing(paths: &[&Path]) -> HashSet<PathBuf> {
+    paths
+        .iter()
+        .filter(|path| held(path))
+        .map(|path| path.to_path_buf())
+        .collect()
+}
+
+/// Whether a writer holds a lock on `path` that keeps readers out.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn held(path: &Path) -> bool {
+    use std::os::fd::AsRawFd;
+
+    let Ok(file) = std::fs::File::open(path) else {
+        return false;
+    };
+    // SAFETY: the descriptor is open for the duration of the call, and the
+    // lock goes with it when the file is closed
+    let locked = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) };
+    locked != 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::EWOULDBLOCK)
+}
+
+/// Whether a writer has `path` open without sharing it for writing.
+#[cfg(windows)]
+fn held(path: &Path) -> bool {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_SHARE_READ: u32 = 0x1;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    std::fs::OpenOptions::new()
+        .read(true)
+        .share_mode(FILE_SHARE_READ)
+        .open(path)
+        .is_err_and(|e| e.raw_os_error() == Some(ERROR_SHARING_VIOLATION))
+}